@@ -1,12 +1,93 @@
+use crate::encryption::Encryptor;
+use crate::redact::{header_fields, HeaderRedactor, RedactMode};
+use crate::shred::overwrite_file;
+use crate::sink::{file_base, maildir_flags, maildir_name, ArchiveSink};
+use crate::strip::AttachmentStripper;
+use crate::sync::strip_sync_markers;
+use crate::transform::TransformStep;
 use maildir::{MailEntry, Maildir};
+use serde::{Deserialize, Serialize};
+use std::ffi::OsStr;
 use std::fmt;
 use std::fs::File;
 use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use time::format_description::well_known::Rfc2822;
+use time::OffsetDateTime;
 
 #[derive(Debug)]
 pub enum MaildirArchiverError {
     IoError(std::io::Error),
     MaildirError(maildir::MaildirError),
+    MailEntryError(maildir::MailEntryError),
+    DateError(time::error::ComponentRange),
+    EncryptionError(age::EncryptError),
+    #[cfg(feature = "compression")]
+    ZipError(zip::result::ZipError),
+    VerificationError(String),
+    Unsupported(String),
+    ParseError(mailparse::MailParseError),
+    RemoteError(String),
+    /// Archive folders needing more space than available on their filesystem
+    InsufficientSpace {
+        path: PathBuf,
+        required: u64,
+        available: u64,
+    },
+    /// Error while archiving an email, with its context
+    EmailError(Box<EmailContext>),
+    /// Error skipping an email, stopping the run with `--strict`
+    Strict(String),
+}
+
+/// Stage of the archiving of an email
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveStage {
+    Read,
+    Transform,
+    Store,
+    Remove,
+}
+
+impl fmt::Display for ArchiveStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stage = match self {
+            ArchiveStage::Read => "reading",
+            ArchiveStage::Transform => "transforming",
+            ArchiveStage::Store => "storing",
+            ArchiveStage::Remove => "removing",
+        };
+        write!(f, "{}", stage)
+    }
+}
+
+/// Context of an error while archiving an email
+#[derive(Debug)]
+pub struct EmailContext {
+    pub stage: ArchiveStage,
+    /// Id of the email in the source maildir
+    pub id: String,
+    /// Path of the email in the source maildir
+    pub path: PathBuf,
+    pub archive_folder: PathBuf,
+    pub error: MaildirArchiverError,
+}
+
+impl MaildirArchiverError {
+    /// Add the context of an email to the error, unless it already has one
+    pub fn in_context(self, stage: ArchiveStage, mail: &MailEntry, archive_folder: &Path) -> Self {
+        match self {
+            MaildirArchiverError::EmailError(_) => self,
+            error => MaildirArchiverError::EmailError(Box::new(EmailContext {
+                stage,
+                id: mail.id().to_string(),
+                path: mail.path().to_path_buf(),
+                archive_folder: archive_folder.to_path_buf(),
+                error,
+            })),
+        }
+    }
 }
 
 impl fmt::Display for MaildirArchiverError {
@@ -14,11 +95,60 @@ impl fmt::Display for MaildirArchiverError {
         let msg = match self {
             MaildirArchiverError::IoError(e) => format!("{}", e),
             MaildirArchiverError::MaildirError(e) => format!("{}", e),
+            MaildirArchiverError::MailEntryError(e) => format!("{}", e),
+            MaildirArchiverError::DateError(e) => format!("{}", e),
+            MaildirArchiverError::EncryptionError(e) => format!("{}", e),
+            #[cfg(feature = "compression")]
+            MaildirArchiverError::ZipError(e) => format!("{}", e),
+            MaildirArchiverError::VerificationError(e) => e.clone(),
+            MaildirArchiverError::Unsupported(e) => e.clone(),
+            MaildirArchiverError::ParseError(e) => format!("{}", e),
+            MaildirArchiverError::RemoteError(e) => e.clone(),
+            MaildirArchiverError::InsufficientSpace {
+                path,
+                required,
+                available,
+            } => format!(
+                "Not enough space for {}: {} bytes to write, {} bytes available",
+                path.display(),
+                required,
+                available
+            ),
+            MaildirArchiverError::EmailError(c) => format!(
+                "Error while {} email {} from {} to folder {}: {}",
+                c.stage,
+                c.id,
+                c.path.display(),
+                c.archive_folder.display(),
+                c.error
+            ),
+            MaildirArchiverError::Strict(e) => format!("Email not handled, stopping: {}", e),
         };
         write!(f, "{}", msg)
     }
 }
 
+impl std::error::Error for MaildirArchiverError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MaildirArchiverError::IoError(e) => Some(e),
+            MaildirArchiverError::MaildirError(e) => Some(e),
+            MaildirArchiverError::MailEntryError(e) => Some(e),
+            MaildirArchiverError::DateError(e) => Some(e),
+            MaildirArchiverError::EncryptionError(e) => Some(e),
+            #[cfg(feature = "compression")]
+            MaildirArchiverError::ZipError(e) => Some(e),
+            MaildirArchiverError::ParseError(e) => Some(e),
+            MaildirArchiverError::EmailError(c) => Some(&c.error),
+            MaildirArchiverError::VerificationError(_)
+            | MaildirArchiverError::Unsupported(_)
+            | MaildirArchiverError::RemoteError(_)
+            | MaildirArchiverError::Strict(_)
+            | MaildirArchiverError::InsufficientSpace { .. } => None,
+        }
+    }
+}
+
 impl From<std::io::Error> for MaildirArchiverError {
     fn from(value: std::io::Error) -> Self {
         MaildirArchiverError::IoError(value)
@@ -31,16 +161,54 @@ impl From<maildir::MaildirError> for MaildirArchiverError {
     }
 }
 
+impl From<maildir::MailEntryError> for MaildirArchiverError {
+    fn from(value: maildir::MailEntryError) -> Self {
+        MaildirArchiverError::MailEntryError(value)
+    }
+}
+
+impl From<time::error::ComponentRange> for MaildirArchiverError {
+    fn from(value: time::error::ComponentRange) -> Self {
+        MaildirArchiverError::DateError(value)
+    }
+}
+
+impl From<age::EncryptError> for MaildirArchiverError {
+    fn from(value: age::EncryptError) -> Self {
+        MaildirArchiverError::EncryptionError(value)
+    }
+}
+
+#[cfg(feature = "compression")]
+impl From<zip::result::ZipError> for MaildirArchiverError {
+    fn from(value: zip::result::ZipError) -> Self {
+        MaildirArchiverError::ZipError(value)
+    }
+}
+
+impl From<mailparse::MailParseError> for MaildirArchiverError {
+    fn from(value: mailparse::MailParseError) -> Self {
+        MaildirArchiverError::ParseError(value)
+    }
+}
+
 /// Trait implemented by the mail archiver.
 ///
 /// The function [`MaildirArchiver::archive_email`] is generally used in a loop.
-pub trait MaildirArchiver {
+/// The archive folder is the path computed from the split policy, its layout
+/// on disk is decided by the [`ArchiveSink`] of the archiver.
+pub trait MaildirArchiver: Send + Sync {
     fn archive_email(
         &self,
         mail: &MailEntry,
         from_maildir: &Maildir,
-        to_maildir: &Maildir,
+        archive_folder: &Path,
     ) -> Result<(), MaildirArchiverError>;
+
+    /// Complete pending writes, called once after the last archived email
+    fn finish(&self) -> Result<(), MaildirArchiverError> {
+        Ok(())
+    }
 }
 
 /// Dry run archiver
@@ -51,162 +219,681 @@ impl MaildirArchiver for DryRunMaildirArchiver {
         &self,
         _mail: &MailEntry,
         _from_maildir: &Maildir,
-        _to_maildir: &Maildir,
+        _archive_folder: &Path,
     ) -> Result<(), MaildirArchiverError> {
         Ok(())
     }
 }
 
+/// How the archived emails are removed from the source maildir
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeleteStrategy {
+    /// Remove the files
+    #[default]
+    Unlink,
+    /// Move the emails to the `.Trash` folder of the maildir, with the `T`
+    /// flag, leaving their deletion to the mail client
+    Trash,
+}
+
+/// Transformations applied to the emails before they are stored, and removal
+/// of the archived emails
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct StoreOptions {
+    pub encryptor: Option<Encryptor>,
+    pub attachment_stripper: Option<AttachmentStripper>,
+    pub header_redactor: Option<HeaderRedactor>,
+    pub stamp_provenance: bool,
+    /// Order of the transformations, completed with the default order
+    #[serde(default)]
+    pub transform_order: Vec<TransformStep>,
+    #[serde(default)]
+    pub delete_via: DeleteStrategy,
+    /// Overwrite the removed emails before unlinking them
+    #[serde(default)]
+    pub secure_delete: bool,
+    /// Remove the markers of the IMAP synchronization tools from the ids of
+    /// the emails moved to the trash
+    #[serde(default)]
+    pub sync_safe: bool,
+    /// Read-ahead cache of the emails to archive
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    #[serde(skip)]
+    pub read_ahead: Option<std::sync::Arc<crate::uring::ReadAhead>>,
+}
+
+/// Path with a suffix appended to its last component, like `2016.zip`,
+/// without converting it to UTF-8
+pub fn path_with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_os_string();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Trash folder of a source maildir: the `.Trash` folder of its Maildir++
+/// root, the parent of the subfolders
+pub fn trash_folder(maildir: &Path) -> PathBuf {
+    let is_subfolder = maildir
+        .file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+    match (is_subfolder, maildir.parent()) {
+        (true, Some(root)) => root.join(".Trash"),
+        _ => maildir.join(".Trash"),
+    }
+}
+
+/// Unlink an email of a source maildir, overwriting it first if requested by
+/// the options
+pub(crate) fn unlink_email(
+    mail: &MailEntry,
+    options: &StoreOptions,
+) -> Result<(), MaildirArchiverError> {
+    unlink_file(mail.path(), options)
+}
+
+fn unlink_file(path: &Path, options: &StoreOptions) -> Result<(), MaildirArchiverError> {
+    if options.secure_delete {
+        overwrite_file(path)?;
+    }
+    // Unlike Maildir::delete, the path is not found again from the lossy id
+    Ok(std::fs::remove_file(path)?)
+}
+
+/// Remove an email from a source maildir as requested by the options. The
+/// emails of the trash folder are always unlinked.
+pub(crate) fn delete_email(
+    from_maildir: &Maildir,
+    mail: &MailEntry,
+    options: &StoreOptions,
+) -> Result<(), MaildirArchiverError> {
+    delete_file(from_maildir, mail.path(), mail.flags(), options)
+}
+
+/// Remove the file of an email with the given flags, see [`delete_email`]
+fn delete_file(
+    from_maildir: &Maildir,
+    path: &Path,
+    flags: &str,
+    options: &StoreOptions,
+) -> Result<(), MaildirArchiverError> {
+    let trash = trash_folder(from_maildir.path());
+    if options.delete_via == DeleteStrategy::Unlink || trash == from_maildir.path() {
+        return unlink_file(path, options);
+    }
+    let trash = Maildir::from(trash);
+    trash.create_dirs()?;
+    let flags = maildir_flags(&format!("{}T", flags), false);
+    let id = match options.sync_safe {
+        true => strip_sync_markers(file_base(path)),
+        false => file_base(path).to_os_string(),
+    };
+    let info = format!("2,{}", flags);
+    let trash_path = trash
+        .path()
+        .join("cur")
+        .join(maildir_name(&id, OsStr::new(&info)));
+    std::fs::rename(path, trash_path)?;
+    Ok(())
+}
+
+/// Line ending used by an email
+pub(crate) fn line_ending(data: &[u8]) -> &'static str {
+    match data.windows(2).any(|w| w == b"\r\n") {
+        true => "\r\n",
+        false => "\n",
+    }
+}
+
+/// `X-Archived-At` and `X-Original-Maildir` headers recording when and from
+/// which maildir an email has been archived
+pub(crate) fn provenance_headers(
+    from_maildir: &Maildir,
+    eol: &str,
+) -> Result<String, MaildirArchiverError> {
+    let archived_at = OffsetDateTime::now_utc()
+        .format(&Rfc2822)
+        .map_err(|e| MaildirArchiverError::Unsupported(e.to_string()))?;
+    let maildir = std::fs::canonicalize(from_maildir.path())
+        .unwrap_or_else(|_| from_maildir.path().to_path_buf());
+    Ok(format!(
+        "X-Archived-At: {}{}X-Original-Maildir: {}{}",
+        archived_at,
+        eol,
+        maildir.display(),
+        eol
+    ))
+}
+
+/// Store a copy of the email in the destination sink, transformed by the
+/// pipeline of the options (redaction of the headers, provenance stamp,
+/// detaching of the large attachments and encryption).
+fn store_email(
+    mail: &MailEntry,
+    from_maildir: &Maildir,
+    archive_folder: &Path,
+    options: &StoreOptions,
+    sink: &dyn ArchiveSink,
+) -> Result<(), MaildirArchiverError> {
+    let context = |stage| move |e: MaildirArchiverError| e.in_context(stage, mail, archive_folder);
+    let mut buff = Vec::<u8>::new();
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    if let Some(data) = options
+        .read_ahead
+        .as_ref()
+        .and_then(|r| r.take(mail.path()))
+    {
+        buff = data;
+    }
+    if buff.is_empty() {
+        File::open(mail.path())
+            .and_then(|mut file| file.read_to_end(&mut buff))
+            .map_err(|e| context(ArchiveStage::Read)(e.into()))?;
+    }
+    for transformer in options.transformers() {
+        buff = transformer
+            .transform(buff, from_maildir)
+            .map_err(context(ArchiveStage::Transform))?;
+    }
+    sink.store(archive_folder, mail, &buff)
+        .map_err(context(ArchiveStage::Store))
+}
+
+/// Remove an archived email from the source maildir
+fn remove_email(
+    mail: &MailEntry,
+    from_maildir: &Maildir,
+    archive_folder: &Path,
+    options: &StoreOptions,
+) -> Result<(), MaildirArchiverError> {
+    delete_email(from_maildir, mail, options)
+        .map_err(|e| e.in_context(ArchiveStage::Remove, mail, archive_folder))
+}
+
+/// Source email stored by a sink writing the emails at its end, see
+/// [`ArchiveSink::deferred`]
+struct PendingEmail {
+    maildir: Maildir,
+    id: String,
+    path: PathBuf,
+    flags: String,
+    archive_folder: PathBuf,
+}
+
+/// Source emails removed once their sink is completed
+#[derive(Default)]
+struct PendingEmails(Mutex<Vec<PendingEmail>>);
+
+impl PendingEmails {
+    fn push(&self, mail: &MailEntry, from_maildir: &Maildir, archive_folder: &Path) {
+        self.0.lock().unwrap().push(PendingEmail {
+            maildir: Maildir::from(from_maildir.path().to_path_buf()),
+            id: mail.id().to_string(),
+            path: mail.path().to_path_buf(),
+            flags: mail.flags().to_string(),
+            archive_folder: archive_folder.to_path_buf(),
+        });
+    }
+
+    /// Remove all the pending emails, returning the first error
+    fn remove<F>(&self, remove: F) -> Result<(), MaildirArchiverError>
+    where
+        F: Fn(&PendingEmail) -> Result<(), MaildirArchiverError>,
+    {
+        let mut result = Ok(());
+        for email in self.0.lock().unwrap().drain(..) {
+            if let Err(error) = remove(&email) {
+                let error = MaildirArchiverError::EmailError(Box::new(EmailContext {
+                    stage: ArchiveStage::Remove,
+                    id: email.id,
+                    path: email.path,
+                    archive_folder: email.archive_folder,
+                    error,
+                }));
+                result = result.and(Err(error));
+            }
+        }
+        result
+    }
+}
+
 /// Archiver that move email from one maildir to another
-struct MoveMaildirArchiver {}
+struct MoveMaildirArchiver {
+    options: StoreOptions,
+    sink: Box<dyn ArchiveSink>,
+    pending: PendingEmails,
+}
 
 impl MaildirArchiver for MoveMaildirArchiver {
     fn archive_email(
         &self,
         mail: &MailEntry,
         from_maildir: &Maildir,
-        to_maildir: &Maildir,
+        archive_folder: &Path,
     ) -> Result<(), MaildirArchiverError> {
-        let mut file = File::open(mail.path())?;
-        let mut buff = Vec::<u8>::new();
+        store_email(
+            mail,
+            from_maildir,
+            archive_folder,
+            &self.options,
+            &*self.sink,
+        )?;
+        match self.sink.deferred() {
+            true => {
+                self.pending.push(mail, from_maildir, archive_folder);
+                Ok(())
+            }
+            false => remove_email(mail, from_maildir, archive_folder, &self.options),
+        }
+    }
 
-        to_maildir.create_dirs()?;
-        file.read_to_end(&mut buff)?;
-        to_maildir.store_cur_with_flags(&buff, mail.flags())?;
-        from_maildir.delete(mail.id())?;
-        Ok(())
+    /// Complete the sink, then remove the emails it stored at its end. The
+    /// emails are kept if the sink can't be completed.
+    fn finish(&self) -> Result<(), MaildirArchiverError> {
+        self.sink.finish()?;
+        self.pending
+            .remove(|email| delete_file(&email.maildir, &email.path, &email.flags, &self.options))
     }
 }
 
 /// Archiver that copy email from one maildir to another
-struct CopyMaildirArchiver {}
+struct CopyMaildirArchiver {
+    options: StoreOptions,
+    sink: Box<dyn ArchiveSink>,
+}
 
 impl MaildirArchiver for CopyMaildirArchiver {
     fn archive_email(
         &self,
         mail: &MailEntry,
-        _from_maildir: &Maildir,
-        to_maildir: &Maildir,
+        from_maildir: &Maildir,
+        archive_folder: &Path,
     ) -> Result<(), MaildirArchiverError> {
-        let mut file = File::open(mail.path())?;
-        let mut buff = Vec::<u8>::new();
+        store_email(
+            mail,
+            from_maildir,
+            archive_folder,
+            &self.options,
+            &*self.sink,
+        )
+    }
 
-        to_maildir.create_dirs()?;
-        file.read_to_end(&mut buff)?;
-        to_maildir.store_cur_with_flags(&buff, mail.flags())?;
-        Ok(())
+    fn finish(&self) -> Result<(), MaildirArchiverError> {
+        self.sink.finish()
+    }
+}
+
+/// Header marking the stubs left in the source maildir, pointing to the
+/// archive folder of the email
+pub const STUB_HEADER: &str = "X-Archive-Stub";
+
+/// Stub of an archived email: the original headers, without the MIME ones,
+/// and a short text body pointing to the archive folder
+fn email_stub(data: &[u8], archive_folder: &Path) -> Result<Vec<u8>, MaildirArchiverError> {
+    let eol = line_ending(data);
+    let (_, body) = header_fields(data);
+    let mime_headers = [
+        "Content-Type".to_string(),
+        "Content-Transfer-Encoding".to_string(),
+        "Content-Disposition".to_string(),
+        "MIME-Version".to_string(),
+    ];
+    let redactor = HeaderRedactor::new(&mime_headers, RedactMode::Remove);
+    let mut stub = redactor.redact(&data[..body])?;
+    let folder =
+        std::fs::canonicalize(archive_folder).unwrap_or_else(|_| archive_folder.to_path_buf());
+    stub.extend_from_slice(
+        format!(
+            "{}: {}{}MIME-Version: 1.0{}Content-Type: text/plain; charset=utf-8{}{}\
+             This email has been archived to {}{}",
+            STUB_HEADER,
+            folder.display(),
+            eol,
+            eol,
+            eol,
+            eol,
+            folder.display(),
+            eol
+        )
+        .as_bytes(),
+    );
+    Ok(stub)
+}
+
+/// Replace an email of a source maildir by its stub, keeping its name and
+/// flags. The email is overwritten first if requested by the options.
+fn replace_with_stub(
+    path: &Path,
+    from_maildir: &Maildir,
+    archive_folder: &Path,
+    options: &StoreOptions,
+) -> Result<(), MaildirArchiverError> {
+    let stub = email_stub(&std::fs::read(path)?, archive_folder)?;
+    // Replace the email atomically
+    let tmp = from_maildir
+        .path()
+        .join("tmp")
+        .join(path.file_name().unwrap());
+    std::fs::write(&tmp, stub)?;
+    if options.secure_delete {
+        overwrite_file(path)?;
     }
+    std::fs::rename(&tmp, path)?;
+    Ok(())
 }
 
+/// Archiver that move email from one maildir to another, leaving a stub with
+/// the original headers in the source maildir
+struct StubMaildirArchiver {
+    options: StoreOptions,
+    sink: Box<dyn ArchiveSink>,
+    pending: PendingEmails,
+}
+
+impl MaildirArchiver for StubMaildirArchiver {
+    fn archive_email(
+        &self,
+        mail: &MailEntry,
+        from_maildir: &Maildir,
+        archive_folder: &Path,
+    ) -> Result<(), MaildirArchiverError> {
+        store_email(
+            mail,
+            from_maildir,
+            archive_folder,
+            &self.options,
+            &*self.sink,
+        )?;
+        if self.sink.deferred() {
+            self.pending.push(mail, from_maildir, archive_folder);
+            return Ok(());
+        }
+        replace_with_stub(mail.path(), from_maildir, archive_folder, &self.options)
+            .map_err(|e| e.in_context(ArchiveStage::Remove, mail, archive_folder))
+    }
+
+    /// Complete the sink, then replace the emails it stored at its end
+    fn finish(&self) -> Result<(), MaildirArchiverError> {
+        self.sink.finish()?;
+        self.pending.remove(|email| {
+            replace_with_stub(
+                &email.path,
+                &email.maildir,
+                &email.archive_folder,
+                &self.options,
+            )
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum ArchiveMode {
     Move,
     Copy,
     DryRun,
+    Stub,
 }
 
-/// Factory method that creates an archiver
-pub fn create_mail_archiver(mode: ArchiveMode) -> Box<dyn MaildirArchiver> {
+/// Factory method that creates an archiver.
+///
+/// Archived emails are written to `sink`, transformed according to `options`.
+pub fn create_mail_archiver(
+    mode: ArchiveMode,
+    options: StoreOptions,
+    sink: Box<dyn ArchiveSink>,
+) -> Box<dyn MaildirArchiver> {
     match mode {
         ArchiveMode::DryRun => Box::new(DryRunMaildirArchiver {}),
-        ArchiveMode::Move => Box::new(MoveMaildirArchiver {}),
-        ArchiveMode::Copy => Box::new(CopyMaildirArchiver {}),
+        ArchiveMode::Move => Box::new(MoveMaildirArchiver {
+            options,
+            sink,
+            pending: PendingEmails::default(),
+        }),
+        ArchiveMode::Copy => Box::new(CopyMaildirArchiver { options, sink }),
+        ArchiveMode::Stub => Box::new(StubMaildirArchiver {
+            options,
+            sink,
+            pending: PendingEmails::default(),
+        }),
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::test_support::{TestEmail, TestMaildir};
     use maildir::Maildir;
-    use std::path::PathBuf;
-
-    struct MaildirRaii {
-        basedir: PathBuf,
-        input_maildir: Maildir,
-        output_maildir: Maildir,
-    }
-
-    impl MaildirRaii {
-        fn new() -> Self {
-            use mktemp::Temp;
-            let basedir = Temp::new_dir().unwrap().to_path_buf();
-            println!("{}", basedir.display());
-            let input_maildir = Maildir::from(basedir.join("in"));
-            let output_maildir = Maildir::from(basedir.join("out"));
-            input_maildir.create_dirs().unwrap();
-
-            let filename = "1463868505.38518452d49213cb409aa1db32f53184:2,S";
-            std::fs::copy(
-                format!("testdata/maildir1/cur/{}", filename),
-                input_maildir.path().join("cur").join(filename),
-            )
-            .unwrap();
-
-            MaildirRaii {
-                basedir: basedir,
-                input_maildir: input_maildir,
-                output_maildir: output_maildir,
-            }
-        }
-    }
 
-    impl Drop for MaildirRaii {
-        fn drop(&mut self) {
-            std::fs::remove_dir_all(&self.basedir).unwrap();
-        }
+    /// Input maildir with one email
+    fn test_maildir() -> TestMaildir {
+        let maildir = TestMaildir::new();
+        maildir.add(&TestEmail::new());
+        maildir
     }
 
     #[test]
     fn test_move_archive_email() {
         use crate::archiver::MaildirArchiver;
         use crate::archiver::MoveMaildirArchiver;
+        use crate::archiver::StoreOptions;
+        use crate::sink::MaildirSink;
 
-        let maildir = MaildirRaii::new();
-        let archiver = MoveMaildirArchiver {};
+        let maildir = test_maildir();
+        let archiver = MoveMaildirArchiver {
+            options: StoreOptions::default(),
+            sink: Box::new(MaildirSink::default()),
+            pending: Default::default(),
+        };
         let mail = maildir.input_maildir.list_cur().next().unwrap().unwrap();
 
         assert_eq!(maildir.input_maildir.count_cur(), 1);
         assert_eq!(maildir.output_maildir.count_cur(), 0);
         archiver
-            .archive_email(&mail, &maildir.input_maildir, &maildir.output_maildir)
+            .archive_email(&mail, &maildir.input_maildir, maildir.output_maildir.path())
             .unwrap();
         assert_eq!(maildir.input_maildir.count_cur(), 0);
         assert!(maildir.output_maildir.path().exists());
         assert_eq!(maildir.output_maildir.count_cur(), 1);
     }
 
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_deferred_move() {
+        use crate::archiver::{create_mail_archiver, ArchiveMode, StoreOptions};
+        use crate::sink::ZipSink;
+
+        let maildir = test_maildir();
+        let archiver = create_mail_archiver(
+            ArchiveMode::Move,
+            StoreOptions::default(),
+            Box::new(ZipSink::default()),
+        );
+        let mail = maildir.input_maildir.list_cur().next().unwrap().unwrap();
+        let archive_folder = maildir.archive_folder("2016");
+        archiver
+            .archive_email(&mail, &maildir.input_maildir, archive_folder.path())
+            .unwrap();
+        // The source is kept until the zip file is completed
+        assert_eq!(maildir.input_maildir.count_cur(), 1);
+        let zip = maildir.output_dir().join("2016.zip");
+        assert!(!zip.exists());
+        archiver.finish().unwrap();
+        assert!(zip.exists());
+        assert_eq!(maildir.input_maildir.count_cur(), 0);
+    }
+
+    #[test]
+    fn test_delete_via_trash() {
+        use crate::archiver::{delete_email, trash_folder, DeleteStrategy, StoreOptions};
+        use std::path::Path;
+
+        assert_eq!(trash_folder(Path::new("/mail")), Path::new("/mail/.Trash"));
+        assert_eq!(
+            trash_folder(Path::new("/mail/.Sent")),
+            Path::new("/mail/.Trash")
+        );
+
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let sent = Maildir::from(basedir.join(".Sent"));
+        sent.create_dirs().unwrap();
+        let id = sent
+            .store_cur_with_flags(b"Subject: a\n\nbody\n", "S")
+            .unwrap();
+        let options = StoreOptions {
+            delete_via: DeleteStrategy::Trash,
+            ..Default::default()
+        };
+        delete_email(&sent, &sent.find(&id).unwrap(), &options).unwrap();
+        assert_eq!(sent.count_cur(), 0);
+        let trash = Maildir::from(basedir.join(".Trash"));
+        let mail = trash.find(&id).unwrap();
+        assert_eq!(mail.flags(), "ST");
+
+        // Emails deleted from the trash are removed
+        delete_email(&trash, &mail, &options).unwrap();
+        assert_eq!(trash.count_cur(), 0);
+    }
+
     #[test]
     fn test_copy_archive_email() {
         use crate::archiver::CopyMaildirArchiver;
         use crate::archiver::MaildirArchiver;
+        use crate::archiver::StoreOptions;
+        use crate::sink::MaildirSink;
 
-        let maildir = MaildirRaii::new();
-        let archiver = CopyMaildirArchiver {};
+        let maildir = test_maildir();
+        let archiver = CopyMaildirArchiver {
+            options: StoreOptions::default(),
+            sink: Box::new(MaildirSink::default()),
+        };
         let mail = maildir.input_maildir.list_cur().next().unwrap().unwrap();
 
         assert_eq!(maildir.input_maildir.count_cur(), 1);
         assert_eq!(maildir.output_maildir.count_cur(), 0);
         archiver
-            .archive_email(&mail, &maildir.input_maildir, &maildir.output_maildir)
+            .archive_email(&mail, &maildir.input_maildir, maildir.output_maildir.path())
             .unwrap();
         assert_eq!(maildir.input_maildir.count_cur(), 1);
         assert!(maildir.output_maildir.path().exists());
         assert_eq!(maildir.output_maildir.count_cur(), 1);
     }
 
+    #[test]
+    fn test_stamp_provenance() {
+        use crate::archiver::CopyMaildirArchiver;
+        use crate::archiver::MaildirArchiver;
+        use crate::archiver::StoreOptions;
+        use crate::sink::MaildirSink;
+
+        let maildir = test_maildir();
+        let archiver = CopyMaildirArchiver {
+            options: StoreOptions {
+                stamp_provenance: true,
+                ..Default::default()
+            },
+            sink: Box::new(MaildirSink::default()),
+        };
+        let mail = maildir.input_maildir.list_cur().next().unwrap().unwrap();
+        archiver
+            .archive_email(&mail, &maildir.input_maildir, maildir.output_maildir.path())
+            .unwrap();
+
+        let mut archived = maildir.output_maildir.list_cur().next().unwrap().unwrap();
+        let headers = archived.headers().unwrap();
+        assert!(headers.iter().any(|h| h.get_key() == "X-Archived-At"));
+        let original = headers
+            .iter()
+            .find(|h| h.get_key() == "X-Original-Maildir")
+            .unwrap()
+            .get_value();
+        assert!(original.ends_with("/in"));
+    }
+
+    #[test]
+    fn test_stub_archive_email() {
+        use crate::archiver::MaildirArchiver;
+        use crate::archiver::StoreOptions;
+        use crate::archiver::StubMaildirArchiver;
+        use crate::sink::MaildirSink;
+
+        let maildir = test_maildir();
+        let archiver = StubMaildirArchiver {
+            options: StoreOptions {
+                secure_delete: true,
+                ..Default::default()
+            },
+            sink: Box::new(MaildirSink::default()),
+            pending: Default::default(),
+        };
+        let mail = maildir.input_maildir.list_cur().next().unwrap().unwrap();
+        let original = std::fs::read(mail.path()).unwrap();
+        // The original content is overwritten before being replaced
+        let link = maildir.input_maildir.path().join("link");
+        std::fs::hard_link(mail.path(), &link).unwrap();
+        archiver
+            .archive_email(&mail, &maildir.input_maildir, maildir.output_maildir.path())
+            .unwrap();
+
+        let archived = maildir.output_maildir.list_cur().next().unwrap().unwrap();
+        assert_eq!(std::fs::read(archived.path()).unwrap(), original);
+        let mut stub = maildir.input_maildir.find(mail.id()).unwrap();
+        assert_eq!(stub.flags(), mail.flags());
+        let headers = stub.headers().unwrap();
+        assert!(headers.iter().any(|h| h.get_key() == "X-Archive-Stub"));
+        assert!(headers.iter().any(|h| h.get_key() == "Subject"));
+        let body = stub.parsed().unwrap().get_body().unwrap();
+        assert!(body.starts_with("This email has been archived to "));
+        assert!(std::fs::read(link).unwrap().iter().all(|b| *b == 0));
+    }
+
     #[test]
     fn test_dryrun_archive_email() {
         use crate::archiver::DryRunMaildirArchiver;
         use crate::archiver::MaildirArchiver;
 
-        let maildir = MaildirRaii::new();
+        let maildir = test_maildir();
         let archiver = DryRunMaildirArchiver {};
         let mail = maildir.input_maildir.list_cur().next().unwrap().unwrap();
 
         assert_eq!(maildir.input_maildir.count_cur(), 1);
         assert_eq!(maildir.output_maildir.count_cur(), 0);
         archiver
-            .archive_email(&mail, &maildir.input_maildir, &maildir.output_maildir)
+            .archive_email(&mail, &maildir.input_maildir, maildir.output_maildir.path())
             .unwrap();
         assert_eq!(maildir.input_maildir.count_cur(), 1);
         assert!(!maildir.output_maildir.path().exists());
     }
+
+    #[test]
+    fn test_error_context() {
+        use crate::archiver::{ArchiveStage, MaildirArchiver, MaildirArchiverError};
+        use crate::archiver::{MoveMaildirArchiver, StoreOptions};
+        use crate::sink::MaildirSink;
+        use std::error::Error;
+
+        let maildir = test_maildir();
+        let archiver = MoveMaildirArchiver {
+            options: StoreOptions::default(),
+            sink: Box::new(MaildirSink::default()),
+            pending: Default::default(),
+        };
+        let mail = maildir.input_maildir.list_cur().next().unwrap().unwrap();
+        // The archive folder can't be created under a file
+        let archive_folder = mail.path().join("2016");
+        let e = archiver
+            .archive_email(&mail, &maildir.input_maildir, &archive_folder)
+            .unwrap_err();
+        match &e {
+            MaildirArchiverError::EmailError(context) => {
+                assert_eq!(context.stage, ArchiveStage::Store);
+                assert_eq!(context.id, mail.id());
+                assert_eq!(context.archive_folder, archive_folder);
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+        assert!(e.to_string().starts_with("Error while storing email "));
+        assert!(e.source().is_some());
+        assert_eq!(maildir.input_maildir.count_cur(), 1);
+    }
 }
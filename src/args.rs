@@ -1,11 +1,27 @@
-use crate::archiver::*;
-use clap::builder::PossibleValue;
-use clap::{arg, command, ArgAction};
-use log::LevelFilter;
+use crate::archiver::{ArchiveMode, StoreOptions};
+use crate::attributes::Account;
+#[cfg(feature = "compression")]
+use crate::compact::CompactFormat;
+use crate::config::Config;
+use crate::dedup::DedupAction;
+#[cfg(feature = "compression")]
+use crate::encryption::Encryptor;
+use crate::filter::HookInput;
+use crate::folders::FolderNaming;
+use crate::index::IndexFormat;
+use crate::orphans::OrphanAction;
+use crate::routes::Routes;
+use crate::script::DryRunFormat;
+use crate::sink::{ArchiveFormat, SinkOptions};
+use crate::spam::SpamRouting;
+pub use crate::split::{MailOrder, SplitBy};
+use crate::stats::HistogramPeriod;
+use crate::utf7::NameEncoding;
+use crate::views::ViewAxis;
 use maildir::Maildir;
+use regex::Regex;
 use std::path::PathBuf;
-use time::macros::format_description;
-use time::{Date, OffsetDateTime};
+use time::Date;
 
 pub struct ProgramOptions {
     pub input_maildir: Maildir,
@@ -15,123 +31,207 @@ pub struct ProgramOptions {
     pub prefix: String,
     pub suffix: String,
     pub split_by: SplitBy,
-    pub verbosity: LevelFilter,
-}
-
-pub enum SplitBy {
-    Year,
-    Day,
-    Month,
-    None,
-}
-
-fn one_year_ago() -> Date {
-    let now = OffsetDateTime::now_utc();
-    now.replace_year(now.year() - 1).unwrap().date()
-}
-
-pub fn parse_args() -> ProgramOptions {
-    let before_default = one_year_ago().to_string();
-    let matches = command!()
-        .version(env!("CARGO_PKG_VERSION"))
-        .about("Archive emails from maildir, grouping them by date")
-        .arg(
-            arg!("prefix")
-                .short('p')
-                .long("prefix")
-                .value_name("PREFIX")
-                .help("Prefix format")
-                .default_value(""),
-        )
-        .arg(
-            arg!("suffix")
-                .short('s')
-                .long("suffix")
-                .value_name("SUFFIX")
-                .help("Suffix format")
-                .default_value(""),
-        )
-        .arg(
-            arg!("split-by")
-                .short('S')
-                .long("split-by")
-                .value_name("PERIOD")
-                .help("Set the split policy")
-                .value_parser([
-                    PossibleValue::new("year"),
-                    PossibleValue::new("month"),
-                    PossibleValue::new("day"),
-                    PossibleValue::new("none"),
-                ])
-                .default_value("year"),
-        )
-        .arg(
-            arg!("mode")
-                .short('m')
-                .long("mode")
-                .help("Archive mode")
-                .value_parser([
-                    PossibleValue::new("copy"),
-                    PossibleValue::new("move"),
-                    PossibleValue::new("dry-run"),
-                ])
-                .default_value("dry-run"),
-        )
-        .arg(
-            arg!("before")
-                .short('b')
-                .long("before")
-                .default_value(before_default)
-                .value_name("YYYY-mm-dd")
-                .help("Archive emails before the given date"),
-        )
-        .arg(
-            arg!("verbose")
-                .short('v')
-                .long("verbose")
-                .help("Set verbosity")
-                .action(ArgAction::Count),
-        )
-        .arg(
-            arg!("input-maildir")
-                .required(true)
-                .value_name("INPUT_PATH")
-                .help("Input maildir path")
-                .index(1),
-        )
-        .arg(
-            arg!("output-dir")
-                .required(true)
-                .value_name("OUTPUT_PATH")
-                .help("Output directory for archive maildirs")
-                .index(2),
-        )
-        .get_matches();
-    let dateformat = format_description!("[year]-[month]-[day]");
-    let p = ProgramOptions {
-        input_maildir: (*matches.get_one::<String>("input-maildir").unwrap().clone()).into(),
-        output_dir: (*matches.get_one::<PathBuf>("output-dir").unwrap().clone()).to_path_buf(),
-        before: Date::parse(matches.get_one::<String>("before").unwrap(), &dateformat).unwrap(),
-        prefix: matches.get_one::<String>("prefix").unwrap().clone(),
-        suffix: matches.get_one::<String>("suffix").unwrap().clone(),
-        split_by: match matches.get_one::<String>("split-by").unwrap().as_str() {
-            "day" => SplitBy::Day,
-            "month" => SplitBy::Month,
-            "year" => SplitBy::Year,
-            _ => SplitBy::None,
-        },
-        verbosity: match matches.get_count("verbose") {
-            0 => LevelFilter::Off,
-            1 => LevelFilter::Error,
-            2 => LevelFilter::Warn,
-            3 => LevelFilter::Info,
-            _ => LevelFilter::Debug,
-        },
-        archive_mode: match matches.get_one::<String>("mode").unwrap().as_str() {
-            "copy" => ArchiveMode::Copy,
-            "move" => ArchiveMode::Move,
-            _ => ArchiveMode::DryRun,
-        },
-    };
-    p
+    /// Number of emails of an archive folder after which the next part is
+    /// started, numbered by the sequence token of the prefix or the suffix
+    pub rotate_after: Option<usize>,
+    /// Size in bytes above which the emails are archived to the
+    /// `big_bucket` folder, whatever the split policy
+    pub big_threshold: Option<u64>,
+    pub big_bucket: String,
+    pub order: MailOrder,
+    pub limit: Option<usize>,
+    /// Number of source folders archived in parallel
+    pub jobs: usize,
+    pub dry_run_format: DryRunFormat,
+    pub incremental: bool,
+    pub state_file: Option<PathBuf>,
+    pub recursive: bool,
+    /// Archive also the emails of the `new` directories
+    pub include_new: bool,
+    /// Check the free space of the archive folders before archiving each
+    /// source folder
+    pub check_free_space: bool,
+    /// In move mode, copy the emails and leave their deletion to `expunge`
+    pub defer_delete: bool,
+    /// Command running a preauthenticated IMAP server of the input maildir,
+    /// removing the purged spam and the emails archived in move mode
+    pub imap_command: Option<String>,
+    /// Directory of the snapshots of the emails removed by the runs
+    pub snapshot: Option<PathBuf>,
+    /// Number of snapshots kept
+    pub snapshot_keep: usize,
+    /// Format of the index of the archived emails written in the output
+    /// directory by each run
+    pub index: Option<IndexFormat>,
+    /// Restrict the run to changing the files of the input and output trees
+    pub sandbox: bool,
+    /// User whose privileges are taken before touching any file
+    pub run_as: Option<Account>,
+    /// Report the end of the run with a desktop notification
+    pub notify: bool,
+    /// File descriptor receiving the progress events, as JSON lines
+    pub progress_fd: Option<i32>,
+    /// Log file of `--log-file`, rotated under the sandbox
+    pub log_file: Option<PathBuf>,
+    /// Stop the run at the first email skipped because of an error
+    pub strict: bool,
+    /// Patterns of the source folders to archive, all if empty
+    pub include_folders: Vec<String>,
+    /// Patterns of the source folders not to archive
+    pub exclude_folders: Vec<String>,
+    pub seen_db: Option<PathBuf>,
+    pub store_options: StoreOptions,
+    pub format: ArchiveFormat,
+    pub sink_options: SinkOptions,
+    /// Roll up the folders of completed periods at the end of the run
+    #[cfg(feature = "compression")]
+    pub rollup: bool,
+    pub filters: FilterOptions,
+    /// Routing of the spam, if archived apart or purged
+    pub spam: Option<SpamRouting>,
+    pub config: Config,
+    /// Output directories of the emails matching the routes file
+    pub routes: Routes,
+    pub doveadm_user: Option<String>,
+    pub folder_naming: FolderNaming,
+    pub folder_name_encoding: NameEncoding,
+}
+
+/// Options of the filters selecting the emails to archive
+#[derive(Default)]
+pub struct FilterOptions {
+    pub has_attachment: bool,
+    pub attachment_larger_than: Option<u64>,
+    pub protect_active_threads: Option<i64>,
+    /// Archive only the emails with a spam score above the threshold
+    pub spam_score_gt: Option<f64>,
+    /// Archive only the emails with a spam score not above the threshold
+    pub spam_score_le: Option<f64>,
+    /// Archive only the emails with a MIME part of one of these content types
+    pub content_types: Vec<String>,
+    /// Archive only the emails with a text part matching the regex
+    pub body: Option<Regex>,
+    /// Archive only the emails without a text part matching the regex
+    pub not_body: Option<Regex>,
+    /// Command run for each candidate email, vetoing it with a non-zero
+    /// exit status
+    pub check_hook: Option<String>,
+    pub check_hook_input: HookInput,
+}
+
+/// Options of the `resplit` subcommand
+pub struct ResplitOptions {
+    pub archive_dir: PathBuf,
+    pub output_dir: PathBuf,
+    pub archive_mode: ArchiveMode,
+    pub prefix: String,
+    pub suffix: String,
+    pub split_by: SplitBy,
+}
+
+/// Options of the `compact` subcommand
+#[cfg(feature = "compression")]
+pub struct CompactOptions {
+    pub archive_dir: PathBuf,
+    pub before: Date,
+    pub archive_mode: ArchiveMode,
+    pub format: CompactFormat,
+    pub prefix: String,
+    pub suffix: String,
+    pub split_by: SplitBy,
+    pub encryptor: Option<Encryptor>,
+    pub verify: bool,
+}
+
+/// Options of the `dedup` subcommand
+pub struct DedupOptions {
+    pub archive_dir: PathBuf,
+    pub action: DedupAction,
+}
+
+/// Options of the `orphans` subcommand
+pub struct OrphansOptions {
+    pub archive_dir: PathBuf,
+    pub action: OrphanAction,
+}
+
+/// Options of the `views` subcommand
+pub struct ViewsOptions {
+    pub archive_dir: PathBuf,
+    /// Directory of the views, `views` in the archive directory by default
+    pub views_dir: Option<PathBuf>,
+    pub axes: Vec<ViewAxis>,
+}
+
+/// Options of the `expunge` subcommand
+pub struct ExpungeOptions {
+    pub archive_dir: PathBuf,
+    /// Minimum age in days of the copies of the deleted emails
+    pub older_than: u32,
+    pub dry_run: bool,
+}
+
+/// Options of the `stats` subcommand
+pub struct StatsOptions {
+    pub maildir: PathBuf,
+    pub recursive: bool,
+    pub histogram: Option<HistogramPeriod>,
+}
+
+/// Options of the `extract` subcommand
+#[cfg(feature = "compression")]
+pub struct ExtractOptions {
+    pub archive_file: PathBuf,
+    pub names: Vec<String>,
+}
+
+/// Options of the `plan` subcommand
+pub struct PlanOptions {
+    pub archive: ProgramOptions,
+    pub plan_file: PathBuf,
+}
+
+/// Options of the `diff` subcommand
+pub struct DiffOptions {
+    pub archive: ProgramOptions,
+}
+
+/// Options of the `check-config` subcommand
+pub struct CheckConfigOptions {
+    pub archive: ProgramOptions,
+}
+
+/// Options of the `bench` subcommand
+pub struct BenchOptions {
+    pub archive: ProgramOptions,
+    /// Directory of the temporary archive copies
+    pub bench_dir: Option<PathBuf>,
+}
+
+/// Options of the `fsck` subcommand
+pub struct FsckOptions {
+    pub maildir: PathBuf,
+    pub recursive: bool,
+    pub repair: bool,
+    pub fix_names: bool,
+}
+
+/// Options of the `apply` subcommand
+pub struct ApplyOptions {
+    pub plan_file: PathBuf,
+    pub check_free_space: bool,
+}
+
+/// Options of the `jmap` subcommand
+#[cfg(feature = "jmap")]
+pub struct JmapOptions {
+    pub session_url: String,
+    pub token: String,
+    pub before: Date,
+    pub output_dir: PathBuf,
+    pub archive_mode: ArchiveMode,
+    pub prefix: String,
+    pub suffix: String,
+    pub split_by: SplitBy,
+    pub filters: FilterOptions,
 }
@@ -0,0 +1,132 @@
+//! Asynchronous archive stage, enabled by the `async` feature: the archivers
+//! of network backends can process many emails concurrently without a thread
+//! per connection.
+//!
+//! Only archiving is asynchronous: the emails are listed, selected and
+//! filtered synchronously beforehand, and handed to [`archive_emails`].
+
+use crate::archiver::{MaildirArchiver, MaildirArchiverError};
+use maildir::{MailEntry, Maildir};
+use std::future::Future;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+/// Asynchronous counterpart of [`MaildirArchiver`]
+pub trait AsyncMaildirArchiver: Send + Sync + 'static {
+    fn archive_email(
+        &self,
+        mail: MailEntry,
+        from_maildir: Maildir,
+        archive_folder: PathBuf,
+    ) -> impl Future<Output = Result<(), MaildirArchiverError>> + Send;
+
+    /// Complete pending writes, called once after the last archived email
+    fn finish(&self) -> impl Future<Output = Result<(), MaildirArchiverError>> + Send {
+        async { Ok(()) }
+    }
+}
+
+fn join_error(e: tokio::task::JoinError) -> MaildirArchiverError {
+    MaildirArchiverError::IoError(io::Error::other(e))
+}
+
+/// Asynchronous archiver running a synchronous one on the blocking threads
+/// of the runtime
+pub struct BlockingArchiver {
+    archiver: Arc<dyn MaildirArchiver>,
+}
+
+impl BlockingArchiver {
+    pub fn new(archiver: Box<dyn MaildirArchiver>) -> Self {
+        BlockingArchiver {
+            archiver: Arc::from(archiver),
+        }
+    }
+}
+
+impl AsyncMaildirArchiver for BlockingArchiver {
+    async fn archive_email(
+        &self,
+        mail: MailEntry,
+        from_maildir: Maildir,
+        archive_folder: PathBuf,
+    ) -> Result<(), MaildirArchiverError> {
+        let archiver = self.archiver.clone();
+        tokio::task::spawn_blocking(move || {
+            archiver.archive_email(&mail, &from_maildir, &archive_folder)
+        })
+        .await
+        .map_err(join_error)?
+    }
+
+    async fn finish(&self) -> Result<(), MaildirArchiverError> {
+        let archiver = self.archiver.clone();
+        tokio::task::spawn_blocking(move || archiver.finish())
+            .await
+            .map_err(join_error)?
+    }
+}
+
+/// Archive emails with at most `concurrency` of them in flight, returning
+/// the result of each email in the order of `emails`. The permit of an email
+/// is taken before spawning its task: no more than `concurrency` tasks exist
+/// at once.
+pub async fn archive_emails<A: AsyncMaildirArchiver>(
+    archiver: Arc<A>,
+    emails: Vec<(MailEntry, Maildir, PathBuf)>,
+    concurrency: usize,
+) -> Vec<Result<(), MaildirArchiverError>> {
+    let semaphore = Arc::new(Semaphore::new(concurrency.max(1)));
+    let mut tasks = JoinSet::new();
+    for (index, (mail, from_maildir, archive_folder)) in emails.into_iter().enumerate() {
+        let archiver = archiver.clone();
+        let permit = semaphore.clone().acquire_owned().await;
+        tasks.spawn(async move {
+            let _permit = permit;
+            let result = archiver
+                .archive_email(mail, from_maildir, archive_folder)
+                .await;
+            (index, result)
+        });
+    }
+    let mut results: Vec<_> = tasks.join_all().await.into_iter().collect();
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::archiver::{create_mail_archiver, ArchiveMode, StoreOptions};
+    use crate::asynchronous::{archive_emails, AsyncMaildirArchiver, BlockingArchiver};
+    use crate::sink::MaildirSink;
+    use maildir::Maildir;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_archive_emails() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let maildir = Maildir::from(PathBuf::from("testdata/maildir1"));
+        let archiver = Arc::new(BlockingArchiver::new(create_mail_archiver(
+            ArchiveMode::Copy,
+            StoreOptions::default(),
+            Box::new(MaildirSink::default()),
+        )));
+        let emails = maildir
+            .list_cur()
+            .map(|mail| {
+                let folder = Maildir::from(PathBuf::from("testdata/maildir1"));
+                (mail.unwrap(), folder, basedir.join("all"))
+            })
+            .collect();
+        let results = archive_emails(archiver.clone(), emails, 2).await;
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|r| r.is_ok()));
+        archiver.finish().await.unwrap();
+        assert_eq!(Maildir::from(basedir.join("all")).count_cur(), 2);
+    }
+}
@@ -0,0 +1,274 @@
+use serde::{Deserialize, Serialize};
+use std::ffi::{CStr, CString};
+use std::fs::{self, Permissions};
+use std::io;
+use std::os::raw::{c_char, c_void};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{chown, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+/// Owner and group given to the files created in the archive
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Owner {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+/// Id of a user or a group, given by name or number
+fn lookup_id(name: &str, group: bool) -> Result<u32, String> {
+    if let Ok(id) = name.parse() {
+        return Ok(id);
+    }
+    let c_name = CString::new(name).map_err(|e| e.to_string())?;
+    // SAFETY: `c_name` is a valid C string, the returned entry is read before
+    // any other call to the functions
+    let id = unsafe {
+        match group {
+            true => libc::getgrnam(c_name.as_ptr()).as_ref().map(|g| g.gr_gid),
+            false => libc::getpwnam(c_name.as_ptr()).as_ref().map(|p| p.pw_uid),
+        }
+    };
+    let kind = if group { "group" } else { "user" };
+    id.ok_or_else(|| format!("unknown {} {}", kind, name))
+}
+
+/// User whose privileges are taken by a run started by root
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Account {
+    pub name: CString,
+    pub uid: u32,
+    /// Primary group of the user
+    pub gid: u32,
+}
+
+/// Parse a user given by name or number, with its primary group
+pub fn parse_account(value: &str) -> Result<Account, String> {
+    let c_name = CString::new(value).map_err(|e| e.to_string())?;
+    // SAFETY: `c_name` is a valid C string, the returned entry is copied
+    // before any other call to the functions
+    unsafe {
+        let entry = match value.parse() {
+            Ok(uid) => libc::getpwuid(uid),
+            Err(_) => libc::getpwnam(c_name.as_ptr()),
+        };
+        entry
+            .as_ref()
+            .map(|p| Account {
+                name: CStr::from_ptr(p.pw_name).to_owned(),
+                uid: p.pw_uid,
+                gid: p.pw_gid,
+            })
+            .ok_or_else(|| format!("unknown user {}", value))
+    }
+}
+
+/// Parse an owner in the `user`, `user:group` or `:group` format
+pub fn parse_owner(value: &str) -> Result<Owner, String> {
+    let (user, group) = value.split_once(':').unwrap_or((value, ""));
+    Ok(Owner {
+        uid: (!user.is_empty())
+            .then(|| lookup_id(user, false))
+            .transpose()?,
+        gid: (!group.is_empty())
+            .then(|| lookup_id(group, true))
+            .transpose()?,
+    })
+}
+
+/// Parse an octal file mode, like `0600`
+pub fn parse_mode(value: &str) -> Result<u32, String> {
+    match u32::from_str_radix(value, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("invalid mode {}", value)),
+    }
+}
+
+/// Mode of the directories for a file mode: the directories can be searched
+/// by who can read the files
+pub fn directory_mode(mode: u32) -> u32 {
+    mode | ((mode & 0o444) >> 2)
+}
+
+/// Give an owner and a mode to a created file or directory
+pub fn set_attributes(path: &Path, owner: Option<Owner>, mode: Option<u32>) -> io::Result<()> {
+    if let Some(owner) = owner {
+        chown(path, owner.uid, owner.gid)?;
+    }
+    if let Some(mode) = mode {
+        let mode = match path.is_dir() {
+            true => directory_mode(mode),
+            false => mode,
+        };
+        fs::set_permissions(path, Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
+
+/// Directories missing for a maildir: its missing ancestors, the maildir
+/// and its `cur`, `new` and `tmp` subdirectories, from the outermost
+pub fn missing_directories(maildir: &Path) -> Vec<PathBuf> {
+    let mut missing: Vec<PathBuf> = maildir
+        .ancestors()
+        .take_while(|dir| !dir.as_os_str().is_empty() && !dir.exists())
+        .map(Path::to_path_buf)
+        .collect();
+    missing.reverse();
+    for subdir in ["cur", "new", "tmp"] {
+        if !maildir.join(subdir).exists() {
+            missing.push(maildir.join(subdir));
+        }
+    }
+    missing
+}
+
+/// Copy the owner, the group and the mode of `source` to `dest`. Changing
+/// the owner requires the privileges of root.
+pub fn copy_permissions(source: &Path, dest: &Path) -> io::Result<()> {
+    let metadata = fs::metadata(source)?;
+    chown(dest, Some(metadata.uid()), Some(metadata.gid()))?;
+    fs::set_permissions(dest, Permissions::from_mode(metadata.mode() & 0o7777))
+}
+
+/// Path as a C string
+fn c_path(path: &Path) -> io::Result<CString> {
+    Ok(CString::new(path.as_os_str().as_bytes())?)
+}
+
+/// Read a list or a value of extended attributes with `get`, called first
+/// with an empty buffer to get the size
+fn read_xattr(get: impl Fn(*mut c_void, usize) -> isize) -> io::Result<Vec<u8>> {
+    loop {
+        let size = get(std::ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buffer = vec![0u8; size as usize];
+        let size = get(buffer.as_mut_ptr() as *mut c_void, buffer.len());
+        if size >= 0 {
+            buffer.truncate(size as usize);
+            return Ok(buffer);
+        }
+        // The attribute may have grown between the two calls
+        let error = io::Error::last_os_error();
+        if error.raw_os_error() != Some(libc::ERANGE) {
+            return Err(error);
+        }
+    }
+}
+
+/// Copy the extended attributes of `source` to `dest`, including the
+/// SELinux security context (`security.selinux`). Nothing is copied from
+/// filesystems without extended attributes.
+pub fn copy_xattrs(source: &Path, dest: &Path) -> io::Result<()> {
+    let (source, dest) = (c_path(source)?, c_path(dest)?);
+    // SAFETY: the paths are valid C strings and the buffers have the given
+    // sizes
+    let names = read_xattr(|buffer, size| unsafe {
+        libc::llistxattr(source.as_ptr(), buffer as *mut c_char, size)
+    });
+    let names = match names {
+        Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(()),
+        names => names?,
+    };
+    for name in names.split_inclusive(|b| *b == 0) {
+        let name = CStr::from_bytes_with_nul(name).map_err(io::Error::other)?;
+        let value = read_xattr(|buffer, size| unsafe {
+            libc::lgetxattr(source.as_ptr(), name.as_ptr(), buffer, size)
+        })?;
+        let res = unsafe {
+            libc::lsetxattr(
+                dest.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr() as *const c_void,
+                value.len(),
+                0,
+            )
+        };
+        if res != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::attributes::{
+        c_path, copy_permissions, copy_xattrs, directory_mode, missing_directories, parse_account,
+        parse_mode, parse_owner, set_attributes, Owner,
+    };
+    use std::fs::{self, Permissions};
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    #[test]
+    fn test_copy_permissions() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let (source, dest) = (basedir.join("source"), basedir.join("dest"));
+        fs::write(&source, "a").unwrap();
+        fs::write(&dest, "a").unwrap();
+        fs::set_permissions(&source, Permissions::from_mode(0o640)).unwrap();
+        copy_permissions(&source, &dest).unwrap();
+        let metadata = fs::metadata(&dest).unwrap();
+        assert_eq!(metadata.mode() & 0o7777, 0o640);
+        assert_eq!(metadata.uid(), fs::metadata(&source).unwrap().uid());
+    }
+
+    #[test]
+    fn test_set_attributes() {
+        assert_eq!(
+            parse_owner("0:0"),
+            Ok(Owner {
+                uid: Some(0),
+                gid: Some(0)
+            })
+        );
+        assert_eq!(parse_owner("root").unwrap().gid, None);
+        assert_eq!(parse_owner(":0").unwrap().uid, None);
+        assert!(parse_owner("no-such-user-here").is_err());
+        let root = parse_account("root").unwrap();
+        assert_eq!((root.uid, root.gid), (0, 0));
+        assert_eq!(parse_account("0").unwrap(), root);
+        assert!(parse_account("no-such-user-here").is_err());
+        assert_eq!(parse_mode("0600"), Ok(0o600));
+        assert!(parse_mode("0800").is_err());
+        assert_eq!(directory_mode(0o640), 0o750);
+
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let maildir = basedir.join("a").join("2016");
+        let missing = missing_directories(&maildir);
+        assert_eq!(missing[0], basedir.join("a"));
+        assert_eq!(missing.len(), 5);
+        fs::create_dir_all(maildir.join("cur")).unwrap();
+        assert_eq!(missing_directories(&maildir).len(), 2);
+        set_attributes(&maildir, None, Some(0o600)).unwrap();
+        let mode = fs::metadata(&maildir).unwrap().mode();
+        assert_eq!(mode & 0o7777, 0o700);
+    }
+
+    #[test]
+    fn test_copy_xattrs() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let (source, dest) = (basedir.join("source"), basedir.join("dest"));
+        fs::write(&source, "a").unwrap();
+        fs::write(&dest, "a").unwrap();
+        let path = c_path(&source).unwrap();
+        let (name, value) = (c"user.archive", b"a");
+        // Not all the filesystems support user extended attributes
+        let supported = unsafe {
+            libc::setxattr(path.as_ptr(), name.as_ptr(), value.as_ptr().cast(), 1, 0) == 0
+        };
+        copy_xattrs(&source, &dest).unwrap();
+        if supported {
+            let dest = c_path(&dest).unwrap();
+            let mut buffer = [0u8; 4];
+            let size = unsafe {
+                libc::getxattr(dest.as_ptr(), name.as_ptr(), buffer.as_mut_ptr().cast(), 4)
+            };
+            assert_eq!(&buffer[..size as usize], value);
+        }
+        assert!(copy_xattrs(&basedir.join("missing"), &dest).is_err());
+    }
+}
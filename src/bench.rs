@@ -0,0 +1,241 @@
+use crate::archiver::{create_mail_archiver, ArchiveMode, MaildirArchiverError};
+use crate::args::BenchOptions;
+use crate::folders::SourceFolder;
+use crate::run::{folder_options, select_emails, source_folders, Destination};
+use crate::sink::create_archive_sink;
+use crate::split::email_date;
+use log::error;
+use maildir::MailEntry;
+use mktemp::Temp;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// tmpfs mounted on most Linux systems, measuring the archiving without the
+/// disk of the archive
+const TMPFS: &str = "/dev/shm";
+
+/// Result of a benchmark of the archive pipeline
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct BenchReport {
+    /// Emails listed and dated
+    pub scanned: usize,
+    pub scan_time: Duration,
+    /// Emails listed by the selection
+    pub examined: usize,
+    pub selected: usize,
+    pub filter_time: Duration,
+    pub archived: usize,
+    pub failed: usize,
+    pub archived_bytes: u64,
+    pub archive_time: Duration,
+}
+
+/// Number of items per second
+fn rate(count: f64, time: Duration) -> f64 {
+    match time.is_zero() {
+        true => 0.0,
+        false => count / time.as_secs_f64(),
+    }
+}
+
+impl BenchReport {
+    /// Emails scanned per second
+    pub fn scan_rate(&self) -> f64 {
+        rate(self.scanned as f64, self.scan_time)
+    }
+
+    /// Emails examined by the selection per second
+    pub fn filter_rate(&self) -> f64 {
+        rate(self.examined as f64, self.filter_time)
+    }
+
+    /// Emails archived per second
+    pub fn archive_rate(&self) -> f64 {
+        rate(self.archived as f64, self.archive_time)
+    }
+
+    /// Bytes archived per second
+    pub fn throughput(&self) -> f64 {
+        rate(self.archived_bytes as f64, self.archive_time)
+    }
+
+    /// Write the rates, one phase per line
+    pub fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(
+            out,
+            "scan     {:>8} email {:>8.2} s {:>10.0} email/s",
+            self.scanned,
+            self.scan_time.as_secs_f64(),
+            self.scan_rate()
+        )?;
+        writeln!(
+            out,
+            "filter   {:>8} email {:>8.2} s {:>10.0} email/s, {} selected",
+            self.examined,
+            self.filter_time.as_secs_f64(),
+            self.filter_rate(),
+            self.selected
+        )?;
+        writeln!(
+            out,
+            "archive  {:>8} email {:>8.2} s {:>10.0} email/s, {:.1} MiB/s",
+            self.archived,
+            self.archive_time.as_secs_f64(),
+            self.archive_rate(),
+            self.throughput() / (1 << 20) as f64
+        )
+    }
+}
+
+/// Directory receiving the archive copies of a benchmark
+fn bench_dir(opts: &BenchOptions) -> io::Result<Temp> {
+    let base = match &opts.bench_dir {
+        Some(dir) => dir.clone(),
+        None if Path::new(TMPFS).is_dir() => PathBuf::from(TMPFS),
+        None => std::env::temp_dir(),
+    };
+    Temp::new_dir_in(base)
+}
+
+/// Archive folder of an email in the benchmark directory, at the same place
+/// as under the output directory, the spam directory or the route output
+fn bench_folder(opts: &BenchOptions, dir: &Path, archive_folder: &Path) -> PathBuf {
+    let archive = &opts.archive;
+    let spam_dir = archive.spam.as_ref().and_then(|s| s.output_dir.as_ref());
+    let relative = std::iter::once(&archive.output_dir)
+        .chain(spam_dir)
+        .chain(archive.routes.outputs())
+        .find_map(|root| archive_folder.strip_prefix(root).ok())
+        .unwrap_or(archive_folder.file_name().map_or(Path::new(""), Path::new));
+    dir.join(relative)
+}
+
+/// Measure the archive pipeline on the source folders of the options,
+/// without changing them: the emails are scanned, selected, then copied
+/// with `jobs` workers to a temporary directory, removed at the end.
+///
+/// The scan lists the emails and reads their date, the selection also
+/// applies the filters of the options. The emails are read once by the scan
+/// first, so the source is measured with a warm cache.
+pub fn bench(opts: &BenchOptions) -> Result<BenchReport, MaildirArchiverError> {
+    let archive = &opts.archive;
+    let mut report = BenchReport::default();
+    let folders = source_folders(archive)?;
+
+    let start = Instant::now();
+    for folder in &folders {
+        for mail in folder.maildir.list_cur().flatten() {
+            report.scanned += 1;
+            let _ = email_date(&mail);
+        }
+    }
+    report.scan_time = start.elapsed();
+
+    let start = Instant::now();
+    let mut emails: Vec<(&SourceFolder, MailEntry, PathBuf)> = Vec::new();
+    for folder in &folders {
+        let folder_opts = folder_options(archive, folder);
+        report.examined += match archive.include_new {
+            true => folder.maildir.count_cur() + folder.maildir.count_new(),
+            false => folder.maildir.count_cur(),
+        };
+        for (mail, destination) in select_emails(archive, folder, &folder_opts, None, None)? {
+            report.selected += 1;
+            if let Destination::Archive(archive_folder) = destination {
+                emails.push((folder, mail, archive_folder));
+            }
+        }
+    }
+    report.filter_time = start.elapsed();
+
+    let dir = bench_dir(opts)?;
+    let mut store_options = archive.store_options.clone();
+    if let Some(stripper) = &mut store_options.attachment_stripper {
+        stripper.sidecar_dir = dir.join("attachments");
+    }
+    let mut sink_options = archive.sink_options.clone();
+    if let Some(index) = &mut sink_options.content_index {
+        *index = dir.join(index.file_name().unwrap_or_default());
+    }
+    if let Some(git_dir) = &mut sink_options.git_dir {
+        *git_dir = dir.to_path_buf();
+    }
+    let archiver = create_mail_archiver(
+        ArchiveMode::Copy,
+        store_options,
+        create_archive_sink(archive.format, &sink_options),
+    );
+    let queue = Mutex::new(emails.iter());
+    let totals = Mutex::new((0, 0, 0));
+    let start = Instant::now();
+    let worker = || loop {
+        let Some((folder, mail, archive_folder)) = queue.lock().unwrap().next() else {
+            break;
+        };
+        let archive_folder = bench_folder(opts, &dir, archive_folder);
+        let res = archiver.archive_email(mail, &folder.maildir, &archive_folder);
+        let (archived, failed, bytes) = &mut *totals.lock().unwrap();
+        match res {
+            Ok(()) => {
+                *archived += 1;
+                *bytes += std::fs::metadata(mail.path()).map_or(0, |m| m.len());
+            }
+            Err(e) => {
+                error!("Error while archiving email {}: {}", mail.id(), e);
+                *failed += 1;
+            }
+        }
+    };
+    std::thread::scope(|scope| {
+        for _ in 1..archive.jobs {
+            scope.spawn(worker);
+        }
+        worker();
+    });
+    archiver.finish()?;
+    report.archive_time = start.elapsed();
+    (report.archived, report.failed, report.archived_bytes) = totals.into_inner().unwrap();
+    Ok(report)
+}
+
+#[cfg(all(test, feature = "cli"))]
+mod tests {
+    use crate::args::BenchOptions;
+    use crate::bench::bench;
+    use crate::cli::{archive_args, parse_archive_args};
+    use crate::test_support::{TestEmail, TestMaildir};
+    use clap::Command;
+    use time::macros::datetime;
+
+    #[test]
+    fn test_bench() {
+        let maildir = TestMaildir::new();
+        maildir.add(&TestEmail::new().size(1000));
+        maildir.add(&TestEmail::new().received(datetime!(2099-01-01 0:00 UTC)));
+        let matches = archive_args(Command::new("test"))
+            .try_get_matches_from([
+                "test",
+                maildir.input_maildir.path().to_str().unwrap(),
+                maildir.output_dir().to_str().unwrap(),
+                "--mode",
+                "move",
+            ])
+            .unwrap();
+        let opts = BenchOptions {
+            archive: parse_archive_args(&matches),
+            bench_dir: Some(maildir.path().to_path_buf()),
+        };
+        let report = bench(&opts).unwrap();
+        assert_eq!((report.scanned, report.examined), (2, 2));
+        assert_eq!((report.selected, report.archived), (1, 1));
+        assert_eq!(report.archived_bytes, 1000);
+        // The source and the output directory are not changed
+        assert_eq!(maildir.input_maildir.count_cur(), 2);
+        assert!(!maildir.output_dir().exists());
+        let mut out = Vec::new();
+        report.write(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap().lines().count(), 3);
+    }
+}
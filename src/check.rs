@@ -0,0 +1,224 @@
+use crate::archiver::{ArchiveMode, MaildirArchiverError};
+use crate::args::{CheckConfigOptions, ProgramOptions};
+use crate::folders::SourceFolder;
+use crate::run::{check_options, check_output_dirs, folder_options, source_folders, state_file};
+use crate::split::expand_sequence;
+use std::ffi::CString;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use time::{Duration, Time};
+
+/// Result of a configuration check
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ConfigReport {
+    /// Number of source folders selected by the options
+    pub folders: usize,
+    /// Problems found in the options, the configuration and the paths
+    pub problems: Vec<String>,
+}
+
+/// Name of an archive mode, as given to `--mode`
+fn mode_name(mode: ArchiveMode) -> &'static str {
+    match mode {
+        ArchiveMode::Move => "move",
+        ArchiveMode::Copy => "copy",
+        ArchiveMode::DryRun => "dry-run",
+        ArchiveMode::Stub => "stub",
+    }
+}
+
+/// Check if the process can access a path with the `access` mode
+/// (`R_OK`, `W_OK`, `X_OK`)
+fn accessible(path: &Path, mode: libc::c_int) -> bool {
+    let Ok(path) = CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    unsafe { libc::access(path.as_ptr(), mode) == 0 }
+}
+
+/// Nearest existing directory of a path, where a missing path would be
+/// created
+fn existing_ancestor(path: &Path) -> PathBuf {
+    path.ancestors()
+        .map(|dir| match dir.as_os_str().is_empty() {
+            true => Path::new("."),
+            false => dir,
+        })
+        .find(|dir| dir.is_dir())
+        .unwrap_or(Path::new("/"))
+        .to_path_buf()
+}
+
+/// Check that a directory, or the directory where it would be created, can
+/// be written
+fn check_writable(dir: &Path, problems: &mut Vec<String>) {
+    let existing = existing_ancestor(dir);
+    if !accessible(&existing, libc::W_OK | libc::X_OK) {
+        problems.push(format!(
+            "{}: {} is not writable",
+            dir.display(),
+            existing.display()
+        ));
+    }
+}
+
+/// Archive folder of the last emails selected in a source folder: the ones
+/// received the day before the cutoff date
+fn last_archive_folder(opts: &ProgramOptions, folder: &SourceFolder) -> PathBuf {
+    let folder_opts = folder_options(opts, folder);
+    let date = (folder_opts.before - Duration::days(1))
+        .with_time(Time::MIDNIGHT)
+        .assume_utc();
+    let bucket = folder_opts
+        .split_by
+        .folder_name(&opts.prefix, &opts.suffix, date);
+    folder.archive_folder(
+        &opts.output_dir,
+        &expand_sequence(&bucket, 1),
+        opts.folder_naming,
+        opts.folder_name_encoding,
+    )
+}
+
+/// Check the options of an archive run, its configuration file and its
+/// routes file, without reading or changing any email.
+///
+/// The effective options of each source folder are written to `out`,
+/// followed by the problems found: unsupported options, overrides and
+/// routes whose folder pattern matches no source folder, and input, output
+/// and state paths that can't be written.
+pub fn check_config<W: Write>(
+    opts: &CheckConfigOptions,
+    out: &mut W,
+) -> Result<ConfigReport, MaildirArchiverError> {
+    let archive = &opts.archive;
+    let mut problems = Vec::new();
+    if let Err(e) = check_options(archive) {
+        problems.push(e.to_string());
+    }
+    let folders = source_folders(archive)?;
+    let mut writes = false;
+    for folder in &folders {
+        let folder_opts = folder_options(archive, folder);
+        writeln!(
+            out,
+            "{}: before {}, {}, split by {}, to {}",
+            folder.display_name(),
+            folder_opts.before,
+            mode_name(folder_opts.archive_mode),
+            String::from(folder_opts.split_by),
+            last_archive_folder(archive, folder).display()
+        )?;
+        writes |= folder_opts.archive_mode != ArchiveMode::DryRun;
+        if folder_opts.archive_mode == ArchiveMode::Move
+            || folder_opts.archive_mode == ArchiveMode::Stub
+        {
+            for dir in ["cur", "new"].map(|dir| folder.maildir.path().join(dir)) {
+                if !accessible(&dir, libc::W_OK | libc::X_OK) {
+                    problems.push(format!("{}: not writable", dir.display()));
+                }
+            }
+        }
+        if archive.incremental {
+            check_writable(
+                state_file(archive, folder)
+                    .parent()
+                    .unwrap_or(Path::new("")),
+                &mut problems,
+            );
+        }
+    }
+    let overrides = archive
+        .config
+        .folders
+        .iter()
+        .map(|o| ("config", &o.pattern));
+    let routes = archive
+        .routes
+        .routes
+        .iter()
+        .filter_map(|route| Some(("routes", route.folder.as_ref()?)));
+    for (file, pattern) in overrides.chain(routes) {
+        if !folders.iter().any(|folder| folder.matches(pattern)) {
+            problems.push(format!(
+                "pattern {} of the {} file matches no folder",
+                pattern, file
+            ));
+        }
+    }
+    if writes {
+        if let Err(e) = check_output_dirs(archive) {
+            problems.push(e.to_string());
+        }
+        let spam_dir = archive.spam.as_ref().and_then(|s| s.output_dir.as_ref());
+        let dirs = std::iter::once(&archive.output_dir)
+            .chain(spam_dir)
+            .chain(archive.routes.outputs());
+        for dir in dirs {
+            check_writable(dir, &mut problems);
+        }
+        let files = [&archive.seen_db, &archive.sink_options.content_index];
+        for file in files.into_iter().flatten() {
+            check_writable(file.parent().unwrap_or(Path::new("")), &mut problems);
+        }
+    }
+    for problem in &problems {
+        writeln!(out, "Problem: {}", problem)?;
+    }
+    Ok(ConfigReport {
+        folders: folders.len(),
+        problems,
+    })
+}
+
+#[cfg(all(test, feature = "cli"))]
+mod tests {
+    use crate::args::CheckConfigOptions;
+    use crate::check::check_config;
+    use crate::cli::{archive_args, parse_archive_args};
+    use clap::Command;
+    use maildir::Maildir;
+
+    #[test]
+    fn test_check_config() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let input = basedir.join("in");
+        Maildir::from(input.clone()).create_dirs().unwrap();
+        Maildir::from(input.join(".Sent")).create_dirs().unwrap();
+        let config = basedir.join("config.toml");
+        std::fs::write(
+            &config,
+            "[[folder]]\npattern = '.Sent'\nmode = 'copy'\nsplit-by = 'month'\nbefore = '2016-05-21'\n\
+             [[folder]]\npattern = '.Lst.*'\nmode = 'move'\n",
+        )
+        .unwrap();
+        let output = basedir.join("missing").join("out");
+        let matches = archive_args(Command::new("test"))
+            .try_get_matches_from([
+                "test",
+                input.to_str().unwrap(),
+                output.to_str().unwrap(),
+                "--recursive",
+                "--config",
+                config.to_str().unwrap(),
+                "--mode",
+                "move",
+            ])
+            .unwrap();
+        let opts = CheckConfigOptions {
+            archive: parse_archive_args(&matches),
+        };
+        let mut out = Vec::new();
+        let report = check_config(&opts, &mut out).unwrap();
+        assert_eq!(report.folders, 2);
+        assert_eq!(report.problems.len(), 2);
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains(&format!(
+            ".Sent: before 2016-05-21, copy, split by month, to {}\n",
+            output.join("Sent").join("2016-05").display()
+        )));
+        assert!(out.contains("Problem: pattern .Lst.* of the config file matches no folder\n"));
+    }
+}
@@ -0,0 +1,2196 @@
+use crate::archiver::*;
+use crate::args::*;
+use crate::attributes::{parse_account, parse_mode, parse_owner, Account, Owner};
+#[cfg(feature = "compression")]
+use crate::compact::{rollup_options, CompactFormat};
+use crate::config::{Config, Profile};
+use crate::dedup::{DedupAction, CONTENT_INDEX_FILE};
+use crate::encryption::{parse_recipient, read_recipients_file, Encryptor};
+use crate::filter::HookInput;
+use crate::folders::FolderNaming;
+use crate::index::IndexFormat;
+use crate::logger::{ColorChoice, LogFileOptions, LogRotation};
+use crate::orphans::OrphanAction;
+use crate::redact::{HeaderRedactor, RedactMode};
+use crate::routes::Routes;
+use crate::script::DryRunFormat;
+use crate::sink::{ArchiveFormat, DirCreation, SinkOptions};
+use crate::spam::SpamRouting;
+pub use crate::split::{MailOrder, SplitBy};
+use crate::stats::{HistogramPeriod, StatsFormat};
+use crate::strip::AttachmentStripper;
+use crate::transform::TransformStep;
+use crate::utf7::NameEncoding;
+use crate::views::ViewAxis;
+use age::x25519::Recipient;
+use clap::builder::PossibleValue;
+use clap::error::ErrorKind;
+use clap::{command, Arg, ArgAction, ArgMatches, Command as ClapCommand};
+use log::LevelFilter;
+use regex::Regex;
+use std::ffi::OsString;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use time::format_description::well_known::Rfc3339;
+use time::macros::format_description;
+use time::{Date, Month, OffsetDateTime, UtcOffset};
+
+pub struct Cli {
+    pub verbosity: LevelFilter,
+    pub color: ColorChoice,
+    pub log_file: Option<LogFileOptions>,
+    pub stats_format: StatsFormat,
+    pub command: Command,
+}
+
+pub enum Command {
+    Archive(ProgramOptions),
+    Resplit(ResplitOptions),
+    #[cfg(feature = "compression")]
+    Compact(CompactOptions),
+    Dedup(DedupOptions),
+    #[cfg(feature = "compression")]
+    Extract(ExtractOptions),
+    Plan(PlanOptions),
+    Diff(DiffOptions),
+    Fsck(FsckOptions),
+    Orphans(OrphansOptions),
+    Expunge(ExpungeOptions),
+    Views(ViewsOptions),
+    Stats(StatsOptions),
+    Apply(ApplyOptions),
+    #[cfg(feature = "jmap")]
+    Jmap(JmapOptions),
+    CheckConfig(CheckConfigOptions),
+    Bench(BenchOptions),
+}
+
+/// Same day `months` months before, or the last day of the month if it is
+/// shorter, like February 28 one year before February 29
+fn months_before(date: Date, months: i64) -> Option<Date> {
+    let index = i64::from(date.year()) * 12 + i64::from(date.month() as u8) - 1 - months;
+    let year = i32::try_from(index.div_euclid(12)).ok()?;
+    let month = Month::try_from(index.rem_euclid(12) as u8 + 1).ok()?;
+    Date::from_calendar_date(year, month, date.day().min(month.length(year))).ok()
+}
+
+fn one_year_ago() -> Date {
+    let today = OffsetDateTime::now_utc().date();
+    months_before(today, 12).unwrap_or(today)
+}
+
+/// Check that the input of an archive run is a maildir: a directory with
+/// `cur`, or an account root with Maildir++ subfolders
+fn check_input_maildir(path: &Path) -> Result<(), String> {
+    if !path.is_dir() {
+        return Err(format!("{} is not a directory", path.display()));
+    }
+    if path.join("cur").is_dir() {
+        return Ok(());
+    }
+    let subfolder = fs::read_dir(path)
+        .map_err(|e| format!("{}: {}", path.display(), e))?
+        .flatten()
+        .any(|entry| {
+            entry.file_name().as_bytes().starts_with(b".") && entry.path().join("cur").is_dir()
+        });
+    match subfolder {
+        true => Ok(()),
+        false => Err(format!(
+            "{} is not a maildir: no cur directory nor maildir folder",
+            path.display()
+        )),
+    }
+}
+
+/// Parse the rotation of the log file: `daily`, or a size
+pub fn parse_log_rotation(value: &str) -> Result<LogRotation, String> {
+    match value {
+        "daily" => Ok(LogRotation::Daily),
+        size => parse_size(size)
+            .map(LogRotation::Size)
+            .map_err(|e| format!("{}, expected daily or a size", e)),
+    }
+}
+
+/// Parse a size in bytes, with an optional `K`, `M` or `G` suffix
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let (number, multiplier) = match value.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&value[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&value[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&value[..i], 1 << 30),
+        _ => (value, 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid size {}", value))
+}
+
+/// Parse a date in the `YYYY-mm-dd` format, reporting the position of the
+/// invalid component
+fn parse_iso_date(value: &str) -> Result<Date, String> {
+    Date::parse(value, format_description!("[year]-[month]-[day]")).map_err(|e| {
+        let component = match &e {
+            time::error::Parse::TryFromParsed(time::error::TryFromParsed::ComponentRange(e)) => {
+                Some(e.name())
+            }
+            time::error::Parse::ParseFromDescription(
+                time::error::ParseFromDescription::InvalidComponent(name),
+            ) => Some(*name),
+            _ => None,
+        };
+        let index = match component {
+            Some("month") => 1,
+            Some("day") => 2,
+            _ => 0,
+        };
+        let position = value
+            .split('-')
+            .take(index)
+            .map(|c| c.len() + 1)
+            .sum::<usize>()
+            + 1;
+        format!("invalid date {} at position {}: {}", value, position, e)
+    })
+}
+
+/// Date relative to `today`: `today`, `yesterday`, `last <unit>` or
+/// `<n> <unit>s ago`, the unit being a day, a week, a month or a year
+fn parse_relative_date(value: &str, today: Date) -> Option<Date> {
+    let value = value.to_ascii_lowercase();
+    let words: Vec<&str> = value.split_whitespace().collect();
+    let (count, unit) = match words.as_slice() {
+        ["today"] => return Some(today),
+        ["yesterday"] => return today.previous_day(),
+        ["last", unit] => (1, *unit),
+        [count, unit, "ago"] => (i64::from(count.parse::<u32>().ok()?), *unit),
+        _ => return None,
+    };
+    match unit.strip_suffix('s').unwrap_or(unit) {
+        "day" => today.checked_sub(time::Duration::days(count)),
+        "week" => today.checked_sub(time::Duration::weeks(count)),
+        "month" => months_before(today, count),
+        "year" => months_before(today, count * 12),
+        _ => None,
+    }
+}
+
+/// Parse a date relative to `today` in any of the formats of [`parse_date`]
+fn parse_date_at(value: &str, today: Date) -> Result<Date, String> {
+    let iso = match parse_iso_date(value) {
+        Ok(date) => return Ok(date),
+        Err(e) => e,
+    };
+    if let Ok(datetime) = OffsetDateTime::parse(value, &Rfc3339) {
+        return Ok(datetime.to_offset(UtcOffset::UTC).date());
+    }
+    if value.len() == 8 && value.bytes().all(|b| b.is_ascii_digit()) {
+        return Date::parse(value, format_description!("[year][month][day]"))
+            .map_err(|e| format!("invalid date {}: {}", value, e));
+    }
+    if let Some(date) = parse_relative_date(value, today) {
+        return Ok(date);
+    }
+    // Report the invalid component of what looks like a YYYY-mm-dd date
+    match value.contains('-') && value.starts_with(|c: char| c.is_ascii_digit()) {
+        true => Err(iso),
+        false => Err(format!(
+            "invalid date {}: expected YYYY-mm-dd, YYYYMMDD, an RFC 3339 timestamp or a \
+             relative date like `2 months ago`",
+            value
+        )),
+    }
+}
+
+/// Parse a date as `YYYY-mm-dd`, `YYYYMMDD`, an RFC 3339 timestamp (its UTC
+/// date) or a date relative to today, like `yesterday`, `last year` or
+/// `2 months ago`
+pub fn parse_date(value: &str) -> Result<Date, String> {
+    parse_date_at(value, OffsetDateTime::now_utc().date())
+}
+
+/// Check that a folder name prefix or suffix doesn't contain path separators
+pub fn parse_affix(value: &str) -> Result<String, String> {
+    match value.char_indices().find(|(_, c)| matches!(c, '/' | '\0')) {
+        Some((i, c)) => Err(format!(
+            "invalid character {:?} at position {} of {}",
+            c,
+            i + 1,
+            value
+        )),
+        None => Ok(value.to_string()),
+    }
+}
+
+fn parse_regex(value: &str) -> Result<Regex, String> {
+    Regex::new(value).map_err(|e| e.to_string())
+}
+
+fn prefix_arg() -> Arg {
+    Arg::new("prefix")
+        .short('p')
+        .long("prefix")
+        .value_name("PREFIX")
+        .help("Prefix format")
+        .default_value("")
+        .value_parser(parse_affix)
+}
+
+fn suffix_arg() -> Arg {
+    Arg::new("suffix")
+        .short('s')
+        .long("suffix")
+        .value_name("SUFFIX")
+        .help("Suffix format")
+        .default_value("")
+        .value_parser(parse_affix)
+}
+
+fn split_by_arg() -> Arg {
+    Arg::new("split-by")
+        .short('S')
+        .long("split-by")
+        .value_name("PERIOD")
+        .help("Set the split policy")
+        .value_parser([
+            PossibleValue::new("year"),
+            PossibleValue::new("month"),
+            PossibleValue::new("day"),
+            PossibleValue::new("hour"),
+            PossibleValue::new("iso-week"),
+            PossibleValue::new("fiscal-year"),
+            PossibleValue::new("recipient"),
+            PossibleValue::new("label").help("First label of the X-Label or Keywords headers"),
+            PossibleValue::new("none"),
+        ])
+        .default_value("year")
+}
+
+fn fiscal_start_arg() -> Arg {
+    Arg::new("fiscal-start")
+        .long("fiscal-start")
+        .value_name("MONTH")
+        .help("First month of the fiscal year (with --split-by fiscal-year)")
+        .value_parser(clap::value_parser!(u8).range(1..=12))
+        .default_value("01")
+}
+
+fn mode_arg() -> Arg {
+    Arg::new("mode")
+        .short('m')
+        .long("mode")
+        .help("Archive mode")
+        .value_parser([
+            PossibleValue::new("copy"),
+            PossibleValue::new("move"),
+            PossibleValue::new("dry-run"),
+        ])
+        .default_value("dry-run")
+}
+
+fn before_arg(help: &'static str) -> Arg {
+    Arg::new("before")
+        .short('b')
+        .long("before")
+        .default_value(one_year_ago().to_string())
+        .value_name("DATE")
+        .help(help)
+        .long_help(format!(
+            "{} (YYYY-mm-dd, YYYYMMDD, RFC 3339, or relative like `yesterday`, `last year` or \
+             `2 months ago`)",
+            help
+        ))
+        .value_parser(parse_date)
+}
+
+fn age_recipient_arg() -> Arg {
+    Arg::new("age-recipient")
+        .long("age-recipient")
+        .value_name("RECIPIENT")
+        .help("Encrypt with the given age public key (can be repeated)")
+        .action(ArgAction::Append)
+        .value_parser(parse_recipient)
+}
+
+fn age_recipients_file_arg() -> Arg {
+    Arg::new("age-recipients-file")
+        .long("age-recipients-file")
+        .value_name("PATH")
+        .help("Encrypt with the age public keys listed in the file (can be repeated)")
+        .action(ArgAction::Append)
+        .value_parser(read_recipients_file)
+}
+
+fn get_encryptor(matches: &ArgMatches) -> Option<Encryptor> {
+    let mut recipients: Vec<Recipient> = matches
+        .get_many::<Recipient>("age-recipient")
+        .into_iter()
+        .flatten()
+        .cloned()
+        .collect();
+    recipients.extend(
+        matches
+            .get_many::<Vec<Recipient>>("age-recipients-file")
+            .into_iter()
+            .flatten()
+            .flatten()
+            .cloned(),
+    );
+    match recipients.is_empty() {
+        true => None,
+        false => Some(Encryptor::new(recipients)),
+    }
+}
+
+fn get_header_redactor(matches: &ArgMatches) -> Option<HeaderRedactor> {
+    let headers: Vec<String> = matches
+        .get_many::<String>("redact-headers")?
+        .cloned()
+        .collect();
+    let mode = match matches.get_one::<String>("redact-mode").unwrap().as_str() {
+        "hash" => RedactMode::Hash,
+        _ => RedactMode::Remove,
+    };
+    Some(HeaderRedactor::new(&headers, mode))
+}
+
+fn get_store_options(matches: &ArgMatches) -> StoreOptions {
+    StoreOptions {
+        encryptor: get_encryptor(matches),
+        attachment_stripper: matches.get_one::<u64>("strip-attachments-larger-than").map(
+            |min_size| AttachmentStripper {
+                sidecar_dir: get_path(matches, "attachment-store"),
+                min_size: *min_size + 1,
+            },
+        ),
+        header_redactor: get_header_redactor(matches),
+        stamp_provenance: matches.get_flag("stamp-provenance"),
+        transform_order: matches
+            .get_many::<String>("transform-order")
+            .unwrap_or_default()
+            .map(|step| match step.as_str() {
+                "redact" => TransformStep::Redact,
+                "stamp" => TransformStep::Stamp,
+                "strip" => TransformStep::Strip,
+                _ => TransformStep::Encrypt,
+            })
+            .collect(),
+        delete_via: match matches.get_one::<String>("delete-via").unwrap().as_str() {
+            "trash" => DeleteStrategy::Trash,
+            _ => DeleteStrategy::Unlink,
+        },
+        secure_delete: matches.get_flag("secure-delete"),
+        sync_safe: matches.get_flag("sync-safe"),
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        read_ahead: crate::uring::ReadAhead::create().map(std::sync::Arc::new),
+    }
+}
+
+fn get_before(matches: &ArgMatches) -> Date {
+    *matches.get_one::<Date>("before").unwrap()
+}
+
+fn get_split_by(matches: &ArgMatches) -> SplitBy {
+    match matches.get_one::<String>("split-by").unwrap().as_str() {
+        "day" => SplitBy::Day,
+        "hour" => SplitBy::Hour,
+        "month" => SplitBy::Month,
+        "year" => SplitBy::Year,
+        "iso-week" => SplitBy::IsoWeek,
+        "fiscal-year" => {
+            let start = *matches.get_one::<u8>("fiscal-start").unwrap();
+            SplitBy::FiscalYear(Month::try_from(start).unwrap())
+        }
+        "recipient" => SplitBy::Recipient,
+        "label" => SplitBy::Label,
+        _ => SplitBy::None,
+    }
+}
+
+fn get_archive_mode(matches: &ArgMatches) -> ArchiveMode {
+    match matches.get_one::<String>("mode").unwrap().as_str() {
+        "copy" => ArchiveMode::Copy,
+        "move" => ArchiveMode::Move,
+        "stub" => ArchiveMode::Stub,
+        _ => ArchiveMode::DryRun,
+    }
+}
+
+fn get_strings(matches: &ArgMatches, id: &str) -> Vec<String> {
+    matches
+        .get_many::<String>(id)
+        .into_iter()
+        .flatten()
+        .cloned()
+        .collect()
+}
+
+fn get_path(matches: &ArgMatches, id: &str) -> PathBuf {
+    matches.get_one::<PathBuf>(id).unwrap().clone()
+}
+
+fn resplit_command() -> ClapCommand {
+    ClapCommand::new("resplit")
+        .about("Reorganize an existing archive under a different split policy")
+        .arg(prefix_arg())
+        .arg(suffix_arg())
+        .arg(split_by_arg())
+        .arg(fiscal_start_arg())
+        .arg(mode_arg())
+        .arg(
+            Arg::new("archive-dir")
+                .required(true)
+                .value_name("ARCHIVE_PATH")
+                .help("Directory containing the archive maildirs")
+                .index(1)
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("output-dir")
+                .value_name("OUTPUT_PATH")
+                .help("Output directory for the new archive maildirs (default: ARCHIVE_PATH)")
+                .index(2)
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+}
+
+fn parse_resplit_args(matches: &ArgMatches) -> ResplitOptions {
+    let archive_dir = get_path(matches, "archive-dir");
+    ResplitOptions {
+        output_dir: match matches.contains_id("output-dir") {
+            true => get_path(matches, "output-dir"),
+            false => archive_dir.clone(),
+        },
+        archive_dir,
+        archive_mode: get_archive_mode(matches),
+        prefix: matches.get_one::<String>("prefix").unwrap().clone(),
+        suffix: matches.get_one::<String>("suffix").unwrap().clone(),
+        split_by: get_split_by(matches),
+    }
+}
+
+#[cfg(feature = "compression")]
+fn compact_command() -> ClapCommand {
+    ClapCommand::new("compact")
+        .about("Convert old archive maildirs into compressed files")
+        .arg(prefix_arg())
+        .arg(suffix_arg())
+        .arg(split_by_arg())
+        .arg(fiscal_start_arg())
+        .arg(mode_arg().help("Compact mode (move removes the compacted maildirs)"))
+        .arg(before_arg(
+            "Compact folders containing only emails before the given date",
+        ))
+        .arg(
+            Arg::new("format")
+                .short('f')
+                .long("format")
+                .help("Compressed file format")
+                .value_parser([
+                    PossibleValue::new("mbox"),
+                    PossibleValue::new("tar"),
+                    PossibleValue::new("tar-zst"),
+                    PossibleValue::new("seekable"),
+                ])
+                .default_value("mbox"),
+        )
+        .arg(
+            Arg::new("verify")
+                .long("verify")
+                .help("Check the compressed files before removing the maildirs")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["age-recipient", "age-recipients-file"]),
+        )
+        .arg(age_recipient_arg())
+        .arg(age_recipients_file_arg())
+        .arg(
+            Arg::new("archive-dir")
+                .required(true)
+                .value_name("ARCHIVE_PATH")
+                .help("Directory containing the archive maildirs")
+                .index(1)
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+}
+
+#[cfg(feature = "compression")]
+fn parse_compact_args(matches: &ArgMatches) -> CompactOptions {
+    CompactOptions {
+        archive_dir: get_path(matches, "archive-dir"),
+        before: get_before(matches),
+        archive_mode: get_archive_mode(matches),
+        format: match matches.get_one::<String>("format").unwrap().as_str() {
+            "tar" => CompactFormat::Tar,
+            "tar-zst" => CompactFormat::TarZst,
+            "seekable" => CompactFormat::Seekable,
+            _ => CompactFormat::Mbox,
+        },
+        prefix: matches.get_one::<String>("prefix").unwrap().clone(),
+        suffix: matches.get_one::<String>("suffix").unwrap().clone(),
+        split_by: get_split_by(matches),
+        encryptor: get_encryptor(matches),
+        verify: matches.get_flag("verify"),
+    }
+}
+
+#[cfg(feature = "compression")]
+fn rollup_command() -> ClapCommand {
+    ClapCommand::new("rollup")
+        .about("Convert the folders of completed periods into verified tar.zst files")
+        .arg(prefix_arg())
+        .arg(suffix_arg())
+        .arg(split_by_arg())
+        .arg(fiscal_start_arg())
+        .arg(mode_arg().help("Rollup mode (move removes the verified maildirs)"))
+        .arg(
+            Arg::new("archive-dir")
+                .required(true)
+                .value_name("ARCHIVE_PATH")
+                .help("Directory containing the archive maildirs")
+                .index(1)
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+}
+
+#[cfg(feature = "compression")]
+fn parse_rollup_args(matches: &ArgMatches) -> CompactOptions {
+    rollup_options(
+        get_path(matches, "archive-dir"),
+        get_archive_mode(matches),
+        matches.get_one::<String>("prefix").unwrap().clone(),
+        matches.get_one::<String>("suffix").unwrap().clone(),
+        get_split_by(matches),
+    )
+}
+
+fn dedup_command() -> ClapCommand {
+    ClapCommand::new("dedup")
+        .about("Find identical emails in the archive and remove the extra copies")
+        .arg(
+            Arg::new("action")
+                .short('a')
+                .long("action")
+                .help("What to do with the duplicated emails")
+                .value_parser([
+                    PossibleValue::new("delete"),
+                    PossibleValue::new("hardlink"),
+                    PossibleValue::new("dry-run"),
+                ])
+                .default_value("dry-run"),
+        )
+        .arg(
+            Arg::new("archive-dir")
+                .required(true)
+                .value_name("ARCHIVE_PATH")
+                .help("Directory containing the archive maildirs")
+                .index(1)
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+}
+
+fn parse_dedup_args(matches: &ArgMatches) -> DedupOptions {
+    DedupOptions {
+        archive_dir: get_path(matches, "archive-dir"),
+        action: match matches.get_one::<String>("action").unwrap().as_str() {
+            "delete" => DedupAction::Delete,
+            "hardlink" => DedupAction::Hardlink,
+            _ => DedupAction::DryRun,
+        },
+    }
+}
+
+fn orphans_command() -> ClapCommand {
+    ClapCommand::new("orphans")
+        .about("Find the emails of the archive left in new/ and tmp/ by interrupted runs")
+        .arg(
+            Arg::new("action")
+                .short('a')
+                .long("action")
+                .help("What to do with the orphans (complete: move new/ to cur/, remove tmp/)")
+                .value_parser([
+                    PossibleValue::new("complete"),
+                    PossibleValue::new("remove"),
+                    PossibleValue::new("dry-run"),
+                ])
+                .default_value("dry-run"),
+        )
+        .arg(
+            Arg::new("archive-dir")
+                .required(true)
+                .value_name("ARCHIVE_PATH")
+                .help("Directory containing the archive maildirs")
+                .index(1)
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+}
+
+fn parse_orphans_args(matches: &ArgMatches) -> OrphansOptions {
+    OrphansOptions {
+        archive_dir: get_path(matches, "archive-dir"),
+        action: match matches.get_one::<String>("action").unwrap().as_str() {
+            "complete" => OrphanAction::Complete,
+            "remove" => OrphanAction::Remove,
+            _ => OrphanAction::DryRun,
+        },
+    }
+}
+
+fn expunge_command() -> ClapCommand {
+    ClapCommand::new("expunge")
+        .about("Delete the source emails copied by --defer-delete runs, once verified")
+        .arg(
+            Arg::new("older-than")
+                .long("older-than")
+                .value_name("DAYS")
+                .help("Minimum age in days of the archive copies")
+                .value_parser(clap::value_parser!(u32))
+                .default_value("7"),
+        )
+        .arg(
+            Arg::new("dry-run")
+                .short('n')
+                .long("dry-run")
+                .help("Only report the emails to delete")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("archive-dir")
+                .required(true)
+                .value_name("ARCHIVE_PATH")
+                .help("Output directory of the --defer-delete runs")
+                .index(1)
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+}
+
+fn parse_expunge_args(matches: &ArgMatches) -> ExpungeOptions {
+    ExpungeOptions {
+        archive_dir: get_path(matches, "archive-dir"),
+        older_than: *matches.get_one::<u32>("older-than").unwrap(),
+        dry_run: matches.get_flag("dry-run"),
+    }
+}
+
+fn views_command() -> ClapCommand {
+    ClapCommand::new("views")
+        .about("Build symlink farms over the archive, like by-sender/<address>/")
+        .arg(
+            Arg::new("by")
+                .long("by")
+                .value_name("AXES")
+                .help("Comma separated list of the views to build")
+                .value_parser([
+                    PossibleValue::new("sender"),
+                    PossibleValue::new("year"),
+                    PossibleValue::new("month"),
+                    PossibleValue::new("list"),
+                ])
+                .value_delimiter(',')
+                .default_value("sender,year"),
+        )
+        .arg(
+            Arg::new("views-dir")
+                .long("views-dir")
+                .value_name("DIR")
+                .help("Directory of the views (default: ARCHIVE_PATH/views)")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("archive-dir")
+                .required(true)
+                .value_name("ARCHIVE_PATH")
+                .help("Directory containing the archive maildirs")
+                .index(1)
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+}
+
+fn parse_views_args(matches: &ArgMatches) -> ViewsOptions {
+    ViewsOptions {
+        archive_dir: get_path(matches, "archive-dir"),
+        views_dir: matches
+            .contains_id("views-dir")
+            .then(|| get_path(matches, "views-dir")),
+        axes: matches
+            .get_many::<String>("by")
+            .unwrap()
+            .map(|axis| match axis.as_str() {
+                "year" => ViewAxis::Year,
+                "month" => ViewAxis::Month,
+                "list" => ViewAxis::List,
+                _ => ViewAxis::Sender,
+            })
+            .collect(),
+    }
+}
+
+fn stats_command() -> ClapCommand {
+    ClapCommand::new("stats")
+        .about("Count the emails of a maildir and their sizes")
+        .arg(
+            Arg::new("recursive")
+                .short('r')
+                .long("recursive")
+                .help("Count also the subfolders of the maildir")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("histogram")
+                .long("histogram")
+                .value_name("PERIOD")
+                .help("Count the emails by month or year of reception")
+                .value_parser([PossibleValue::new("month"), PossibleValue::new("year")])
+                .num_args(0..=1)
+                .default_missing_value("month"),
+        )
+        .arg(
+            Arg::new("maildir")
+                .required(true)
+                .value_name("MAILDIR")
+                .help("Path of the maildir")
+                .index(1)
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+}
+
+fn parse_stats_args(matches: &ArgMatches) -> StatsOptions {
+    StatsOptions {
+        maildir: get_path(matches, "maildir"),
+        recursive: matches.get_flag("recursive"),
+        histogram: matches
+            .get_one::<String>("histogram")
+            .map(|period| match period.as_str() {
+                "year" => HistogramPeriod::Year,
+                _ => HistogramPeriod::Month,
+            }),
+    }
+}
+
+#[cfg(feature = "compression")]
+fn extract_command() -> ClapCommand {
+    ClapCommand::new("extract")
+        .about("Print emails stored in a seekable archive, or list them")
+        .arg(
+            Arg::new("archive-file")
+                .required(true)
+                .value_name("ARCHIVE_FILE")
+                .help("Seekable archive file (.zst)")
+                .index(1)
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("names")
+                .value_name("NAME")
+                .help("Names of the emails to print (default: list all the names)")
+                .action(ArgAction::Append)
+                .index(2),
+        )
+}
+
+#[cfg(feature = "compression")]
+fn parse_extract_args(matches: &ArgMatches) -> ExtractOptions {
+    ExtractOptions {
+        archive_file: get_path(matches, "archive-file"),
+        names: matches
+            .get_many::<String>("names")
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect(),
+    }
+}
+
+fn plan_command() -> ClapCommand {
+    archive_args(
+        ClapCommand::new("plan")
+            .about("Write the operations of an archive run to a JSON file, without executing them"),
+    )
+    .arg(
+        Arg::new("plan-file")
+            .short('o')
+            .long("output")
+            .required(true)
+            .value_name("PLAN_FILE")
+            .help("Path of the plan file")
+            .value_parser(clap::value_parser!(PathBuf)),
+    )
+}
+
+fn parse_plan_args(matches: &ArgMatches) -> PlanOptions {
+    PlanOptions {
+        archive: parse_archive_args(matches),
+        plan_file: get_path(matches, "plan-file"),
+    }
+}
+
+fn diff_command() -> ClapCommand {
+    archive_args(
+        ClapCommand::new("diff")
+            .about("Compare the selected emails of the source with the archive maildirs"),
+    )
+}
+
+fn parse_diff_args(matches: &ArgMatches) -> DiffOptions {
+    DiffOptions {
+        archive: parse_archive_args(matches),
+    }
+}
+
+fn check_config_command() -> ClapCommand {
+    archive_args(ClapCommand::new("check-config").about(
+        "Check the options, the config and the routes of an archive run, and print the \
+         effective options of each folder, without touching any email",
+    ))
+}
+
+fn parse_check_config_args(matches: &ArgMatches) -> CheckConfigOptions {
+    CheckConfigOptions {
+        archive: parse_archive_args(matches),
+    }
+}
+
+fn bench_command() -> ClapCommand {
+    archive_args(ClapCommand::new("bench").about(
+        "Measure the scan, the selection and the archiving of the emails, copying them to a \
+         temporary directory",
+    ))
+    .arg(
+        Arg::new("bench-dir")
+            .long("bench-dir")
+            .value_name("PATH")
+            .help("Directory of the temporary archive copies [default: /dev/shm, or $TMPDIR]")
+            .value_parser(clap::value_parser!(PathBuf)),
+    )
+}
+
+fn parse_bench_args(matches: &ArgMatches) -> BenchOptions {
+    BenchOptions {
+        archive: parse_archive_args(matches),
+        bench_dir: matches.get_one::<PathBuf>("bench-dir").cloned(),
+    }
+}
+
+fn fsck_command() -> ClapCommand {
+    ClapCommand::new("fsck")
+        .about("Check the structure of a maildir, and optionally repair it")
+        .arg(
+            Arg::new("recursive")
+                .short('r')
+                .long("recursive")
+                .help("Check also the subfolders of the maildir")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("repair")
+                .long("repair")
+                .help("Delete the stale temporary files and rename the duplicated unique ids")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("fix-names")
+                .long("fix-names")
+                .help("Rename the emails with a malformed or missing info")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("maildir")
+                .required(true)
+                .value_name("MAILDIR")
+                .help("Path of the maildir")
+                .index(1)
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+}
+
+fn parse_fsck_args(matches: &ArgMatches) -> FsckOptions {
+    FsckOptions {
+        maildir: get_path(matches, "maildir"),
+        recursive: matches.get_flag("recursive"),
+        repair: matches.get_flag("repair"),
+        fix_names: matches.get_flag("fix-names"),
+    }
+}
+
+fn apply_command() -> ClapCommand {
+    ClapCommand::new("apply")
+        .about("Execute the operations of a plan file, if the planned emails didn't change")
+        .arg(
+            Arg::new("plan-file")
+                .required(true)
+                .value_name("PLAN_FILE")
+                .help("Path of the plan file")
+                .index(1)
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("no-space-check")
+                .long("no-space-check")
+                .help("Don't check the free space of the archive before writing to it")
+                .action(ArgAction::SetTrue),
+        )
+}
+
+fn parse_apply_args(matches: &ArgMatches) -> ApplyOptions {
+    ApplyOptions {
+        plan_file: get_path(matches, "plan-file"),
+        check_free_space: !matches.get_flag("no-space-check"),
+    }
+}
+
+#[cfg(feature = "jmap")]
+fn jmap_command() -> ClapCommand {
+    filter_args(ClapCommand::new("jmap"))
+        .about("Archive the emails of a JMAP account (move destroys them on the server)")
+        .arg(prefix_arg())
+        .arg(suffix_arg())
+        .arg(split_by_arg())
+        .arg(fiscal_start_arg())
+        .arg(mode_arg())
+        .arg(before_arg("Archive emails received before the given date"))
+        .arg(
+            Arg::new("token-file")
+                .long("token-file")
+                .value_name("PATH")
+                .help(format!(
+                    "Read the bearer token of the JMAP account from a file instead of ${}",
+                    JMAP_TOKEN_VAR
+                ))
+                .value_parser(read_token_file),
+        )
+        .arg(
+            Arg::new("session-url")
+                .required(true)
+                .value_name("SESSION_URL")
+                .help("JMAP session URL (e.g. https://api.fastmail.com/jmap/session)")
+                .index(1),
+        )
+        .arg(
+            Arg::new("output-dir")
+                .required(true)
+                .value_name("OUTPUT_PATH")
+                .help("Output directory for archive maildirs")
+                .index(2)
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+}
+
+/// Environment variable with the bearer token of the JMAP account, kept off
+/// the command line where other users can read it
+#[cfg(feature = "jmap")]
+const JMAP_TOKEN_VAR: &str = "ARCHIVE_MAILDIR_JMAP_TOKEN";
+
+/// Read a bearer token from the first line of a file
+#[cfg(feature = "jmap")]
+fn read_token_file(value: &str) -> Result<String, String> {
+    let content = fs::read_to_string(value).map_err(|e| format!("{}: {}", value, e))?;
+    match content.lines().next().map(str::trim) {
+        Some(token) if !token.is_empty() => Ok(token.to_string()),
+        _ => Err(format!("{}: no token", value)),
+    }
+}
+
+#[cfg(feature = "jmap")]
+fn get_jmap_token(matches: &ArgMatches) -> String {
+    match matches.get_one::<String>("token-file") {
+        Some(token) => token.clone(),
+        None => std::env::var(JMAP_TOKEN_VAR).unwrap_or_else(|_| {
+            clap::Error::raw(
+                ErrorKind::MissingRequiredArgument,
+                format!(
+                    "the JMAP token is read from --token-file or ${}\n",
+                    JMAP_TOKEN_VAR
+                ),
+            )
+            .exit()
+        }),
+    }
+}
+
+#[cfg(feature = "jmap")]
+fn parse_jmap_args(matches: &ArgMatches) -> JmapOptions {
+    JmapOptions {
+        session_url: matches.get_one::<String>("session-url").unwrap().clone(),
+        token: get_jmap_token(matches),
+        before: get_before(matches),
+        output_dir: get_path(matches, "output-dir"),
+        archive_mode: get_archive_mode(matches),
+        prefix: matches.get_one::<String>("prefix").unwrap().clone(),
+        suffix: matches.get_one::<String>("suffix").unwrap().clone(),
+        split_by: get_split_by(matches),
+        filters: parse_filter_args(matches),
+    }
+}
+
+/// Add the options selecting the emails to archive to `command`
+fn filter_args(command: ClapCommand) -> ClapCommand {
+    command
+        .arg(
+            Arg::new("has-attachment")
+                .long("has-attachment")
+                .help("Archive only emails with attachments")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("attachment-larger-than")
+                .long("attachment-larger-than")
+                .value_name("SIZE")
+                .help("Archive only emails with an attachment larger than SIZE (e.g. 2M)")
+                .value_parser(parse_size),
+        )
+        .arg(
+            Arg::new("spam-score-gt")
+                .long("spam-score-gt")
+                .value_name("SCORE")
+                .help("Archive only emails with a spam score greater than SCORE")
+                .value_parser(clap::value_parser!(f64)),
+        )
+        .arg(
+            Arg::new("spam-score-le")
+                .long("spam-score-le")
+                .value_name("SCORE")
+                .help("Archive only emails with a spam score not greater than SCORE")
+                .value_parser(clap::value_parser!(f64)),
+        )
+        .arg(
+            Arg::new("content-type")
+                .long("content-type")
+                .value_name("TYPE")
+                .help("Archive only emails with a MIME part of type TYPE (e.g. text/calendar)")
+                .action(ArgAction::Append),
+        )
+        .arg(
+            Arg::new("body")
+                .long("body")
+                .value_name("REGEX")
+                .help("Archive only emails with a text part matching REGEX")
+                .value_parser(parse_regex),
+        )
+        .arg(
+            Arg::new("not-body")
+                .long("not-body")
+                .value_name("REGEX")
+                .help("Archive only emails without a text part matching REGEX")
+                .value_parser(parse_regex),
+        )
+        .arg(
+            Arg::new("check-hook")
+                .long("check-hook")
+                .value_name("COMMAND")
+                .help("Archive only emails for which COMMAND exits successfully"),
+        )
+        .arg(
+            Arg::new("check-hook-input")
+                .long("check-hook-input")
+                .value_name("INPUT")
+                .help("Pass the email path as last argument or the email on stdin")
+                .value_parser([PossibleValue::new("path"), PossibleValue::new("stdin")])
+                .default_value("path")
+                .requires("check-hook"),
+        )
+}
+
+/// Values of `--format`, the zip and tar formats being only built with
+/// compression
+fn archive_formats() -> Vec<PossibleValue> {
+    let mut formats = vec![PossibleValue::new("maildir")];
+    #[cfg(feature = "compression")]
+    formats.extend([PossibleValue::new("zip"), PossibleValue::new("tar")]);
+    formats.extend([
+        PossibleValue::new("mbox"),
+        PossibleValue::new("cas").help("Content-addressable objects linked in maildirs"),
+        PossibleValue::new("git").help("Commit to a bare git repository (experimental)"),
+    ]);
+    formats
+}
+
+/// Options selecting the emails to archive, but the protection of the active
+/// threads
+fn parse_filter_args(matches: &ArgMatches) -> FilterOptions {
+    FilterOptions {
+        has_attachment: matches.get_flag("has-attachment"),
+        attachment_larger_than: matches.get_one::<u64>("attachment-larger-than").copied(),
+        protect_active_threads: None,
+        spam_score_gt: matches.get_one::<f64>("spam-score-gt").copied(),
+        spam_score_le: matches.get_one::<f64>("spam-score-le").copied(),
+        content_types: get_strings(matches, "content-type"),
+        body: matches.get_one::<Regex>("body").cloned(),
+        not_body: matches.get_one::<Regex>("not-body").cloned(),
+        check_hook: matches.get_one::<String>("check-hook").cloned(),
+        check_hook_input: match matches
+            .get_one::<String>("check-hook-input")
+            .unwrap()
+            .as_str()
+        {
+            "stdin" => HookInput::Stdin,
+            _ => HookInput::Path,
+        },
+    }
+}
+
+/// Add the options of the archive command to `command`
+pub(crate) fn archive_args(command: ClapCommand) -> ClapCommand {
+    let command = command
+        .arg(prefix_arg())
+        .arg(suffix_arg())
+        .arg(split_by_arg())
+        .arg(fiscal_start_arg())
+        .arg(
+            mode_arg()
+                .help("Archive mode (stub leaves the email headers in the input maildir)")
+                .value_parser([
+                    PossibleValue::new("copy"),
+                    PossibleValue::new("move"),
+                    PossibleValue::new("stub"),
+                    PossibleValue::new("dry-run"),
+                ]),
+        )
+        .arg(before_arg("Archive emails before the given date"))
+        .arg(
+            Arg::new("dry-run-format")
+                .long("dry-run-format")
+                .help("Dry run output (shell prints the commands of the copy or move mode)")
+                .value_parser([PossibleValue::new("log"), PossibleValue::new("shell")])
+                .default_value("log"),
+        )
+        .arg(
+            Arg::new("format")
+                .short('f')
+                .long("format")
+                .help("Archive format")
+                .value_parser(archive_formats())
+                .default_value("maildir"),
+        )
+        .arg(
+            Arg::new("normalize-flags")
+                .long("normalize-flags")
+                .help("Remove the characters other than letters from the flags of the emails")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("preserve-perms")
+                .long("preserve-perms")
+                .help("Keep the owner, group and mode of the source files (maildir format)")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("preserve-xattrs")
+                .long("preserve-xattrs")
+                .help("Keep the extended attributes and SELinux context (maildir format)")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("hardlink-duplicates")
+                .long("hardlink-duplicates")
+                .help("Hardlink the emails already in the archive instead of copying them")
+                .long_help(
+                    "Hardlink the emails whose content is already in the archive instead of \
+                    copying them, using an index of the archived emails saved in the output \
+                    directory (maildir format)",
+                )
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("chown")
+                .long("chown")
+                .value_name("USER:GROUP")
+                .help("Owner of the created files and directories (maildir format)")
+                .value_parser(parse_owner),
+        )
+        .arg(
+            Arg::new("chmod")
+                .long("chmod")
+                .value_name("MODE")
+                .help("Octal mode of the created files, e.g. 0600 (maildir format)")
+                .value_parser(parse_mode),
+        )
+        .arg(
+            Arg::new("folder-naming")
+                .long("folder-naming")
+                .help("Archive folder layout (courier: subscribed folders of the account OUTPUT_PATH)")
+                .value_parser([PossibleValue::new("plain"), PossibleValue::new("courier")])
+                .default_value("plain"),
+        )
+        .arg(
+            Arg::new("folder-name-encoding")
+                .long("folder-name-encoding")
+                .help("Encoding of the archive folder names (utf8 decodes IMAP modified UTF-7)")
+                .value_parser([
+                    PossibleValue::new("preserve"),
+                    PossibleValue::new("utf8"),
+                    PossibleValue::new("imap-utf7"),
+                ])
+                .default_value("preserve"),
+        )
+        .arg(
+            Arg::new("rotate-after")
+                .long("rotate-after")
+                .value_name("COUNT")
+                .help("Start a new archive folder part every COUNT emails, numbered by a {seq} token of the prefix or suffix")
+                .value_parser(clap::value_parser!(u32).range(1..)),
+        )
+        .arg(
+            Arg::new("big-threshold")
+                .long("big-threshold")
+                .value_name("SIZE")
+                .help("Archive the emails bigger than SIZE (like 10M) to the --big-bucket folder")
+                .value_parser(parse_size),
+        )
+        .arg(
+            Arg::new("big-bucket")
+                .long("big-bucket")
+                .value_name("NAME")
+                .help("Archive folder of the emails bigger than --big-threshold")
+                .value_parser(parse_affix)
+                .default_value("big-messages"),
+        )
+        .arg(
+            Arg::new("order")
+                .long("order")
+                .help(
+                    "Order in which the emails are archived (directory streams the folders \
+                     without listing them first, inode reads the files in their order on disk)",
+                )
+                .value_parser([
+                    PossibleValue::new("oldest"),
+                    PossibleValue::new("newest"),
+                    PossibleValue::new("path"),
+                    PossibleValue::new("directory"),
+                    PossibleValue::new("inode"),
+                ])
+                .default_value("oldest"),
+        )
+        .arg(
+            Arg::new("limit")
+                .long("limit")
+                .value_name("COUNT")
+                .help("Archive at most COUNT emails")
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("jobs")
+                .short('j')
+                .long("jobs")
+                .value_name("N")
+                .help("Archive N source folders in parallel, each one sequentially")
+                .value_parser(clap::value_parser!(u16).range(1..))
+                .conflicts_with("limit")
+                .default_value("1"),
+        )
+        .arg(
+            Arg::new("recursive")
+                .short('r')
+                .long("recursive")
+                .help("Archive also the subfolders of the input maildir")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("no-space-check")
+                .long("no-space-check")
+                .help("Don't check the free space of the archive before writing to it")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("defer-delete")
+                .long("defer-delete")
+                .help("In move mode, copy the emails and leave their deletion to expunge")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("imap-command")
+                .long("imap-command")
+                .value_name("COMMAND")
+                .help("Expunge the purged spam, and in move mode the archived emails, through the preauthenticated IMAP server run by COMMAND, e.g. 'doveadm exec imap -u USER'")
+                .conflicts_with_all(["defer-delete", "delete-via", "secure-delete"]),
+        )
+        .arg(
+            Arg::new("snapshot")
+                .long("snapshot")
+                .value_name("DIR")
+                .help("Hardlink the emails to remove into a timestamped directory of DIR first")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("snapshot-keep")
+                .long("snapshot-keep")
+                .value_name("N")
+                .help("Number of snapshots kept, the oldest ones are removed")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("7"),
+        )
+        .arg(
+            Arg::new("index")
+                .long("index")
+                .value_name("FORMAT")
+                .help("Write the index of the archived emails in OUTPUT_PATH, one file per run")
+                .value_parser([PossibleValue::new("csv"), PossibleValue::new("jsonl")]),
+        )
+        .arg(
+            Arg::new("sandbox")
+                .long("sandbox")
+                .help(
+                    "Only allow changing the files of the input and output trees (Landlock and \
+                     seccomp on Linux, unveil and pledge on OpenBSD)",
+                )
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("run-as")
+                .long("run-as")
+                .value_name("USER")
+                .help(
+                    "Take the uid and the groups of USER before touching any file, like the \
+                     owner of the mailbox when run by root",
+                )
+                .value_parser(parse_account),
+        )
+        .arg(
+            Arg::new("notify")
+                .long("notify")
+                .help(
+                    "Show a desktop notification with the summary at the end of the run \
+                     (notify-send)",
+                )
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("progress-fd")
+                .long("progress-fd")
+                .value_name("FD")
+                .help(
+                    "Write the progress events as JSON lines (scanned, archived, failed, \
+                     percent) on an open file descriptor",
+                )
+                .value_parser(clap::value_parser!(i32).range(0..)),
+        )
+        .arg(
+            Arg::new("strict")
+                .long("strict")
+                .help(
+                    "Stop with an error at the first email not handled, like an unparsable date, \
+                     an unreadable email or a failed filter, instead of skipping it",
+                )
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("include-new")
+                .long("include-new")
+                .help("Archive also the emails never seen by a client, in new/")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("include-folder")
+                .long("include-folder")
+                .value_name("PATTERN")
+                .help("Archive only the folders matching PATTERN, e.g. '.Lists.*' (implies -r)")
+                .action(ArgAction::Append),
+        )
+        .arg(
+            Arg::new("exclude-folder")
+                .long("exclude-folder")
+                .value_name("PATTERN")
+                .help("Don't archive the folders matching PATTERN, e.g. INBOX (implies -r)")
+                .action(ArgAction::Append),
+        )
+        .arg(
+            Arg::new("incremental")
+                .long("incremental")
+                .help("Skip the emails already examined by the last successful run")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("state-file")
+                .long("state-file")
+                .value_name("PATH")
+                .help("Incremental state file (default: INPUT_PATH/archive-maildir.state)")
+                .conflicts_with_all(["recursive", "include-folder", "exclude-folder"])
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("seen-db")
+                .long("seen-db")
+                .value_name("PATH")
+                .help("Skip the emails recorded in the database, record the archived ones")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("protect-active-threads")
+                .long("protect-active-threads")
+                .value_name("DAYS")
+                .help("Keep the threads with a message received in the last DAYS days")
+                .value_parser(clap::value_parser!(i64).range(0..)),
+        );
+    let command = filter_args(command)
+        .arg(
+            Arg::new("spam-dir")
+                .long("spam-dir")
+                .value_name("PATH")
+                .help("Archive the spam in PATH instead of the output directory")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("purge-spam-after")
+                .long("purge-spam-after")
+                .value_name("DAYS")
+                .help("Delete the spam older than DAYS days instead of archiving it")
+                .value_parser(clap::value_parser!(i64).range(0..)),
+        )
+        .arg(
+            Arg::new("spam-threshold")
+                .long("spam-threshold")
+                .value_name("SCORE")
+                .help("Spam score above which an email is routed as spam")
+                .value_parser(clap::value_parser!(f64))
+                .default_value("5"),
+        )
+        .arg(
+            Arg::new("strip-attachments-larger-than")
+                .long("strip-attachments-larger-than")
+                .value_name("SIZE")
+                .help("Detach attachments larger than SIZE into the attachment store")
+                .value_parser(parse_size)
+                .requires("attachment-store"),
+        )
+        .arg(
+            Arg::new("attachment-store")
+                .long("attachment-store")
+                .value_name("PATH")
+                .help("Directory storing the detached attachments")
+                .requires("strip-attachments-larger-than")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("redact-headers")
+                .long("redact-headers")
+                .value_name("HEADERS")
+                .help("Comma separated list of headers to redact in the archived emails")
+                .value_delimiter(','),
+        )
+        .arg(
+            Arg::new("redact-mode")
+                .long("redact-mode")
+                .help("Remove the redacted headers or replace their values with a SHA-256 digest")
+                .value_parser([PossibleValue::new("remove"), PossibleValue::new("hash")])
+                .default_value("remove"),
+        )
+        .arg(
+            Arg::new("delete-via")
+                .long("delete-via")
+                .value_name("STRATEGY")
+                .help("How the archived emails are removed in move mode")
+                .value_parser([
+                    PossibleValue::new("unlink").help("Remove the files"),
+                    PossibleValue::new("trash").help("Move them to .Trash with the T flag"),
+                ])
+                .default_value("unlink"),
+        )
+        .arg(
+            Arg::new("secure-delete")
+                .long("secure-delete")
+                .help("Overwrite the removed emails before unlinking them (best effort)")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("sync-safe")
+                .long("sync-safe")
+                .help("Keep the email ids in the archive and the trash, without the mbsync and offlineimap UID markers")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("no-create-dirs")
+                .long("no-create-dirs")
+                .help("Fail on the archive folders that don't exist, instead of creating them")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("create-parents"),
+        )
+        .arg(
+            Arg::new("create-parents")
+                .long("create-parents")
+                .help("Create the missing parent directories of the output directories")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("stamp-provenance")
+                .long("stamp-provenance")
+                .help("Add X-Archived-At and X-Original-Maildir headers to the archived emails")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("transform-order")
+                .long("transform-order")
+                .value_name("STEPS")
+                .help("Order of the transformations of the archived emails")
+                .value_delimiter(',')
+                .value_parser([
+                    PossibleValue::new("redact"),
+                    PossibleValue::new("stamp"),
+                    PossibleValue::new("strip"),
+                    PossibleValue::new("encrypt"),
+                ])
+                .default_values(["redact", "stamp", "strip", "encrypt"]),
+        )
+        .arg(
+            Arg::new("doveadm-user")
+                .long("doveadm-user")
+                .value_name("USER")
+                .help("Resync the Dovecot mailboxes of USER after removing emails from them"),
+        );
+    #[cfg(feature = "compression")]
+    let command = command.arg(
+        Arg::new("rollup")
+            .long("rollup")
+            .help("Roll up the folders of completed periods at the end of the run")
+            .action(ArgAction::SetTrue)
+            .conflicts_with("format"),
+    );
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    let command = command.arg(
+        Arg::new("batch-writes")
+            .long("batch-writes")
+            .help(
+                "Write the archived emails by batches with io_uring, moved to the archive \
+                 folders at the end of the run",
+            )
+            .action(ArgAction::SetTrue),
+    );
+    command
+        .arg(age_recipient_arg())
+        .arg(age_recipients_file_arg())
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .value_name("PATH")
+                .help("Configuration file with the per-folder overrides and the profiles")
+                .value_parser(Config::read),
+        )
+        .arg(
+            Arg::new("routes")
+                .long("routes")
+                .value_name("PATH")
+                .help(
+                    "Routes file sending the emails matching sender, list id or folder patterns \
+                     to other output directories",
+                )
+                .value_parser(Routes::read),
+        )
+        .arg(
+            Arg::new("profile")
+                .long("profile")
+                .value_name("NAME")
+                .help("Take the default values of the options from a profile of the config")
+                .requires("config"),
+        )
+        .arg(
+            Arg::new("input-maildir")
+                .required(true)
+                .value_name("INPUT_PATH")
+                .help("Input maildir path")
+                .index(1)
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("output-dir")
+                .required(true)
+                .value_name("OUTPUT_PATH")
+                .help("Output directory for archive maildirs")
+                .index(2)
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .mut_args(|arg| {
+            let name = env_var(option_name(arg.get_id().as_str()));
+            arg.env(name)
+        })
+}
+
+/// Names of the positional archive arguments in the profiles and in the
+/// environment variables
+const POSITIONAL_NAMES: [(&str, &str); 2] = [("input", "input-maildir"), ("output", "output-dir")];
+
+/// Name of an archive option in the profiles and in the environment variables
+fn option_name(id: &str) -> &str {
+    POSITIONAL_NAMES
+        .iter()
+        .find(|(_, i)| *i == id)
+        .map_or(id, |(name, _)| name)
+}
+
+/// Id of the argument of an archive option named in a profile
+fn option_id(name: &str) -> &str {
+    POSITIONAL_NAMES
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map_or(name, |(_, id)| id)
+}
+
+/// Environment variable of an archive option (`ARCHIVE_MAILDIR_SPLIT_BY` for
+/// `split-by`)
+fn env_var(name: &str) -> String {
+    format!("ARCHIVE_MAILDIR_{}", name.to_uppercase().replace('-', "_"))
+}
+
+pub(crate) fn parse_archive_args(matches: &ArgMatches) -> ProgramOptions {
+    ProgramOptions {
+        input_maildir: get_path(matches, "input-maildir").into(),
+        output_dir: get_path(matches, "output-dir"),
+        before: get_before(matches),
+        prefix: matches.get_one::<String>("prefix").unwrap().clone(),
+        suffix: matches.get_one::<String>("suffix").unwrap().clone(),
+        split_by: get_split_by(matches),
+        archive_mode: get_archive_mode(matches),
+        order: match matches.get_one::<String>("order").unwrap().as_str() {
+            "newest" => MailOrder::Newest,
+            "path" => MailOrder::Path,
+            "directory" => MailOrder::Directory,
+            "inode" => MailOrder::Inode,
+            _ => MailOrder::Oldest,
+        },
+        rotate_after: matches
+            .get_one::<u32>("rotate-after")
+            .map(|count| *count as usize),
+        big_threshold: matches.get_one::<u64>("big-threshold").copied(),
+        big_bucket: matches.get_one::<String>("big-bucket").unwrap().clone(),
+        limit: matches.get_one::<usize>("limit").copied(),
+        jobs: usize::from(*matches.get_one::<u16>("jobs").unwrap()),
+        incremental: matches.get_flag("incremental"),
+        seen_db: matches
+            .contains_id("seen-db")
+            .then(|| get_path(matches, "seen-db")),
+        state_file: matches
+            .contains_id("state-file")
+            .then(|| get_path(matches, "state-file")),
+        recursive: matches.get_flag("recursive")
+            || matches.contains_id("include-folder")
+            || matches.contains_id("exclude-folder"),
+        include_new: matches.get_flag("include-new"),
+        check_free_space: !matches.get_flag("no-space-check"),
+        defer_delete: matches.get_flag("defer-delete"),
+        imap_command: matches.get_one::<String>("imap-command").cloned(),
+        snapshot: matches
+            .contains_id("snapshot")
+            .then(|| get_path(matches, "snapshot")),
+        snapshot_keep: *matches.get_one::<usize>("snapshot-keep").unwrap(),
+        index: matches
+            .get_one::<String>("index")
+            .map(|format| match format.as_str() {
+                "jsonl" => IndexFormat::JsonLines,
+                _ => IndexFormat::Csv,
+            }),
+        sandbox: matches.get_flag("sandbox"),
+        run_as: matches.get_one::<Account>("run-as").cloned(),
+        notify: matches.get_flag("notify"),
+        progress_fd: matches.get_one::<i32>("progress-fd").copied(),
+        // Global option, missing from the commands without the global ones
+        log_file: matches
+            .try_get_one::<PathBuf>("log-file")
+            .ok()
+            .flatten()
+            .cloned(),
+        strict: matches.get_flag("strict"),
+        include_folders: get_strings(matches, "include-folder"),
+        exclude_folders: get_strings(matches, "exclude-folder"),
+        dry_run_format: match matches
+            .get_one::<String>("dry-run-format")
+            .unwrap()
+            .as_str()
+        {
+            "shell" => DryRunFormat::Shell,
+            _ => DryRunFormat::Log,
+        },
+        store_options: get_store_options(matches),
+        format: match matches.get_one::<String>("format").unwrap().as_str() {
+            #[cfg(feature = "compression")]
+            "zip" => ArchiveFormat::Zip,
+            #[cfg(feature = "compression")]
+            "tar" => ArchiveFormat::Tar,
+            "mbox" => ArchiveFormat::Mbox,
+            "cas" => ArchiveFormat::Cas,
+            "git" => ArchiveFormat::Git,
+            _ => ArchiveFormat::Maildir,
+        },
+        sink_options: SinkOptions {
+            normalize_flags: matches.get_flag("normalize-flags"),
+            preserve_perms: matches.get_flag("preserve-perms"),
+            preserve_xattrs: matches.get_flag("preserve-xattrs"),
+            owner: matches.get_one::<Owner>("chown").copied(),
+            mode: matches.get_one::<u32>("chmod").copied(),
+            content_index: (matches.get_flag("hardlink-duplicates")
+                || matches.get_one::<String>("format").unwrap() == "cas")
+                .then(|| get_path(matches, "output-dir").join(CONTENT_INDEX_FILE)),
+            git_dir: (matches.get_one::<String>("format").unwrap() == "git")
+                .then(|| get_path(matches, "output-dir")),
+            sync_safe: matches.get_flag("sync-safe"),
+            dir_creation: match (
+                matches.get_flag("no-create-dirs"),
+                matches.get_flag("create-parents"),
+            ) {
+                (true, _) => DirCreation::None,
+                (_, true) => DirCreation::Parents,
+                _ => DirCreation::Folders,
+            },
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            batch_writes: matches.get_flag("batch-writes"),
+        },
+        #[cfg(feature = "compression")]
+        rollup: matches.get_flag("rollup"),
+        filters: FilterOptions {
+            protect_active_threads: matches.get_one::<i64>("protect-active-threads").copied(),
+            ..parse_filter_args(matches)
+        },
+        spam: (matches.contains_id("spam-dir") || matches.contains_id("purge-spam-after")).then(
+            || SpamRouting {
+                threshold: *matches.get_one::<f64>("spam-threshold").unwrap(),
+                output_dir: matches
+                    .contains_id("spam-dir")
+                    .then(|| get_path(matches, "spam-dir")),
+                purge_after: matches.get_one::<i64>("purge-spam-after").copied(),
+            },
+        ),
+        config: get_config(matches),
+        routes: matches
+            .get_one::<Routes>("routes")
+            .cloned()
+            .unwrap_or_default(),
+        doveadm_user: matches.get_one::<String>("doveadm-user").cloned(),
+        folder_naming: match matches.get_one::<String>("folder-naming").unwrap().as_str() {
+            "courier" => FolderNaming::Courier,
+            _ => FolderNaming::Plain,
+        },
+        folder_name_encoding: match matches
+            .get_one::<String>("folder-name-encoding")
+            .unwrap()
+            .as_str()
+        {
+            "utf8" => NameEncoding::Utf8,
+            "imap-utf7" => NameEncoding::ImapUtf7,
+            _ => NameEncoding::Preserve,
+        },
+    }
+}
+
+fn get_config(matches: &ArgMatches) -> Config {
+    let config = matches
+        .get_one::<Config>("config")
+        .cloned()
+        .unwrap_or_default();
+    match matches.get_one::<String>("profile") {
+        // The profile has been checked by apply_profile before parsing
+        Some(name) => config
+            .select_profile(name)
+            .unwrap_or_else(|e| clap::Error::raw(ErrorKind::InvalidValue, e + "\n").exit()),
+        None => config,
+    }
+}
+
+/// Value of a long option in the command line arguments or in its
+/// environment variable, before parsing
+fn find_option(args: &[OsString], name: &str) -> Option<String> {
+    let option = format!("--{}", name);
+    args.iter()
+        .enumerate()
+        .find_map(|(i, arg)| match arg.to_str()?.strip_prefix(&option) {
+            Some("") => args.get(i + 1)?.to_str().map(str::to_string),
+            Some(value) => value.strip_prefix('=').map(str::to_string),
+            None => None,
+        })
+        .or_else(|| std::env::var(env_var(name)).ok())
+}
+
+/// Values of a profile option, as given on the command line
+fn profile_values(value: &toml::Value) -> Result<Vec<String>, String> {
+    match value {
+        toml::Value::String(s) => Ok(vec![s.clone()]),
+        toml::Value::Integer(i) => Ok(vec![i.to_string()]),
+        toml::Value::Boolean(b) => Ok(vec![b.to_string()]),
+        toml::Value::Datetime(d) => Ok(vec![d.to_string()]),
+        toml::Value::Array(values) => values.iter().try_fold(Vec::new(), |mut all, v| {
+            all.extend(profile_values(v)?);
+            Ok(all)
+        }),
+        _ => Err(format!("unsupported value {}", value)),
+    }
+}
+
+/// Ids and values of the archive options set by a profile
+fn profile_options(
+    command: &ClapCommand,
+    profile: &Profile,
+) -> Result<Vec<(String, Vec<String>)>, String> {
+    let mut options = Vec::new();
+    for (key, value) in &profile.options {
+        let id = option_id(key);
+        if !command.get_arguments().any(|arg| arg.get_id() == id) {
+            return Err(format!("unknown option {}", key));
+        }
+        let values = profile_values(value).map_err(|e| format!("{}: {}", key, e))?;
+        options.push((id.to_string(), values));
+    }
+    Ok(options)
+}
+
+/// Use the options of a profile as default values of the arguments
+fn set_defaults(mut command: ClapCommand, options: &[(String, Vec<String>)]) -> ClapCommand {
+    for (id, values) in options {
+        command = command.mut_arg(id, |arg| arg.required(false).default_values(values.clone()));
+    }
+    command
+}
+
+/// Apply the profile selected by `--profile` to the archive, plan, diff,
+/// check-config and bench commands
+fn apply_profile(command: ClapCommand, args: &[OsString]) -> ClapCommand {
+    let (Some(path), Some(name)) = (find_option(args, "config"), find_option(args, "profile"))
+    else {
+        return command;
+    };
+    let options = Config::read(&path).and_then(|config| {
+        let profile = config
+            .profiles
+            .get(&name)
+            .ok_or_else(|| format!("not found in {}", path))?;
+        profile_options(&command, profile)
+    });
+    match options {
+        Ok(options) => set_defaults(command, &options)
+            .mut_subcommand("plan", |plan| set_defaults(plan, &options))
+            .mut_subcommand("diff", |diff| set_defaults(diff, &options))
+            .mut_subcommand("check-config", |check| set_defaults(check, &options))
+            .mut_subcommand("bench", |bench| set_defaults(bench, &options)),
+        Err(e) => command
+            .clone()
+            .error(ErrorKind::InvalidValue, format!("profile {}: {}", name, e))
+            .exit(),
+    }
+}
+
+pub fn parse_args() -> Cli {
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let command = archive_args(
+        command!()
+            .version(env!("CARGO_PKG_VERSION"))
+            .about("Archive emails from maildir, grouping them by date")
+            .subcommand_negates_reqs(true),
+    )
+    .arg(
+        Arg::new("verbose")
+            .short('v')
+            .long("verbose")
+            .help("Increase the verbosity: -vvv logs the archived emails (info), -vvvv the debug messages")
+            .global(true)
+            .action(ArgAction::Count),
+    )
+    .arg(
+        Arg::new("log-level")
+            .long("log-level")
+            .value_name("LEVEL")
+            .help("Level of the log messages [default: warn]")
+            .global(true)
+            .conflicts_with("verbose")
+            .value_parser([
+                PossibleValue::new("error"),
+                PossibleValue::new("warn"),
+                PossibleValue::new("info"),
+                PossibleValue::new("debug"),
+                PossibleValue::new("trace"),
+            ]),
+    )
+    .arg(
+        Arg::new("color")
+            .long("color")
+            .help("Color the console output: errors, archived emails and dry runs")
+            .global(true)
+            .value_parser([
+                PossibleValue::new("auto").help("Color the output of a terminal"),
+                PossibleValue::new("always"),
+                PossibleValue::new("never"),
+            ])
+            .default_value("auto"),
+    )
+    .arg(
+        Arg::new("log-file")
+            .long("log-file")
+            .value_name("PATH")
+            .help("Append the log messages to a file instead of the standard error")
+            .global(true)
+            .value_parser(clap::value_parser!(PathBuf)),
+    )
+    .arg(
+        Arg::new("log-rotate")
+            .long("log-rotate")
+            .value_name("SIZE|daily")
+            .help("Rotate the log file before it exceeds a size, or every day")
+            .global(true)
+            .requires("log-file")
+            .value_parser(parse_log_rotation),
+    )
+    .arg(
+        Arg::new("log-keep")
+            .long("log-keep")
+            .value_name("N")
+            .help("Number of rotated log files kept, as <PATH>.1 to <PATH>.<N>")
+            .global(true)
+            .default_value("5")
+            .value_parser(clap::value_parser!(usize)),
+    )
+    .arg(
+        Arg::new("stats-format")
+            .long("stats-format")
+            .help("Output of the statistics (csv: header and values on stdout)")
+            .global(true)
+            .value_parser([PossibleValue::new("log"), PossibleValue::new("csv")])
+            .default_value("log"),
+    )
+    .subcommands([
+        resplit_command(),
+        #[cfg(feature = "compression")]
+        compact_command(),
+        dedup_command(),
+        orphans_command(),
+        expunge_command(),
+        views_command(),
+        stats_command(),
+        #[cfg(feature = "compression")]
+        rollup_command(),
+        #[cfg(feature = "compression")]
+        extract_command(),
+        plan_command(),
+        diff_command(),
+        fsck_command(),
+        apply_command(),
+        #[cfg(feature = "jmap")]
+        jmap_command(),
+        check_config_command(),
+        bench_command(),
+    ]);
+    let mut cli = apply_profile(command, &args);
+    let matches = cli.get_matches_mut();
+    let verbosity = match matches.get_one::<String>("log-level").map(String::as_str) {
+        Some("error") => LevelFilter::Error,
+        Some("warn") => LevelFilter::Warn,
+        Some("info") => LevelFilter::Info,
+        Some("debug") => LevelFilter::Debug,
+        Some(_) => LevelFilter::Trace,
+        // The errors and the warnings are always logged, -vvv still gives
+        // the info messages
+        None => match matches.get_count("verbose") {
+            0..=2 => LevelFilter::Warn,
+            3 => LevelFilter::Info,
+            _ => LevelFilter::Debug,
+        },
+    };
+    let command = match matches.subcommand() {
+        Some(("resplit", sub_matches)) => Command::Resplit(parse_resplit_args(sub_matches)),
+        #[cfg(feature = "compression")]
+        Some(("compact", sub_matches)) => Command::Compact(parse_compact_args(sub_matches)),
+        Some(("dedup", sub_matches)) => Command::Dedup(parse_dedup_args(sub_matches)),
+        Some(("orphans", sub_matches)) => Command::Orphans(parse_orphans_args(sub_matches)),
+        Some(("expunge", sub_matches)) => Command::Expunge(parse_expunge_args(sub_matches)),
+        Some(("views", sub_matches)) => Command::Views(parse_views_args(sub_matches)),
+        Some(("stats", sub_matches)) => Command::Stats(parse_stats_args(sub_matches)),
+        #[cfg(feature = "compression")]
+        Some(("rollup", sub_matches)) => Command::Compact(parse_rollup_args(sub_matches)),
+        #[cfg(feature = "compression")]
+        Some(("extract", sub_matches)) => Command::Extract(parse_extract_args(sub_matches)),
+        Some(("plan", sub_matches)) => Command::Plan(parse_plan_args(sub_matches)),
+        Some(("diff", sub_matches)) => Command::Diff(parse_diff_args(sub_matches)),
+        Some(("fsck", sub_matches)) => Command::Fsck(parse_fsck_args(sub_matches)),
+        Some(("apply", sub_matches)) => Command::Apply(parse_apply_args(sub_matches)),
+        #[cfg(feature = "jmap")]
+        Some(("jmap", sub_matches)) => Command::Jmap(parse_jmap_args(sub_matches)),
+        Some(("check-config", sub_matches)) => {
+            Command::CheckConfig(parse_check_config_args(sub_matches))
+        }
+        Some(("bench", sub_matches)) => Command::Bench(parse_bench_args(sub_matches)),
+        _ => Command::Archive(parse_archive_args(&matches)),
+    };
+    // Fail before starting instead of archiving nothing
+    let archive = match &command {
+        Command::Archive(opts) => Some(opts),
+        Command::Plan(opts) => Some(&opts.archive),
+        Command::Diff(opts) => Some(&opts.archive),
+        Command::CheckConfig(opts) => Some(&opts.archive),
+        Command::Bench(opts) => Some(&opts.archive),
+        _ => None,
+    };
+    if let Some(opts) = archive {
+        if let Err(e) = check_input_maildir(opts.input_maildir.path()) {
+            cli.error(
+                ErrorKind::ValueValidation,
+                format!("invalid value for <INPUT_PATH>: {}", e),
+            )
+            .exit();
+        }
+    }
+    let stats_format = match matches.get_one::<String>("stats-format").unwrap().as_str() {
+        "csv" => StatsFormat::Csv,
+        _ => StatsFormat::Log,
+    };
+    let color = match matches.get_one::<String>("color").unwrap().as_str() {
+        "always" => ColorChoice::Always,
+        "never" => ColorChoice::Never,
+        _ => ColorChoice::Auto,
+    };
+    let log_file = matches
+        .get_one::<PathBuf>("log-file")
+        .map(|path| LogFileOptions {
+            path: path.clone(),
+            rotation: matches.get_one::<LogRotation>("log-rotate").copied(),
+            keep: *matches.get_one::<usize>("log-keep").unwrap(),
+        });
+    Cli {
+        verbosity,
+        color,
+        log_file,
+        stats_format,
+        command,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::{
+        archive_args, check_input_maildir, months_before, parse_affix, parse_archive_args,
+        parse_date, parse_date_at, parse_size, profile_options, set_defaults,
+    };
+    use crate::config::Profile;
+    use clap::Command;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("2M"), Ok(2 * 1024 * 1024));
+        assert_eq!(parse_size("1k"), Ok(1024));
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("2X").is_err());
+        assert!(parse_size("18446744073709551615G").is_err());
+    }
+
+    #[test]
+    fn test_parse_date() {
+        assert_eq!(
+            parse_date("2016-05-21"),
+            Ok(time::macros::date!(2016 - 05 - 21))
+        );
+        assert!(parse_date("2016-13-01")
+            .unwrap_err()
+            .starts_with("invalid date 2016-13-01 at position 6"));
+        assert!(parse_date("2016-02-30")
+            .unwrap_err()
+            .starts_with("invalid date 2016-02-30 at position 9"));
+        assert_eq!(parse_affix("mail-"), Ok("mail-".to_string()));
+        assert_eq!(
+            parse_affix("a/b"),
+            Err("invalid character '/' at position 2 of a/b".to_string())
+        );
+        use time::macros::date;
+        assert_eq!(
+            months_before(date!(2024 - 02 - 29), 12),
+            Some(date!(2023 - 02 - 28))
+        );
+        assert_eq!(
+            months_before(date!(2024 - 03 - 31), 1),
+            Some(date!(2024 - 02 - 29))
+        );
+        assert_eq!(
+            months_before(date!(2024 - 01 - 15), 2),
+            Some(date!(2023 - 11 - 15))
+        );
+        let today = date!(2024 - 03 - 31);
+        for (value, date) in [
+            ("20160521", date!(2016 - 05 - 21)),
+            ("2016-05-21T23:30:00-02:00", date!(2016 - 05 - 22)),
+            ("yesterday", date!(2024 - 03 - 30)),
+            ("last year", date!(2023 - 03 - 31)),
+            ("2 months ago", date!(2024 - 01 - 31)),
+            ("1 Week Ago", date!(2024 - 03 - 24)),
+        ] {
+            assert_eq!(parse_date_at(value, today), Ok(date), "{}", value);
+        }
+        assert!(parse_date_at("2 fortnights ago", today)
+            .unwrap_err()
+            .contains("expected YYYY-mm-dd"));
+        assert!(parse_date_at("20161301", today).is_err());
+    }
+
+    #[test]
+    fn test_check_input_maildir() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        assert!(check_input_maildir(&basedir.join("missing")).is_err());
+        assert!(check_input_maildir(&basedir)
+            .unwrap_err()
+            .ends_with("is not a maildir: no cur directory nor maildir folder"));
+        std::fs::create_dir_all(basedir.join(".Sent").join("cur")).unwrap();
+        assert!(check_input_maildir(&basedir).is_ok());
+        assert!(check_input_maildir(&basedir.join(".Sent")).is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "jmap")]
+    fn test_jmap_token_file() {
+        use crate::cli::{jmap_command, parse_jmap_args};
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let token_file = basedir.join("token");
+        std::fs::write(&token_file, "secret\n").unwrap();
+        let matches = jmap_command()
+            .try_get_matches_from([
+                "jmap",
+                "--token-file",
+                token_file.to_str().unwrap(),
+                "https://example.com/jmap/session",
+                basedir.to_str().unwrap(),
+            ])
+            .unwrap();
+        assert_eq!(parse_jmap_args(&matches).token, "secret");
+        // The token can't be given on the command line
+        assert!(jmap_command()
+            .try_get_matches_from([
+                "jmap",
+                "--token",
+                "secret",
+                "https://example.com/jmap/session",
+                basedir.to_str().unwrap(),
+            ])
+            .is_err());
+        std::fs::write(&token_file, "\n").unwrap();
+        assert!(jmap_command()
+            .try_get_matches_from([
+                "jmap",
+                "--token-file",
+                token_file.to_str().unwrap(),
+                "https://example.com/jmap/session",
+                basedir.to_str().unwrap(),
+            ])
+            .is_err());
+    }
+
+    #[test]
+    fn test_profile_defaults() {
+        let command = archive_args(Command::new("test"));
+        let profile: Profile = toml::from_str(
+            r#"
+            input = "/mail/work"
+            output = "/archive/work"
+            before = 2020-01-01
+            stamp-provenance = true
+            "#,
+        )
+        .unwrap();
+        let options = profile_options(&command, &profile).unwrap();
+        let matches = set_defaults(command.clone(), &options)
+            .try_get_matches_from(["test", "--before", "2021-01-01"])
+            .unwrap();
+        let opts = parse_archive_args(&matches);
+        assert_eq!(
+            opts.input_maildir.path(),
+            std::path::Path::new("/mail/work")
+        );
+        assert_eq!(opts.before, time::macros::date!(2021 - 01 - 01));
+        assert!(opts.store_options.stamp_provenance);
+
+        let profile: Profile = toml::from_str("inptu = '/mail'").unwrap();
+        assert!(profile_options(&command, &profile).is_err());
+    }
+}
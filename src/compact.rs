@@ -0,0 +1,422 @@
+use crate::archiver::{path_with_suffix, ArchiveMode, MaildirArchiverError};
+use crate::args::{CompactOptions, SplitBy};
+use crate::dedup::{file_digest, reader_digest};
+use crate::encryption::Encryptor;
+use crate::mbox::write_mbox_message;
+use crate::resplit::list_archive_maildirs;
+use crate::seekable::{index_path, write_index, IndexEntry, SeekableReader, SeekableWriter};
+use crate::split::email_date;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::{debug, error, info, warn};
+use maildir::Maildir;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use time::OffsetDateTime;
+
+/// Format of compacted archive folders
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompactFormat {
+    /// Gzipped mbox file, `<folder>.mbox.gz`
+    Mbox,
+    /// Gzipped tarball of the maildir, `<folder>.tar.gz`
+    Tar,
+    /// Zstandard compressed tarball of the maildir, `<folder>.tar.zst`
+    TarZst,
+    /// Seekable archive with an index, `<folder>.zst` and `<folder>.zst.idx`
+    Seekable,
+}
+
+impl CompactFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            CompactFormat::Mbox => "mbox.gz",
+            CompactFormat::Tar => "tar.gz",
+            CompactFormat::TarZst => "tar.zst",
+            CompactFormat::Seekable => "zst",
+        }
+    }
+}
+
+/// Options used to roll up the folders of completed periods into
+/// verified `.tar.zst` files.
+pub fn rollup_options(
+    archive_dir: PathBuf,
+    archive_mode: ArchiveMode,
+    prefix: String,
+    suffix: String,
+    split_by: SplitBy,
+) -> CompactOptions {
+    CompactOptions {
+        archive_dir,
+        before: OffsetDateTime::now_utc().date(),
+        archive_mode,
+        format: CompactFormat::TarZst,
+        prefix,
+        suffix,
+        split_by,
+        encryptor: None,
+        verify: true,
+    }
+}
+
+fn write_mbox<W: Write>(maildir: &Maildir, writer: &mut W) -> Result<(), MaildirArchiverError> {
+    for entry in maildir.list_cur().chain(maildir.list_new()) {
+        let mail = entry?;
+        let date = match email_date(&mail) {
+            Ok(d) => d,
+            Err(e) => {
+                warn!("{}, using file modification time", e);
+                OffsetDateTime::from(fs::metadata(mail.path())?.modified()?)
+            }
+        };
+        write_mbox_message(writer, &fs::read(mail.path())?, mail.flags(), date)?;
+    }
+    Ok(())
+}
+
+fn write_tar<W: Write>(maildir: &Maildir, writer: W) -> Result<(), MaildirArchiverError> {
+    let mut builder = tar::Builder::new(writer);
+    let name = maildir.path().file_name().unwrap();
+    builder.append_dir_all(name, maildir.path())?;
+    builder.finish()?;
+    Ok(())
+}
+
+/// Write the compressed compacted form of a maildir, returning the inner
+/// writer.
+fn write_compressed<W: Write>(
+    maildir: &Maildir,
+    format: CompactFormat,
+    writer: W,
+) -> Result<W, MaildirArchiverError> {
+    match format {
+        CompactFormat::Mbox => {
+            let mut encoder = GzEncoder::new(writer, Compression::default());
+            write_mbox(maildir, &mut encoder)?;
+            Ok(encoder.finish()?)
+        }
+        CompactFormat::Tar => {
+            let mut encoder = GzEncoder::new(writer, Compression::default());
+            write_tar(maildir, &mut encoder)?;
+            Ok(encoder.finish()?)
+        }
+        CompactFormat::TarZst => {
+            let mut encoder = zstd::Encoder::new(writer, 0)?;
+            write_tar(maildir, &mut encoder)?;
+            Ok(encoder.finish()?)
+        }
+        CompactFormat::Seekable => unreachable!("seekable archives are written by write_seekable"),
+    }
+}
+
+fn list_files(dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        match entry.file_type()?.is_dir() {
+            true => list_files(&entry.path(), files)?,
+            false => files.push(entry.path()),
+        }
+    }
+    Ok(())
+}
+
+/// Check that a compacted file contains every email of the maildir.
+///
+/// Tarballs must contain every file of the maildir with the same content,
+/// mbox files must contain as many messages as the maildir.
+pub fn verify_compacted(
+    maildir: &Maildir,
+    format: CompactFormat,
+    path: &Path,
+) -> Result<(), MaildirArchiverError> {
+    let fail = |reason: String| {
+        Err(MaildirArchiverError::VerificationError(format!(
+            "{}: {}",
+            path.display(),
+            reason
+        )))
+    };
+    if format == CompactFormat::Seekable {
+        let mut reader = SeekableReader::open(path)?;
+        for (subdir, entries) in [("cur", maildir.list_cur()), ("new", maildir.list_new())] {
+            for entry in entries {
+                let mail = entry?;
+                let filename = mail.path().file_name().unwrap().to_string_lossy();
+                let name = format!("{}/{}", subdir, filename);
+                match reader.read(&name) {
+                    Err(e) => return fail(format!("{}: {}", name, e)),
+                    Ok(data) if data != fs::read(mail.path())? => {
+                        return fail(format!("{} differs", name))
+                    }
+                    Ok(_) => {}
+                }
+            }
+        }
+        return Ok(());
+    }
+    let file = BufReader::new(File::open(path)?);
+    let reader: Box<dyn Read> = match format {
+        CompactFormat::TarZst => Box::new(zstd::Decoder::with_buffer(file)?),
+        _ => Box::new(MultiGzDecoder::new(file)),
+    };
+    if format == CompactFormat::Mbox {
+        let mut count = 0;
+        for line in BufReader::new(reader).split(b'\n') {
+            if line?.starts_with(b"From ") {
+                count += 1;
+            }
+        }
+        let expected = maildir.count_cur() + maildir.count_new();
+        return match count == expected {
+            true => Ok(()),
+            false => fail(format!("{} emails found, {} expected", count, expected)),
+        };
+    }
+
+    let mut digests = HashMap::new();
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.header().entry_type().is_file() {
+            let entry_path = entry.path()?.into_owned();
+            digests.insert(entry_path, reader_digest(&mut entry)?);
+        }
+    }
+    let name = maildir.path().file_name().unwrap();
+    let mut files = Vec::new();
+    list_files(maildir.path(), &mut files)?;
+    for file in files {
+        let entry_path = Path::new(name).join(file.strip_prefix(maildir.path()).unwrap());
+        match digests.get(&entry_path) {
+            None => return fail(format!("{} is missing", entry_path.display())),
+            Some(digest) if *digest != file_digest(&file)? => {
+                return fail(format!("{} differs", entry_path.display()))
+            }
+            Some(_) => {}
+        }
+    }
+    Ok(())
+}
+
+/// Write a maildir as a seekable archive, returning the inner writer and the
+/// archive index.
+fn write_seekable<W: Write>(
+    maildir: &Maildir,
+    writer: W,
+) -> Result<(W, Vec<IndexEntry>), MaildirArchiverError> {
+    let mut writer = SeekableWriter::new(writer);
+    for (subdir, entries) in [("cur", maildir.list_cur()), ("new", maildir.list_new())] {
+        for entry in entries {
+            let mail = entry?;
+            let filename = mail.path().file_name().unwrap().to_string_lossy();
+            writer.add(&format!("{}/{}", subdir, filename), &fs::read(mail.path())?)?;
+        }
+    }
+    Ok(writer.finish()?)
+}
+
+/// Write the compacted form of a maildir to `dest`, encrypted if an
+/// encryptor is given.
+///
+/// The file is first written with a `.tmp` extension and renamed once
+/// complete, so that an interrupted run never leaves a truncated archive.
+pub fn write_compacted(
+    maildir: &Maildir,
+    format: CompactFormat,
+    encryptor: Option<&Encryptor>,
+    dest: &Path,
+) -> Result<(), MaildirArchiverError> {
+    let tmp = path_with_suffix(dest, ".tmp");
+    let file = BufWriter::new(File::create(&tmp)?);
+    let res = match (format, encryptor) {
+        (CompactFormat::Seekable, _) => write_seekable(maildir, file).and_then(|(w, entries)| {
+            let mut index = BufWriter::new(File::create(index_path(&tmp))?);
+            write_index(&mut index, &entries)?;
+            index.flush()?;
+            fs::rename(index_path(&tmp), index_path(dest))?;
+            Ok(w)
+        }),
+        (_, Some(encryptor)) => encryptor
+            .wrap_output(file)
+            .and_then(|w| write_compressed(maildir, format, w))
+            .and_then(|w| Ok(w.finish()?)),
+        (_, None) => write_compressed(maildir, format, file),
+    };
+    if let Err(e) = res.and_then(|mut w| Ok(w.flush()?)) {
+        fs::remove_file(&tmp)?;
+        return Err(e);
+    }
+    fs::rename(&tmp, dest)?;
+    Ok(())
+}
+
+/// Convert the archive folders older than `opts.before` into compressed
+/// files. Returns the number of compacted folders.
+pub fn compact(opts: &CompactOptions) -> Result<usize, MaildirArchiverError> {
+    if opts.format == CompactFormat::Seekable && opts.encryptor.is_some() {
+        return Err(MaildirArchiverError::Unsupported(
+            "seekable archives cannot be encrypted".to_string(),
+        ));
+    }
+    let mut compacted = 0;
+    for maildir in list_archive_maildirs(&opts.archive_dir)? {
+        let name = maildir.path().file_name().unwrap().to_string_lossy();
+        if !opts
+            .split_by
+            .is_folder_before(&opts.prefix, &opts.suffix, &name, opts.before)
+        {
+            debug!("Folder {} is not older than threshold", name);
+            continue;
+        }
+        let extension = match opts.encryptor {
+            Some(_) => format!("{}.age", opts.format.extension()),
+            None => opts.format.extension().to_string(),
+        };
+        let dest = opts.archive_dir.join(format!("{}.{}", name, extension));
+        if dest.exists() {
+            error!(
+                "Cannot compact folder {}: {} already exists",
+                maildir.path().display(),
+                dest.display()
+            );
+            continue;
+        }
+        if opts.archive_mode != ArchiveMode::DryRun {
+            if let Err(e) = write_compacted(&maildir, opts.format, opts.encryptor.as_ref(), &dest) {
+                error!(
+                    "Error while compacting folder {}: {}",
+                    maildir.path().display(),
+                    e
+                );
+                continue;
+            }
+            if opts.verify {
+                if let Err(e) = verify_compacted(&maildir, opts.format, &dest) {
+                    error!(
+                        "Verification of folder {} failed, keeping it: {}",
+                        maildir.path().display(),
+                        e
+                    );
+                    continue;
+                }
+                debug!("Verified {}", dest.display());
+            }
+        }
+        if opts.archive_mode == ArchiveMode::Move {
+            fs::remove_dir_all(maildir.path())?;
+        }
+        info!(
+            "Folder {} compacted to {}",
+            maildir.path().display(),
+            dest.display()
+        );
+        compacted += 1;
+    }
+    Ok(compacted)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::archiver::ArchiveMode;
+    use crate::args::CompactOptions;
+    use crate::compact::{compact, rollup_options, CompactFormat};
+    use crate::split::SplitBy;
+    use flate2::read::GzDecoder;
+    use maildir::Maildir;
+    use std::io::Read;
+    use time::macros::date;
+
+    #[test]
+    fn test_compact_mbox() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        for name in ["2016", "2017"] {
+            let maildir = Maildir::from(basedir.join(name));
+            maildir.create_dirs().unwrap();
+            let filename = "1463868505.38518452d49213cb409aa1db32f53184:2,S";
+            std::fs::copy(
+                format!("testdata/maildir1/cur/{}", filename),
+                maildir.path().join("cur").join(filename),
+            )
+            .unwrap();
+        }
+
+        let opts = CompactOptions {
+            archive_dir: basedir.to_path_buf(),
+            before: date!(2017 - 06 - 01),
+            archive_mode: ArchiveMode::Move,
+            format: CompactFormat::Mbox,
+            prefix: String::new(),
+            suffix: String::new(),
+            split_by: SplitBy::Year,
+            encryptor: None,
+            verify: true,
+        };
+        assert_eq!(compact(&opts).unwrap(), 1);
+        assert!(!basedir.join("2016").exists());
+        assert!(basedir.join("2017").exists());
+
+        let mut mbox = String::new();
+        GzDecoder::new(std::fs::File::open(basedir.join("2016.mbox.gz")).unwrap())
+            .read_to_string(&mut mbox)
+            .unwrap();
+        assert!(mbox.starts_with("From MAILER-DAEMON Sat May 21 22:08:27 2016\nStatus: RO\n"));
+    }
+
+    #[test]
+    fn test_rollup() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let maildir = Maildir::from(basedir.join("2016"));
+        maildir.create_dirs().unwrap();
+        let filename = "1463868505.38518452d49213cb409aa1db32f53184:2,S";
+        std::fs::copy(
+            format!("testdata/maildir1/cur/{}", filename),
+            maildir.path().join("cur").join(filename),
+        )
+        .unwrap();
+
+        let opts = rollup_options(
+            basedir.to_path_buf(),
+            ArchiveMode::Move,
+            String::new(),
+            String::new(),
+            SplitBy::Year,
+        );
+        assert_eq!(compact(&opts).unwrap(), 1);
+        assert!(!maildir.path().exists());
+        assert!(basedir.join("2016.tar.zst").exists());
+    }
+
+    #[test]
+    fn test_compact_seekable() {
+        use crate::seekable::SeekableReader;
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let maildir = Maildir::from(basedir.join("2016"));
+        maildir.create_dirs().unwrap();
+        let filename = "1463868505.38518452d49213cb409aa1db32f53184:2,S";
+        std::fs::copy(
+            format!("testdata/maildir1/cur/{}", filename),
+            maildir.path().join("cur").join(filename),
+        )
+        .unwrap();
+
+        let mut opts = rollup_options(
+            basedir.to_path_buf(),
+            ArchiveMode::Move,
+            String::new(),
+            String::new(),
+            SplitBy::Year,
+        );
+        opts.format = CompactFormat::Seekable;
+        assert_eq!(compact(&opts).unwrap(), 1);
+        let mut reader = SeekableReader::open(&basedir.join("2016.zst")).unwrap();
+        let data = reader.read(&format!("cur/{}", filename)).unwrap();
+        assert!(data.starts_with(b"Return-Path: <example@example.com>\n"));
+    }
+}
@@ -0,0 +1,187 @@
+use crate::archiver::ArchiveMode;
+use crate::folders::SourceFolder;
+use crate::split::SplitBy;
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::fs;
+use time::macros::format_description;
+use time::{Date, Duration, OffsetDateTime};
+
+/// Settings read from the configuration file (`--config`), in TOML:
+///
+/// ```toml
+/// [[folder]]
+/// pattern = ".Spam"
+/// older-than-days = 30
+/// mode = "move"
+///
+/// [[folder]]
+/// pattern = ".Sent"
+/// older-than-days = 1825
+/// split-by = "month"
+///
+/// [profile.work]
+/// input = "/home/user/Maildir/work"
+/// output = "/srv/archive/work"
+/// mode = "move"
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Overrides of the archive options for the folders matching a pattern
+    #[serde(default, rename = "folder")]
+    pub folders: Vec<FolderOverride>,
+    /// Named archive jobs, selected with `--profile`
+    #[serde(default, rename = "profile")]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+/// Named section of the configuration file, giving the values of the command
+/// line options of an archive job
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+pub struct Profile {
+    /// Overrides of the profile, applied after the global ones
+    #[serde(default, rename = "folder")]
+    pub folders: Vec<FolderOverride>,
+    /// Values of the options, by long name (`input` and `output` for the
+    /// positional arguments)
+    #[serde(flatten)]
+    pub options: BTreeMap<String, toml::Value>,
+}
+
+/// Archive options overridden for the source folders matching `pattern`
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct FolderOverride {
+    /// Folder name pattern (`INBOX`, `.Sent`, `.Lists.*`), where `*` matches
+    /// any sequence of characters and `?` any single character
+    pub pattern: String,
+    #[serde(default, deserialize_with = "deserialize_date")]
+    pub before: Option<Date>,
+    /// Archive the emails older than the given number of days
+    pub older_than_days: Option<i64>,
+    pub mode: Option<ArchiveMode>,
+    pub split_by: Option<SplitBy>,
+}
+
+fn deserialize_date<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Date>, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    Date::parse(&value, format_description!("[year]-[month]-[day]"))
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+impl FolderOverride {
+    /// Check if the override applies to a source folder
+    pub fn matches(&self, folder: &SourceFolder) -> bool {
+        folder.matches(&self.pattern)
+    }
+}
+
+/// Archive options of a source folder
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FolderOptions {
+    pub before: Date,
+    pub archive_mode: ArchiveMode,
+    pub split_by: SplitBy,
+}
+
+impl Config {
+    /// Read the configuration file
+    pub fn read(path: &str) -> Result<Self, String> {
+        let content = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        toml::from_str(&content).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    /// Configuration of a profile: the global overrides followed by the ones
+    /// of the profile
+    pub fn select_profile(&self, name: &str) -> Result<Config, String> {
+        let profile = self
+            .profiles
+            .get(name)
+            .ok_or_else(|| format!("profile {} not found", name))?;
+        Ok(Config {
+            folders: self
+                .folders
+                .iter()
+                .chain(&profile.folders)
+                .cloned()
+                .collect(),
+            profiles: BTreeMap::new(),
+        })
+    }
+
+    /// Options of a source folder: all the overrides matching the folder are
+    /// applied to `defaults`, in the order of the configuration file.
+    pub fn resolve(&self, folder: &SourceFolder, defaults: FolderOptions) -> FolderOptions {
+        let mut options = defaults;
+        for folder_override in self.folders.iter().filter(|o| o.matches(folder)) {
+            if let Some(days) = folder_override.older_than_days {
+                options.before = (OffsetDateTime::now_utc() - Duration::days(days)).date();
+            }
+            if let Some(before) = folder_override.before {
+                options.before = before;
+            }
+            if let Some(mode) = folder_override.mode {
+                options.archive_mode = mode;
+            }
+            if let Some(split_by) = folder_override.split_by {
+                options.split_by = split_by;
+            }
+        }
+        options
+    }
+
+    /// Archive modes used by the overrides
+    pub fn archive_modes(&self) -> impl Iterator<Item = ArchiveMode> + '_ {
+        self.folders.iter().filter_map(|o| o.mode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::archiver::ArchiveMode;
+    use crate::config::{Config, FolderOptions};
+    use crate::folders::SourceFolder;
+    use crate::split::SplitBy;
+    use maildir::Maildir;
+    use std::path::PathBuf;
+    use time::macros::date;
+
+    #[test]
+    fn test_resolve() {
+        let config: Config = toml::from_str(
+            r#"
+            [[folder]]
+            pattern = ".Lists.*"
+            mode = "copy"
+            split-by = "month"
+
+            [[folder]]
+            pattern = ".Lists.rust"
+            before = "2020-01-01"
+            "#,
+        )
+        .unwrap();
+        let defaults = FolderOptions {
+            before: date!(2024 - 01 - 01),
+            archive_mode: ArchiveMode::Move,
+            split_by: SplitBy::Year,
+        };
+        let folder = |name: &str| SourceFolder {
+            name: name.into(),
+            maildir: Maildir::from(PathBuf::from("/mail")),
+        };
+        assert_eq!(config.resolve(&folder(""), defaults), defaults);
+        assert_eq!(config.resolve(&folder(".Listsrust"), defaults), defaults);
+        assert_eq!(
+            config.resolve(&folder(".Lists.rust"), defaults),
+            FolderOptions {
+                before: date!(2020 - 01 - 01),
+                archive_mode: ArchiveMode::Copy,
+                split_by: SplitBy::Month,
+            }
+        );
+        assert!(toml::from_str::<Config>("[[folder]]\npattern = 'x'\nmod = 'copy'").is_err());
+    }
+}
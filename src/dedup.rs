@@ -0,0 +1,271 @@
+use crate::archiver::{path_with_suffix, MaildirArchiverError};
+use crate::args::DedupOptions;
+use log::{debug, error, info};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// What to do with duplicated emails
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DedupAction {
+    /// Remove the duplicated files
+    Delete,
+    /// Replace the duplicated files with hardlinks to the first copy
+    Hardlink,
+    /// Only report the duplicated files
+    DryRun,
+}
+
+/// Result of a deduplication run
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct DedupReport {
+    /// Number of duplicated emails
+    pub duplicates: usize,
+    /// Bytes reclaimed by removing or hardlinking duplicates
+    pub reclaimed: u64,
+}
+
+/// SHA-256 digest of the data read from `reader`
+pub fn reader_digest<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut hasher = Sha256::new();
+    io::copy(reader, &mut hasher)?;
+    Ok(hasher.finalize().to_vec())
+}
+
+/// Hexadecimal representation of a digest
+pub fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// SHA-256 digest of the content of a file
+pub fn file_digest(path: &Path) -> io::Result<Vec<u8>> {
+    reader_digest(&mut File::open(path)?)
+}
+
+/// Recursively collect the emails stored in the `cur` and `new` folders of
+/// all the maildirs under `dir`.
+pub fn find_emails(dir: &Path, emails: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let path = entry.path();
+        match path.file_name().and_then(|n| n.to_str()) {
+            Some("cur") | Some("new") => {
+                for mail in fs::read_dir(&path)? {
+                    let mail = mail?;
+                    if mail.file_type()?.is_file()
+                        && !mail.file_name().to_string_lossy().starts_with('.')
+                    {
+                        emails.push(mail.path());
+                    }
+                }
+            }
+            Some("tmp") => {}
+            _ => find_emails(&path, emails)?,
+        }
+    }
+    Ok(())
+}
+
+/// Name of the hash index of the archived emails, in the output directory
+pub const CONTENT_INDEX_FILE: &str = ".archive-maildir-index";
+
+/// Index of the emails of an archive tree by SHA-256 digest of their
+/// content, saved as lines with the hexadecimal digest and the path of the
+/// email relative to the directory of the index, separated by a tab.
+///
+/// Emails renamed or removed after being indexed, e.g. when a mail client
+/// changes their flags, leave stale entries: the paths must be checked
+/// before use.
+#[derive(Debug, Default)]
+pub struct ContentIndex {
+    path: PathBuf,
+    emails: HashMap<String, PathBuf>,
+    changed: bool,
+}
+
+impl ContentIndex {
+    /// Load an index, empty if the file does not exist yet
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut index = ContentIndex {
+            path: path.to_path_buf(),
+            ..Default::default()
+        };
+        let content = match fs::read(path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(index),
+            content => content?,
+        };
+        let dir = path.parent().unwrap_or(Path::new(""));
+        // The paths are written as raw bytes, they may not be UTF-8
+        for line in content.split(|&b| b == b'\n') {
+            if let Some(i) = line.iter().position(|&b| b == b'\t') {
+                let digest = String::from_utf8_lossy(&line[..i]).into_owned();
+                let email = OsStr::from_bytes(&line[i + 1..]);
+                index.emails.insert(digest, dir.join(email));
+            }
+        }
+        Ok(index)
+    }
+
+    /// Path of the indexed email with the given hexadecimal digest
+    pub fn get(&self, digest: &str) -> Option<&Path> {
+        self.emails.get(digest).map(PathBuf::as_path)
+    }
+
+    /// Index an email
+    pub fn insert(&mut self, digest: String, email: PathBuf) {
+        self.emails.insert(digest, email);
+        self.changed = true;
+    }
+
+    /// Write the index, if it has changed, replacing the file atomically
+    pub fn save(&mut self) -> io::Result<()> {
+        if !self.changed {
+            return Ok(());
+        }
+        let dir = self.path.parent().unwrap_or(Path::new(""));
+        let mut entries: Vec<_> = self.emails.iter().collect();
+        entries.sort();
+        let mut content = Vec::new();
+        for (digest, email) in entries {
+            let email = email.strip_prefix(dir).unwrap_or(email);
+            content.extend_from_slice(digest.as_bytes());
+            content.push(b'\t');
+            content.extend_from_slice(email.as_os_str().as_bytes());
+            content.push(b'\n');
+        }
+        if !dir.as_os_str().is_empty() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp = path_with_suffix(&self.path, ".tmp");
+        fs::write(&tmp, content)?;
+        fs::rename(&tmp, &self.path)?;
+        self.changed = false;
+        Ok(())
+    }
+}
+
+/// Replace `path` with a hardlink to `original`, atomically.
+pub fn replace_with_hardlink(original: &Path, path: &Path) -> io::Result<()> {
+    let tmp = path_with_suffix(path, ".dedup");
+    fs::hard_link(original, &tmp)?;
+    fs::rename(&tmp, path).inspect_err(|_| {
+        let _ = fs::remove_file(&tmp);
+    })
+}
+
+/// Find identical emails in the archive tree and remove or hardlink the extra
+/// copies, keeping the first one in path order.
+pub fn dedup(opts: &DedupOptions) -> Result<DedupReport, MaildirArchiverError> {
+    let mut emails = Vec::new();
+    find_emails(&opts.archive_dir, &mut emails)?;
+    emails.sort();
+
+    // Only files with the same size can be identical
+    let mut by_size: HashMap<u64, Vec<PathBuf>> = HashMap::new();
+    for path in emails {
+        by_size
+            .entry(fs::metadata(&path)?.len())
+            .or_default()
+            .push(path);
+    }
+
+    let mut report = DedupReport::default();
+    let mut sizes: Vec<_> = by_size.into_iter().filter(|(_, p)| p.len() > 1).collect();
+    sizes.sort();
+    for (size, paths) in sizes {
+        let mut by_digest: HashMap<Vec<u8>, &PathBuf> = HashMap::new();
+        for path in &paths {
+            let digest = match file_digest(path) {
+                Ok(d) => d,
+                Err(e) => {
+                    error!("Cannot read {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            let original = match by_digest.get(&digest) {
+                Some(original) => *original,
+                None => {
+                    by_digest.insert(digest, path);
+                    continue;
+                }
+            };
+            let (original_meta, meta) = (fs::metadata(original)?, fs::metadata(path)?);
+            if original_meta.dev() == meta.dev() && original_meta.ino() == meta.ino() {
+                debug!(
+                    "{} is already a hardlink to {}",
+                    path.display(),
+                    original.display()
+                );
+                continue;
+            }
+            let res = match opts.action {
+                DedupAction::Delete => fs::remove_file(path),
+                DedupAction::Hardlink => replace_with_hardlink(original, path),
+                DedupAction::DryRun => Ok(()),
+            };
+            match res {
+                Ok(()) => {
+                    info!(
+                        "{} is a duplicate of {}",
+                        path.display(),
+                        original.display()
+                    );
+                    report.duplicates += 1;
+                    report.reclaimed += size;
+                }
+                Err(e) => error!("Cannot deduplicate {}: {}", path.display(), e),
+            }
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::args::DedupOptions;
+    use crate::dedup::{dedup, DedupAction, DedupReport};
+    use maildir::Maildir;
+    use std::os::unix::fs::MetadataExt;
+
+    #[test]
+    fn test_dedup_hardlink() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let filename = "1463868505.38518452d49213cb409aa1db32f53184:2,S";
+        for name in ["2016", "2017/.Sent"] {
+            let maildir = Maildir::from(basedir.join(name));
+            maildir.create_dirs().unwrap();
+            std::fs::copy(
+                format!("testdata/maildir1/cur/{}", filename),
+                maildir.path().join("cur").join(filename),
+            )
+            .unwrap();
+        }
+        let size = std::fs::metadata(basedir.join("2016/cur").join(filename))
+            .unwrap()
+            .len();
+
+        let mut opts = DedupOptions {
+            archive_dir: basedir.to_path_buf(),
+            action: DedupAction::Hardlink,
+        };
+        let expected = DedupReport {
+            duplicates: 1,
+            reclaimed: size,
+        };
+        assert_eq!(dedup(&opts).unwrap(), expected);
+        let meta = std::fs::metadata(basedir.join("2016/cur").join(filename)).unwrap();
+        assert_eq!(meta.nlink(), 2);
+
+        opts.action = DedupAction::Delete;
+        assert_eq!(dedup(&opts).unwrap(), DedupReport::default());
+    }
+}
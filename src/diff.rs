@@ -0,0 +1,170 @@
+use crate::archiver::MaildirArchiverError;
+use crate::args::DiffOptions;
+use crate::dedup::{file_digest, find_emails, to_hex};
+use crate::run::{folder_options, select_emails, source_folders, Destination};
+use log::error;
+use mailparse::MailHeaderMap;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Result of a diff between the source and the archive
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct DiffReport {
+    /// Selected emails of the source missing from the archive
+    pub source_only: usize,
+    /// Emails of the archive missing from the selected emails of the source
+    pub archive_only: usize,
+}
+
+impl DiffReport {
+    /// Check if the source and the archive contain the same emails
+    pub fn in_sync(&self) -> bool {
+        self.source_only == 0 && self.archive_only == 0
+    }
+}
+
+/// Key identifying an email in the source and in the archive: its
+/// Message-ID, or the digest of its content if it has none
+pub fn diff_key(path: &Path) -> io::Result<String> {
+    let data = std::fs::read(path)?;
+    let message_id = mailparse::parse_headers(&data)
+        .ok()
+        .and_then(|(headers, _)| headers.get_first_value("Message-ID"))
+        .map(|id| id.trim().to_string())
+        .filter(|id| !id.is_empty());
+    match message_id {
+        Some(id) => Ok(id),
+        None => Ok(format!("sha256:{}", to_hex(&file_digest(path)?))),
+    }
+}
+
+/// Emails by key, skipping the unreadable ones
+fn index_emails(paths: impl IntoIterator<Item = PathBuf>) -> HashMap<String, Vec<PathBuf>> {
+    let mut index: HashMap<String, Vec<PathBuf>> = HashMap::new();
+    for path in paths {
+        match diff_key(&path) {
+            Ok(key) => index.entry(key).or_default().push(path),
+            Err(e) => error!("Cannot read {}: {}", path.display(), e),
+        }
+    }
+    index
+}
+
+/// Write the emails found only on one side, sorted by path
+fn write_missing<W: Write>(
+    out: &mut W,
+    marker: char,
+    emails: &HashMap<String, Vec<PathBuf>>,
+    other: &HashMap<String, Vec<PathBuf>>,
+) -> io::Result<usize> {
+    let mut missing: Vec<_> = emails
+        .iter()
+        .filter(|(key, _)| !other.contains_key(*key))
+        .flat_map(|(key, paths)| paths.iter().map(move |path| (path, key)))
+        .collect();
+    missing.sort();
+    for (path, key) in &missing {
+        writeln!(out, "{}\t{}\t{}", marker, key, path.display())?;
+    }
+    Ok(missing.len())
+}
+
+/// Compare the emails of the source selected by the archive options with the
+/// emails of the maildirs of the archive, by Message-ID or content digest.
+///
+/// The emails found only in the source are written with a `-` marker, the
+/// ones found only in the archive with a `+` marker, followed by their key
+/// and their path. Nothing is archived or deleted, and the seen database is
+/// not used: the emails already archived are compared too.
+pub fn diff<W: Write>(opts: &DiffOptions, out: &mut W) -> Result<DiffReport, MaildirArchiverError> {
+    let archive = &opts.archive;
+    let mut source = Vec::new();
+    for folder in source_folders(archive)? {
+        let folder_opts = folder_options(archive, &folder);
+        for (mail, destination) in select_emails(archive, &folder, &folder_opts, None, None)? {
+            // Purged spam is never in the archive
+            if let Destination::Archive(_) = destination {
+                source.push(mail.path().to_path_buf());
+            }
+        }
+    }
+    let mut archived = Vec::new();
+    let spam_dir = archive.spam.as_ref().and_then(|s| s.output_dir.as_ref());
+    let dirs = std::iter::once(&archive.output_dir)
+        .chain(spam_dir)
+        .chain(archive.routes.outputs());
+    for dir in dirs {
+        if dir.is_dir() {
+            find_emails(dir, &mut archived)?;
+        }
+    }
+    let (source, archived) = (index_emails(source), index_emails(archived));
+    Ok(DiffReport {
+        source_only: write_missing(out, '-', &source, &archived)?,
+        archive_only: write_missing(out, '+', &archived, &source)?,
+    })
+}
+
+#[cfg(all(test, feature = "cli"))]
+mod tests {
+    use crate::args::DiffOptions;
+    use crate::cli::{archive_args, parse_archive_args};
+    use crate::diff::{diff, DiffReport};
+    use clap::Command;
+    use maildir::Maildir;
+
+    #[test]
+    fn test_diff() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let input_maildir = Maildir::from(basedir.join("in"));
+        input_maildir.create_dirs().unwrap();
+        let filename = "1463868505.38518452d49213cb409aa1db32f53184:2,S";
+        std::fs::copy(
+            format!("testdata/maildir1/cur/{}", filename),
+            input_maildir.path().join("cur").join(filename),
+        )
+        .unwrap();
+        let output = basedir.join("out");
+        let archive_maildir = Maildir::from(output.join("2016"));
+        archive_maildir.create_dirs().unwrap();
+        let matches = archive_args(Command::new("test"))
+            .try_get_matches_from([
+                "test",
+                input_maildir.path().to_str().unwrap(),
+                output.to_str().unwrap(),
+            ])
+            .unwrap();
+        let opts = DiffOptions {
+            archive: parse_archive_args(&matches),
+        };
+
+        let mut out = Vec::new();
+        let report = diff(&opts, &mut out).unwrap();
+        assert_eq!(
+            report,
+            DiffReport {
+                source_only: 1,
+                archive_only: 0
+            }
+        );
+        assert!(String::from_utf8(out).unwrap().starts_with("-\t"));
+
+        std::fs::copy(
+            format!("testdata/maildir1/cur/{}", filename),
+            archive_maildir.path().join("cur").join(filename),
+        )
+        .unwrap();
+        archive_maildir
+            .store_cur_with_flags(b"Message-ID: <extra@example.org>\r\n\r\nbody\r\n", "S")
+            .unwrap();
+        let mut out = Vec::new();
+        let report = diff(&opts, &mut out).unwrap();
+        assert!(!report.in_sync());
+        assert_eq!(report.archive_only, 1);
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .starts_with("+\t<extra@example.org>\t"));
+    }
+}
@@ -0,0 +1,45 @@
+use crate::folders::SourceFolder;
+use std::ffi::OsStr;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::process::Command;
+
+/// Name of the Dovecot mailbox of a source folder, with `.` as hierarchy
+/// separator as in the Maildir++ layout
+pub fn mailbox_name(folder: &SourceFolder) -> &OsStr {
+    match folder.name.as_bytes().strip_prefix(b".") {
+        Some(name) => OsStr::from_bytes(name),
+        None => OsStr::new("INBOX"),
+    }
+}
+
+/// Run `doveadm force-resync` on the mailbox of a source folder, so that the
+/// IMAP clients of `user` see the emails removed by the run
+pub fn force_resync(user: &str, folder: &SourceFolder) -> io::Result<()> {
+    let status = Command::new("doveadm")
+        .args(["force-resync", "-u", user])
+        .arg(mailbox_name(folder))
+        .status()?;
+    match status.success() {
+        true => Ok(()),
+        false => Err(io::Error::other(format!("doveadm force-resync {}", status))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dovecot::mailbox_name;
+    use crate::folders::SourceFolder;
+    use maildir::Maildir;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_mailbox_name() {
+        let folder = |name: &str| SourceFolder {
+            name: name.into(),
+            maildir: Maildir::from(PathBuf::from("/mail")),
+        };
+        assert_eq!(mailbox_name(&folder("")), "INBOX");
+        assert_eq!(mailbox_name(&folder(".Lists.rust")), "Lists.rust");
+    }
+}
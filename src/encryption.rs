@@ -0,0 +1,103 @@
+use crate::archiver::MaildirArchiverError;
+use age::stream::StreamWriter;
+use age::x25519::Recipient;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+
+/// Encrypt archived data for a set of age recipients.
+///
+/// It is serialized as the list of recipients.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(into = "Vec<String>", try_from = "Vec<String>")]
+pub struct Encryptor {
+    recipients: Vec<Recipient>,
+}
+
+impl From<Encryptor> for Vec<String> {
+    fn from(value: Encryptor) -> Self {
+        value.recipients.iter().map(|r| r.to_string()).collect()
+    }
+}
+
+impl TryFrom<Vec<String>> for Encryptor {
+    type Error = String;
+
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        let recipients = value
+            .iter()
+            .map(|r| parse_recipient(r))
+            .collect::<Result<_, _>>()?;
+        Ok(Encryptor::new(recipients))
+    }
+}
+
+impl Encryptor {
+    pub fn new(recipients: Vec<Recipient>) -> Self {
+        Encryptor { recipients }
+    }
+
+    fn age_encryptor(&self) -> Result<age::Encryptor, MaildirArchiverError> {
+        Ok(age::Encryptor::with_recipients(
+            self.recipients.iter().map(|r| r as &dyn age::Recipient),
+        )?)
+    }
+
+    /// Encrypt a whole email
+    pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>, MaildirArchiverError> {
+        let mut encrypted = Vec::new();
+        let mut writer = self.wrap_output(&mut encrypted)?;
+        writer.write_all(data)?;
+        writer.finish()?;
+        Ok(encrypted)
+    }
+
+    /// Wrap a writer, encrypting everything written to it. The returned
+    /// writer must be finished with [`StreamWriter::finish`].
+    pub fn wrap_output<W: Write>(
+        &self,
+        output: W,
+    ) -> Result<StreamWriter<W>, MaildirArchiverError> {
+        Ok(self.age_encryptor()?.wrap_output(output)?)
+    }
+}
+
+/// Parse an age recipient (`age1...`)
+pub fn parse_recipient(value: &str) -> Result<Recipient, String> {
+    value.parse::<Recipient>().map_err(|e| e.to_string())
+}
+
+/// Read age recipients from a file, one per line. Empty lines and lines
+/// starting with `#` are ignored.
+pub fn read_recipients_file(path: &str) -> Result<Vec<Recipient>, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(parse_recipient)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encryption::Encryptor;
+    use std::io::Read;
+
+    #[test]
+    fn test_encrypt() {
+        let identity = age::x25519::Identity::generate();
+        let encryptor = Encryptor::new(vec![identity.to_public()]);
+        let encrypted = encryptor.encrypt(b"Subject: test\n\nbody\n").unwrap();
+        assert!(encrypted.starts_with(b"age-encryption.org/v1\n"));
+
+        let decryptor = age::Decryptor::new(&encrypted[..]).unwrap();
+        let mut decrypted = Vec::new();
+        decryptor
+            .decrypt(std::iter::once(&identity as &dyn age::Identity))
+            .unwrap()
+            .read_to_end(&mut decrypted)
+            .unwrap();
+        assert_eq!(decrypted, b"Subject: test\n\nbody\n");
+    }
+}
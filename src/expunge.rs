@@ -0,0 +1,240 @@
+use crate::archiver::{path_with_suffix, MaildirArchiverError};
+use crate::args::ExpungeOptions;
+use crate::dedup::{file_digest, find_emails, to_hex};
+use log::{error, info};
+use maildir::Maildir;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use time::OffsetDateTime;
+
+/// Name of the journal of the emails whose deletion is deferred, in the
+/// output directory
+pub const DEFERRED_JOURNAL_FILE: &str = "archive-maildir.deferred";
+
+/// Email copied to the archive, to be deleted from the source maildir by
+/// [`expunge`]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeferredEmail {
+    /// Source maildir of the email
+    pub maildir: PathBuf,
+    pub id: String,
+    /// Hexadecimal SHA-256 digest of the content of the email
+    pub digest: String,
+    pub archive_folder: PathBuf,
+    /// Unix timestamp of the copy to the archive
+    pub archived_at: i64,
+}
+
+/// Journal of the emails whose deletion is deferred: a JSON object per
+/// line, appended as soon as an email is copied.
+pub struct DeferredJournal {
+    digests: Mutex<HashSet<String>>,
+    file: Mutex<File>,
+}
+
+/// Read the entries of a journal, none if it doesn't exist
+fn read_journal(path: &Path) -> io::Result<Vec<DeferredEmail>> {
+    let file = match File::open(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        file => file?,
+    };
+    let mut entries = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if !line.is_empty() {
+            entries.push(serde_json::from_str(&line)?);
+        }
+    }
+    Ok(entries)
+}
+
+impl DeferredJournal {
+    /// Open the journal, creating it if it doesn't exist
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let digests = read_journal(path)?.into_iter().map(|e| e.digest).collect();
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(DeferredJournal {
+            digests: Mutex::new(digests),
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Check if an email with the given digest is waiting for its deletion
+    pub fn contains(&self, digest: &str) -> bool {
+        self.digests.lock().unwrap().contains(digest)
+    }
+
+    /// Record an email copied to the archive
+    pub fn record(&self, email: &DeferredEmail) -> io::Result<()> {
+        self.digests.lock().unwrap().insert(email.digest.clone());
+        let line = serde_json::to_string(email)?;
+        writeln!(self.file.lock().unwrap(), "{}", line)
+    }
+}
+
+/// Result of an expunge
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ExpungeReport {
+    /// Number of source emails deleted, or to delete in a dry run
+    pub expunged: usize,
+    /// Number of emails copied too recently
+    pub pending: usize,
+    /// Number of emails without an identical copy in their archive folder
+    pub failed: usize,
+}
+
+/// Digests of the emails of the archive folders, each folder being read
+/// once by an expunge
+#[derive(Default)]
+struct ArchiveDigests(HashMap<PathBuf, HashSet<String>>);
+
+impl ArchiveDigests {
+    /// Check that the archive folder of an email has a copy of it
+    fn verify(&mut self, email: &DeferredEmail) -> io::Result<bool> {
+        if !self.0.contains_key(&email.archive_folder) {
+            let mut archived = Vec::new();
+            find_emails(&email.archive_folder, &mut archived)?;
+            let digests = archived
+                .iter()
+                .map(|path| Ok(to_hex(&file_digest(path)?)))
+                .collect::<io::Result<_>>()?;
+            self.0.insert(email.archive_folder.clone(), digests);
+        }
+        Ok(self.0[&email.archive_folder].contains(&email.digest))
+    }
+}
+
+/// Delete the source emails copied to the archive more than `older_than`
+/// days ago, after checking that their archive folder still has an identical
+/// copy. The emails whose copy is missing or corrupted are kept, as their
+/// journal entries.
+///
+/// The journal is rewritten: no run writing to it must be in progress.
+pub fn expunge(opts: &ExpungeOptions) -> Result<ExpungeReport, MaildirArchiverError> {
+    let path = opts.archive_dir.join(DEFERRED_JOURNAL_FILE);
+    let cutoff = OffsetDateTime::now_utc().unix_timestamp() - i64::from(opts.older_than) * 86400;
+    let mut report = ExpungeReport::default();
+    let mut kept = Vec::new();
+    let mut digests = ArchiveDigests::default();
+    for email in read_journal(&path)? {
+        if email.archived_at > cutoff {
+            report.pending += 1;
+            kept.push(email);
+            continue;
+        }
+        let maildir = Maildir::from(email.maildir.clone());
+        let mail = match maildir.find(&email.id) {
+            Some(mail) => mail,
+            None => {
+                info!("Email {} already deleted", email.id);
+                continue;
+            }
+        };
+        // A changed email would be archived again by the next run
+        if to_hex(&file_digest(mail.path())?) != email.digest {
+            info!("Email {} changed since it was archived", email.id);
+            continue;
+        }
+        if !digests.verify(&email)? {
+            error!(
+                "Email {} has no identical copy in {}, not deleted",
+                email.id,
+                email.archive_folder.display()
+            );
+            report.failed += 1;
+            kept.push(email);
+            continue;
+        }
+        if !opts.dry_run {
+            maildir.delete(&email.id)?;
+        }
+        info!(
+            "Email {} deleted from {}",
+            email.id,
+            email.maildir.display()
+        );
+        report.expunged += 1;
+    }
+    if !opts.dry_run {
+        let tmp = path_with_suffix(&path, ".tmp");
+        let mut file = File::create(&tmp)?;
+        for email in &kept {
+            writeln!(
+                file,
+                "{}",
+                serde_json::to_string(email).map_err(io::Error::from)?
+            )?;
+        }
+        fs::rename(&tmp, &path)?;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::args::ExpungeOptions;
+    use crate::expunge::{
+        expunge, DeferredEmail, DeferredJournal, ExpungeReport, DEFERRED_JOURNAL_FILE,
+    };
+    use crate::seen::SeenDatabase;
+    use maildir::Maildir;
+
+    #[test]
+    fn test_expunge() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let source = Maildir::from(basedir.join("mail"));
+        let archive = Maildir::from(basedir.join("archive").join("2016"));
+        source.create_dirs().unwrap();
+        archive.create_dirs().unwrap();
+        let journal =
+            DeferredJournal::open(&basedir.join("archive").join(DEFERRED_JOURNAL_FILE)).unwrap();
+        let mut ids = Vec::new();
+        let now = time::OffsetDateTime::now_utc().unix_timestamp();
+        for subject in ["a", "b", "c"] {
+            let data = format!("Subject: {}\n\nbody\n", subject);
+            let id = source.store_cur_with_flags(data.as_bytes(), "S").unwrap();
+            // The copy of b is corrupted, c is too recent
+            let copy = data.replace("b\n\nbody", "b\n\nbodz");
+            archive.store_cur_with_flags(copy.as_bytes(), "S").unwrap();
+            let digest = SeenDatabase::digest(source.find(&id).unwrap().path()).unwrap();
+            journal
+                .record(&DeferredEmail {
+                    maildir: source.path().to_path_buf(),
+                    id: id.clone(),
+                    digest,
+                    archive_folder: archive.path().to_path_buf(),
+                    archived_at: if subject == "c" { now } else { 1_000_000_000 },
+                })
+                .unwrap();
+            ids.push(id);
+        }
+        assert!(
+            journal.contains(&SeenDatabase::digest(source.find(&ids[0]).unwrap().path()).unwrap())
+        );
+        drop(journal);
+
+        let mut opts = ExpungeOptions {
+            archive_dir: basedir.join("archive"),
+            older_than: 7,
+            dry_run: true,
+        };
+        let expected = ExpungeReport {
+            expunged: 1,
+            pending: 1,
+            failed: 1,
+        };
+        assert_eq!(expunge(&opts).unwrap(), expected);
+        assert_eq!(source.count_cur(), 3);
+        opts.dry_run = false;
+        assert_eq!(expunge(&opts).unwrap(), expected);
+        assert!(source.find(&ids[0]).is_none());
+        assert_eq!(source.count_cur(), 2);
+        let report = expunge(&opts).unwrap();
+        assert_eq!((report.expunged, report.failed), (0, 1));
+    }
+}
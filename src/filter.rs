@@ -0,0 +1,441 @@
+use crate::archiver::{MaildirArchiverError, STUB_HEADER};
+use crate::args::FilterOptions;
+use maildir::{MailEntry, Maildir};
+use mailparse::{DispositionType, MailHeaderMap, ParsedMail};
+use regex::Regex;
+use std::collections::HashSet;
+use std::fs::File;
+use std::process::Command;
+use time::{Duration, OffsetDateTime};
+
+/// Trait implemented by the filters selecting the emails to archive.
+///
+/// Filters are applied after the date threshold, only emails matching all
+/// the filters are archived.
+pub trait MailFilter {
+    fn matches(&self, mail: &mut MailEntry) -> Result<bool, MaildirArchiverError>;
+}
+
+/// Check if a MIME part is an attachment
+pub fn is_attachment(part: &ParsedMail) -> bool {
+    let disposition = part.get_content_disposition();
+    disposition.disposition == DispositionType::Attachment
+        || disposition.params.contains_key("filename")
+}
+
+/// Decoded sizes of the attachments of an email
+fn attachment_sizes(part: &ParsedMail, sizes: &mut Vec<u64>) -> Result<(), MaildirArchiverError> {
+    if part.subparts.is_empty() {
+        if is_attachment(part) {
+            sizes.push(part.get_body_raw()?.len() as u64);
+        }
+    } else {
+        for subpart in &part.subparts {
+            attachment_sizes(subpart, sizes)?;
+        }
+    }
+    Ok(())
+}
+
+/// Filter emails with at least one attachment larger than `min_size` bytes
+struct AttachmentFilter {
+    min_size: u64,
+}
+
+impl MailFilter for AttachmentFilter {
+    fn matches(&self, mail: &mut MailEntry) -> Result<bool, MaildirArchiverError> {
+        let mut sizes = Vec::new();
+        attachment_sizes(&mail.parsed()?, &mut sizes)?;
+        Ok(sizes.iter().any(|s| *s >= self.min_size))
+    }
+}
+
+/// Filter out the stubs left by the stub archive mode
+struct StubFilter {}
+
+impl MailFilter for StubFilter {
+    fn matches(&self, mail: &mut MailEntry) -> Result<bool, MaildirArchiverError> {
+        Ok(!mail
+            .headers()?
+            .iter()
+            .any(|h| h.get_key_ref().eq_ignore_ascii_case(STUB_HEADER)))
+    }
+}
+
+/// Spam score of an email, from its `X-Spam-Score` header or the `score=`
+/// field of its SpamAssassin `X-Spam-Status` header
+pub fn spam_score(mail: &mut MailEntry) -> Result<Option<f64>, MaildirArchiverError> {
+    let headers = mail.headers()?;
+    let score = headers
+        .get_first_value("X-Spam-Score")
+        .and_then(|v| v.split_whitespace().next()?.parse().ok());
+    Ok(score.or_else(|| {
+        headers.get_first_value("X-Spam-Status").and_then(|v| {
+            v.split(|c: char| c.is_whitespace() || c == ',')
+                .find_map(|field| field.strip_prefix("score=")?.parse().ok())
+        })
+    }))
+}
+
+/// Filter emails by spam score: with `spam`, the emails scoring more than
+/// `threshold`, otherwise the other ones. Emails without a score are never
+/// spam.
+struct SpamScoreFilter {
+    threshold: f64,
+    spam: bool,
+}
+
+impl MailFilter for SpamScoreFilter {
+    fn matches(&self, mail: &mut MailEntry) -> Result<bool, MaildirArchiverError> {
+        let is_spam = spam_score(mail)?.is_some_and(|score| score > self.threshold);
+        Ok(is_spam == self.spam)
+    }
+}
+
+/// Decoded text parts of an email, excluding the attachments
+fn text_parts(part: &ParsedMail, texts: &mut Vec<String>) -> Result<(), MaildirArchiverError> {
+    if part.subparts.is_empty() {
+        if part.ctype.mimetype.starts_with("text/") && !is_attachment(part) {
+            texts.push(part.get_body()?);
+        }
+    } else {
+        for subpart in &part.subparts {
+            text_parts(subpart, texts)?;
+        }
+    }
+    Ok(())
+}
+
+/// Filter emails with a text part matching `regex`, or without any with
+/// `matching` false
+struct BodyFilter {
+    regex: Regex,
+    matching: bool,
+}
+
+impl MailFilter for BodyFilter {
+    fn matches(&self, mail: &mut MailEntry) -> Result<bool, MaildirArchiverError> {
+        let mut texts = Vec::new();
+        text_parts(&mail.parsed()?, &mut texts)?;
+        Ok(texts.iter().any(|text| self.regex.is_match(text)) == self.matching)
+    }
+}
+
+/// Check if an email or one of its MIME parts has a content type matching
+/// one of `types` (`text/calendar`, or `text/*` for all the subtypes)
+fn has_content_type(part: &ParsedMail, types: &[String]) -> bool {
+    let mimetype = part.ctype.mimetype.to_ascii_lowercase();
+    types.iter().any(|t| match t.strip_suffix("/*") {
+        Some(main_type) => mimetype.split('/').next() == Some(main_type),
+        None => mimetype == *t,
+    }) || part.subparts.iter().any(|p| has_content_type(p, types))
+}
+
+/// Filter emails containing a MIME part with one of the given content types
+struct ContentTypeFilter {
+    types: Vec<String>,
+}
+
+impl MailFilter for ContentTypeFilter {
+    fn matches(&self, mail: &mut MailEntry) -> Result<bool, MaildirArchiverError> {
+        Ok(has_content_type(&mail.parsed()?, &self.types))
+    }
+}
+
+/// Input of the check hook
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HookInput {
+    /// Path of the email as last argument
+    #[default]
+    Path,
+    /// Content of the email on the standard input
+    Stdin,
+}
+
+/// Filter out the emails vetoed by an external command, run with the shell
+/// for each email: a non-zero exit status excludes the email
+struct HookFilter {
+    command: String,
+    input: HookInput,
+}
+
+impl MailFilter for HookFilter {
+    fn matches(&self, mail: &mut MailEntry) -> Result<bool, MaildirArchiverError> {
+        let mut command = Command::new("sh");
+        let status = match self.input {
+            HookInput::Path => command
+                .arg("-c")
+                .arg(format!("{} \"$1\"", self.command))
+                .arg("sh")
+                .arg(mail.path())
+                .status()?,
+            HookInput::Stdin => command
+                .arg("-c")
+                .arg(&self.command)
+                .stdin(File::open(mail.path())?)
+                .status()?,
+        };
+        Ok(status.success())
+    }
+}
+
+/// Thread of an email: the first of its references, its parent or the email
+/// itself
+fn thread_key(mail: &mut MailEntry) -> Result<Option<String>, MaildirArchiverError> {
+    let headers = mail.headers()?;
+    let references = headers.get_first_value("References").unwrap_or_default();
+    Ok(references
+        .split_whitespace()
+        .next()
+        .map(str::to_string)
+        .or_else(|| headers.get_first_value("In-Reply-To"))
+        .or_else(|| headers.get_first_value("Message-ID"))
+        .map(|id| id.trim().to_string()))
+}
+
+/// Filter out the emails of the threads with a message received after
+/// `since`, so that active threads are never partially archived
+struct ActiveThreadFilter {
+    active: HashSet<String>,
+}
+
+impl ActiveThreadFilter {
+    /// Collect the active threads of all the emails of the maildir
+    fn new(maildir: &Maildir, since: i64) -> Self {
+        let mut active = HashSet::new();
+        for mut mail in maildir.list_cur().chain(maildir.list_new()).flatten() {
+            if mail.received().is_ok_and(|t| t >= since) {
+                if let Ok(Some(key)) = thread_key(&mut mail) {
+                    active.insert(key);
+                }
+            }
+        }
+        ActiveThreadFilter { active }
+    }
+}
+
+impl MailFilter for ActiveThreadFilter {
+    fn matches(&self, mail: &mut MailEntry) -> Result<bool, MaildirArchiverError> {
+        Ok(thread_key(mail)?.is_none_or(|key| !self.active.contains(&key)))
+    }
+}
+
+/// Factory method that creates the filters requested by the filter options
+/// for the emails of `maildir`.
+///
+/// Stubs are never archived again.
+pub fn create_mail_filters(opts: &FilterOptions, maildir: &Maildir) -> Vec<Box<dyn MailFilter>> {
+    let mut filters: Vec<Box<dyn MailFilter>> = vec![Box::new(StubFilter {})];
+    if let Some(days) = opts.protect_active_threads {
+        let since = OffsetDateTime::now_utc() - Duration::days(days);
+        filters.push(Box::new(ActiveThreadFilter::new(
+            maildir,
+            since.unix_timestamp(),
+        )));
+    }
+    if let Some(threshold) = opts.spam_score_gt {
+        filters.push(Box::new(SpamScoreFilter {
+            threshold,
+            spam: true,
+        }));
+    }
+    if let Some(threshold) = opts.spam_score_le {
+        filters.push(Box::new(SpamScoreFilter {
+            threshold,
+            spam: false,
+        }));
+    }
+    match opts.attachment_larger_than {
+        Some(min_size) => filters.push(Box::new(AttachmentFilter {
+            min_size: min_size.saturating_add(1),
+        })),
+        None if opts.has_attachment => filters.push(Box::new(AttachmentFilter { min_size: 0 })),
+        None => {}
+    }
+    if !opts.content_types.is_empty() {
+        filters.push(Box::new(ContentTypeFilter {
+            types: opts
+                .content_types
+                .iter()
+                .map(|t| t.to_ascii_lowercase())
+                .collect(),
+        }));
+    }
+    if let Some(regex) = &opts.body {
+        filters.push(Box::new(BodyFilter {
+            regex: regex.clone(),
+            matching: true,
+        }));
+    }
+    if let Some(regex) = &opts.not_body {
+        filters.push(Box::new(BodyFilter {
+            regex: regex.clone(),
+            matching: false,
+        }));
+    }
+    // The hook is the most expensive filter, run it last
+    if let Some(command) = &opts.check_hook {
+        filters.push(Box::new(HookFilter {
+            command: command.clone(),
+            input: opts.check_hook_input,
+        }));
+    }
+    filters
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::filter::{
+        spam_score, ActiveThreadFilter, AttachmentFilter, BodyFilter, ContentTypeFilter,
+        HookFilter, HookInput, MailFilter, SpamScoreFilter,
+    };
+    use maildir::Maildir;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_attachment_filter() {
+        let maildir = Maildir::from(PathBuf::from("testdata/maildir1"));
+        let mut plain = maildir
+            .find("1463868505.38518452d49213cb409aa1db32f53184")
+            .unwrap();
+        let mut attachment = maildir
+            .find("1700000000.4f1c2b7a9e3d4c0b8a6f5e2d1c0b9a87")
+            .unwrap();
+
+        let filter = AttachmentFilter { min_size: 0 };
+        assert!(!filter.matches(&mut plain).unwrap());
+        assert!(filter.matches(&mut attachment).unwrap());
+        let filter = AttachmentFilter { min_size: 1024 };
+        assert!(!filter.matches(&mut attachment).unwrap());
+    }
+
+    #[test]
+    fn test_active_thread_filter() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let maildir = Maildir::from(basedir.to_path_buf());
+        maildir.create_dirs().unwrap();
+        let email = |id: &str, references: &str, date: &str| {
+            format!(
+                "Received: from a by b; {}\r\nMessage-ID: {}\r\n{}\r\nbody\r\n",
+                date, id, references
+            )
+        };
+        let old = "Sat, 21 May 2016 22:08:25 +0000";
+        let recent = "Tue, 14 Nov 2023 22:13:20 +0000";
+        let root = maildir
+            .store_cur_with_flags(email("<root@a>", "", old).as_bytes(), "S")
+            .unwrap();
+        maildir
+            .store_cur_with_flags(
+                email("<reply@a>", "References: <root@a>\r\n", recent).as_bytes(),
+                "S",
+            )
+            .unwrap();
+        let other = maildir
+            .store_cur_with_flags(email("<other@a>", "", old).as_bytes(), "S")
+            .unwrap();
+
+        let filter = ActiveThreadFilter::new(&maildir, 1700000000);
+        assert!(!filter.matches(&mut maildir.find(&root).unwrap()).unwrap());
+        assert!(filter.matches(&mut maildir.find(&other).unwrap()).unwrap());
+    }
+
+    #[test]
+    fn test_spam_score_filter() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let maildir = Maildir::from(basedir.to_path_buf());
+        maildir.create_dirs().unwrap();
+        let email = |headers: &str| {
+            let id = maildir
+                .store_cur_with_flags(
+                    format!("{}Subject: a\r\n\r\nbody\r\n", headers).as_bytes(),
+                    "S",
+                )
+                .unwrap();
+            maildir.find(&id).unwrap()
+        };
+        let mut scored = email("X-Spam-Score: 7.5 (+++++++)\r\n");
+        let mut status = email("X-Spam-Status: No, score=-0.1 required=5.0 tests=NONE\r\n");
+        let mut unscored = email("");
+        assert_eq!(spam_score(&mut status).unwrap(), Some(-0.1));
+
+        let spam = SpamScoreFilter {
+            threshold: 5.0,
+            spam: true,
+        };
+        assert!(spam.matches(&mut scored).unwrap());
+        assert!(!spam.matches(&mut status).unwrap());
+        assert!(!spam.matches(&mut unscored).unwrap());
+        let ham = SpamScoreFilter {
+            threshold: 5.0,
+            spam: false,
+        };
+        assert!(!ham.matches(&mut scored).unwrap());
+        assert!(ham.matches(&mut unscored).unwrap());
+    }
+
+    #[test]
+    fn test_hook_filter() {
+        let maildir = Maildir::from(PathBuf::from("testdata/maildir1"));
+        let mut mail = maildir
+            .find("1463868505.38518452d49213cb409aa1db32f53184")
+            .unwrap();
+        let hook = |command: &str, input| HookFilter {
+            command: command.to_string(),
+            input,
+        };
+        assert!(hook("test -f", HookInput::Path).matches(&mut mail).unwrap());
+        assert!(!hook("test ! -f", HookInput::Path)
+            .matches(&mut mail)
+            .unwrap());
+        assert!(hook("grep -q '^To:'", HookInput::Stdin)
+            .matches(&mut mail)
+            .unwrap());
+        assert!(!hook("grep -q '^X-None:'", HookInput::Stdin)
+            .matches(&mut mail)
+            .unwrap());
+    }
+
+    #[test]
+    fn test_body_filter() {
+        let maildir = Maildir::from(PathBuf::from("testdata/maildir1"));
+        let mut attachment = maildir
+            .find("1700000000.4f1c2b7a9e3d4c0b8a6f5e2d1c0b9a87")
+            .unwrap();
+        let filter = |regex: &str, matching| BodyFilter {
+            regex: regex::Regex::new(regex).unwrap(),
+            matching,
+        };
+        assert!(filter("(?i)attached", true)
+            .matches(&mut attachment)
+            .unwrap());
+        assert!(!filter("(?i)attached", false)
+            .matches(&mut attachment)
+            .unwrap());
+        assert!(!filter("^INV-[0-9]+$", true)
+            .matches(&mut attachment)
+            .unwrap());
+    }
+
+    #[test]
+    fn test_content_type_filter() {
+        let maildir = Maildir::from(PathBuf::from("testdata/maildir1"));
+        let mut attachment = maildir
+            .find("1700000000.4f1c2b7a9e3d4c0b8a6f5e2d1c0b9a87")
+            .unwrap();
+        let filter = |types: &[&str]| ContentTypeFilter {
+            types: types.iter().map(|t| t.to_string()).collect(),
+        };
+        assert!(filter(&["application/pdf"])
+            .matches(&mut attachment)
+            .unwrap());
+        assert!(filter(&["text/calendar", "multipart/*"])
+            .matches(&mut attachment)
+            .unwrap());
+        assert!(!filter(&["multipart/report"])
+            .matches(&mut attachment)
+            .unwrap());
+    }
+}
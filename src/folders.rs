@@ -0,0 +1,359 @@
+use crate::dedup::{file_digest, to_hex};
+use crate::utf7::{self, NameEncoding};
+use maildir::{MailEntry, Maildir};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Naming of the archive folders
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FolderNaming {
+    /// Plain directories: `<output_dir>/<subfolder>/<bucket>`
+    Plain,
+    /// Courier-IMAP folders of the account `<output_dir>`:
+    /// `<output_dir>/.<subfolder>.<bucket>`
+    Courier,
+}
+
+/// Name of the Courier-IMAP file listing the subscribed folders
+pub const COURIER_SUBSCRIBED: &str = "courierimapsubscribed";
+
+/// Folder of the input maildir to archive: the maildir itself (INBOX) or one
+/// of its Maildir++ subfolders (`.Sent`, `.Lists.rust`, ...)
+pub struct SourceFolder {
+    /// Name of the subfolder, empty for INBOX
+    pub name: OsString,
+    pub maildir: Maildir,
+}
+
+impl SourceFolder {
+    /// Archive folder of the emails of this folder for a split bucket.
+    ///
+    /// Emails from INBOX are archived in `<output_dir>/<bucket>`, emails from
+    /// a subfolder in `<output_dir>/<subfolder>/<bucket>`, without the leading
+    /// dot of the subfolder name. With the Courier naming, the archive
+    /// folders are `<output_dir>/.<bucket>` and
+    /// `<output_dir>/.<subfolder>.<bucket>`. The subfolder name and the bucket
+    /// are encoded with `encoding`, except for the subfolder names that are
+    /// not UTF-8, kept as they are.
+    pub fn archive_folder(
+        &self,
+        output_dir: &Path,
+        bucket: &str,
+        naming: FolderNaming,
+        encoding: NameEncoding,
+    ) -> PathBuf {
+        let name = self
+            .name
+            .as_bytes()
+            .strip_prefix(b".")
+            .map(|n| match std::str::from_utf8(n) {
+                Ok(n) => OsString::from(encoding.apply(n)),
+                Err(_) => OsStr::from_bytes(n).to_os_string(),
+            });
+        let bucket = &encoding.apply(bucket);
+        match (name, naming) {
+            (Some(name), FolderNaming::Plain) => output_dir.join(name).join(bucket),
+            (None, FolderNaming::Plain) => output_dir.join(bucket),
+            (Some(name), FolderNaming::Courier) => {
+                let mut folder = OsString::from(".");
+                folder.push(name);
+                folder.push(format!(".{}", bucket));
+                output_dir.join(folder)
+            }
+            (None, FolderNaming::Courier) => output_dir.join(format!(".{}", bucket)),
+        }
+    }
+
+    /// Check if the folder matches a pattern (`INBOX`, `.Sent`, `.Lists.*`),
+    /// where `*` matches any sequence of characters and `?` any single
+    /// character. The pattern is matched against the name of the folder on
+    /// disk and decoded from IMAP modified UTF-7.
+    pub fn matches(&self, pattern: &str) -> bool {
+        let pattern: Vec<char> = pattern.chars().collect();
+        [self.display_name().into_owned(), self.decoded_name()]
+            .iter()
+            .any(|name| matches_pattern(&pattern, &name.chars().collect::<Vec<_>>()))
+    }
+
+    /// Name of the folder decoded from IMAP modified UTF-7
+    pub fn decoded_name(&self) -> String {
+        utf7::decode(&self.display_name()).unwrap_or_else(|| self.display_name().into_owned())
+    }
+
+    /// Name used in the log messages, lossy for the names that are not UTF-8
+    pub fn display_name(&self) -> Cow<'_, str> {
+        match self.name.is_empty() {
+            true => Cow::Borrowed("INBOX"),
+            false => self.name.to_string_lossy(),
+        }
+    }
+}
+
+/// Check if a name matches a pattern with `*` and `?` wildcards
+pub(crate) fn matches_pattern(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => (0..=name.len()).any(|i| matches_pattern(rest, &name[i..])),
+        Some((c, rest)) => match name.split_first() {
+            Some((n, name)) if *c == '?' || c == n => matches_pattern(rest, name),
+            _ => false,
+        },
+    }
+}
+
+/// Folders of the input maildir to archive: INBOX and, if `recursive`, all
+/// its subfolders sorted by name.
+///
+/// An input directory without `cur` is an account root without INBOX: only
+/// its subfolders are archived, even if not `recursive`.
+pub fn list_source_folders(input: &Path, recursive: bool) -> io::Result<Vec<SourceFolder>> {
+    let mut folders = Vec::new();
+    let account_root = !input.join("cur").is_dir();
+    if !account_root {
+        folders.push(SourceFolder {
+            name: OsString::new(),
+            maildir: Maildir::from(input.to_path_buf()),
+        });
+    }
+    if recursive || account_root {
+        let mut subfolders = Vec::new();
+        // Listed with read_dir instead of Maildir::list_subdirs, which makes
+        // the paths of the names that are not UTF-8 lossy
+        for entry in fs::read_dir(input)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let bytes = name.as_bytes();
+            if bytes.starts_with(b".")
+                && !bytes.starts_with(b"..")
+                && entry.path().join("cur").is_dir()
+            {
+                subfolders.push(SourceFolder {
+                    name,
+                    maildir: Maildir::from(entry.path()),
+                });
+            }
+        }
+        subfolders.sort_by(|a, b| a.name.cmp(&b.name));
+        folders.extend(subfolders);
+    }
+    Ok(folders)
+}
+
+/// Subscribe the Courier-IMAP folders of the account `output_dir`, adding
+/// them to its `courierimapsubscribed` file if missing
+pub fn courier_subscribe(output_dir: &Path, folders: &[PathBuf]) -> io::Result<()> {
+    let path = output_dir.join(COURIER_SUBSCRIBED);
+    let subscribed = match fs::read(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+    let mut names: Vec<Vec<u8>> = subscribed
+        .split(|b| *b == b'\n')
+        .map(<[u8]>::to_vec)
+        .collect();
+    for folder in folders {
+        let name = match folder.file_name().map(OsStrExt::as_bytes) {
+            Some(name) if name.starts_with(b".") => [&b"INBOX"[..], name].concat(),
+            _ => continue,
+        };
+        if !names.contains(&name) {
+            file.write_all(&[&name[..], b"\n"].concat())?;
+            names.push(name);
+        }
+    }
+    Ok(())
+}
+
+/// Key identifying the same email in different folders: its Message-ID and
+/// the digest of its content
+pub fn duplicate_key(mail: &mut MailEntry) -> io::Result<String> {
+    let message_id = mail
+        .headers()
+        .ok()
+        .and_then(|headers| {
+            headers
+                .iter()
+                .find(|h| h.get_key_ref().eq_ignore_ascii_case("Message-ID"))
+                .map(|h| h.get_value())
+        })
+        .unwrap_or_default();
+    Ok(format!(
+        "{}\t{}",
+        message_id.trim(),
+        to_hex(&file_digest(mail.path())?)
+    ))
+}
+
+struct ArchivedEmail {
+    archive_folder: PathBuf,
+    source: PathBuf,
+    reported: bool,
+}
+
+/// Emails archived by a recursive run, used to archive only once the emails
+/// found in several folders.
+///
+/// The locations of the duplicated emails are written in an index file, a
+/// line per location with the Message-ID, the digest, the archive folder and
+/// the source email, separated by tabs.
+pub struct DuplicateIndex {
+    archived: Mutex<HashMap<String, ArchivedEmail>>,
+    file: Mutex<Option<File>>,
+    path: PathBuf,
+}
+
+impl DuplicateIndex {
+    /// Create an index written to `path` when the first duplicate is found
+    pub fn new(path: PathBuf) -> Self {
+        DuplicateIndex {
+            archived: Mutex::new(HashMap::new()),
+            file: Mutex::new(None),
+            path,
+        }
+    }
+
+    /// Archive folder of a previous copy of the email, if any
+    pub fn archived_folder(&self, key: &str) -> Option<PathBuf> {
+        let archived = self.archived.lock().unwrap();
+        archived.get(key).map(|a| a.archive_folder.clone())
+    }
+
+    /// Record an archived email
+    pub fn insert(&self, key: String, archive_folder: &Path, source: &Path) {
+        self.archived.lock().unwrap().insert(
+            key,
+            ArchivedEmail {
+                archive_folder: archive_folder.to_path_buf(),
+                source: source.to_path_buf(),
+                reported: false,
+            },
+        );
+    }
+
+    fn write_line(&self, key: &str, archive_folder: &Path, source: &Path) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        if file.is_none() {
+            *file = Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?,
+            );
+        }
+        writeln!(
+            file.as_mut().unwrap(),
+            "{}\t{}\t{}",
+            key,
+            archive_folder.display(),
+            source.display()
+        )
+    }
+
+    /// Record a copy of an already archived email in the index
+    pub fn record_duplicate(&self, key: &str, source: &Path) -> io::Result<()> {
+        let mut archived = self.archived.lock().unwrap();
+        let original = match archived.get_mut(key) {
+            Some(original) => original,
+            None => return Ok(()),
+        };
+        if !original.reported {
+            self.write_line(key, &original.archive_folder, &original.source)?;
+            original.reported = true;
+        }
+        self.write_line(key, &original.archive_folder, source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::folders::{
+        courier_subscribe, duplicate_key, list_source_folders, DuplicateIndex, FolderNaming,
+        COURIER_SUBSCRIBED,
+    };
+    use crate::utf7::NameEncoding;
+    use maildir::Maildir;
+    use std::path::Path;
+
+    #[test]
+    fn test_source_folders() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let filename = "1463868505.38518452d49213cb409aa1db32f53184:2,S";
+        for folder in ["", ".Sent", ".Lists.rust"] {
+            let maildir = Maildir::from(basedir.join(folder));
+            maildir.create_dirs().unwrap();
+            std::fs::copy(
+                format!("testdata/maildir1/cur/{}", filename),
+                maildir.path().join("cur").join(filename),
+            )
+            .unwrap();
+        }
+        std::fs::create_dir(basedir.join(".notamaildir")).unwrap();
+
+        assert_eq!(list_source_folders(&basedir, false).unwrap().len(), 1);
+        let account = basedir.join("account");
+        Maildir::from(account.join(".Sent")).create_dirs().unwrap();
+        let folders = list_source_folders(&account, false).unwrap();
+        assert_eq!(folders.len(), 1);
+        assert_eq!(folders[0].display_name(), ".Sent");
+        let folders = list_source_folders(&basedir, true).unwrap();
+        let names: Vec<_> = folders.iter().map(|f| f.display_name()).collect();
+        assert_eq!(names, ["INBOX", ".Lists.rust", ".Sent"]);
+        assert!(folders[1].matches(".Lists.*"));
+        assert!(!folders[2].matches(".Lists.*"));
+        assert!(folders[0].matches("INBOX"));
+        assert_eq!(
+            folders[2].archive_folder(
+                Path::new("/archive"),
+                "2016",
+                FolderNaming::Plain,
+                NameEncoding::Preserve
+            ),
+            Path::new("/archive/Sent/2016")
+        );
+        let courier = [
+            folders[0].archive_folder(
+                &basedir,
+                "2016",
+                FolderNaming::Courier,
+                NameEncoding::Preserve,
+            ),
+            folders[2].archive_folder(
+                &basedir,
+                "2016",
+                FolderNaming::Courier,
+                NameEncoding::Preserve,
+            ),
+        ];
+        assert_eq!(courier[1], basedir.join(".Sent.2016"));
+        courier_subscribe(&basedir, &courier).unwrap();
+        courier_subscribe(&basedir, &courier).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(basedir.join(COURIER_SUBSCRIBED)).unwrap(),
+            "INBOX.2016\nINBOX.Sent.2016\n"
+        );
+
+        let index = DuplicateIndex::new(basedir.join("duplicates"));
+        let mut keys = Vec::new();
+        for folder in &folders {
+            let mut mail = folder.maildir.list_cur().next().unwrap().unwrap();
+            let key = duplicate_key(&mut mail).unwrap();
+            match index.archived_folder(&key) {
+                Some(_) => index.record_duplicate(&key, mail.path()).unwrap(),
+                None => index.insert(key.clone(), Path::new("/archive/2016"), mail.path()),
+            }
+            keys.push(key);
+        }
+        assert!(keys.iter().all(|k| *k == keys[0]));
+        let lines = std::fs::read_to_string(basedir.join("duplicates")).unwrap();
+        assert_eq!(lines.lines().count(), 3);
+    }
+}
@@ -0,0 +1,337 @@
+use crate::archiver::MaildirArchiverError;
+use crate::args::FsckOptions;
+use crate::folders::{list_source_folders, SourceFolder};
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Age after which a file of `tmp` is stale, as in the maildir specification
+pub const STALE_TMP_AGE: Duration = Duration::from_secs(36 * 60 * 60);
+
+/// Structural problem of a maildir
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Problem {
+    /// File of `cur` without the `:2,` info, missing or malformed
+    MissingInfo,
+    /// File of `tmp` older than [`STALE_TMP_AGE`]
+    StaleTmp,
+    /// Entry that is not a readable regular file
+    Unreadable,
+    /// Email with the unique id of another email of the folder
+    DuplicateId,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Problem::MissingInfo => "missing-info",
+            Problem::StaleTmp => "stale-tmp",
+            Problem::Unreadable => "unreadable",
+            Problem::DuplicateId => "duplicate-id",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Result of a maildir check
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct FsckReport {
+    /// Problems found, with the path of the entry
+    pub problems: Vec<(Problem, PathBuf)>,
+    /// Number of problems repaired
+    pub repaired: usize,
+}
+
+impl FsckReport {
+    /// Check if some problems have not been repaired
+    pub fn has_errors(&self) -> bool {
+        self.repaired < self.problems.len()
+    }
+}
+
+/// Split a file name at the first of the given bytes
+fn split_name<'a>(name: &'a [u8], separators: &[u8]) -> Option<(&'a [u8], &'a [u8])> {
+    let i = name.iter().position(|b| separators.contains(b))?;
+    Some((&name[..i], &name[i + 1..]))
+}
+
+/// Unique id of an email, the part of its file name before the info
+fn unique_id(name: &OsStr) -> &OsStr {
+    split_name(name.as_bytes(), b":").map_or(name, |(id, _)| OsStr::from_bytes(id))
+}
+
+/// Spec-compliant name of an email of `cur` with a malformed or missing
+/// info, keeping the flags found in the info: `1.a:RS`, `1.a!2,S` (info
+/// separator of some Windows clients) and `1.a` become `1.a:2,RS`,
+/// `1.a:2,S` and `1.a:2,`
+pub fn fixed_name(name: &OsStr) -> OsString {
+    let name = name.as_bytes();
+    let (id, info) = match split_name(name, b":") {
+        Some((id, info)) => (id, info.strip_prefix(b"2").unwrap_or(info)),
+        None => match split_name(name, b"!;") {
+            Some((id, info)) if info.starts_with(b"2,") => (id, &info[1..]),
+            _ => (name, &b""[..]),
+        },
+    };
+    let mut flags: Vec<u8> = info
+        .iter()
+        .copied()
+        .filter(u8::is_ascii_alphabetic)
+        .collect();
+    flags.sort();
+    flags.dedup();
+    OsString::from_vec([id, b":2,", &flags].concat())
+}
+
+/// Rename an email of `cur` to its fixed name
+fn fix_name(path: &Path, name: &OsStr) -> io::Result<PathBuf> {
+    let renamed = path.with_file_name(fixed_name(name));
+    if renamed.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists", renamed.display()),
+        ));
+    }
+    fs::rename(path, &renamed)?;
+    Ok(renamed)
+}
+
+/// Check that an entry is a regular file that can be opened
+fn readable(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|m| m.is_file()) && File::open(path).is_ok()
+}
+
+/// Entries of a maildir subdirectory, sorted by name, without the hidden ones
+fn entries(dir: &Path) -> io::Result<Vec<(OsString, PathBuf)>> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if !name.as_bytes().starts_with(b".") {
+            entries.push((name, entry.path()));
+        }
+    }
+    entries.sort();
+    Ok(entries)
+}
+
+/// Rename an email with a duplicated unique id to a free unique id
+fn rename_duplicate(path: &Path, name: &OsStr) -> io::Result<PathBuf> {
+    let name = name.as_bytes();
+    let (id, info) = match name.iter().position(|&b| b == b':') {
+        Some(i) => name.split_at(i),
+        None => (name, &b""[..]),
+    };
+    let dir = path.parent().unwrap();
+    let mut n = 1;
+    loop {
+        let dup = format!(".dup{}", n);
+        let renamed = dir.join(OsStr::from_bytes(&[id, dup.as_bytes(), info].concat()));
+        if !renamed.exists() {
+            fs::rename(path, &renamed)?;
+            return Ok(renamed);
+        }
+        n += 1;
+    }
+}
+
+/// Check a folder, repairing the problems as requested by the options
+fn check_folder(
+    folder: &SourceFolder,
+    opts: &FsckOptions,
+    report: &mut FsckReport,
+) -> Result<(), MaildirArchiverError> {
+    let repair = opts.repair;
+    let path = folder.maildir.path();
+    let now = SystemTime::now();
+    for (_, tmp) in entries(&path.join("tmp"))? {
+        let stale = fs::symlink_metadata(&tmp)
+            .and_then(|m| m.modified())
+            .is_ok_and(|mtime| now.duration_since(mtime).unwrap_or_default() > STALE_TMP_AGE);
+        if stale {
+            if repair {
+                match fs::remove_file(&tmp) {
+                    Ok(()) => report.repaired += 1,
+                    Err(e) => error!("Error while deleting {}: {}", tmp.display(), e),
+                }
+            }
+            report.problems.push((Problem::StaleTmp, tmp));
+        }
+    }
+    let mut ids: HashMap<OsString, PathBuf> = HashMap::new();
+    for subdir in ["cur", "new"] {
+        for (name, entry) in entries(&path.join(subdir))? {
+            if !readable(&entry) {
+                report.problems.push((Problem::Unreadable, entry));
+                continue;
+            }
+            let has_info = name.as_bytes().windows(3).any(|w| w == b":2,");
+            let (name, entry) = match subdir == "cur" && !has_info {
+                true => {
+                    report.problems.push((Problem::MissingInfo, entry.clone()));
+                    match opts.fix_names.then(|| fix_name(&entry, &name)) {
+                        Some(Ok(renamed)) => {
+                            info!("Renamed {} to {}", entry.display(), renamed.display());
+                            report.repaired += 1;
+                            (fixed_name(&name), renamed)
+                        }
+                        Some(Err(e)) => {
+                            error!("Error while renaming {}: {}", entry.display(), e);
+                            (name, entry)
+                        }
+                        None => (name, entry),
+                    }
+                }
+                false => (name, entry),
+            };
+            if !ids.contains_key(unique_id(&name)) {
+                ids.insert(unique_id(&name).to_os_string(), entry);
+                continue;
+            }
+            if repair {
+                match rename_duplicate(&entry, &name) {
+                    Ok(renamed) => {
+                        info!("Renamed {} to {}", entry.display(), renamed.display());
+                        report.repaired += 1;
+                    }
+                    Err(e) => error!("Error while renaming {}: {}", entry.display(), e),
+                }
+            }
+            report.problems.push((Problem::DuplicateId, entry));
+        }
+    }
+    Ok(())
+}
+
+/// Check the structure of the input maildir, and of its subfolders if
+/// `recursive`, writing a line per problem with its name and the path of the
+/// entry.
+///
+/// With `repair`, the stale files of `tmp` are deleted and the emails with a
+/// duplicated unique id renamed. With `fix_names`, the emails of `cur` with a
+/// malformed or missing info are renamed with [`fixed_name`]. The unreadable
+/// entries are only reported.
+pub fn fsck<W: Write>(opts: &FsckOptions, out: &mut W) -> Result<FsckReport, MaildirArchiverError> {
+    let mut report = FsckReport::default();
+    for folder in list_source_folders(&opts.maildir, opts.recursive)? {
+        let count = report.problems.len();
+        check_folder(&folder, opts, &mut report)?;
+        if report.problems.len() > count {
+            warn!(
+                "Found {} problems in folder {}",
+                report.problems.len() - count,
+                folder.display_name()
+            );
+        }
+    }
+    // The paths are written verbatim, even if they are not UTF-8
+    for (problem, path) in &report.problems {
+        write!(out, "{}\t", problem)?;
+        out.write_all(path.as_os_str().as_bytes())?;
+        out.write_all(b"\n")?;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::args::FsckOptions;
+    use crate::fsck::{fixed_name, fsck, Problem};
+    use maildir::Maildir;
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_fsck() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let maildir = Maildir::from(basedir.to_path_buf());
+        maildir.create_dirs().unwrap();
+        let cur = basedir.join("cur");
+        std::fs::write(cur.join("1.a:2,S"), "Subject: a\n\nbody\n").unwrap();
+        std::fs::write(cur.join("2.b"), "Subject: b\n\nbody\n").unwrap();
+        std::fs::write(basedir.join("new").join("1.a"), "Subject: c\n\nbody\n").unwrap();
+        std::fs::create_dir(cur.join("3.c:2,")).unwrap();
+        let tmp = std::fs::File::create(basedir.join("tmp").join("4.d")).unwrap();
+        tmp.set_modified(SystemTime::now() - Duration::from_secs(40 * 60 * 60))
+            .unwrap();
+        std::fs::write(basedir.join("tmp").join("5.e"), "").unwrap();
+
+        let mut opts = FsckOptions {
+            maildir: basedir.to_path_buf(),
+            recursive: false,
+            repair: false,
+            fix_names: false,
+        };
+        let mut out = Vec::new();
+        let report = fsck(&opts, &mut out).unwrap();
+        let mut problems: Vec<_> = report.problems.iter().map(|(p, _)| *p).collect();
+        problems.sort();
+        assert_eq!(
+            problems,
+            [
+                Problem::MissingInfo,
+                Problem::StaleTmp,
+                Problem::Unreadable,
+                Problem::DuplicateId
+            ]
+        );
+        assert!(report.has_errors());
+        assert_eq!(String::from_utf8(out).unwrap().lines().count(), 4);
+
+        opts.repair = true;
+        let report = fsck(&opts, &mut Vec::new()).unwrap();
+        assert_eq!(report.repaired, 2);
+        assert!(basedir.join("new").join("1.a.dup1").is_file());
+        assert!(!basedir.join("tmp").join("4.d").exists());
+        assert!(basedir.join("tmp").join("5.e").exists());
+        let report = fsck(&opts, &mut Vec::new()).unwrap();
+        assert_eq!(report.problems.len(), 2);
+
+        opts.fix_names = true;
+        let report = fsck(&opts, &mut Vec::new()).unwrap();
+        assert_eq!(report.repaired, 1);
+        assert!(cur.join("2.b:2,").is_file());
+        assert_eq!(fsck(&opts, &mut Vec::new()).unwrap().problems.len(), 1);
+    }
+
+    #[test]
+    fn test_fsck_non_utf8() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let maildir = Maildir::from(basedir.to_path_buf());
+        maildir.create_dirs().unwrap();
+        let cur = basedir.join("cur");
+        // Distinct names, the same once made valid UTF-8
+        for name in [&b"1.\xff:2,S"[..], b"1.\xfe:2,S", b"2.\xff"] {
+            std::fs::write(cur.join(OsStr::from_bytes(name)), "Subject: a\n\nbody\n").unwrap();
+        }
+        let opts = FsckOptions {
+            maildir: basedir.to_path_buf(),
+            recursive: false,
+            repair: true,
+            fix_names: true,
+        };
+        let report = fsck(&opts, &mut Vec::new()).unwrap();
+        assert_eq!(report.problems.len(), 1);
+        assert_eq!(report.problems[0].0, Problem::MissingInfo);
+        assert!(cur.join(OsStr::from_bytes(b"2.\xff:2,")).is_file());
+    }
+
+    #[test]
+    fn test_fixed_name() {
+        assert_eq!(fixed_name(OsStr::new("1.a")), "1.a:2,");
+        assert_eq!(fixed_name(OsStr::new("1.a:SR")), "1.a:2,RS");
+        assert_eq!(fixed_name(OsStr::new("1.a:2S")), "1.a:2,S");
+        assert_eq!(fixed_name(OsStr::new("1.a!2,S")), "1.a:2,S");
+        assert_eq!(fixed_name(OsStr::new("1.a;2,FSS")), "1.a:2,FS");
+        assert_eq!(fixed_name(OsStr::new("1.a!b")), "1.a!b:2,");
+    }
+}
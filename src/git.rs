@@ -0,0 +1,197 @@
+use crate::archiver::MaildirArchiverError;
+use crate::sink::{email_base, maildir_info, maildir_name, ArchiveSink};
+use maildir::MailEntry;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+
+/// Run git on a repository, with `input` on the standard input, returning
+/// the trimmed standard output
+fn git(
+    git_dir: &Path,
+    args: &[&str],
+    index: Option<&Path>,
+    input: Option<&[u8]>,
+) -> io::Result<String> {
+    let mut command = Command::new("git");
+    command
+        .arg("--git-dir")
+        .arg(git_dir)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    if let Some(index) = index {
+        command.env("GIT_INDEX_FILE", index);
+    }
+    for (name, value) in [
+        ("GIT_AUTHOR_NAME", "archive-maildir"),
+        ("GIT_AUTHOR_EMAIL", "archive-maildir@localhost"),
+        ("GIT_COMMITTER_NAME", "archive-maildir"),
+        ("GIT_COMMITTER_EMAIL", "archive-maildir@localhost"),
+    ] {
+        if std::env::var_os(name).is_none() {
+            command.env(name, value);
+        }
+    }
+    let mut child = command.spawn()?;
+    // Dropping stdin closes it, ending the input of git
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(input.unwrap_or_default())?;
+    drop(stdin);
+    let output = child.wait_with_output()?;
+    match output.status.success() {
+        true => Ok(String::from_utf8_lossy(&output.stdout).trim().to_string()),
+        false => Err(io::Error::other(format!(
+            "git {}: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))),
+    }
+}
+
+/// Experimental sink that stores emails in a bare git repository, created
+/// if missing: each email is a blob named after its maildir file, under the
+/// path of its archive folder relative to the repository.
+///
+/// All the emails of a run are committed by [`ArchiveSink::finish`], in a
+/// single commit on the current branch. The commits are authored by
+/// `archive-maildir`, unless the `GIT_AUTHOR_*` and `GIT_COMMITTER_*`
+/// environment variables are set.
+pub struct GitSink {
+    git_dir: PathBuf,
+    normalize_flags: bool,
+    /// Stored blobs, with their path in the repository
+    blobs: Mutex<Vec<(PathBuf, String)>>,
+}
+
+impl GitSink {
+    pub fn new(git_dir: &Path, normalize_flags: bool) -> Self {
+        GitSink {
+            git_dir: git_dir.to_path_buf(),
+            normalize_flags,
+            blobs: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl ArchiveSink for GitSink {
+    fn store(
+        &self,
+        folder: &Path,
+        mail: &MailEntry,
+        data: &[u8],
+    ) -> Result<(), MaildirArchiverError> {
+        let folder = folder.strip_prefix(&self.git_dir).map_err(|_| {
+            MaildirArchiverError::Unsupported(format!(
+                "{} is not in the git repository {}",
+                folder.display(),
+                self.git_dir.display()
+            ))
+        })?;
+        let mut blobs = self.blobs.lock().unwrap();
+        if !self.git_dir.join("HEAD").exists() {
+            let status = Command::new("git")
+                .args(["init", "--bare", "--quiet"])
+                .arg(&self.git_dir)
+                .status()?;
+            if !status.success() {
+                return Err(io::Error::other(format!("git init {}", status)).into());
+            }
+        }
+        let blob = git(
+            &self.git_dir,
+            &["hash-object", "-w", "--stdin"],
+            None,
+            Some(data),
+        )?;
+        let info = maildir_info(mail, self.normalize_flags);
+        blobs.push((folder.join(maildir_name(email_base(mail), &info)), blob));
+        Ok(())
+    }
+
+    fn finish(&self) -> Result<(), MaildirArchiverError> {
+        let mut blobs = self.blobs.lock().unwrap();
+        if blobs.is_empty() {
+            return Ok(());
+        }
+        let index_path = self.git_dir.join("archive-maildir.index");
+        let index = Some(index_path.as_path());
+        let parent = git(
+            &self.git_dir,
+            &["rev-parse", "-q", "--verify", "HEAD"],
+            None,
+            None,
+        )
+        .ok();
+        let res = (|| {
+            match &parent {
+                Some(parent) => git(&self.git_dir, &["read-tree", parent], index, None)?,
+                None => git(&self.git_dir, &["read-tree", "--empty"], index, None)?,
+            };
+            // Terminated by NUL, the paths are passed as raw bytes
+            let mut entries = Vec::new();
+            for (path, blob) in blobs.iter() {
+                entries.extend_from_slice(format!("100644 {}\t", blob).as_bytes());
+                entries.extend_from_slice(path.as_os_str().as_bytes());
+                entries.push(0);
+            }
+            let args = ["update-index", "--add", "-z", "--index-info"];
+            git(&self.git_dir, &args, index, Some(&entries))?;
+            let tree = git(&self.git_dir, &["write-tree"], index, None)?;
+            let message = format!("Archive {} emails", blobs.len());
+            let mut args = vec!["commit-tree", &tree, "-m", &message];
+            if let Some(parent) = &parent {
+                args.extend(["-p", parent]);
+            }
+            let commit = git(&self.git_dir, &args, None, None)?;
+            git(&self.git_dir, &["update-ref", "HEAD", &commit], None, None)
+        })();
+        let _ = std::fs::remove_file(&index_path);
+        res?;
+        blobs.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::git::{git, GitSink};
+    use crate::sink::ArchiveSink;
+    use maildir::Maildir;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_git_sink() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let git_dir = basedir.join("archive.git");
+        let maildir = Maildir::from(PathBuf::from("testdata/maildir1"));
+        for folder in ["2016", "2017"] {
+            let sink = GitSink::new(&git_dir, false);
+            for mail in maildir.list_cur() {
+                let mail = mail.unwrap();
+                let data = std::fs::read(mail.path()).unwrap();
+                sink.store(&git_dir.join(folder), &mail, &data).unwrap();
+            }
+            sink.finish().unwrap();
+        }
+
+        let log = git(&git_dir, &["log", "--format=%s"], None, None).unwrap();
+        assert_eq!(log, "Archive 2 emails\nArchive 2 emails");
+        let files = git(
+            &git_dir,
+            &["ls-tree", "-r", "--name-only", "HEAD"],
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(files.lines().count(), 4);
+        assert!(files.starts_with("2016/"));
+        let sink = GitSink::new(&git_dir, false);
+        let mail = maildir.list_cur().next().unwrap().unwrap();
+        assert!(sink.store(&basedir.join("2016"), &mail, b"").is_err());
+    }
+}
@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+
+/// Name of the Dovecot file mapping the IMAP UIDs to the emails of a maildir
+pub const DOVECOT_UIDLIST: &str = "dovecot-uidlist";
+
+/// Read the IMAP UIDs of the emails of a maildir from its `dovecot-uidlist`
+/// file (version 3), by base filename: the id without the info, as raw
+/// bytes.
+pub fn read_uidlist(maildir: &Path) -> io::Result<HashMap<OsString, u32>> {
+    let mut uids = HashMap::new();
+    let file = BufReader::new(File::open(maildir.join(DOVECOT_UIDLIST))?);
+    // The first line is the header
+    for line in file.split(b'\n').skip(1) {
+        let line = line?;
+        // The extension fields are listed before `:filename`
+        let mut parts = line.split(|&b| b == b':');
+        let (Some(fields), Some(base)) = (parts.next(), parts.next()) else {
+            continue;
+        };
+        let uid = fields
+            .split(|&b| b == b' ')
+            .next()
+            .and_then(|uid| std::str::from_utf8(uid).ok()?.parse().ok());
+        if let Some(uid) = uid {
+            uids.insert(OsStr::from_bytes(base).to_os_string(), uid);
+        }
+    }
+    Ok(uids)
+}
+
+/// Compact IMAP sequence set of UIDs, like `1:3,7`
+fn uid_set(uids: &[u32]) -> String {
+    let mut uids = uids.to_vec();
+    uids.sort_unstable();
+    uids.dedup();
+    let mut ranges: Vec<(u32, u32)> = Vec::new();
+    for uid in uids {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == uid => *end = uid,
+            _ => ranges.push((uid, uid)),
+        }
+    }
+    ranges
+        .iter()
+        .map(|(start, end)| match start == end {
+            true => start.to_string(),
+            false => format!("{}:{}", start, end),
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Quote a mailbox name as an IMAP quoted string
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Preauthenticated IMAP session with a server run by a shell command on its
+/// standard input and output, like `doveadm exec imap -u <user>` or
+/// `ssh host /usr/lib/dovecot/imap`
+pub struct ImapSession {
+    child: Child,
+    input: ChildStdin,
+    output: BufReader<ChildStdout>,
+    tag: u32,
+}
+
+impl ImapSession {
+    /// Start the server, which must greet with `PREAUTH`
+    pub fn spawn(command: &str) -> io::Result<Self> {
+        let mut child = Command::new("sh")
+            .args(["-c", command])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let input = child.stdin.take().unwrap();
+        let output = BufReader::new(child.stdout.take().unwrap());
+        let mut session = ImapSession {
+            child,
+            input,
+            output,
+            tag: 0,
+        };
+        let greeting = session.read_line()?;
+        if !greeting.starts_with("* PREAUTH") {
+            let _ = session.child.kill();
+            return Err(io::Error::other(format!(
+                "IMAP server not preauthenticated: {}",
+                greeting.trim_end()
+            )));
+        }
+        Ok(session)
+    }
+
+    /// Read a response line, with its literals
+    fn read_line(&mut self) -> io::Result<String> {
+        let mut line = String::new();
+        loop {
+            if self.output.read_line(&mut line)? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "IMAP server disconnected",
+                ));
+            }
+            // A literal `{n}` is followed by n bytes, then by the rest of
+            // the line
+            let size = line
+                .trim_end()
+                .strip_suffix('}')
+                .and_then(|l| l.rsplit_once('{'))
+                .and_then(|(_, size)| size.parse::<u64>().ok());
+            match size {
+                Some(size) => {
+                    let mut literal = Vec::new();
+                    (&mut self.output).take(size).read_to_end(&mut literal)?;
+                    line.push_str(&String::from_utf8_lossy(&literal));
+                }
+                None => return Ok(line),
+            }
+        }
+    }
+
+    /// Run a command, failing unless the server completes it with `OK`
+    pub fn command(&mut self, command: &str) -> io::Result<Vec<String>> {
+        self.tag += 1;
+        let tag = format!("A{}", self.tag);
+        write!(self.input, "{} {}\r\n", tag, command)?;
+        self.input.flush()?;
+        let mut untagged = Vec::new();
+        loop {
+            let line = self.read_line()?;
+            match line.strip_prefix(&tag).map(str::trim) {
+                Some(status) if status.starts_with("OK") => return Ok(untagged),
+                Some(status) => {
+                    return Err(io::Error::other(format!("IMAP {}: {}", command, status)))
+                }
+                None => untagged.push(line.trim_end().to_string()),
+            }
+        }
+    }
+
+    /// Select a mailbox, which also synchronizes the server with its maildir
+    pub fn select(&mut self, mailbox: &str) -> io::Result<()> {
+        self.command(&format!("SELECT {}", quote(mailbox)))?;
+        Ok(())
+    }
+
+    /// Mark emails of the selected mailbox as `\Deleted` and expunge them,
+    /// without expunging its other deleted emails (UIDPLUS)
+    pub fn expunge(&mut self, uids: &[u32]) -> io::Result<()> {
+        if uids.is_empty() {
+            return Ok(());
+        }
+        let set = uid_set(uids);
+        self.command(&format!("UID STORE {} +FLAGS.SILENT (\\Deleted)", set))?;
+        self.command(&format!("UID EXPUNGE {}", set))?;
+        Ok(())
+    }
+
+    /// Log out and wait for the server
+    pub fn logout(mut self) -> io::Result<()> {
+        self.command("LOGOUT")?;
+        drop(self.input);
+        self.child.wait()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::imap::{read_uidlist, uid_set, ImapSession, DOVECOT_UIDLIST};
+    use std::ffi::OsStr;
+
+    #[test]
+    fn test_imap_session() {
+        assert_eq!(uid_set(&[7, 1, 2, 3, 9, 8, 2]), "1:3,7:9");
+
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        std::fs::write(
+            basedir.join(DOVECOT_UIDLIST),
+            "3 V1463868505 N12 G0123\n3 :1463868505.a.host:2,S\n5 W120 :1463868506.b.host\n",
+        )
+        .unwrap();
+        let uids = read_uidlist(&basedir).unwrap();
+        assert_eq!(uids[OsStr::new("1463868505.a.host")], 3);
+        assert_eq!(uids[OsStr::new("1463868506.b.host")], 5);
+
+        // A scripted server answering the commands in order
+        let script = basedir.join("server.sh");
+        std::fs::write(
+            &script,
+            "echo '* PREAUTH ready'\n\
+             read l; echo '* 3 EXISTS'; echo 'A1 OK selected'\n\
+             read -r l; echo \"$l\" > \"$(dirname \"$0\")/store\"; echo 'A2 OK stored'\n\
+             read l; echo 'A3 NO no UIDPLUS'\n",
+        )
+        .unwrap();
+        let mut session = ImapSession::spawn(&format!("sh {}", script.display())).unwrap();
+        session.select("Lists.rust").unwrap();
+        let res = session.expunge(&[3, 5]);
+        assert!(res.unwrap_err().to_string().contains("no UIDPLUS"));
+        let store = std::fs::read_to_string(basedir.join("store")).unwrap();
+        assert_eq!(
+            store.trim_end(),
+            "A2 UID STORE 3,5 +FLAGS.SILENT (\\Deleted)"
+        );
+
+        let res = ImapSession::spawn("echo '* OK login first'");
+        assert!(res.is_err());
+    }
+}
@@ -0,0 +1,197 @@
+use crate::sink::maildir_flags;
+use crate::stats::csv_field;
+use maildir::MailEntry;
+use mailparse::MailHeaderMap;
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use time::format_description::well_known::Rfc3339;
+use time::macros::format_description;
+use time::OffsetDateTime;
+
+/// Format of the index of the emails archived by a run
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IndexFormat {
+    Csv,
+    /// A JSON object per line
+    JsonLines,
+}
+
+impl IndexFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            IndexFormat::Csv => "csv",
+            IndexFormat::JsonLines => "jsonl",
+        }
+    }
+}
+
+/// Email archived by a run, as written in the index
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct IndexEntry {
+    pub message_id: String,
+    pub from: String,
+    pub subject: String,
+    /// Date of the `Date` header, in RFC 3339 format, empty if missing or
+    /// invalid
+    pub date: String,
+    pub flags: String,
+    pub original_path: PathBuf,
+    /// Archive folder of the email: its maildir, mbox or zip file, or its
+    /// directory in a git repository
+    pub archive_path: PathBuf,
+}
+
+/// Columns of the CSV index
+const CSV_HEADER: [&str; 7] = [
+    "message_id",
+    "from",
+    "subject",
+    "date",
+    "flags",
+    "original_path",
+    "archive_path",
+];
+
+impl IndexEntry {
+    /// Entry of an email archived in `archive_folder`. The headers are read
+    /// from the source file: the entry must be created before moving it.
+    pub fn new(mail: &mut MailEntry, archive_folder: &Path) -> Self {
+        let original_path = mail.path().to_path_buf();
+        let flags = maildir_flags(mail.flags(), false);
+        let archive_path = archive_folder.to_path_buf();
+        let headers = match mail.headers() {
+            Ok(headers) => headers,
+            Err(_) => {
+                return IndexEntry {
+                    flags,
+                    original_path,
+                    archive_path,
+                    ..Default::default()
+                }
+            }
+        };
+        let header = |name| headers.get_first_value(name).unwrap_or_default();
+        let date = headers
+            .get_first_value("Date")
+            .and_then(|date| mailparse::dateparse(&date).ok())
+            .and_then(|timestamp| OffsetDateTime::from_unix_timestamp(timestamp).ok())
+            .and_then(|date| date.format(&Rfc3339).ok())
+            .unwrap_or_default();
+        IndexEntry {
+            message_id: header("Message-ID").trim().to_string(),
+            from: header("From"),
+            subject: header("Subject"),
+            date,
+            flags,
+            original_path,
+            archive_path,
+        }
+    }
+}
+
+/// Index of the emails archived by a run, written next to the archive
+/// folders as a searchable manifest
+pub struct RunIndex {
+    pub path: PathBuf,
+    format: IndexFormat,
+    file: Mutex<BufWriter<File>>,
+}
+
+impl RunIndex {
+    /// Create the index of a run in `dir`, named after the current time like
+    /// `archive-maildir-index-20240131T235959Z.csv`
+    pub fn create(dir: &Path, format: IndexFormat) -> io::Result<Self> {
+        let timestamp = OffsetDateTime::now_utc()
+            .format(format_description!(
+                "[year][month][day]T[hour][minute][second]Z"
+            ))
+            .map_err(io::Error::other)?;
+        std::fs::create_dir_all(dir)?;
+        let name = format!("archive-maildir-index-{}", timestamp);
+        let mut path = dir.join(format!("{}.{}", name, format.extension()));
+        let mut n = 1;
+        let file = loop {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    path = dir.join(format!("{}.{}.{}", name, n, format.extension()));
+                    n += 1;
+                }
+                file => break file?,
+            }
+        };
+        let mut file = BufWriter::new(file);
+        if format == IndexFormat::Csv {
+            writeln!(file, "{}", CSV_HEADER.join(","))?;
+        }
+        Ok(RunIndex {
+            path,
+            format,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Append an entry to the index
+    pub fn write(&self, entry: &IndexEntry) -> io::Result<()> {
+        let line = match self.format {
+            IndexFormat::Csv => [
+                entry.message_id.as_str(),
+                &entry.from,
+                &entry.subject,
+                &entry.date,
+                &entry.flags,
+                &entry.original_path.to_string_lossy(),
+                &entry.archive_path.to_string_lossy(),
+            ]
+            .map(csv_field)
+            .join(","),
+            IndexFormat::JsonLines => serde_json::to_string(entry)?,
+        };
+        writeln!(self.file.lock().unwrap(), "{}", line)
+    }
+
+    /// Flush the entries to the file
+    pub fn finish(&self) -> io::Result<()> {
+        self.file.lock().unwrap().flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::index::{IndexEntry, IndexFormat, RunIndex};
+    use maildir::Maildir;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn test_run_index() {
+        let maildir = Maildir::from(PathBuf::from("testdata/maildir1"));
+        let mut mail = maildir.list_cur().next().unwrap().unwrap();
+        let entry = IndexEntry::new(&mut mail, Path::new("archive/2016"));
+        assert!(!entry.message_id.is_empty());
+        assert_eq!(entry.archive_path, Path::new("archive/2016"));
+
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let entry = IndexEntry {
+            subject: "a, \"b\"".to_string(),
+            ..entry
+        };
+        let csv = RunIndex::create(&basedir, IndexFormat::Csv).unwrap();
+        let jsonl = RunIndex::create(&basedir, IndexFormat::JsonLines).unwrap();
+        let other = RunIndex::create(&basedir, IndexFormat::Csv).unwrap();
+        assert_ne!(csv.path, other.path);
+        for index in [&csv, &jsonl] {
+            index.write(&entry).unwrap();
+            index.finish().unwrap();
+        }
+        let content = std::fs::read_to_string(&csv.path).unwrap();
+        let lines: Vec<_> = content.lines().collect();
+        assert!(lines[0].starts_with("message_id,from,subject"));
+        assert!(lines[1].contains(",\"a, \"\"b\"\"\","));
+        let content = std::fs::read_to_string(&jsonl.path).unwrap();
+        let value: serde_json::Value = serde_json::from_str(content.trim()).unwrap();
+        assert_eq!(value["subject"], "a, \"b\"");
+    }
+}
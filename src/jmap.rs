@@ -0,0 +1,369 @@
+use crate::archiver::{
+    create_mail_archiver, ArchiveMode, MaildirArchiver, MaildirArchiverError, StoreOptions,
+};
+use crate::args::JmapOptions;
+use crate::filter::{create_mail_filters, MailFilter};
+use crate::sink::MaildirSink;
+use log::{debug, error, info};
+use maildir::Maildir;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs;
+use std::io::{self, Read};
+use time::format_description::well_known::Rfc3339;
+use time::macros::format_description;
+use time::OffsetDateTime;
+
+const CORE_CAPABILITY: &str = "urn:ietf:params:jmap:core";
+const MAIL_CAPABILITY: &str = "urn:ietf:params:jmap:mail";
+
+/// Number of emails listed or fetched by request
+const BATCH_SIZE: usize = 50;
+
+/// JMAP session resource, as returned by the session URL
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Session {
+    api_url: String,
+    download_url: String,
+    primary_accounts: HashMap<String, String>,
+}
+
+/// Properties of an email returned by `Email/get`
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct JmapEmail {
+    id: String,
+    blob_id: String,
+    received_at: String,
+    #[serde(default)]
+    keywords: HashMap<String, bool>,
+}
+
+impl JmapEmail {
+    /// Maildir flags of the keywords of the email
+    fn flags(&self) -> String {
+        let mut flags: Vec<char> = self
+            .keywords
+            .iter()
+            .filter(|(_, set)| **set)
+            .filter_map(|(keyword, _)| match keyword.as_str() {
+                "$draft" => Some('D'),
+                "$flagged" => Some('F'),
+                "$answered" => Some('R'),
+                "$seen" => Some('S'),
+                _ => None,
+            })
+            .collect();
+        flags.sort();
+        flags.into_iter().collect()
+    }
+}
+
+fn remote_error<E: Display>(e: E) -> MaildirArchiverError {
+    MaildirArchiverError::RemoteError(e.to_string())
+}
+
+/// Expand the download URL template of the session (RFC 8620, section 6.2)
+fn download_url(template: &str, account_id: &str, blob_id: &str) -> String {
+    template
+        .replace("{accountId}", account_id)
+        .replace("{blobId}", blob_id)
+        .replace("{type}", "message%2Frfc822")
+        .replace("{name}", "email.eml")
+}
+
+/// Client of the mail account of a JMAP server, authenticated with a bearer
+/// token
+pub struct JmapClient {
+    agent: ureq::Agent,
+    authorization: String,
+    session: Session,
+    account_id: String,
+}
+
+impl JmapClient {
+    /// Fetch the session of the account
+    pub fn connect(session_url: &str, token: &str) -> Result<Self, MaildirArchiverError> {
+        let agent = ureq::Agent::new();
+        let authorization = format!("Bearer {}", token);
+        let session: Session = agent
+            .get(session_url)
+            .set("Authorization", &authorization)
+            .call()
+            .map_err(remote_error)?
+            .into_json()?;
+        let account_id = session
+            .primary_accounts
+            .get(MAIL_CAPABILITY)
+            .cloned()
+            .ok_or_else(|| remote_error("no mail account in the JMAP session"))?;
+        Ok(JmapClient {
+            agent,
+            authorization,
+            session,
+            account_id,
+        })
+    }
+
+    /// Call a method of the API, returning the arguments of its response
+    fn call(&self, method: &str, mut arguments: Value) -> Result<Value, MaildirArchiverError> {
+        arguments["accountId"] = json!(self.account_id);
+        let request = json!({
+            "using": [CORE_CAPABILITY, MAIL_CAPABILITY],
+            "methodCalls": [[method, arguments, "0"]],
+        });
+        let response: Value = self
+            .agent
+            .post(&self.session.api_url)
+            .set("Authorization", &self.authorization)
+            .send_json(request)
+            .map_err(remote_error)?
+            .into_json()?;
+        match &response["methodResponses"][0] {
+            Value::Array(r) if r.len() == 3 && r[0] == method => Ok(r[1].clone()),
+            r => Err(remote_error(format!("{} failed: {}", method, r))),
+        }
+    }
+
+    /// Ids of the emails received before `before`, oldest first
+    fn query(&self, before: OffsetDateTime) -> Result<Vec<String>, MaildirArchiverError> {
+        let before = before
+            .format(format_description!(
+                "[year]-[month]-[day]T[hour]:[minute]:[second]Z"
+            ))
+            .unwrap();
+        let mut ids = Vec::new();
+        loop {
+            let result = self.call(
+                "Email/query",
+                json!({
+                    "filter": {"before": before},
+                    "sort": [{"property": "receivedAt", "isAscending": true}],
+                    "position": ids.len(),
+                    "limit": BATCH_SIZE,
+                }),
+            )?;
+            let page: Vec<String> =
+                serde_json::from_value(result["ids"].clone()).map_err(io::Error::from)?;
+            if page.is_empty() {
+                return Ok(ids);
+            }
+            ids.extend(page);
+        }
+    }
+
+    fn get(&self, ids: &[String]) -> Result<Vec<JmapEmail>, MaildirArchiverError> {
+        let result = self.call(
+            "Email/get",
+            json!({
+                "ids": ids,
+                "properties": ["id", "blobId", "receivedAt", "keywords"],
+            }),
+        )?;
+        Ok(serde_json::from_value(result["list"].clone()).map_err(io::Error::from)?)
+    }
+
+    /// Content of an email, in RFC 5322 format
+    fn download(&self, email: &JmapEmail) -> Result<Vec<u8>, MaildirArchiverError> {
+        let url = download_url(&self.session.download_url, &self.account_id, &email.blob_id);
+        let mut data = Vec::new();
+        self.agent
+            .get(&url)
+            .set("Authorization", &self.authorization)
+            .call()
+            .map_err(remote_error)?
+            .into_reader()
+            .read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn destroy(&self, ids: &[String]) -> Result<(), MaildirArchiverError> {
+        let result = self.call("Email/set", json!({ "destroy": ids }))?;
+        match result["notDestroyed"].as_object() {
+            Some(failed) if !failed.is_empty() => Err(remote_error(format!(
+                "{} email not destroyed: {}",
+                failed.len(),
+                result["notDestroyed"]
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Archiving of the downloaded emails, staged in a local maildir
+struct JmapArchive<'a> {
+    opts: &'a JmapOptions,
+    staging: Maildir,
+    mail_filters: Vec<Box<dyn MailFilter>>,
+    mail_archiver: Box<dyn MaildirArchiver>,
+}
+
+impl<'a> JmapArchive<'a> {
+    fn new(opts: &'a JmapOptions, staging: Maildir) -> Result<Self, MaildirArchiverError> {
+        staging.create_dirs()?;
+        let mail_archiver = create_mail_archiver(
+            match opts.archive_mode {
+                ArchiveMode::DryRun => ArchiveMode::DryRun,
+                _ => ArchiveMode::Move,
+            },
+            StoreOptions::default(),
+            Box::new(MaildirSink::default()),
+        );
+        Ok(JmapArchive {
+            opts,
+            mail_filters: create_mail_filters(&opts.filters, &staging),
+            staging,
+            mail_archiver,
+        })
+    }
+
+    /// Archive a downloaded email if it matches the filters. Returns false
+    /// if it is filtered out.
+    fn archive(&self, email: &JmapEmail, data: &[u8]) -> Result<bool, MaildirArchiverError> {
+        let id = self.staging.store_cur_with_flags(data, &email.flags())?;
+        let mut mail = match self.staging.find(&id) {
+            Some(mail) => mail,
+            None => return Ok(false),
+        };
+        for mail_filter in &self.mail_filters {
+            if !mail_filter.matches(&mut mail)? {
+                debug!("Email {} filtered out", email.id);
+                fs::remove_file(mail.path())?;
+                return Ok(false);
+            }
+        }
+        let date = OffsetDateTime::parse(&email.received_at, &Rfc3339).map_err(remote_error)?;
+        let archive_folder = self
+            .opts
+            .output_dir
+            .join(self.opts.split_by.email_folder_name(
+                &self.opts.prefix,
+                &self.opts.suffix,
+                &mut mail,
+                date,
+            ));
+        self.mail_archiver
+            .archive_email(&mail, &self.staging, &archive_folder)?;
+        info!(
+            "Email {} archived to folder {}",
+            email.id,
+            archive_folder.display()
+        );
+        Ok(true)
+    }
+}
+
+/// Archive the emails of a JMAP account received before `opts.before`.
+///
+/// The emails are downloaded in a staging maildir, filtered, then archived
+/// in `opts.output_dir` with the split policy, using their JMAP reception
+/// date. In move mode, the archived emails are destroyed on the server.
+/// Returns the number of archived emails.
+///
+/// Active threads are not protected: the recent emails are never downloaded.
+pub fn archive_jmap(opts: &JmapOptions) -> Result<usize, MaildirArchiverError> {
+    let client = JmapClient::connect(&opts.session_url, &opts.token)?;
+    let ids = client.query(opts.before.midnight().assume_utc())?;
+    info!("Found {} email received before {}", ids.len(), opts.before);
+    let staging_dir = mktemp::Temp::new_dir()?;
+    let archive = JmapArchive::new(opts, Maildir::from(staging_dir.to_path_buf()))?;
+    let mut archived = 0;
+    for batch in ids.chunks(BATCH_SIZE) {
+        let mut destroyed = Vec::new();
+        for email in client.get(batch)? {
+            let data = match client.download(&email) {
+                Ok(data) => data,
+                Err(e) => {
+                    error!("Error while downloading email {}: {}", email.id, e);
+                    continue;
+                }
+            };
+            match archive.archive(&email, &data) {
+                Err(e) => error!("Error while archiving email {}: {}", email.id, e),
+                Ok(false) => {}
+                Ok(true) => {
+                    archived += 1;
+                    destroyed.push(email.id);
+                }
+            }
+        }
+        if opts.archive_mode == ArchiveMode::Move && !destroyed.is_empty() {
+            client.destroy(&destroyed)?;
+        }
+    }
+    archive.mail_archiver.finish()?;
+    Ok(archived)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::archiver::ArchiveMode;
+    use crate::args::{FilterOptions, JmapOptions, SplitBy};
+    use crate::jmap::{download_url, JmapArchive, JmapEmail};
+    use maildir::Maildir;
+    use mktemp::Temp;
+    use regex::Regex;
+    use time::macros::date;
+
+    #[test]
+    fn test_jmap_email() {
+        let email: JmapEmail = serde_json::from_str(
+            r#"{
+                "id": "M1",
+                "blobId": "B1",
+                "receivedAt": "2016-05-21T22:08:25Z",
+                "keywords": {"$seen": true, "$answered": true, "$junk": true}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(email.flags(), "RS");
+        assert_eq!(
+            download_url(
+                "https://jmap.example.com/download/{accountId}/{blobId}/{name}?type={type}",
+                "A1",
+                &email.blob_id
+            ),
+            "https://jmap.example.com/download/A1/B1/email.eml?type=message%2Frfc822"
+        );
+    }
+
+    #[test]
+    fn test_jmap_archive_filters() {
+        let staging_dir = Temp::new_dir().unwrap();
+        let output_dir = Temp::new_dir().unwrap();
+        let opts = JmapOptions {
+            session_url: String::new(),
+            token: String::new(),
+            before: date!(2020 - 01 - 01),
+            output_dir: output_dir.to_path_buf(),
+            archive_mode: ArchiveMode::Move,
+            prefix: String::new(),
+            suffix: String::new(),
+            split_by: SplitBy::Year,
+            filters: FilterOptions {
+                body: Some(Regex::new("invoice").unwrap()),
+                ..Default::default()
+            },
+        };
+        let archive = JmapArchive::new(&opts, Maildir::from(staging_dir.to_path_buf())).unwrap();
+        let email = |id: &str| -> JmapEmail {
+            serde_json::from_value(serde_json::json!({
+                "id": id,
+                "blobId": id,
+                "receivedAt": "2016-05-21T22:08:25Z",
+            }))
+            .unwrap()
+        };
+        let data = |body: &str| format!("Subject: test\r\n\r\n{}\r\n", body).into_bytes();
+        assert!(archive.archive(&email("M1"), &data("invoice")).unwrap());
+        assert!(!archive.archive(&email("M2"), &data("hello")).unwrap());
+        archive.mail_archiver.finish().unwrap();
+
+        let archived = Maildir::from(output_dir.join("2016"));
+        assert_eq!(archived.count_cur(), 1);
+        assert_eq!(archive.staging.count_cur(), 0);
+    }
+}
@@ -1,2 +1,60 @@
 pub mod archiver;
 pub mod args;
+#[cfg(feature = "async")]
+pub mod asynchronous;
+pub mod attributes;
+pub mod bench;
+pub mod check;
+#[cfg(feature = "cli")]
+pub mod cli;
+#[cfg(feature = "compression")]
+pub mod compact;
+pub mod config;
+pub mod dedup;
+pub mod diff;
+pub mod dovecot;
+pub mod encryption;
+pub mod expunge;
+pub mod filter;
+pub mod folders;
+pub mod fsck;
+pub mod git;
+pub mod imap;
+pub mod index;
+#[cfg(feature = "jmap")]
+pub mod jmap;
+pub mod logger;
+pub mod mbox;
+pub mod notify;
+pub mod orphans;
+pub mod plan;
+pub mod progress;
+pub mod quota;
+pub mod redact;
+pub mod resplit;
+pub mod routes;
+pub mod run;
+pub mod sandbox;
+pub mod script;
+#[cfg(feature = "compression")]
+pub mod seekable;
+pub mod seen;
+pub mod shred;
+pub mod signals;
+pub mod sink;
+pub mod snapshot;
+pub mod source;
+pub mod space;
+pub mod spam;
+pub mod split;
+pub mod state;
+pub mod stats;
+pub mod strip;
+pub mod sync;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
+pub mod transform;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub mod uring;
+pub mod utf7;
+pub mod views;
@@ -0,0 +1,303 @@
+use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use time::macros::format_description;
+use time::{Date, OffsetDateTime};
+
+/// Log target of the messages of the archived emails
+pub const ARCHIVED: &str = "archive_maildir::archived";
+
+/// Log target of the messages of the emails that a dry run would archive
+pub const DRY_RUN: &str = "archive_maildir::dry_run";
+
+/// When to color the console output, see `--color`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColorChoice {
+    /// Color the output of a terminal, unless `NO_COLOR` is set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// Check if the standard error is colored
+    pub fn enabled(&self) -> bool {
+        match self {
+            ColorChoice::Auto => {
+                io::stderr().is_terminal()
+                    && std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+                    && std::env::var_os("TERM").is_none_or(|term| term != "dumb")
+            }
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+}
+
+/// When to rotate the log file, see `--log-rotate`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogRotation {
+    /// Before the file exceeds the size, in bytes
+    Size(u64),
+    /// At the first message of a day, in UTC
+    Daily,
+}
+
+/// Log file of `--log-file`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogFileOptions {
+    pub path: PathBuf,
+    pub rotation: Option<LogRotation>,
+    /// Number of rotated files kept
+    pub keep: usize,
+}
+
+/// Path of a rotated log file: the log file with a `.n` suffix, `.1` being
+/// the most recent
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_os_string();
+    rotated.push(format!(".{}", n));
+    PathBuf::from(rotated)
+}
+
+/// Log file appended to by the logger, and rotated without an external tool
+struct LogFile {
+    options: LogFileOptions,
+    file: File,
+    size: u64,
+    /// Day of the last message, in UTC
+    date: Date,
+}
+
+impl LogFile {
+    fn open(options: LogFileOptions) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&options.path)?;
+        let metadata = file.metadata()?;
+        Ok(LogFile {
+            options,
+            file,
+            size: metadata.len(),
+            date: OffsetDateTime::from(metadata.modified()?).date(),
+        })
+    }
+
+    /// Rename the log file and the rotated files, removing the oldest one,
+    /// and start a new log file
+    fn rotate(&mut self) -> io::Result<()> {
+        let path = &self.options.path;
+        match self.options.keep {
+            0 => fs::remove_file(path)?,
+            keep => {
+                for n in (1..keep).rev() {
+                    match fs::rename(rotated_path(path, n), rotated_path(path, n + 1)) {
+                        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                        _ => {}
+                    }
+                }
+                fs::rename(path, rotated_path(path, 1))?;
+            }
+        }
+        self.file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    /// Append a line, rotating the log file first if needed
+    fn write_line(&mut self, line: &str, today: Date) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        let rotate = match self.options.rotation {
+            Some(LogRotation::Size(max)) => self.size > 0 && self.size + len > max,
+            Some(LogRotation::Daily) => self.size > 0 && self.date != today,
+            None => false,
+        };
+        // Losing the messages is worse than a log file too large
+        if rotate {
+            if let Err(e) = self.rotate() {
+                eprintln!("Error while rotating the log file, appending to it: {}", e);
+                self.options.rotation = None;
+            }
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += len;
+        self.date = today;
+        Ok(())
+    }
+}
+
+/// ANSI color codes of the level names
+fn level_color(level: Level) -> &'static str {
+    match level {
+        Level::Error => "31",
+        Level::Warn => "33",
+        Level::Info => "36",
+        Level::Debug => "35",
+        Level::Trace => "0",
+    }
+}
+
+/// ANSI color code of a message: the errors are red, the archived emails
+/// green and the emails of the dry runs yellow
+fn message_color(record: &Record) -> Option<&'static str> {
+    match (record.level(), record.target()) {
+        (Level::Error, _) => Some("31"),
+        (_, ARCHIVED) => Some("32"),
+        (_, DRY_RUN) => Some("33"),
+        _ => None,
+    }
+}
+
+/// Logger writing the messages to the standard error, or to a log file,
+/// with their timestamp in UTC, their level and their module
+pub struct Logger {
+    level: LevelFilter,
+    colors: bool,
+    file: Option<Mutex<LogFile>>,
+}
+
+impl Logger {
+    pub fn new(level: LevelFilter, color: ColorChoice) -> Self {
+        Logger {
+            level,
+            colors: color.enabled(),
+            file: None,
+        }
+    }
+
+    /// Write the messages to a log file instead of the standard error,
+    /// without colors
+    pub fn with_file(mut self, options: LogFileOptions) -> io::Result<Self> {
+        self.file = Some(Mutex::new(LogFile::open(options)?));
+        self.colors = false;
+        Ok(self)
+    }
+
+    /// Format a log record as a line, without the newline
+    fn format(&self, record: &Record) -> String {
+        let timestamp = OffsetDateTime::now_utc()
+            .format(format_description!(
+                "[year]-[month]-[day]T[hour]:[minute]:[second].[subsecond digits:3]Z"
+            ))
+            .unwrap();
+        let level = format!("{:<5}", record.level());
+        let module = record.module_path().unwrap_or(record.target());
+        match self.colors {
+            true => {
+                let message = match message_color(record) {
+                    Some(color) => format!("\x1b[{}m{}\x1b[0m", color, record.args()),
+                    None => record.args().to_string(),
+                };
+                let color = level_color(record.level());
+                format!(
+                    "{} \x1b[{}m{}\x1b[0m [{}] {}",
+                    timestamp, color, level, module, message
+                )
+            }
+            false => format!("{} {} [{}] {}", timestamp, level, module, record.args()),
+        }
+    }
+
+    /// Use the logger for the messages of the `log` macros
+    pub fn init(self) -> Result<(), SetLoggerError> {
+        log::set_max_level(self.level);
+        log::set_logger(Box::leak(Box::new(self)))
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = self.format(record);
+        match &self.file {
+            Some(file) => {
+                let today = OffsetDateTime::now_utc().date();
+                if let Err(e) = file.lock().unwrap().write_line(&line, today) {
+                    eprintln!("Error while writing the log file: {}", e);
+                }
+            }
+            None => {
+                let _ = writeln!(io::stderr().lock(), "{}", line);
+            }
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::logger::{
+        rotated_path, ColorChoice, LogFile, LogFileOptions, LogRotation, Logger, ARCHIVED,
+    };
+    use log::{Level, LevelFilter, Record};
+    use time::macros::date;
+
+    #[test]
+    fn test_format() {
+        let format = |color, target| {
+            Logger::new(LevelFilter::Info, color).format(
+                &Record::builder()
+                    .level(Level::Info)
+                    .target(target)
+                    .module_path(Some("archive_maildir::run"))
+                    .args(format_args!("Email 1 archived"))
+                    .build(),
+            )
+        };
+        let line = format(ColorChoice::Never, ARCHIVED);
+        assert!(line.ends_with("Z INFO  [archive_maildir::run] Email 1 archived"));
+        let line = format(ColorChoice::Always, ARCHIVED);
+        assert!(line.ends_with(
+            "Z \x1b[36mINFO \x1b[0m [archive_maildir::run] \x1b[32mEmail 1 archived\x1b[0m"
+        ));
+        let line = format(ColorChoice::Always, "archive_maildir::run");
+        assert!(line.ends_with("[archive_maildir::run] Email 1 archived"));
+    }
+
+    #[test]
+    fn test_log_file() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let path = basedir.join("archive-maildir.log");
+        let mut file = LogFile::open(LogFileOptions {
+            path: path.clone(),
+            rotation: Some(LogRotation::Size(10)),
+            keep: 2,
+        })
+        .unwrap();
+        let today = date!(2016 - 05 - 21);
+        for line in ["first", "second", "third", "fourth"] {
+            file.write_line(line, today).unwrap();
+        }
+        let read = |path| std::fs::read_to_string(path).unwrap();
+        assert_eq!(read(path.clone()), "fourth\n");
+        assert_eq!(read(rotated_path(&path, 1)), "third\n");
+        assert_eq!(read(rotated_path(&path, 2)), "second\n");
+        assert!(!rotated_path(&path, 3).exists());
+
+        file.options.rotation = Some(LogRotation::Daily);
+        file.write_line("fifth", today).unwrap();
+        file.write_line("sixth", date!(2016 - 05 - 22)).unwrap();
+        assert_eq!(read(path.clone()), "sixth\n");
+        assert_eq!(read(rotated_path(&path, 1)), "fourth\nfifth\n");
+
+        // A failed rotation keeps appending to the log file
+        std::fs::remove_file(rotated_path(&path, 1)).unwrap();
+        std::fs::create_dir_all(rotated_path(&path, 1).join("dir")).unwrap();
+        file.options.keep = 1;
+        file.write_line("seventh", date!(2016 - 05 - 23)).unwrap();
+        file.write_line("eighth", date!(2016 - 05 - 24)).unwrap();
+        assert_eq!(read(path.clone()), "sixth\nseventh\neighth\n");
+    }
+}
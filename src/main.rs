@@ -1,101 +1,254 @@
-use archive_maildir::archiver::*;
 use archive_maildir::args::*;
+use archive_maildir::bench::bench;
+use archive_maildir::check::check_config;
+use archive_maildir::cli::{parse_args, Command};
+#[cfg(feature = "compression")]
+use archive_maildir::compact::compact;
+use archive_maildir::dedup::dedup;
+use archive_maildir::diff::diff;
+use archive_maildir::expunge::expunge;
+use archive_maildir::fsck::fsck;
+#[cfg(feature = "jmap")]
+use archive_maildir::jmap::archive_jmap;
+use archive_maildir::logger::Logger;
+use archive_maildir::notify::notify_run;
+use archive_maildir::orphans::orphans;
+use archive_maildir::plan::apply;
+use archive_maildir::resplit::resplit;
+use archive_maildir::run::{plan, run_with_signals};
+#[cfg(feature = "compression")]
+use archive_maildir::seekable::extract;
+use archive_maildir::signals::{Signals, INTERRUPTED_EXIT_CODE};
+use archive_maildir::stats::{
+    maildir_stats, print_stats, write_histogram, Count, Statistics, StatsFormat,
+};
+use archive_maildir::views::build_views;
 
-use log::{debug, error, info};
-use maildir::Maildir;
-use simple_logger::SimpleLogger;
-use std::path::PathBuf;
-use time::macros::format_description;
-use time::OffsetDateTime;
+use log::{error, info, warn};
+
+/// Print the statistics of a command in the requested format
+fn show_stats(format: StatsFormat, stats: &dyn Statistics) {
+    if let Err(e) = print_stats(format, stats) {
+        error!("Error while writing the statistics: {}", e);
+    }
+}
+
+/// Archive command: the run is stopped, paused and reported by the signals
+/// of the process
+fn archive(opts: ProgramOptions, stats_format: StatsFormat) {
+    let signals = match Signals::register() {
+        Ok(signals) => signals,
+        Err(e) => {
+            error!("Error while registering the signal handlers: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let result =
+        run_with_signals(&opts, signals).inspect(|report| show_stats(stats_format, report));
+    if opts.notify {
+        if let Err(e) = notify_run(opts.input_maildir.path(), &result) {
+            warn!("Error while sending the desktop notification: {}", e);
+        }
+    }
+    match result {
+        Ok(report) if report.interrupted => {
+            warn!("Archive run interrupted");
+            std::process::exit(INTERRUPTED_EXIT_CODE);
+        }
+        Ok(_) => {}
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
 
 fn main() {
-    let opts = parse_args();
-    SimpleLogger::new()
-        .with_level(opts.verbosity)
-        .init()
-        .unwrap();
-    let mail_archiver = create_mail_archiver(opts.archive_mode);
-    info!(
-        "Archiving emails older than {}",
-        opts.input_maildir.path().display(),
-    );
-    let maildir_size = opts.input_maildir.count_cur();
-    let archived = opts
-        .input_maildir
-        .list_cur()
-        .enumerate()
-        .filter_map(|(index, entry)| match entry {
-            Ok(m) => {
-                debug!("{}/{} email {}", index + 1, maildir_size, m.id());
-                Some(m)
-            }
+    let cli = parse_args();
+    let mut logger = Logger::new(cli.verbosity, cli.color);
+    if let Some(options) = &cli.log_file {
+        logger = logger.with_file(options.clone()).unwrap_or_else(|e| {
+            eprintln!("Error while opening {}: {}", options.path.display(), e);
+            std::process::exit(1);
+        });
+    }
+    logger.init().unwrap();
+    let stats_format = cli.stats_format;
+    match cli.command {
+        Command::Archive(opts) => archive(opts, stats_format),
+        Command::Resplit(opts) => match resplit(&opts)
+            .inspect(|count| show_stats(stats_format, &Count("archived", *count)))
+        {
+            Ok(count) => info!("Archived {} email", count),
             Err(e) => {
                 error!("{}", e);
-                None
+                std::process::exit(1);
             }
-        })
-        .filter_map(|mut mail| match mail.received() {
-            Ok(timestamp) => OffsetDateTime::from_unix_timestamp(timestamp)
-                .ok()
-                .map(|dt| (mail, dt)),
+        },
+        #[cfg(feature = "compression")]
+        Command::Compact(opts) => match compact(&opts)
+            .inspect(|count| show_stats(stats_format, &Count("compacted", *count)))
+        {
+            Ok(count) => info!("Compacted {} folders", count),
             Err(e) => {
                 error!("{}", e);
-                None
-            }
-        })
-        .filter(|(mail, maildate)| {
-            if maildate.date() < opts.before {
-                debug!(
-                    "Email {} with timestamp {} is older than threshold",
-                    mail.id(),
-                    maildate
+                std::process::exit(1);
+            }
+        },
+        #[cfg(feature = "compression")]
+        Command::Extract(opts) => {
+            if let Err(e) = extract(&opts, &mut std::io::stdout()) {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        Command::Plan(opts) => {
+            if let Err(e) =
+                plan(&opts).map(|count| show_stats(stats_format, &Count("planned", count)))
+            {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        Command::Diff(opts) => match diff(&opts, &mut std::io::stdout())
+            .inspect(|report| show_stats(stats_format, report))
+        {
+            Ok(report) if report.in_sync() => info!("Source and archive in sync"),
+            Ok(report) => {
+                warn!(
+                    "{} email missing from the archive, {} email missing from the source",
+                    report.source_only, report.archive_only
                 );
-                true
-            } else {
-                debug!(
-                    "Email {} with timestamp {} is newer than threshold",
-                    mail.id(),
-                    maildate
+                std::process::exit(1);
+            }
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(2);
+            }
+        },
+        Command::CheckConfig(opts) => match check_config(&opts, &mut std::io::stdout()) {
+            Ok(report) if report.problems.is_empty() => {
+                info!("Configuration of {} folders checked", report.folders)
+            }
+            Ok(report) => {
+                warn!("Found {} problems", report.problems.len());
+                std::process::exit(1);
+            }
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(2);
+            }
+        },
+        Command::Bench(opts) => {
+            let res = bench(&opts).inspect(|report| show_stats(stats_format, report));
+            if let Err(e) = res.and_then(|report| Ok(report.write(&mut std::io::stdout())?)) {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        Command::Fsck(opts) => match fsck(&opts, &mut std::io::stdout())
+            .inspect(|report| show_stats(stats_format, report))
+        {
+            Ok(report) if report.has_errors() => {
+                warn!(
+                    "Found {} problems, {} repaired",
+                    report.problems.len(),
+                    report.repaired
                 );
-                false
-            }
-        })
-        .filter_map(|(mail, maildate)| {
-            let mut output_folder = PathBuf::from(&opts.output_dir);
-            let dateformat = match opts.split_by {
-                SplitBy::Year => format_description!("[year]"),
-                SplitBy::Month => format_description!("[year]-[month]"),
-                SplitBy::Day => format_description!("[year]-[month]-[day]"),
-                SplitBy::None => format_description!(""),
-            };
-            output_folder.push(format!(
-                "{}{}{}",
-                opts.prefix,
-                maildate.format(&dateformat).unwrap(),
-                opts.suffix
-            ));
-            let to_maildir = Maildir::from(output_folder);
-            match mail_archiver.archive_email(&mail, &opts.input_maildir, &to_maildir) {
+                std::process::exit(1);
+            }
+            Ok(report) if report.problems.is_empty() => info!("No problems found"),
+            Ok(report) => info!("Repaired {} problems", report.repaired),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(2);
+            }
+        },
+        Command::Apply(opts) => match apply(&opts)
+            .inspect(|count| show_stats(stats_format, &Count("archived", *count)))
+        {
+            Ok(count) => info!("Archived {} email", count),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        #[cfg(feature = "jmap")]
+        Command::Jmap(opts) => match archive_jmap(&opts)
+            .inspect(|count| show_stats(stats_format, &Count("archived", *count)))
+        {
+            Ok(count) => info!("Archived {} email", count),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        Command::Dedup(opts) => {
+            match dedup(&opts).inspect(|report| show_stats(stats_format, report)) {
+                Ok(report) => info!(
+                    "Found {} duplicated email, {} bytes reclaimed",
+                    report.duplicates, report.reclaimed
+                ),
                 Err(e) => {
-                    error!(
-                        "Error while archiving email {} from folder {} to folder {}: {}",
-                        mail.id(),
-                        opts.input_maildir.path().display(),
-                        to_maildir.path().display(),
-                        e
-                    );
-                    None
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Orphans(opts) => {
+            match orphans(&opts).inspect(|report| show_stats(stats_format, report)) {
+                Ok(report) => info!(
+                    "Found {} orphans, {} fixed",
+                    report.orphans.len(),
+                    report.fixed
+                ),
+                Err(e) => {
+                    error!("{}", e);
+                    std::process::exit(1);
                 }
-                Ok(()) => {
+            }
+        }
+        Command::Expunge(opts) => {
+            match expunge(&opts).inspect(|report| show_stats(stats_format, report)) {
+                Ok(report) => {
                     info!(
-                        "Email {} from folder {} archived to folder {}",
-                        mail.id(),
-                        opts.input_maildir.path().display(),
-                        to_maildir.path().display()
+                        "Deleted {} email, {} pending, {} without a verified copy",
+                        report.expunged, report.pending, report.failed
                     );
-                    Some((mail.id().to_string(), to_maildir))
+                    if report.failed > 0 {
+                        std::process::exit(1);
+                    }
+                }
+                Err(e) => {
+                    error!("{}", e);
+                    std::process::exit(1);
                 }
             }
-        });
-    info!("Archived {}/{} email", archived.count(), maildir_size);
+        }
+        Command::Views(opts) => {
+            match build_views(&opts).inspect(|report| show_stats(stats_format, report)) {
+                Ok(report) => info!(
+                    "Linked {} archived email {} times",
+                    report.emails, report.links
+                ),
+                Err(e) => {
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Stats(opts) => {
+            let written = maildir_stats(&opts).and_then(|histogram| {
+                Ok(write_histogram(
+                    &mut std::io::stdout(),
+                    stats_format,
+                    &histogram,
+                )?)
+            });
+            if let Err(e) = written {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
 }
@@ -0,0 +1,71 @@
+use std::io::{self, Write};
+use time::macros::format_description;
+use time::OffsetDateTime;
+
+/// Convert maildir flags to the `Status` and `X-Status` headers used by mbox
+/// readers like mutt.
+fn status_headers(flags: &str) -> String {
+    let mut status = String::new();
+    if flags.contains('S') {
+        status.push('R');
+    }
+    status.push('O');
+    let mut x_status = String::new();
+    for (flag, x_flag) in [('R', 'A'), ('F', 'F'), ('D', 'T'), ('T', 'D')] {
+        if flags.contains(flag) {
+            x_status.push(x_flag);
+        }
+    }
+    match x_status.is_empty() {
+        true => format!("Status: {}\n", status),
+        false => format!("Status: {}\nX-Status: {}\n", status, x_status),
+    }
+}
+
+/// Write an email in mboxrd format: the `From ` separator line is added and
+/// body lines starting with `From ` (optionally quoted) are quoted again.
+pub fn write_mbox_message<W: Write>(
+    writer: &mut W,
+    data: &[u8],
+    flags: &str,
+    date: OffsetDateTime,
+) -> io::Result<()> {
+    let dateformat = format_description!(
+        "[weekday repr:short] [month repr:short] [day padding:space] [hour]:[minute]:[second] [year]"
+    );
+    writeln!(
+        writer,
+        "From MAILER-DAEMON {}",
+        date.format(&dateformat).unwrap()
+    )?;
+    writer.write_all(status_headers(flags).as_bytes())?;
+    for line in data.split_inclusive(|c| *c == b'\n') {
+        if line.iter().skip_while(|c| **c == b'>').take(5).eq(b"From ") {
+            writer.write_all(b">")?;
+        }
+        writer.write_all(line)?;
+    }
+    if !data.ends_with(b"\n") {
+        writer.write_all(b"\n")?;
+    }
+    writer.write_all(b"\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mbox::write_mbox_message;
+    use time::macros::datetime;
+
+    #[test]
+    fn test_write_mbox_message() {
+        let mut out = Vec::new();
+        let data = b"Subject: test\n\nFrom here\n>From there\nend";
+        write_mbox_message(&mut out, data, "RS", datetime!(2016-05-01 22:08:25 UTC)).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "From MAILER-DAEMON Sun May  1 22:08:25 2016\n\
+             Status: RO\nX-Status: A\n\
+             Subject: test\n\n>From here\n>>From there\nend\n\n"
+        );
+    }
+}
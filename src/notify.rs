@@ -0,0 +1,118 @@
+use crate::archiver::MaildirArchiverError;
+use crate::run::RunReport;
+use std::ffi::OsStr;
+use std::io;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Program sending the desktop notifications to the notification server of
+/// the session, over D-Bus
+const NOTIFY_SEND: &str = "notify-send";
+
+/// Title, text and urgency of the notification of an archive run
+fn run_notification(
+    input: &Path,
+    result: &Result<RunReport, MaildirArchiverError>,
+) -> (String, String, &'static str) {
+    match result {
+        Ok(report) => {
+            let mut text = format!(
+                "Archived {}/{} email from {}",
+                report.processed(),
+                report.examined,
+                input.display()
+            );
+            if !report.failed.is_empty() {
+                text.push_str(&format!(", {} failed", report.failed.len()));
+            }
+            match (report.interrupted, report.failed.is_empty()) {
+                (true, _) => ("Archive run interrupted".to_string(), text, "normal"),
+                (false, true) => ("Archive run completed".to_string(), text, "low"),
+                (false, false) => (
+                    "Archive run completed with errors".to_string(),
+                    text,
+                    "critical",
+                ),
+            }
+        }
+        Err(e) => (
+            "Archive run failed".to_string(),
+            format!("{}: {}", input.display(), e),
+            "critical",
+        ),
+    }
+}
+
+/// Send a desktop notification with a `notify-send` program
+fn send_notification(program: &OsStr, title: &str, text: &str, urgency: &str) -> io::Result<()> {
+    let status = Command::new(program)
+        .args([
+            "--app-name",
+            "archive-maildir",
+            "--urgency",
+            urgency,
+            title,
+            text,
+        ])
+        .stdin(Stdio::null())
+        .status()?;
+    match status.success() {
+        true => Ok(()),
+        false => Err(io::Error::other(format!("notify-send {}", status))),
+    }
+}
+
+/// Report the outcome of an archive run of `input` with a desktop
+/// notification, for the runs left in the background of a terminal
+pub fn notify_run(
+    input: &Path,
+    result: &Result<RunReport, MaildirArchiverError>,
+) -> io::Result<()> {
+    let (title, text, urgency) = run_notification(input, result);
+    send_notification(OsStr::new(NOTIFY_SEND), &title, &text, urgency)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::archiver::MaildirArchiverError;
+    use crate::notify::{run_notification, send_notification};
+    use crate::run::RunReport;
+    use std::path::Path;
+
+    #[test]
+    fn test_notify_run() {
+        let input = Path::new("/home/user/Maildir");
+        let report = RunReport {
+            examined: 3,
+            interrupted: true,
+            ..Default::default()
+        };
+        let (title, text, urgency) = run_notification(input, &Ok(report));
+        assert_eq!(title, "Archive run interrupted");
+        assert_eq!(text, "Archived 0/3 email from /home/user/Maildir");
+        assert_eq!(urgency, "normal");
+        let error = Err(MaildirArchiverError::Unsupported("no".to_string()));
+        assert_eq!(run_notification(input, &error).2, "critical");
+
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let script = basedir.join("notify-send");
+        std::fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\nprintf '%s\\n' \"$@\" > {}\n",
+                basedir.join("args").display()
+            ),
+        )
+        .unwrap();
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        send_notification(script.as_os_str(), "Title", "Some text", "low").unwrap();
+        let args = std::fs::read_to_string(basedir.join("args")).unwrap();
+        assert_eq!(
+            args,
+            "--app-name\narchive-maildir\n--urgency\nlow\nTitle\nSome text\n"
+        );
+        assert!(send_notification(basedir.join("missing").as_os_str(), "a", "b", "low").is_err());
+    }
+}
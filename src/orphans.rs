@@ -0,0 +1,132 @@
+use crate::archiver::MaildirArchiverError;
+use crate::args::OrphansOptions;
+use log::{error, info};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// What to do with the orphans of the archive maildirs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OrphanAction {
+    /// Move the emails of `new` to `cur` and remove the files of `tmp`,
+    /// which may be incomplete
+    Complete,
+    /// Remove the orphans
+    Remove,
+    /// Only report the orphans
+    DryRun,
+}
+
+/// Result of an orphan check
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct OrphanReport {
+    /// Files of `new` and `tmp` of the archive maildirs
+    pub orphans: Vec<PathBuf>,
+    /// Number of orphans completed or removed
+    pub fixed: usize,
+}
+
+/// Recursively collect the files of the `new` and `tmp` folders of all the
+/// maildirs under `dir`
+fn find_orphans(dir: &Path, orphans: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let path = entry.path();
+        match path.file_name().and_then(|n| n.to_str()) {
+            Some("new") | Some("tmp") => {
+                for file in fs::read_dir(&path)? {
+                    let file = file?;
+                    if file.file_type()?.is_file()
+                        && !file.file_name().to_string_lossy().starts_with('.')
+                    {
+                        orphans.push(file.path());
+                    }
+                }
+            }
+            Some("cur") => {}
+            _ => find_orphans(&path, orphans)?,
+        }
+    }
+    Ok(())
+}
+
+/// Move an email of `new` to `cur`, with an empty info
+fn move_to_cur(path: &Path) -> io::Result<PathBuf> {
+    let name = path.file_name().unwrap().to_string_lossy();
+    let id = name.split_once(':').map_or(&*name, |(id, _)| id);
+    let maildir = path.parent().and_then(Path::parent).unwrap();
+    let cur = maildir.join("cur").join(format!("{}:2,", id));
+    fs::create_dir_all(cur.parent().unwrap())?;
+    fs::rename(path, &cur)?;
+    Ok(cur)
+}
+
+/// Find the files of the archive maildirs that never made it to `cur`, left
+/// by interrupted runs, and complete or remove them
+pub fn orphans(opts: &OrphansOptions) -> Result<OrphanReport, MaildirArchiverError> {
+    let mut report = OrphanReport::default();
+    find_orphans(&opts.archive_dir, &mut report.orphans)?;
+    report.orphans.sort();
+    for path in &report.orphans {
+        let in_tmp = path.parent().and_then(Path::file_name) == Some("tmp".as_ref());
+        let res = match (opts.action, in_tmp) {
+            (OrphanAction::DryRun, _) => {
+                info!("Orphan {}", path.display());
+                continue;
+            }
+            (OrphanAction::Complete, false) => move_to_cur(path).map(|cur| {
+                info!("Moved {} to {}", path.display(), cur.display());
+            }),
+            (OrphanAction::Complete, true) | (OrphanAction::Remove, _) => fs::remove_file(path)
+                .map(|()| {
+                    info!("Removed {}", path.display());
+                }),
+        };
+        match res {
+            Ok(()) => report.fixed += 1,
+            Err(e) => error!("Cannot fix orphan {}: {}", path.display(), e),
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::args::OrphansOptions;
+    use crate::orphans::{orphans, OrphanAction};
+    use maildir::Maildir;
+
+    #[test]
+    fn test_orphans() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let maildir = Maildir::from(basedir.join("2016"));
+        maildir.create_dirs().unwrap();
+        maildir
+            .store_cur_with_flags(b"Subject: a\n\nbody\n", "S")
+            .unwrap();
+        let id = maildir.store_new(b"Subject: b\n\nbody\n").unwrap();
+        std::fs::write(maildir.path().join("tmp").join("1.partial"), "Subj").unwrap();
+
+        let mut opts = OrphansOptions {
+            archive_dir: basedir.to_path_buf(),
+            action: OrphanAction::DryRun,
+        };
+        let report = orphans(&opts).unwrap();
+        assert_eq!(report.orphans.len(), 2);
+        assert_eq!(report.fixed, 0);
+
+        opts.action = OrphanAction::Complete;
+        assert_eq!(orphans(&opts).unwrap().fixed, 2);
+        assert_eq!(maildir.count_cur(), 2);
+        assert!(maildir
+            .path()
+            .join("cur")
+            .join(format!("{}:2,", id))
+            .is_file());
+        assert!(orphans(&opts).unwrap().orphans.is_empty());
+    }
+}
@@ -0,0 +1,219 @@
+use crate::archiver::{
+    create_mail_archiver, ArchiveMode, ArchiveStage, MaildirArchiverError, StoreOptions,
+};
+use crate::args::ApplyOptions;
+use crate::sink::{create_archive_sink, ArchiveFormat, SinkOptions};
+use crate::space::check_free_space;
+use log::{error, info};
+use maildir::{MailEntry, Maildir};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// Planned archive operation for a single email, with the state of the
+/// source file when the plan was made
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlannedEmail {
+    pub id: String,
+    pub maildir: PathBuf,
+    pub path: PathBuf,
+    pub size: u64,
+    pub modified: u64,
+    pub destination: PathBuf,
+    /// Archive mode of the email, if it differs from the mode of the plan
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub archive_mode: Option<ArchiveMode>,
+}
+
+/// Size and modification time of a file
+fn file_state(path: &Path) -> io::Result<(u64, u64)> {
+    let metadata = fs::metadata(path)?;
+    let modified = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    Ok((metadata.len(), modified))
+}
+
+impl PlannedEmail {
+    pub fn new(
+        mail: &MailEntry,
+        from_maildir: &Maildir,
+        archive_folder: &Path,
+    ) -> io::Result<Self> {
+        let (size, modified) = file_state(mail.path())?;
+        Ok(PlannedEmail {
+            id: mail.id().to_string(),
+            maildir: from_maildir.path().to_path_buf(),
+            path: mail.path().to_path_buf(),
+            size,
+            modified,
+            destination: archive_folder.to_path_buf(),
+            archive_mode: None,
+        })
+    }
+
+    /// Check that the source file is still the one seen when planning
+    fn check(&self) -> Result<(), MaildirArchiverError> {
+        match file_state(&self.path) {
+            Ok(state) if state == (self.size, self.modified) => Ok(()),
+            Ok(_) => Err(MaildirArchiverError::VerificationError(format!(
+                "{} changed since the plan was made",
+                self.path.display()
+            ))),
+            Err(e) => Err(MaildirArchiverError::VerificationError(format!(
+                "{}: {}",
+                self.path.display(),
+                e
+            ))),
+        }
+    }
+}
+
+/// Archive operations reviewed before being executed by [`apply`]
+#[derive(Serialize, Deserialize)]
+pub struct Plan {
+    pub input_maildir: PathBuf,
+    pub archive_mode: ArchiveMode,
+    pub format: ArchiveFormat,
+    #[serde(default)]
+    pub sink_options: SinkOptions,
+    pub store_options: StoreOptions,
+    pub emails: Vec<PlannedEmail>,
+}
+
+impl Plan {
+    /// Size of the planned emails of each archive folder
+    pub fn destination_sizes(&self) -> BTreeMap<PathBuf, u64> {
+        let mut sizes = BTreeMap::new();
+        for email in &self.emails {
+            *sizes.entry(email.destination.clone()).or_default() += email.size;
+        }
+        sizes
+    }
+
+    /// Write the plan as JSON
+    pub fn write<W: Write>(&self, writer: W) -> io::Result<()> {
+        let mut writer = BufWriter::new(writer);
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writeln!(writer)
+    }
+
+    /// Read a plan written by [`Plan::write`]
+    pub fn read(path: &Path) -> io::Result<Self> {
+        Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+    }
+}
+
+/// Execute a saved plan, returning the number of archived emails.
+///
+/// Nothing is archived if any of the planned emails changed or disappeared
+/// since the plan was made.
+pub fn apply(opts: &ApplyOptions) -> Result<usize, MaildirArchiverError> {
+    let plan = Plan::read(&opts.plan_file)?;
+    for email in &plan.emails {
+        email.check()?;
+    }
+    if opts.check_free_space && plan.archive_mode != ArchiveMode::DryRun {
+        check_free_space(&plan.destination_sizes())?;
+    }
+    let mut mail_archivers = HashMap::new();
+    let mut archived = 0;
+    for email in &plan.emails {
+        let mail_archiver = mail_archivers
+            .entry(email.archive_mode.unwrap_or(plan.archive_mode))
+            .or_insert_with_key(|mode| {
+                create_mail_archiver(
+                    *mode,
+                    plan.store_options.clone(),
+                    create_archive_sink(plan.format, &plan.sink_options),
+                )
+            });
+        let from_maildir = Maildir::from(email.maildir.clone());
+        let mail = match from_maildir.find(&email.id) {
+            Some(mail) => mail,
+            None => {
+                error!("Email {} not found", email.id);
+                continue;
+            }
+        };
+        match mail_archiver.archive_email(&mail, &from_maildir, &email.destination) {
+            Err(e) => error!(
+                "{}",
+                e.in_context(ArchiveStage::Store, &mail, &email.destination)
+            ),
+            Ok(()) => {
+                info!(
+                    "Email {} from folder {} archived to folder {}",
+                    mail.id(),
+                    from_maildir.path().display(),
+                    email.destination.display()
+                );
+                archived += 1;
+            }
+        }
+    }
+    for mail_archiver in mail_archivers.values() {
+        mail_archiver.finish()?;
+    }
+    Ok(archived)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::archiver::{ArchiveMode, StoreOptions};
+    use crate::args::ApplyOptions;
+    use crate::plan::{apply, Plan, PlannedEmail};
+    use crate::sink::{ArchiveFormat, SinkOptions};
+    use maildir::Maildir;
+
+    #[test]
+    fn test_plan_apply() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let input_maildir = Maildir::from(basedir.join("in"));
+        input_maildir.create_dirs().unwrap();
+        let filename = "1463868505.38518452d49213cb409aa1db32f53184:2,S";
+        std::fs::copy(
+            format!("testdata/maildir1/cur/{}", filename),
+            input_maildir.path().join("cur").join(filename),
+        )
+        .unwrap();
+        let mail = input_maildir.list_cur().next().unwrap().unwrap();
+        let to_maildir = Maildir::from(basedir.join("out").join("2016"));
+
+        let plan = Plan {
+            input_maildir: input_maildir.path().to_path_buf(),
+            archive_mode: ArchiveMode::Move,
+            format: ArchiveFormat::Maildir,
+            sink_options: SinkOptions::default(),
+            store_options: StoreOptions::default(),
+            emails: vec![PlannedEmail::new(&mail, &input_maildir, to_maildir.path()).unwrap()],
+        };
+        let plan_file = basedir.join("plan.json");
+        plan.write(std::fs::File::create(&plan_file).unwrap())
+            .unwrap();
+        let opts = ApplyOptions {
+            plan_file,
+            check_free_space: true,
+        };
+
+        // The source changed: nothing is archived
+        std::fs::write(mail.path(), "Subject: changed\n\n").unwrap();
+        assert!(apply(&opts).is_err());
+        assert_eq!(input_maildir.count_cur(), 1);
+
+        std::fs::copy(format!("testdata/maildir1/cur/{}", filename), mail.path()).unwrap();
+        let mut plan = Plan::read(&opts.plan_file).unwrap();
+        plan.emails = vec![PlannedEmail::new(&mail, &input_maildir, to_maildir.path()).unwrap()];
+        plan.write(std::fs::File::create(&opts.plan_file).unwrap())
+            .unwrap();
+        assert_eq!(apply(&opts).unwrap(), 1);
+        assert_eq!(input_maildir.count_cur(), 0);
+        assert_eq!(to_maildir.count_cur(), 1);
+    }
+}
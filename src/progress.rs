@@ -0,0 +1,108 @@
+use log::debug;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::fd::FromRawFd;
+use std::sync::Mutex;
+
+/// Progress event, written as a JSON line on the progress stream
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ProgressEvent<'a> {
+    /// `progress` while a folder is archived, `folder` at the end of a
+    /// folder and `done` at the end of the run
+    pub event: &'static str,
+    /// Source folder, except for `done`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub folder: Option<&'a str>,
+    /// Listed emails
+    pub scanned: usize,
+    pub archived: usize,
+    pub failed: usize,
+    /// Share of the emails of the folder already listed, or 100 at the end
+    pub percent: f64,
+}
+
+/// Stream of the progress events of `--progress-fd`, for the programs
+/// running archive-maildir
+pub struct ProgressStream {
+    file: Mutex<File>,
+}
+
+impl ProgressStream {
+    /// Use a file descriptor inherited from the parent process
+    pub fn open(fd: i32) -> io::Result<Self> {
+        // The descriptor must be open before being owned by the file
+        if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+            let e = io::Error::last_os_error();
+            return Err(io::Error::new(
+                e.kind(),
+                format!("progress fd {}: {}", fd, e),
+            ));
+        }
+        Ok(ProgressStream {
+            file: Mutex::new(unsafe { File::from_raw_fd(fd) }),
+        })
+    }
+
+    /// Write an event. The errors don't stop the run: the reader may be gone.
+    pub fn emit(&self, event: &ProgressEvent) {
+        let mut line = serde_json::to_string(event).unwrap();
+        line.push('\n');
+        if let Err(e) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            debug!("Error while writing the progress event: {}", e);
+        }
+    }
+}
+
+/// Share of the `total` emails already listed, in percent
+pub fn percent(scanned: usize, total: usize) -> f64 {
+    match total {
+        0 => 100.0,
+        total => (scanned as f64 * 100.0 / total as f64).min(100.0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::progress::{percent, ProgressEvent, ProgressStream};
+    use std::fs::File;
+    use std::io::Read;
+    use std::os::fd::FromRawFd;
+
+    #[test]
+    fn test_progress_stream() {
+        assert_eq!(percent(1, 4), 25.0);
+        assert_eq!(percent(5, 4), 100.0);
+        assert!(ProgressStream::open(-1).is_err());
+
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let stream = ProgressStream::open(fds[1]).unwrap();
+        stream.emit(&ProgressEvent {
+            event: "progress",
+            folder: Some(".Sent"),
+            scanned: 1,
+            archived: 1,
+            failed: 0,
+            percent: 25.0,
+        });
+        stream.emit(&ProgressEvent {
+            event: "done",
+            folder: None,
+            scanned: 4,
+            archived: 3,
+            failed: 1,
+            percent: 100.0,
+        });
+        drop(stream);
+        let mut output = String::new();
+        let mut reader = unsafe { File::from_raw_fd(fds[0]) };
+        reader.read_to_string(&mut output).unwrap();
+        assert_eq!(
+            output,
+            "{\"event\":\"progress\",\"folder\":\".Sent\",\"scanned\":1,\"archived\":1,\"failed\":0,\
+             \"percent\":25.0}\n{\"event\":\"done\",\"scanned\":4,\"archived\":3,\"failed\":1,\
+             \"percent\":100.0}\n"
+        );
+    }
+}
@@ -0,0 +1,157 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Name of the Maildir++ quota file, at the root of the account maildir
+pub const MAILDIRSIZE: &str = "maildirsize";
+
+/// Size above which the quota file is recalculated, as recommended by the
+/// Maildir++ specification
+const MAX_MAILDIRSIZE: u64 = 5120;
+
+/// Change of the quota usage of an account
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UsageDelta {
+    pub bytes: i64,
+    pub count: i64,
+}
+
+impl UsageDelta {
+    /// Record the change of an email that was `size` bytes long before the
+    /// run: it has been removed if `path` doesn't exist anymore, replaced
+    /// otherwise. An email moved to the trash folder, `trashed`, still counts
+    /// in the account.
+    pub fn record(&mut self, size: u64, path: &Path, trashed: bool) {
+        match fs::metadata(path) {
+            Ok(metadata) => self.bytes += metadata.len() as i64 - size as i64,
+            Err(_) if trashed => (),
+            Err(_) => {
+                self.bytes -= size as i64;
+                self.count -= 1;
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == UsageDelta::default()
+    }
+}
+
+/// Maildir++ quota file of an account
+pub struct QuotaFile {
+    root: PathBuf,
+}
+
+impl QuotaFile {
+    /// Quota file of the account maildir `root`, if any
+    pub fn open(root: &Path) -> Option<Self> {
+        root.join(MAILDIRSIZE).is_file().then(|| QuotaFile {
+            root: root.to_path_buf(),
+        })
+    }
+
+    fn path(&self) -> PathBuf {
+        self.root.join(MAILDIRSIZE)
+    }
+
+    /// Append a change to the quota file, recalculating it when it grows
+    /// too large
+    pub fn update(&self, delta: UsageDelta) -> io::Result<()> {
+        let mut file = OpenOptions::new().append(true).open(self.path())?;
+        file.write_all(format!("{} {}\n", delta.bytes, delta.count).as_bytes())?;
+        if file.metadata()?.len() > MAX_MAILDIRSIZE {
+            self.recalculate()?;
+        }
+        Ok(())
+    }
+
+    /// Replace the quota file with the quota definition and the current
+    /// usage of the account
+    pub fn recalculate(&self) -> io::Result<()> {
+        let content = fs::read_to_string(self.path())?;
+        let definition = content.lines().next().unwrap_or_default();
+        let (bytes, count) = account_usage(&self.root)?;
+        let tmp = self.root.join("tmp").join(MAILDIRSIZE);
+        fs::write(&tmp, format!("{}\n{} {}\n", definition, bytes, count))?;
+        fs::rename(&tmp, self.path())
+    }
+}
+
+/// Size and number of the emails in `cur` and `new` of a folder
+fn folder_usage(folder: &Path) -> io::Result<(u64, u64)> {
+    let mut usage = (0, 0);
+    for subdir in ["cur", "new"] {
+        let dir = match fs::read_dir(folder.join(subdir)) {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        for entry in dir {
+            let metadata = entry?.metadata()?;
+            if metadata.is_file() {
+                usage.0 += metadata.len();
+                usage.1 += 1;
+            }
+        }
+    }
+    Ok(usage)
+}
+
+/// Size and number of the emails of an account: INBOX and its Maildir++
+/// subfolders
+fn account_usage(root: &Path) -> io::Result<(u64, u64)> {
+    let mut usage = folder_usage(root)?;
+    for entry in fs::read_dir(root)? {
+        let path = entry?.path();
+        let subfolder = path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+        if subfolder && path.join("cur").is_dir() {
+            let (bytes, count) = folder_usage(&path)?;
+            usage.0 += bytes;
+            usage.1 += count;
+        }
+    }
+    Ok(usage)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::quota::{QuotaFile, UsageDelta, MAILDIRSIZE};
+    use maildir::Maildir;
+
+    #[test]
+    fn test_quota_file() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        assert!(QuotaFile::open(&basedir).is_none());
+        let maildir = Maildir::from(basedir.to_path_buf());
+        maildir.create_dirs().unwrap();
+        let id = maildir
+            .store_cur_with_flags(b"Subject: a\r\n\r\n", "S")
+            .unwrap();
+        let path = maildir.find(&id).unwrap().path().to_path_buf();
+        std::fs::write(basedir.join(MAILDIRSIZE), "1000000S\n24 2\n").unwrap();
+
+        let quota = QuotaFile::open(&basedir).unwrap();
+        let mut delta = UsageDelta::default();
+        std::fs::write(&path, b"Subject: b\r\n\r\nstub\r\n").unwrap();
+        delta.record(14, &path, false);
+        delta.record(10, &basedir.join("missing"), false);
+        delta.record(10, &basedir.join("trashed"), true);
+        assert_eq!(
+            delta,
+            UsageDelta {
+                bytes: -4,
+                count: -1
+            }
+        );
+        quota.update(delta).unwrap();
+        let content = std::fs::read_to_string(basedir.join(MAILDIRSIZE)).unwrap();
+        assert_eq!(content, "1000000S\n24 2\n-4 -1\n");
+
+        quota.recalculate().unwrap();
+        let content = std::fs::read_to_string(basedir.join(MAILDIRSIZE)).unwrap();
+        assert_eq!(content, "1000000S\n20 1\n");
+    }
+}
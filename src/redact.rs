@@ -0,0 +1,118 @@
+use crate::dedup::{reader_digest, to_hex};
+use serde::{Deserialize, Serialize};
+use std::io;
+
+/// What to do with the redacted headers
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RedactMode {
+    /// Remove the headers
+    Remove,
+    /// Replace the header values with their SHA-256 digest, so that equal
+    /// values can still be correlated
+    Hash,
+}
+
+/// Remove or hash a set of headers of the emails
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HeaderRedactor {
+    headers: Vec<String>,
+    mode: RedactMode,
+}
+
+/// Split the header section of an email into its (possibly folded) header
+/// fields, returning them and the offset of the separator line.
+pub(crate) fn header_fields(data: &[u8]) -> (Vec<&[u8]>, usize) {
+    let mut fields: Vec<&[u8]> = Vec::new();
+    let mut start = 0;
+    let mut offset = 0;
+    while offset < data.len() {
+        let end = match data[offset..].iter().position(|b| *b == b'\n') {
+            Some(i) => offset + i + 1,
+            None => data.len(),
+        };
+        let line = &data[offset..end];
+        if line == b"\n" || line == b"\r\n" {
+            break;
+        }
+        if offset > start && !line.starts_with(b" ") && !line.starts_with(b"\t") {
+            fields.push(&data[start..offset]);
+            start = offset;
+        }
+        offset = end;
+    }
+    if offset > start {
+        fields.push(&data[start..offset]);
+    }
+    (fields, offset)
+}
+
+impl HeaderRedactor {
+    pub fn new(headers: &[String], mode: RedactMode) -> Self {
+        HeaderRedactor {
+            headers: headers.iter().map(|h| h.to_ascii_lowercase()).collect(),
+            mode,
+        }
+    }
+
+    /// Return the email with the headers redacted
+    pub fn redact(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let (fields, body) = header_fields(data);
+        let mut redacted = Vec::with_capacity(data.len());
+        for field in fields {
+            let colon = field.iter().position(|b| *b == b':');
+            let name = colon.map(|i| {
+                String::from_utf8_lossy(&field[..i])
+                    .trim()
+                    .to_ascii_lowercase()
+            });
+            let colon = match (name, colon) {
+                (Some(name), Some(colon)) if self.headers.contains(&name) => colon,
+                _ => {
+                    redacted.extend_from_slice(field);
+                    continue;
+                }
+            };
+            if self.mode == RedactMode::Hash {
+                let eol: &[u8] = match field.ends_with(b"\r\n") {
+                    true => b"\r\n",
+                    false => b"\n",
+                };
+                let value = field[colon + 1..].trim_ascii();
+                let digest = to_hex(&reader_digest(&mut &value[..])?);
+                redacted.extend_from_slice(&field[..colon + 1]);
+                redacted.extend_from_slice(format!(" {}", digest).as_bytes());
+                redacted.extend_from_slice(eol);
+            }
+        }
+        redacted.extend_from_slice(&data[body..]);
+        Ok(redacted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::redact::{HeaderRedactor, RedactMode};
+
+    #[test]
+    fn test_redact_headers() {
+        let data = b"Received: from a\n\tby b\nSubject: test\nX-Originating-IP: 10.0.0.1\n\nReceived: body\n";
+        let headers = ["received".to_string(), "X-Originating-IP".to_string()];
+
+        let redactor = HeaderRedactor::new(&headers, RedactMode::Remove);
+        assert_eq!(
+            redactor.redact(data).unwrap(),
+            b"Subject: test\n\nReceived: body\n"
+        );
+
+        let redactor = HeaderRedactor::new(&headers, RedactMode::Hash);
+        assert_eq!(
+            String::from_utf8(redactor.redact(data).unwrap()).unwrap(),
+            "Received: fbfc29816c1d3b394d1f843cd4e83a124d8240bade0393c20722c6a5bcee09b1\n\
+             Subject: test\n\
+             X-Originating-IP: f5047344122f0dee9974ba6761e61c6b8649e1f3968d13a635ebbf7be53a3a0d\n\
+             \n\
+             Received: body\n"
+        );
+    }
+}
@@ -0,0 +1,139 @@
+use crate::archiver::{
+    create_mail_archiver, ArchiveMode, ArchiveStage, MaildirArchiverError, StoreOptions,
+};
+use crate::args::ResplitOptions;
+use crate::sink::MaildirSink;
+use crate::split::email_date;
+use log::{debug, error, info};
+use maildir::Maildir;
+use std::fs;
+use std::path::Path;
+
+/// List the maildirs directly contained in an archive directory
+pub fn list_archive_maildirs(archive_dir: &Path) -> Result<Vec<Maildir>, MaildirArchiverError> {
+    let mut maildirs = Vec::new();
+    for entry in fs::read_dir(archive_dir)? {
+        let path = entry?.path();
+        if path.join("cur").is_dir() {
+            maildirs.push(Maildir::from(path));
+        }
+    }
+    maildirs.sort_by(|a, b| a.path().cmp(b.path()));
+    Ok(maildirs)
+}
+
+/// Remove a maildir if it does not contain anything else than the empty
+/// `cur`, `new` and `tmp` folders.
+fn remove_if_empty(maildir: &Maildir) {
+    for subdir in ["cur", "new", "tmp"] {
+        if fs::remove_dir(maildir.path().join(subdir)).is_err() {
+            return;
+        }
+    }
+    if fs::remove_dir(maildir.path()).is_ok() {
+        info!("Removed empty folder {}", maildir.path().display());
+    }
+}
+
+/// Reorganize the archive maildirs under a different split policy.
+///
+/// Every email found in the maildirs of `opts.archive_dir` is archived again
+/// in `opts.output_dir`, using the new split policy. Emails already in the
+/// right folder are left untouched. Returns the number of archived emails.
+pub fn resplit(opts: &ResplitOptions) -> Result<usize, MaildirArchiverError> {
+    let mail_archiver = create_mail_archiver(
+        opts.archive_mode,
+        StoreOptions::default(),
+        Box::new(MaildirSink::default()),
+    );
+    let mut archived = 0;
+    for from_maildir in list_archive_maildirs(&opts.archive_dir)? {
+        info!("Splitting folder {}", from_maildir.path().display());
+        for entry in from_maildir.list_cur() {
+            let mut mail = match entry {
+                Ok(m) => m,
+                Err(e) => {
+                    error!("{}", e);
+                    continue;
+                }
+            };
+            let maildate = match email_date(&mail) {
+                Ok(d) => d,
+                Err(e) => {
+                    error!("{}", e);
+                    continue;
+                }
+            };
+            let archive_folder = opts.output_dir.join(opts.split_by.email_folder_name(
+                &opts.prefix,
+                &opts.suffix,
+                &mut mail,
+                maildate,
+            ));
+            if archive_folder == from_maildir.path() {
+                debug!(
+                    "Email {} already in folder {}",
+                    mail.id(),
+                    archive_folder.display()
+                );
+                continue;
+            }
+            match mail_archiver.archive_email(&mail, &from_maildir, &archive_folder) {
+                Err(e) => error!(
+                    "{}",
+                    e.in_context(ArchiveStage::Store, &mail, &archive_folder)
+                ),
+                Ok(()) => {
+                    info!(
+                        "Email {} from folder {} archived to folder {}",
+                        mail.id(),
+                        from_maildir.path().display(),
+                        archive_folder.display()
+                    );
+                    archived += 1;
+                }
+            }
+        }
+        if opts.archive_mode == ArchiveMode::Move {
+            remove_if_empty(&from_maildir);
+        }
+    }
+    Ok(archived)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::archiver::ArchiveMode;
+    use crate::args::ResplitOptions;
+    use crate::resplit::{list_archive_maildirs, resplit};
+    use crate::split::SplitBy;
+    use maildir::Maildir;
+
+    #[test]
+    fn test_resplit() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let yearly = Maildir::from(basedir.join("2016"));
+        yearly.create_dirs().unwrap();
+        let filename = "1463868505.38518452d49213cb409aa1db32f53184:2,S";
+        std::fs::copy(
+            format!("testdata/maildir1/cur/{}", filename),
+            yearly.path().join("cur").join(filename),
+        )
+        .unwrap();
+
+        let opts = ResplitOptions {
+            archive_dir: basedir.to_path_buf(),
+            output_dir: basedir.to_path_buf(),
+            archive_mode: ArchiveMode::Move,
+            prefix: String::new(),
+            suffix: String::new(),
+            split_by: SplitBy::Month,
+        };
+        assert_eq!(resplit(&opts).unwrap(), 1);
+        let maildirs = list_archive_maildirs(&basedir).unwrap();
+        assert_eq!(maildirs.len(), 1);
+        assert_eq!(maildirs[0].path(), basedir.join("2016-05"));
+        assert_eq!(maildirs[0].count_cur(), 1);
+    }
+}
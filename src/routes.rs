@@ -0,0 +1,183 @@
+use crate::folders::{matches_pattern, SourceFolder};
+use log::debug;
+use maildir::MailEntry;
+use mailparse::{addrparse_header, MailAddr, MailHeaderMap};
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Routing map read from the routes file (`--routes`), in TOML: the first
+/// route whose patterns all match an email gives its output directory,
+/// instead of the output directory of the run.
+///
+/// ```toml
+/// [[route]]
+/// sender = "*@customer.example.com"
+/// output = "/srv/archive/customers"
+///
+/// [[route]]
+/// folder = ".Lists.*"
+/// list-id = "*.lists.example.com"
+/// output = "/srv/archive/lists"
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Routes {
+    #[serde(default, rename = "route")]
+    pub routes: Vec<Route>,
+}
+
+/// Output directory of the emails matching the patterns of the route, with
+/// the `*` and `?` wildcards. The addresses and the list ids are matched
+/// ignoring the case.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Route {
+    /// Pattern of the address of the `From` header
+    pub sender: Option<String>,
+    /// Pattern of the identifier of the `List-Id` header, without the `<>`
+    pub list_id: Option<String>,
+    /// Pattern of the source folder, like `.Lists.*`
+    pub folder: Option<String>,
+    pub output: PathBuf,
+}
+
+/// Check if a value matches a pattern, ignoring the case
+fn matches_lowercase(pattern: &str, value: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    matches_pattern(&pattern, &value.to_lowercase().chars().collect::<Vec<_>>())
+}
+
+/// Address of the `From` header of an email
+fn sender(headers: &[mailparse::MailHeader]) -> Option<String> {
+    let addrs = addrparse_header(headers.get_first_header("From")?).ok()?;
+    addrs.iter().find_map(|addr| match addr {
+        MailAddr::Single(info) => Some(info.addr.clone()),
+        MailAddr::Group(group) => group.addrs.first().map(|info| info.addr.clone()),
+    })
+}
+
+/// Identifier of the `List-Id` header of an email
+fn list_id(headers: &[mailparse::MailHeader]) -> Option<String> {
+    let value = headers.get_first_value("List-Id")?;
+    Some(match (value.rfind('<'), value.rfind('>')) {
+        (Some(start), Some(end)) if start < end => value[start + 1..end].to_string(),
+        _ => value.trim().to_string(),
+    })
+}
+
+impl Route {
+    /// Check if an email of a source folder matches all the patterns
+    fn matches(&self, folder: &SourceFolder, mail: &mut MailEntry) -> bool {
+        if let Some(pattern) = &self.folder {
+            if !folder.matches(pattern) {
+                return false;
+            }
+        }
+        if self.sender.is_none() && self.list_id.is_none() {
+            return true;
+        }
+        let headers = match mail.headers() {
+            Ok(headers) => headers,
+            Err(e) => {
+                debug!("Email {} not routed: {}", mail.id(), e);
+                return false;
+            }
+        };
+        let matches = |pattern: &Option<String>, value: Option<String>| match pattern {
+            Some(pattern) => value.is_some_and(|value| matches_lowercase(pattern, &value)),
+            None => true,
+        };
+        matches(&self.sender, sender(&headers)) && matches(&self.list_id, list_id(&headers))
+    }
+}
+
+impl Routes {
+    /// Read the routes file
+    pub fn read(path: &str) -> Result<Self, String> {
+        let content = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let routes: Routes = toml::from_str(&content).map_err(|e| format!("{}: {}", path, e))?;
+        // A route without patterns would take all the emails
+        match routes
+            .routes
+            .iter()
+            .find(|r| r.sender.is_none() && r.list_id.is_none() && r.folder.is_none())
+        {
+            Some(route) => Err(format!(
+                "{}: route to {} without sender, list-id or folder",
+                path,
+                route.output.display()
+            )),
+            None => Ok(routes),
+        }
+    }
+
+    /// Output directory of an email of a source folder, if a route matches
+    pub fn output_dir(&self, folder: &SourceFolder, mail: &mut MailEntry) -> Option<&Path> {
+        self.routes
+            .iter()
+            .find(|route| route.matches(folder, mail))
+            .map(|route| route.output.as_path())
+    }
+
+    /// Output directories of the routes
+    pub fn outputs(&self) -> impl Iterator<Item = &PathBuf> {
+        self.routes.iter().map(|route| &route.output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::folders::SourceFolder;
+    use crate::routes::Routes;
+    use maildir::Maildir;
+    use std::path::Path;
+
+    #[test]
+    fn test_routes() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let path = basedir.join("routes.toml");
+        std::fs::write(
+            &path,
+            r#"
+            [[route]]
+            sender = "*@Customer.example.com"
+            output = "/archive/customers"
+
+            [[route]]
+            folder = ".Lists.*"
+            list-id = "*.lists.example.com"
+            output = "/archive/lists"
+            "#,
+        )
+        .unwrap();
+        let routes = Routes::read(path.to_str().unwrap()).unwrap();
+        let maildir = Maildir::from(basedir.join("in"));
+        maildir.create_dirs().unwrap();
+        let folder = |name: &str| SourceFolder {
+            name: name.into(),
+            maildir: Maildir::from(basedir.join("in")),
+        };
+        let email = |data: &str| {
+            let id = maildir.store_cur_with_flags(data.as_bytes(), "S").unwrap();
+            maildir.find(&id).unwrap()
+        };
+        let mut customer = email("From: A <a@customer.example.com>\n\nbody\n");
+        let mut list = email("From: b@example.com\nList-Id: Rust <rust.lists.example.com>\n\nb\n");
+        assert_eq!(
+            routes.output_dir(&folder(""), &mut customer),
+            Some(Path::new("/archive/customers"))
+        );
+        assert_eq!(routes.output_dir(&folder(""), &mut list), None);
+        assert_eq!(
+            routes.output_dir(&folder(".Lists.rust"), &mut list),
+            Some(Path::new("/archive/lists"))
+        );
+
+        std::fs::write(&path, "[[route]]\noutput = \"/archive/all\"\n").unwrap();
+        assert!(Routes::read(path.to_str().unwrap())
+            .unwrap_err()
+            .contains("without sender, list-id or folder"));
+    }
+}
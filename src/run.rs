@@ -0,0 +1,1965 @@
+use crate::archiver::*;
+use crate::args::{PlanOptions, ProgramOptions};
+#[cfg(feature = "compression")]
+use crate::compact::{compact, rollup_options};
+use crate::config::FolderOptions;
+use crate::dovecot::{force_resync, mailbox_name};
+use crate::expunge::{DeferredEmail, DeferredJournal, DEFERRED_JOURNAL_FILE};
+use crate::filter::create_mail_filters;
+use crate::folders::{
+    courier_subscribe, duplicate_key, list_source_folders, DuplicateIndex, FolderNaming,
+    SourceFolder,
+};
+use crate::imap::{read_uidlist, ImapSession};
+use crate::index::{IndexEntry, RunIndex};
+use crate::logger::{ARCHIVED, DRY_RUN};
+use crate::plan::{Plan, PlannedEmail};
+use crate::progress::{percent, ProgressEvent, ProgressStream};
+use crate::quota::{QuotaFile, UsageDelta};
+use crate::sandbox::{drop_privileges, enter_sandbox, writable_dirs};
+use crate::script::{create_shell_archiver, DryRunFormat};
+use crate::seen::SeenDatabase;
+use crate::shred::copy_on_write_filesystem;
+use crate::signals::Signals;
+use crate::sink::{create_archive_sink, email_base, ArchiveFormat, DirCreation};
+use crate::snapshot::{prune_snapshots, Snapshot};
+use crate::source::MailSource;
+use crate::space::check_free_space;
+use crate::spam::SpamRouting;
+use crate::split::{email_date, expand_sequence, has_sequence, MailOrder};
+use crate::state::{default_state_path, delivery_time, RunState};
+use log::{debug, error, info, warn};
+use maildir::{MailEntry, Maildir};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ffi::OsString;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
+
+/// Number of selected emails waiting to be archived: the selection of a
+/// source folder stops when it is that far ahead of a slow destination
+const PIPELINE_DEPTH: usize = 64;
+
+/// Email processed by a run
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReportedEmail {
+    pub id: String,
+    /// Name of the source folder of the email
+    pub folder: String,
+    /// Archive folder of the email, or of its first copy for a duplicate
+    pub archive_folder: Option<PathBuf>,
+    /// Size of the source file, in bytes
+    pub size: u64,
+}
+
+/// Email that could not be archived
+#[derive(Debug)]
+pub struct FailedEmail {
+    pub id: String,
+    /// Name of the source folder of the email
+    pub folder: String,
+    pub error: MaildirArchiverError,
+}
+
+/// Counts of the emails of a source folder processed by a run
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FolderSummary {
+    pub folder: String,
+    /// Number of emails selected for archiving
+    pub candidates: usize,
+    pub archived: usize,
+    /// Emails already archived, and spam purged
+    pub skipped: usize,
+    pub errors: usize,
+    /// Size of the archived emails, in bytes
+    pub bytes: u64,
+}
+
+impl FolderSummary {
+    /// Add the counts of another folder
+    fn add(&mut self, other: &FolderSummary) {
+        self.candidates += other.candidates;
+        self.archived += other.archived;
+        self.skipped += other.skipped;
+        self.errors += other.errors;
+        self.bytes += other.bytes;
+    }
+}
+
+/// Results of an archive run
+#[derive(Debug, Default)]
+pub struct RunReport {
+    pub archived: Vec<ReportedEmail>,
+    /// Copies of emails already archived from another folder
+    pub skipped: Vec<ReportedEmail>,
+    /// Spam deleted without archiving it
+    pub purged: Vec<ReportedEmail>,
+    pub failed: Vec<FailedEmail>,
+    /// Number of emails of the source folders
+    pub examined: usize,
+    /// Number of emails selected for archiving
+    pub selected: usize,
+    /// Number of emails of `new` older than the cutoff, not archived without
+    /// `include_new`
+    pub stuck_in_new: usize,
+    /// Size of the archived emails, in bytes
+    pub archived_bytes: u64,
+    /// Whether the run has been interrupted before its end
+    pub interrupted: bool,
+    /// Number of emails selected for archiving, by source folder
+    pub selected_by_folder: BTreeMap<String, usize>,
+}
+
+impl RunReport {
+    /// Number of selected emails processed without error
+    pub fn processed(&self) -> usize {
+        self.archived.len() + self.skipped.len() + self.purged.len()
+    }
+
+    /// Add the results of another part of the run
+    pub fn merge(&mut self, other: RunReport) {
+        self.archived.extend(other.archived);
+        self.skipped.extend(other.skipped);
+        self.purged.extend(other.purged);
+        self.failed.extend(other.failed);
+        self.examined += other.examined;
+        self.selected += other.selected;
+        self.stuck_in_new += other.stuck_in_new;
+        self.archived_bytes += other.archived_bytes;
+        self.interrupted |= other.interrupted;
+        for (folder, selected) in other.selected_by_folder {
+            *self.selected_by_folder.entry(folder).or_default() += selected;
+        }
+    }
+
+    /// Counts of the emails processed by the run, by source folder sorted by
+    /// name
+    pub fn folder_summaries(&self) -> Vec<FolderSummary> {
+        fn summary<'a>(
+            summaries: &'a mut BTreeMap<String, FolderSummary>,
+            folder: &str,
+        ) -> &'a mut FolderSummary {
+            summaries
+                .entry(folder.to_string())
+                .or_insert_with(|| FolderSummary {
+                    folder: folder.to_string(),
+                    ..Default::default()
+                })
+        }
+        let mut summaries = BTreeMap::new();
+        for (folder, selected) in &self.selected_by_folder {
+            summary(&mut summaries, folder).candidates += selected;
+        }
+        for email in &self.archived {
+            let summary = summary(&mut summaries, &email.folder);
+            summary.archived += 1;
+            summary.bytes += email.size;
+        }
+        for email in self.skipped.iter().chain(&self.purged) {
+            summary(&mut summaries, &email.folder).skipped += 1;
+        }
+        for email in &self.failed {
+            summary(&mut summaries, &email.folder).errors += 1;
+        }
+        summaries.into_values().collect()
+    }
+
+    /// Size of the emails archived to each archive folder. The transformations
+    /// and the archive format may change the size actually written.
+    pub fn destination_sizes(&self) -> BTreeMap<PathBuf, u64> {
+        let mut sizes = BTreeMap::new();
+        for email in &self.archived {
+            if let Some(folder) = &email.archive_folder {
+                *sizes.entry(folder.clone()).or_default() += email.size;
+            }
+        }
+        sizes
+    }
+}
+
+/// Log the estimated size of a run not writing anything, per archive folder
+fn log_estimate(sizes: &BTreeMap<PathBuf, u64>) {
+    for (folder, size) in sizes {
+        info!("Folder {} would receive {} bytes", folder.display(), size);
+    }
+    info!(
+        "Estimated archive size: {} bytes",
+        sizes.values().sum::<u64>()
+    );
+}
+
+/// Log the counts of each source folder of a run, as a table with their
+/// total
+fn log_folder_summaries(summaries: &[FolderSummary]) {
+    let width = summaries
+        .iter()
+        .map(|summary| summary.folder.len())
+        .chain(std::iter::once("Folder".len()))
+        .max()
+        .unwrap_or_default();
+    info!(
+        "{:width$} {:>10} {:>10} {:>10} {:>10} {:>12}",
+        "Folder", "candidates", "archived", "skipped", "errors", "bytes"
+    );
+    let mut total = FolderSummary {
+        folder: "Total".to_string(),
+        ..Default::default()
+    };
+    for summary in summaries {
+        total.add(summary);
+    }
+    for summary in summaries.iter().chain(std::iter::once(&total)) {
+        info!(
+            "{:width$} {:>10} {:>10} {:>10} {:>10} {:>12}",
+            summary.folder,
+            summary.candidates,
+            summary.archived,
+            summary.skipped,
+            summary.errors,
+            summary.bytes
+        );
+    }
+}
+
+/// Check the combinations of options that an archive run doesn't support
+pub(crate) fn check_options(opts: &ProgramOptions) -> Result<(), MaildirArchiverError> {
+    // The archive copies are checked by content
+    let defer_delete = opts.defer_delete && opts.dry_run_format == DryRunFormat::Log;
+    if defer_delete
+        && (!matches!(opts.format, ArchiveFormat::Maildir | ArchiveFormat::Cas)
+            || !opts.store_options.transformers().is_empty())
+    {
+        return Err(MaildirArchiverError::Unsupported(
+            "--defer-delete needs the maildir or cas format, without transformations".to_string(),
+        ));
+    }
+    // The workers would each count the limit against their own folders
+    if opts.limit.is_some() && opts.jobs > 1 {
+        return Err(MaildirArchiverError::Unsupported(
+            "--limit can't be used with more than one job".to_string(),
+        ));
+    }
+    if opts.rotate_after.is_some() && !has_sequence(&opts.prefix) && !has_sequence(&opts.suffix) {
+        return Err(MaildirArchiverError::Unsupported(
+            "--rotate-after needs a {seq} or {part} token in the prefix or the suffix".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Check that the output directories of the archive folders, including
+/// the spam directory and the route outputs, can be used as allowed by
+/// the directory creation policy: with [`DirCreation::Folders`] only the
+/// last component of an output directory is created.
+pub(crate) fn check_output_dirs(opts: &ProgramOptions) -> Result<(), MaildirArchiverError> {
+    let spam_dir = opts
+        .spam
+        .as_ref()
+        .and_then(|spam| spam.output_dir.as_deref());
+    let dirs = std::iter::once(opts.output_dir.as_path())
+        .chain(spam_dir)
+        .chain(opts.routes.outputs().map(PathBuf::as_path));
+    for dir in dirs {
+        let missing = match opts.sink_options.dir_creation {
+            DirCreation::None => Some(dir),
+            DirCreation::Folders => dir.parent().filter(|p| !p.as_os_str().is_empty()),
+            DirCreation::Parents => None,
+        };
+        if let Some(missing) = missing.filter(|d| !d.is_dir()) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{}: directory not found", missing.display()),
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// Open the seen database requested by the program options
+fn open_seen_database(opts: &ProgramOptions) -> Result<Option<SeenDatabase>, MaildirArchiverError> {
+    match &opts.seen_db {
+        Some(path) => SeenDatabase::open(path)
+            .map(Some)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)).into()),
+        None => Ok(None),
+    }
+}
+
+/// State file of the incremental runs of a source folder
+pub(crate) fn state_file(opts: &ProgramOptions, folder: &SourceFolder) -> PathBuf {
+    opts.state_file
+        .clone()
+        .unwrap_or_else(|| default_state_path(&folder.maildir))
+}
+
+/// Options of a source folder, with the overrides of the configuration file
+pub(crate) fn folder_options(opts: &ProgramOptions, folder: &SourceFolder) -> FolderOptions {
+    opts.config.resolve(
+        folder,
+        FolderOptions {
+            before: opts.before,
+            archive_mode: opts.archive_mode,
+            split_by: opts.split_by,
+        },
+    )
+}
+
+/// Destination of a selected email
+pub(crate) enum Destination {
+    /// Archive folder of the email
+    Archive(PathBuf),
+    /// Spam to delete without archiving it
+    Purge,
+}
+
+/// Progress of a selection of emails
+#[derive(Default)]
+struct Selection {
+    /// Number of listed emails
+    examined: AtomicUsize,
+    /// Number of emails of the listing, once it is complete: never set with
+    /// the directory order, streaming the directory entries
+    listed: OnceLock<usize>,
+    /// Error that stopped the selection, with `--strict`
+    failure: Mutex<Option<MaildirArchiverError>>,
+    /// Ids and delivery times of the listed emails left in the folder: newer
+    /// than the threshold, filtered out or skipped because of an error
+    retained: Mutex<BTreeMap<String, i64>>,
+}
+
+impl Selection {
+    /// Log the error skipping an email. With `--strict` the error stops the
+    /// selection instead.
+    fn skip(&self, strict: bool, message: String) {
+        error!("{}", message);
+        if strict {
+            let mut failure = self.failure.lock().unwrap();
+            failure.get_or_insert(MaildirArchiverError::Strict(message));
+        }
+    }
+
+    /// Whether the selection has been stopped by an error
+    fn failed(&self) -> bool {
+        self.failure.lock().unwrap().is_some()
+    }
+
+    /// Record a listed email left in the folder, examined again by the next
+    /// incremental run
+    fn retain(&self, mail: &MailEntry) {
+        // The emails without a delivery time are always examined
+        if let Some(time) = delivery_time(mail) {
+            let mut retained = self.retained.lock().unwrap();
+            retained.insert(mail.id().to_string(), time);
+        }
+    }
+}
+
+/// Emails of a source folder to archive, with their destination, up to
+/// `limit`. Emails found in the seen database are skipped.
+pub(crate) fn select_emails(
+    opts: &ProgramOptions,
+    folder: &SourceFolder,
+    folder_opts: &FolderOptions,
+    seen: Option<&SeenDatabase>,
+    limit: Option<usize>,
+) -> Result<Vec<(MailEntry, Destination)>, MaildirArchiverError> {
+    let selection = Selection::default();
+    let emails = selected_emails(opts, folder, folder_opts, seen, limit, &selection).collect();
+    match selection.failure.into_inner().unwrap() {
+        Some(e) => Err(e),
+        None => Ok(emails),
+    }
+}
+
+/// Iterator over the emails selected by [`select_emails`], counting the
+/// listed emails in `selection`. The emails are listed and sorted when it is
+/// created, the spam classification, the filters and the seen database are
+/// applied as it is consumed. With the directory order nothing is listed up
+/// front: the directory entries are streamed. With the inode order the
+/// emails are sorted before the first one is read. With `--strict` it stops
+/// at the first email skipped because of an error.
+fn selected_emails<'a>(
+    opts: &'a ProgramOptions,
+    folder: &'a SourceFolder,
+    folder_opts: &'a FolderOptions,
+    seen: Option<&'a SeenDatabase>,
+    limit: Option<usize>,
+    selection: &'a Selection,
+) -> impl Iterator<Item = (MailEntry, Destination)> + 'a {
+    let mail_filters = create_mail_filters(&opts.filters, &folder.maildir);
+    let purge_before = opts.spam.as_ref().and_then(|spam| spam.purge_before());
+    let mut parts = HashMap::new();
+    let state = match opts.incremental {
+        true => {
+            let path = state_file(opts, folder);
+            RunState::read(&path).unwrap_or_else(|e| {
+                error!("Error while reading {}: {}", path.display(), e);
+                None
+            })
+        }
+        false => None,
+    };
+    let new_emails: Box<dyn Iterator<Item = _>> = match opts.include_new {
+        true => Box::new(folder.maildir.list_new().map(|entry| Ok(entry?))),
+        false => Box::new(std::iter::empty()),
+    };
+    let listed = folder
+        .emails()
+        .chain(new_emails)
+        .filter_map(move |entry| match entry {
+            Ok(m) => {
+                let index = selection.examined.fetch_add(1, Ordering::Relaxed);
+                debug!("Email #{} {}", index + 1, m.id());
+                Some(m)
+            }
+            Err(e) => {
+                selection.skip(opts.strict, e.to_string());
+                None
+            }
+        })
+        .filter(move |mail| match &state {
+            Some(state) if !state.is_pending(mail) => {
+                debug!("Email {} already examined by the last run", mail.id());
+                false
+            }
+            _ => true,
+        });
+    let listed: Box<dyn Iterator<Item = _>> = match opts.order {
+        MailOrder::Inode => {
+            let mut emails: Vec<_> = listed.collect();
+            let _ = selection
+                .listed
+                .set(selection.examined.load(Ordering::Relaxed));
+            MailOrder::sort_by_inode(&mut emails);
+            Box::new(emails.into_iter())
+        }
+        _ => Box::new(listed),
+    };
+    let dated = listed.filter_map(move |mail| match email_date(&mail) {
+        Ok(dt) => Some((mail, dt)),
+        Err(e) => {
+            selection.skip(opts.strict, e.to_string());
+            selection.retain(&mail);
+            None
+        }
+    });
+    let emails: Box<dyn Iterator<Item = _>> = match opts.order {
+        MailOrder::Directory | MailOrder::Inode => Box::new(dated),
+        order => {
+            let mut emails: Vec<_> = dated.collect();
+            let _ = selection
+                .listed
+                .set(selection.examined.load(Ordering::Relaxed));
+            order.sort(&mut emails);
+            Box::new(emails.into_iter())
+        }
+    };
+    emails
+        .map(move |(mut mail, maildate)| {
+            let spam = match &opts.spam {
+                Some(routing) => routing.is_spam(&mut mail).unwrap_or_else(|e| {
+                    let message = format!("Error while classifying email {}: {}", mail.id(), e);
+                    selection.skip(opts.strict, message);
+                    false
+                }),
+                None => false,
+            };
+            let purge = spam && purge_before.is_some_and(|date| maildate.date() < date);
+            (mail, maildate, spam, purge)
+        })
+        .filter(move |(mail, maildate, _, purge)| {
+            if *purge {
+                debug!("Spam {} with timestamp {} is purged", mail.id(), maildate);
+                true
+            } else if maildate.date() < folder_opts.before {
+                debug!(
+                    "Email {} with timestamp {} is older than threshold",
+                    mail.id(),
+                    maildate
+                );
+                true
+            } else {
+                debug!(
+                    "Email {} with timestamp {} is newer than threshold",
+                    mail.id(),
+                    maildate
+                );
+                selection.retain(mail);
+                false
+            }
+        })
+        .filter_map(move |(mut mail, maildate, spam, purge)| {
+            for mail_filter in &mail_filters {
+                match mail_filter.matches(&mut mail) {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        debug!("Email {} filtered out", mail.id());
+                        selection.retain(&mail);
+                        return None;
+                    }
+                    Err(e) => {
+                        let message = format!("Error while filtering email {}: {}", mail.id(), e);
+                        selection.skip(opts.strict, message);
+                        selection.retain(&mail);
+                        return None;
+                    }
+                }
+            }
+            Some((mail, maildate, spam, purge))
+        })
+        .filter(move |(mail, _, _, _)| {
+            let seen = match seen {
+                Some(seen) => seen,
+                None => return true,
+            };
+            match SeenDatabase::digest(mail.path()) {
+                Ok(digest) if seen.contains(&digest) => {
+                    debug!("Email {} already archived", mail.id());
+                    false
+                }
+                Ok(_) => true,
+                Err(e) => {
+                    let message = format!("Error while reading email {}: {}", mail.id(), e);
+                    selection.skip(opts.strict, message);
+                    selection.retain(mail);
+                    false
+                }
+            }
+        })
+        .take_while(move |_| !selection.failed())
+        .take(limit.unwrap_or(usize::MAX))
+        .map(move |(mut mail, maildate, spam, purge)| {
+            if purge {
+                return (mail, Destination::Purge);
+            }
+            let big = opts.big_threshold.is_some_and(|threshold| {
+                std::fs::metadata(mail.path()).is_ok_and(|metadata| metadata.len() > threshold)
+            });
+            let bucket = match big {
+                true => opts.big_bucket.clone(),
+                false => folder_opts.split_by.email_folder_name(
+                    &opts.prefix,
+                    &opts.suffix,
+                    &mut mail,
+                    maildate,
+                ),
+            };
+            let output_dir = match &opts.spam {
+                Some(SpamRouting {
+                    output_dir: Some(spam_dir),
+                    ..
+                }) if spam => spam_dir.as_path(),
+                _ => opts
+                    .routes
+                    .output_dir(folder, &mut mail)
+                    .unwrap_or(&opts.output_dir),
+            };
+            let folder_for = |bucket: &str| {
+                folder.archive_folder(
+                    output_dir,
+                    bucket,
+                    opts.folder_naming,
+                    opts.folder_name_encoding,
+                )
+            };
+            let part = match opts.rotate_after {
+                Some(max) if has_sequence(&bucket) => {
+                    let existing = |part| {
+                        let maildir = Maildir::from(folder_for(&expand_sequence(&bucket, part)));
+                        maildir.count_cur() + maildir.count_new()
+                    };
+                    next_part(&mut parts, &bucket, max, existing)
+                }
+                _ => 1,
+            };
+            let archive_folder = folder_for(&expand_sequence(&bucket, part));
+            (mail, Destination::Archive(archive_folder))
+        })
+}
+
+/// Part of a rotated archive folder receiving the next email: the first one
+/// with less than `max` emails, counting the `existing` emails of each part
+/// and the emails already selected for it
+fn next_part(
+    parts: &mut HashMap<String, (usize, usize)>,
+    bucket: &str,
+    max: usize,
+    existing: impl Fn(usize) -> usize,
+) -> usize {
+    let (part, count) = parts
+        .entry(bucket.to_string())
+        .or_insert_with(|| (1, existing(1)));
+    while *count >= max {
+        *part += 1;
+        *count = existing(*part);
+    }
+    *count += 1;
+    *part
+}
+
+/// Expunge archived emails of a source folder through the IMAP server run by
+/// `command`, finding their UIDs in the Dovecot UID list of the folder
+fn expunge_via_imap(command: &str, folder: &SourceFolder, ids: &[OsString]) -> io::Result<()> {
+    let mut session = ImapSession::spawn(command)?;
+    // Selecting the mailbox assigns the UIDs of the new emails
+    session.select(&mailbox_name(folder).to_string_lossy())?;
+    let uidlist = read_uidlist(folder.maildir.path())?;
+    let mut uids = Vec::new();
+    for id in ids {
+        match uidlist.get(id) {
+            Some(uid) => uids.push(*uid),
+            None => error!(
+                "Email {} unknown to the IMAP server, left in folder {}",
+                id.to_string_lossy(),
+                folder.display_name()
+            ),
+        }
+    }
+    session.expunge(&uids)?;
+    info!(
+        "Expunged {} email from folder {} through IMAP",
+        uids.len(),
+        folder.display_name()
+    );
+    session.logout()
+}
+
+/// Emails of the `new` directory of a source folder older than the cutoff:
+/// never seen by a client, they are archived only with `--include-new`
+pub fn stuck_in_new(folder: &SourceFolder, before: time::Date) -> Vec<MailEntry> {
+    folder
+        .maildir
+        .list_new()
+        .filter_map(Result::ok)
+        .filter_map(|mail| {
+            let date = email_date(&mail).ok()?;
+            (date.date() < before).then_some(mail)
+        })
+        .collect()
+}
+
+/// List the source folders of the program options, without the trash folder
+/// when the archived emails are moved to it
+pub(crate) fn source_folders(
+    opts: &ProgramOptions,
+) -> Result<Vec<SourceFolder>, MaildirArchiverError> {
+    let input = opts.input_maildir.path();
+    let folders = list_source_folders(input, opts.recursive).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!(
+                "Error while listing the folders of {}: {}",
+                input.display(),
+                e
+            ),
+        )
+    })?;
+    let trash =
+        (opts.store_options.delete_via == DeleteStrategy::Trash).then(|| trash_folder(input));
+    Ok(folders
+        .into_iter()
+        .filter(|folder| trash.as_deref() != Some(folder.maildir.path()))
+        .filter(|folder| {
+            (opts.include_folders.is_empty()
+                || opts.include_folders.iter().any(|p| folder.matches(p)))
+                && !opts.exclude_folders.iter().any(|p| folder.matches(p))
+        })
+        .collect())
+}
+
+/// Shared state of an archive run
+struct ArchiveRun<'a> {
+    opts: &'a ProgramOptions,
+    /// Archivers of the modes used by the run
+    mail_archivers: HashMap<ArchiveMode, Box<dyn MaildirArchiver>>,
+    signals: Signals,
+    seen: Option<SeenDatabase>,
+    duplicates: Option<DuplicateIndex>,
+    /// Maildir++ quota file of the input maildir
+    quota: Option<QuotaFile>,
+    /// Archive folders written by the run
+    archive_folders: Mutex<BTreeSet<PathBuf>>,
+    /// Snapshot of the removed emails, created before removing the first one
+    snapshot: Mutex<Option<Snapshot>>,
+    /// Journal of the emails copied in move mode with deferred deletion
+    deferred: Option<DeferredJournal>,
+    /// Index of the archived emails
+    index: Option<RunIndex>,
+    /// Stream of the progress events
+    progress: Option<ProgressStream>,
+}
+
+impl ArchiveRun<'_> {
+    /// Whether archiving with `mode` changes anything on disk
+    fn writes(&self, mode: ArchiveMode) -> bool {
+        mode != ArchiveMode::DryRun && self.opts.dry_run_format == DryRunFormat::Log
+    }
+
+    /// Emit a progress event of a source folder, if requested
+    fn emit_progress(
+        &self,
+        event: &'static str,
+        folder: &SourceFolder,
+        scanned: usize,
+        report: &RunReport,
+        percent: f64,
+    ) {
+        if let Some(progress) = &self.progress {
+            progress.emit(&ProgressEvent {
+                event,
+                folder: Some(&folder.display_name()),
+                scanned,
+                archived: report.archived.len(),
+                failed: report.failed.len(),
+                percent,
+            });
+        }
+    }
+
+    /// Check if the run removes the archived emails from a source folder
+    fn removes_emails(&self, folder: &SourceFolder) -> bool {
+        let mode = folder_options(self.opts, folder).archive_mode;
+        self.writes(mode)
+            && match mode {
+                ArchiveMode::Move => self.deferred.is_none(),
+                ArchiveMode::Stub => true,
+                _ => false,
+            }
+    }
+
+    /// Archive the source folders with `jobs` workers, each archiving one
+    /// folder at a time, recording the processed emails in the report.
+    /// Returns the folders whose emails have been removed, and the error that
+    /// stopped the run, if any.
+    fn archive_folders(
+        &self,
+        folders: Vec<SourceFolder>,
+        report: &mut RunReport,
+    ) -> (Vec<SourceFolder>, Option<MaildirArchiverError>) {
+        let opts = self.opts;
+        let queue = Mutex::new(folders.into_iter());
+        let state = Mutex::new((std::mem::take(report), Vec::new(), None));
+        let worker = || loop {
+            if self.signals.interrupted() || state.lock().unwrap().2.is_some() {
+                break;
+            }
+            let Some(folder) = queue.lock().unwrap().next() else {
+                break;
+            };
+            info!("Archiving folder {}", folder.display_name());
+            let mut folder_report = RunReport::default();
+            let limit = opts
+                .limit
+                .map(|limit| limit.saturating_sub(state.lock().unwrap().0.processed()));
+            let res = self.archive_folder(&folder, limit, &mut folder_report);
+            let (report, changed_folders, failure) = &mut *state.lock().unwrap();
+            report.merge(folder_report);
+            match res {
+                Ok(archived) if archived > 0 && self.removes_emails(&folder) => {
+                    changed_folders.push(folder)
+                }
+                Ok(_) => {}
+                Err(e) => *failure = Some(e),
+            }
+        };
+        std::thread::scope(|scope| {
+            for _ in 1..opts.jobs {
+                scope.spawn(worker);
+            }
+            worker();
+        });
+        let (merged, changed_folders, failure) = state.into_inner().unwrap();
+        *report = merged;
+        (changed_folders, failure)
+    }
+
+    /// Check the free space for the selected emails of a source folder and
+    /// save the ones to remove in the snapshot
+    fn prepare_folder(
+        &self,
+        folder: &SourceFolder,
+        emails: &[(MailEntry, Destination)],
+    ) -> Result<(), MaildirArchiverError> {
+        let opts = self.opts;
+        if opts.check_free_space {
+            let mut sizes = BTreeMap::new();
+            for (mail, destination) in emails {
+                if let Destination::Archive(archive_folder) = destination {
+                    let size = std::fs::metadata(mail.path()).map_or(0, |m| m.len());
+                    *sizes.entry(archive_folder.clone()).or_default() += size;
+                }
+            }
+            check_free_space(&sizes)?;
+        }
+        if let Some(root) = &opts.snapshot {
+            let removes = self.removes_emails(folder);
+            let removed = emails
+                .iter()
+                .filter(|(_, destination)| removes || matches!(destination, Destination::Purge));
+            let mut snapshot = self.snapshot.lock().unwrap();
+            for (mail, _) in removed {
+                if snapshot.is_none() {
+                    let created = Snapshot::create(root, opts.store_options.secure_delete)?;
+                    info!("Saving the removed emails in {}", created.dir.display());
+                    *snapshot = Some(created);
+                }
+                snapshot.as_ref().unwrap().save(folder, mail)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Archive a source folder, recording the processed emails in the
+    /// report. Returns the number of processed emails, or an error if the
+    /// archive folders don't have enough space for the selected emails.
+    fn archive_folder(
+        &self,
+        folder: &SourceFolder,
+        limit: Option<usize>,
+        report: &mut RunReport,
+    ) -> Result<usize, MaildirArchiverError> {
+        let opts = self.opts;
+        let signals = &self.signals;
+        let folder_opts = folder_options(opts, folder);
+        let mail_archiver = &self.mail_archivers[&folder_opts.archive_mode];
+        let writes = self.writes(folder_opts.archive_mode);
+        let deferred = self
+            .deferred
+            .as_ref()
+            .filter(|_| writes && folder_opts.archive_mode == ArchiveMode::Move);
+        // The purged spam is expunged through IMAP in all the modes
+        let imap_command = opts.imap_command.as_ref().filter(|_| writes);
+        let imap = imap_command.filter(|_| folder_opts.archive_mode == ArchiveMode::Move);
+        // Ids of the emails to expunge through IMAP
+        let mut imap_removed = Vec::new();
+        if !opts.include_new {
+            let stuck = stuck_in_new(folder, folder_opts.before);
+            if !stuck.is_empty() {
+                warn!(
+                    "{} email of folder {} still in new/ and older than the cutoff, not archived \
+                     without --include-new",
+                    stuck.len(),
+                    folder.display_name()
+                );
+                report.stuck_in_new += stuck.len();
+            }
+        }
+        let mut usage = UsageDelta::default();
+        // The trash folder is never a source folder with --delete-via trash
+        let trashed = opts.store_options.delete_via == DeleteStrategy::Trash;
+        let reported = |mail: &MailEntry, archive_folder, size: &io::Result<u64>| ReportedEmail {
+            id: mail.id().to_string(),
+            folder: folder.display_name().to_string(),
+            archive_folder,
+            size: *size.as_ref().unwrap_or(&0),
+        };
+        let selection = Selection::default();
+        // Emails listed by the selection, for the share of the progress events:
+        // only the streamed directory order needs to count them apart
+        let streamed = match (&self.progress, opts.order, opts.include_new) {
+            (Some(_), MailOrder::Directory, true) => {
+                folder.maildir.count_cur() + folder.maildir.count_new()
+            }
+            (Some(_), MailOrder::Directory, false) => folder.maildir.count_cur(),
+            _ => 0,
+        };
+        let (archived_count, selected) = std::thread::scope(|scope| {
+            // The free space check and the snapshot need all the selected
+            // emails before archiving the first one, otherwise the emails
+            // are selected by a producer thread as they are archived
+            let (emails, total): (Box<dyn Iterator<Item = _>>, _) = if writes
+                && (opts.check_free_space || opts.snapshot.is_some())
+            {
+                let seen = self.seen.as_ref();
+                let emails: Vec<_> =
+                    selected_emails(opts, folder, &folder_opts, seen, limit, &selection).collect();
+                if !selection.failed() {
+                    self.prepare_folder(folder, &emails)?;
+                }
+                let total = emails.len();
+                (Box::new(emails.into_iter()), Some(total))
+            } else {
+                let (sender, receiver) = mpsc::sync_channel(PIPELINE_DEPTH);
+                let (folder_opts, seen, selection) = (&folder_opts, self.seen.as_ref(), &selection);
+                scope.spawn(move || {
+                    for email in selected_emails(opts, folder, folder_opts, seen, limit, selection)
+                    {
+                        // The receiver is dropped when the archiving stops
+                        if sender.send(email).is_err() {
+                            break;
+                        }
+                    }
+                });
+                (Box::new(receiver.into_iter()), None)
+            };
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            let emails: Box<dyn Iterator<Item = _>> = match &opts.store_options.read_ahead {
+                Some(read_ahead) => Box::new(read_ahead.batches(emails, |(mail, _)| mail.path())),
+                None => emails,
+            };
+            let mut selected = 0;
+            let archived_count = emails
+                .inspect(|_| selected += 1)
+                .enumerate()
+                .take_while(|_| {
+                    signals.wait_if_paused();
+                    !signals.interrupted() && !selection.failed()
+                })
+                .filter_map(|(index, (mut mail, destination))| {
+                    if self.progress.is_some() {
+                        let scanned = selection.examined.load(Ordering::Relaxed);
+                        let listed = selection.listed.get().copied().unwrap_or(streamed);
+                        let share = percent(scanned, listed);
+                        self.emit_progress("progress", folder, scanned, report, share);
+                    }
+                    if signals.progress_requested() {
+                        match total {
+                            Some(total) => info!(
+                                "Progress: {}/{} selected email of folder {} processed",
+                                index,
+                                total,
+                                folder.display_name()
+                            ),
+                            None => info!(
+                                "Progress: {} selected email of folder {} processed",
+                                index,
+                                folder.display_name()
+                            ),
+                        }
+                    }
+                    let size = std::fs::metadata(mail.path()).map(|m| m.len());
+                    let archive_folder = match destination {
+                        Destination::Archive(archive_folder) => archive_folder,
+                        Destination::Purge => {
+                            if !writes {
+                                info!(
+                                    target: DRY_RUN,
+                                    "Spam {} from folder {} purged",
+                                    mail.id(),
+                                    folder.display_name()
+                                );
+                                report.purged.push(reported(&mail, None, &size));
+                                return Some(());
+                            }
+                            let purged = match imap_command {
+                                Some(_) => {
+                                    imap_removed.push(email_base(&mail).to_os_string());
+                                    Ok(())
+                                }
+                                None => unlink_email(&mail, &opts.store_options),
+                            };
+                            return match purged {
+                                Ok(()) => {
+                                    info!(
+                                        "Spam {} from folder {} purged",
+                                        mail.id(),
+                                        folder.display_name()
+                                    );
+                                    if let Ok(size) = size {
+                                        usage.record(size, mail.path(), false);
+                                    }
+                                    report.purged.push(reported(&mail, None, &size));
+                                    Some(())
+                                }
+                                Err(e) => {
+                                    let message =
+                                        format!("Error while deleting email {}: {}", mail.id(), e);
+                                    selection.skip(opts.strict, message);
+                                    report.failed.push(FailedEmail {
+                                        id: mail.id().to_string(),
+                                        folder: folder.display_name().to_string(),
+                                        error: e,
+                                    });
+                                    None
+                                }
+                            };
+                        }
+                    };
+                    let digest = match (&self.seen, writes) {
+                        (Some(_), true) => SeenDatabase::digest(mail.path()).ok(),
+                        _ if deferred.is_some() => SeenDatabase::digest(mail.path()).ok(),
+                        _ => None,
+                    };
+                    if let (Some(journal), Some(digest)) = (deferred, &digest) {
+                        if journal.contains(digest) {
+                            info!(
+                                "Email {} from folder {} already archived, waiting for expunge",
+                                mail.id(),
+                                folder.display_name()
+                            );
+                            report.skipped.push(reported(&mail, None, &size));
+                            return Some(());
+                        }
+                    }
+                    let key = match &self.duplicates {
+                        Some(_) => duplicate_key(&mut mail).ok(),
+                        None => None,
+                    };
+                    if let (Some(duplicates), Some(key)) = (&self.duplicates, &key) {
+                        if let Some(archive_folder) = duplicates.archived_folder(key) {
+                            info!(
+                                "Email {} from folder {} already archived to folder {}",
+                                mail.id(),
+                                folder.maildir.path().display(),
+                                archive_folder.display()
+                            );
+                            if let Err(e) = duplicates.record_duplicate(key, mail.path()) {
+                                error!("Error while recording email {}: {}", mail.id(), e);
+                            }
+                            if folder_opts.archive_mode == ArchiveMode::Move && deferred.is_none() {
+                                if imap.is_some() {
+                                    imap_removed.push(email_base(&mail).to_os_string());
+                                } else if let Err(e) =
+                                    delete_email(&folder.maildir, &mail, &opts.store_options)
+                                {
+                                    error!("Error while deleting email {}: {}", mail.id(), e);
+                                }
+                                if let Ok(size) = size {
+                                    usage.record(size, mail.path(), trashed);
+                                }
+                            }
+                            report
+                                .skipped
+                                .push(reported(&mail, Some(archive_folder), &size));
+                            return Some(());
+                        }
+                    }
+                    // The source file may be moved by the archiving
+                    let entry = match (&self.index, writes) {
+                        (Some(_), true) => Some(IndexEntry::new(&mut mail, &archive_folder)),
+                        _ => None,
+                    };
+                    match mail_archiver.archive_email(&mail, &folder.maildir, &archive_folder) {
+                        Err(e) => {
+                            let error = e.in_context(ArchiveStage::Store, &mail, &archive_folder);
+                            selection.skip(opts.strict, error.to_string());
+                            report.failed.push(FailedEmail {
+                                id: mail.id().to_string(),
+                                folder: folder.display_name().to_string(),
+                                error,
+                            });
+                            None
+                        }
+                        Ok(()) => {
+                            info!(
+                                target: match writes {
+                                    true => ARCHIVED,
+                                    false => DRY_RUN,
+                                },
+                                "Email {} from folder {} archived to folder {}",
+                                mail.id(),
+                                folder.maildir.path().display(),
+                                archive_folder.display()
+                            );
+                            if let (Some(seen), Some(digest)) = (&self.seen, &digest) {
+                                if let Err(e) = seen.insert(digest) {
+                                    error!("Error while recording email {}: {}", mail.id(), e);
+                                }
+                            }
+                            if let (Some(journal), Some(digest)) = (deferred, digest) {
+                                let email = DeferredEmail {
+                                    maildir: folder.maildir.path().to_path_buf(),
+                                    id: mail.id().to_string(),
+                                    digest,
+                                    archive_folder: archive_folder.clone(),
+                                    archived_at: time::OffsetDateTime::now_utc().unix_timestamp(),
+                                };
+                                if let Err(e) = journal.record(&email) {
+                                    error!("Error while recording email {}: {}", mail.id(), e);
+                                }
+                            }
+                            if let (Some(duplicates), Some(key)) = (&self.duplicates, key) {
+                                duplicates.insert(key, &archive_folder, mail.path());
+                            }
+                            if let (Some(index), Some(entry)) = (&self.index, entry) {
+                                if let Err(e) = index.write(&entry) {
+                                    error!("Error while indexing email {}: {}", mail.id(), e);
+                                }
+                            }
+                            if imap.is_some() {
+                                imap_removed.push(email_base(&mail).to_os_string());
+                            }
+                            if let Ok(size) = size {
+                                report.archived_bytes += size;
+                                if writes {
+                                    usage.record(size, mail.path(), trashed);
+                                }
+                            }
+                            if writes {
+                                let mut archive_folders = self.archive_folders.lock().unwrap();
+                                archive_folders.insert(archive_folder.clone());
+                            }
+                            report
+                                .archived
+                                .push(reported(&mail, Some(archive_folder), &size));
+                            Some(())
+                        }
+                    }
+                })
+                .count();
+            Ok::<_, MaildirArchiverError>((archived_count, selected))
+        })?;
+        report.selected += selected;
+        report
+            .selected_by_folder
+            .insert(folder.display_name().to_string(), selected);
+        let Selection {
+            examined,
+            failure,
+            retained,
+            ..
+        } = selection;
+        let examined = examined.into_inner();
+        report.examined += examined;
+        self.emit_progress("folder", folder, examined, report, 100.0);
+        let failure = failure.into_inner().unwrap();
+        if let (Some(command), false) = (imap_command, imap_removed.is_empty()) {
+            if let Err(e) = expunge_via_imap(command, folder, &imap_removed) {
+                error!(
+                    "Error while expunging the archived emails of folder {}, left in the \
+                     source folder: {}",
+                    folder.display_name(),
+                    e
+                );
+            }
+        }
+        if let (Some(quota), false) = (&self.quota, usage.is_empty()) {
+            if let Err(e) = quota.update(usage) {
+                error!(
+                    "Error while updating the quota of {}: {}",
+                    folder.display_name(),
+                    e
+                );
+            }
+        }
+        if let Some(e) = failure {
+            return Err(e);
+        }
+        // The next run can skip the emails examined by this one only if all
+        // the selected emails have been archived
+        if opts.incremental
+            && writes
+            && !signals.interrupted()
+            && archived_count == selected
+            && limit.is_none_or(|limit| selected < limit)
+        {
+            let mut state = RunState {
+                last_run: time::OffsetDateTime::now_utc().unix_timestamp(),
+                high_water_mark: folder_opts.before.midnight().assume_utc().unix_timestamp(),
+                retained: Default::default(),
+            };
+            for (id, time) in retained.into_inner().unwrap() {
+                state.retain(id, time);
+            }
+            let path = state_file(opts, folder);
+            if let Err(e) = state.write(&path) {
+                error!("Error while writing {}: {}", path.display(), e);
+            }
+        }
+        Ok(archived_count)
+    }
+}
+
+/// Archive the emails selected by the program options.
+///
+/// The run is not connected to the signals of the process, see
+/// [`run_with_signals`] and [`run_with_cancellation`].
+pub fn run(opts: &ProgramOptions) -> Result<RunReport, MaildirArchiverError> {
+    run_with_signals(opts, Signals::default())
+}
+
+/// Archive the emails selected by the program options until `token` is set.
+///
+/// A cancelled run stops after the email being archived and returns the
+/// report of the emails processed so far, with `interrupted` set.
+pub fn run_with_cancellation(
+    opts: &ProgramOptions,
+    token: Arc<AtomicBool>,
+) -> Result<RunReport, MaildirArchiverError> {
+    run_with_signals(opts, Signals::with_cancellation(token))
+}
+
+/// Archive the emails selected by the program options, stopping, pausing
+/// and logging the progress as requested by `signals`
+pub fn run_with_signals(
+    opts: &ProgramOptions,
+    signals: Signals,
+) -> Result<RunReport, MaildirArchiverError> {
+    if let Some(account) = &opts.run_as {
+        drop_privileges(account)?;
+    }
+    check_options(opts)?;
+    // The archive copies are checked by content
+    let defer_delete = opts.defer_delete && opts.dry_run_format == DryRunFormat::Log;
+    let mut mail_archivers = HashMap::new();
+    for mode in std::iter::once(opts.archive_mode).chain(opts.config.archive_modes()) {
+        // The emails archived in move mode are only copied until expunged
+        let store_mode = match mode {
+            ArchiveMode::Move if defer_delete || opts.imap_command.is_some() => ArchiveMode::Copy,
+            mode => mode,
+        };
+        mail_archivers
+            .entry(mode)
+            .or_insert_with(|| match opts.dry_run_format {
+                DryRunFormat::Shell => create_shell_archiver(mode, Box::new(std::io::stdout())),
+                DryRunFormat::Log => create_mail_archiver(
+                    store_mode,
+                    opts.store_options.clone(),
+                    create_archive_sink(opts.format, &opts.sink_options),
+                ),
+            });
+    }
+    if opts.store_options.secure_delete {
+        if let Ok(Some(filesystem)) = copy_on_write_filesystem(opts.input_maildir.path()) {
+            warn!(
+                "The input maildir is on {}, a copy-on-write filesystem: --secure-delete can't \
+                 overwrite the content of the removed emails",
+                filesystem
+            );
+        }
+    }
+    info!(
+        "Archiving emails older than {}",
+        opts.input_maildir.path().display(),
+    );
+    let mut run = ArchiveRun {
+        opts,
+        mail_archivers,
+        signals,
+        seen: open_seen_database(opts)?,
+        duplicates: None,
+        quota: QuotaFile::open(opts.input_maildir.path()),
+        archive_folders: Mutex::new(BTreeSet::new()),
+        snapshot: Mutex::new(None),
+        deferred: None,
+        index: None,
+        progress: opts.progress_fd.map(ProgressStream::open).transpose()?,
+    };
+    if run.mail_archivers.keys().any(|mode| run.writes(*mode)) {
+        check_output_dirs(opts)?;
+    }
+    if defer_delete && run.mail_archivers.keys().any(|mode| run.writes(*mode)) {
+        std::fs::create_dir_all(&opts.output_dir)?;
+        let journal = DeferredJournal::open(&opts.output_dir.join(DEFERRED_JOURNAL_FILE))?;
+        run.deferred = Some(journal);
+    }
+    if let Some(format) = opts.index {
+        if run.mail_archivers.keys().any(|mode| run.writes(*mode)) {
+            run.index = Some(RunIndex::create(&opts.output_dir, format)?);
+        }
+    }
+    // Dry runs don't record anything on disk
+    if opts.recursive && run.mail_archivers.keys().any(|mode| run.writes(*mode)) {
+        run.duplicates = Some(DuplicateIndex::new(
+            opts.output_dir.join("archive-maildir.duplicates"),
+        ));
+    }
+    if opts.sandbox {
+        let writes = run.mail_archivers.keys().any(|mode| run.writes(*mode));
+        enter_sandbox(&writable_dirs(opts, writes)?)?;
+    }
+    let mut report = RunReport::default();
+    let (changed_folders, failure) = run.archive_folders(source_folders(opts)?, &mut report);
+    if let Some(progress) = &run.progress {
+        progress.emit(&ProgressEvent {
+            event: "done",
+            folder: None,
+            scanned: report.examined,
+            archived: report.archived.len(),
+            failed: report.failed.len(),
+            percent: 100.0,
+        });
+    }
+    // The archive is incomplete without the finish of the sinks
+    let mut failure = failure;
+    for mail_archiver in run.mail_archivers.values() {
+        if let Err(e) = mail_archiver.finish() {
+            error!("Error while completing the archive: {}", e);
+            failure.get_or_insert(e);
+        }
+    }
+    if let Some(index) = &run.index {
+        match index.finish() {
+            Ok(()) => info!("Archived emails indexed in {}", index.path.display()),
+            Err(e) => error!("Error while writing {}: {}", index.path.display(), e),
+        }
+    }
+    if let (Some(root), Some(_)) = (&opts.snapshot, &*run.snapshot.lock().unwrap()) {
+        if let Err(e) = prune_snapshots(root, opts.snapshot_keep) {
+            error!("Error while pruning the snapshots: {}", e);
+        }
+    }
+    // Stop before rolling up or resyncing: the run didn't complete
+    if let Some(e) = failure {
+        return Err(e);
+    }
+    info!("Archived {}/{} email", report.processed(), report.examined);
+    let summaries = report.folder_summaries();
+    if summaries.len() > 1 {
+        log_folder_summaries(&summaries);
+    }
+    if !run.mail_archivers.keys().any(|mode| run.writes(*mode)) {
+        log_estimate(&report.destination_sizes());
+    }
+    if opts.folder_naming == FolderNaming::Courier {
+        let archive_folders: Vec<_> = run
+            .archive_folders
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|folder| folder.join("cur").is_dir())
+            .cloned()
+            .collect();
+        if let Err(e) = courier_subscribe(&opts.output_dir, &archive_folders) {
+            error!("Error while subscribing the archive folders: {}", e);
+        }
+    }
+    if let Some(user) = &opts.doveadm_user {
+        for folder in &changed_folders {
+            match force_resync(user, folder) {
+                Ok(()) => info!("Resynced mailbox of folder {}", folder.display_name()),
+                Err(e) => error!(
+                    "Error while resyncing mailbox of folder {}: {}",
+                    folder.display_name(),
+                    e
+                ),
+            }
+        }
+    }
+    if run.signals.interrupted() {
+        report.interrupted = true;
+        return Ok(report);
+    }
+    #[cfg(feature = "compression")]
+    if opts.rollup && opts.dry_run_format == DryRunFormat::Log {
+        let rollup = rollup_options(
+            opts.output_dir.clone(),
+            opts.archive_mode,
+            opts.prefix.clone(),
+            opts.suffix.clone(),
+            opts.split_by,
+        );
+        match compact(&rollup) {
+            Ok(count) => info!("Rolled up {} folders", count),
+            Err(e) => error!(
+                "Error while rolling up {}: {}",
+                opts.output_dir.display(),
+                e
+            ),
+        }
+    }
+    Ok(report)
+}
+
+/// Write the plan of the emails selected by the archive options, returning
+/// the number of planned emails. The spam to purge is not planned.
+pub fn plan(opts: &PlanOptions) -> Result<usize, MaildirArchiverError> {
+    let archive = &opts.archive;
+    let seen = open_seen_database(archive)?;
+    let mut emails = Vec::new();
+    for folder in source_folders(archive)? {
+        let limit = archive
+            .limit
+            .map(|limit| limit.saturating_sub(emails.len()));
+        let folder_opts = folder_options(archive, &folder);
+        for (mail, destination) in
+            select_emails(archive, &folder, &folder_opts, seen.as_ref(), limit)?
+        {
+            let archive_folder = match destination {
+                Destination::Archive(archive_folder) => archive_folder,
+                Destination::Purge => {
+                    warn!("Spam {} to purge not written to the plan", mail.id());
+                    continue;
+                }
+            };
+            let mut email = PlannedEmail::new(&mail, &folder.maildir, &archive_folder)?;
+            if folder_opts.archive_mode != archive.archive_mode {
+                email.archive_mode = Some(folder_opts.archive_mode);
+            }
+            emails.push(email);
+        }
+    }
+    info!(
+        "Planned {} email, written to {}",
+        emails.len(),
+        opts.plan_file.display()
+    );
+    let plan = Plan {
+        input_maildir: archive.input_maildir.path().to_path_buf(),
+        archive_mode: archive.archive_mode,
+        format: archive.format,
+        sink_options: archive.sink_options.clone(),
+        store_options: archive.store_options.clone(),
+        emails,
+    };
+    let count = plan.emails.len();
+    log_estimate(&plan.destination_sizes());
+    plan.write(std::fs::File::create(&opts.plan_file)?)?;
+    Ok(count)
+}
+
+#[cfg(all(test, feature = "cli"))]
+mod tests {
+    use crate::archiver::MaildirArchiverError;
+    use crate::cli::{archive_args, parse_archive_args};
+    use crate::run::{run, run_with_cancellation};
+    use clap::Command;
+    use maildir::Maildir;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_run_report() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let input_maildir = Maildir::from(basedir.join("in"));
+        input_maildir.create_dirs().unwrap();
+        for entry in std::fs::read_dir("testdata/maildir1/cur").unwrap() {
+            let path = entry.unwrap().path();
+            std::fs::copy(
+                &path,
+                input_maildir
+                    .path()
+                    .join("cur")
+                    .join(path.file_name().unwrap()),
+            )
+            .unwrap();
+        }
+        let input = input_maildir.path().to_str().unwrap();
+        let output = basedir.join("out");
+        let matches = archive_args(Command::new("test"))
+            .try_get_matches_from(["test", input, output.to_str().unwrap(), "--mode", "copy"])
+            .unwrap();
+        let report = run(&parse_archive_args(&matches)).unwrap();
+        assert_eq!(report.examined, 2);
+        assert_eq!(report.selected, 2);
+        assert_eq!(report.archived.len(), 2);
+        assert!(report.failed.is_empty() && !report.interrupted);
+        assert!(report.archived_bytes > 0);
+        assert_eq!(report.archived[0].folder, "INBOX");
+        let sizes = report.destination_sizes();
+        assert_eq!(sizes.len(), 2);
+        assert_eq!(sizes.values().sum::<u64>(), report.archived_bytes);
+        assert_eq!(input_maildir.count_cur(), 2);
+        let summaries = report.folder_summaries();
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(
+            (summaries[0].folder.as_str(), summaries[0].candidates),
+            ("INBOX", 2)
+        );
+        assert_eq!(summaries[0].archived, 2);
+        assert_eq!(summaries[0].bytes, report.archived_bytes);
+
+        // Emails of new/ are reported, and archived with --include-new
+        let id = input_maildir
+            .store_new(b"Received: from a by b; Sat, 21 May 2016 22:08:26 +0000\r\n\r\nbody\r\n")
+            .unwrap();
+        let report = run(&parse_archive_args(&matches)).unwrap();
+        assert_eq!(report.stuck_in_new, 1);
+        assert_eq!(report.selected, 2);
+        let matches = archive_args(Command::new("test"))
+            .try_get_matches_from([
+                "test",
+                input,
+                output.to_str().unwrap(),
+                "--mode",
+                "move",
+                "--include-new",
+            ])
+            .unwrap();
+        let report = run(&parse_archive_args(&matches)).unwrap();
+        assert_eq!(report.stuck_in_new, 0);
+        assert_eq!(report.archived.len(), 3);
+        assert!(input_maildir.find(&id).is_none());
+
+        // A cancelled run reports the emails processed before the cancellation
+        let token = Arc::new(AtomicBool::new(true));
+        let report = run_with_cancellation(&parse_archive_args(&matches), token).unwrap();
+        assert!(report.interrupted);
+        assert_eq!(report.processed(), 0);
+    }
+
+    #[test]
+    fn test_incremental_retained() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let input = Maildir::from(basedir.join("in"));
+        input.create_dirs().unwrap();
+        for entry in std::fs::read_dir("testdata/maildir1/cur").unwrap() {
+            let path = entry.unwrap().path();
+            let dest = input.path().join("cur").join(path.file_name().unwrap());
+            std::fs::copy(&path, dest).unwrap();
+        }
+        let output = basedir.join("out");
+        let args = [input.path().to_str().unwrap(), output.to_str().unwrap()];
+        let incremental = ["test", args[0], args[1], "--incremental", "--mode", "move"];
+        // The email filtered out by the first run is examined by the second
+        for filter in [&["--has-attachment"][..], &[]] {
+            let matches = archive_args(Command::new("test"))
+                .try_get_matches_from(incremental.iter().chain(filter))
+                .unwrap();
+            let report = run(&parse_archive_args(&matches)).unwrap();
+            assert_eq!(report.examined, 1 + filter.len());
+            assert_eq!(report.archived.len(), 1);
+        }
+        assert_eq!(input.count_cur(), 0);
+    }
+
+    #[test]
+    fn test_incremental_bounded_state() {
+        use crate::state::RunState;
+        use mktemp::Temp;
+        use time::format_description::well_known::Rfc2822;
+        let basedir = Temp::new_dir().unwrap();
+        let input = Maildir::from(basedir.join("in"));
+        input.create_dirs().unwrap();
+        let output = basedir.join("out");
+        let state_file = basedir.join("state");
+        let args = [input.path().to_str().unwrap(), output.to_str().unwrap()];
+        let matches = archive_args(Command::new("test"))
+            .try_get_matches_from([
+                "test",
+                args[0],
+                args[1],
+                "--incremental",
+                "--state-file",
+                state_file.to_str().unwrap(),
+                "--mode",
+                "move",
+            ])
+            .unwrap();
+        let now = time::OffsetDateTime::now_utc().format(&Rfc2822).unwrap();
+        let data = format!("Received: from a by b; {}\r\n\r\nbody\r\n", now);
+        // The recent emails are left in the folder by each run, but are
+        // examined again by the next runs without being recorded
+        let mut sizes = Vec::new();
+        for _ in 0..3 {
+            for _ in 0..10 {
+                input.store_cur_with_flags(data.as_bytes(), "S").unwrap();
+            }
+            let report = run(&parse_archive_args(&matches)).unwrap();
+            assert!(report.archived.is_empty());
+            let state = RunState::read(&state_file).unwrap().unwrap();
+            assert!(state.retained.is_empty());
+            sizes.push(std::fs::metadata(&state_file).unwrap().len());
+        }
+        assert_eq!(input.count_cur(), 30);
+        assert!(sizes.windows(2).all(|w| w[0] == w[1]));
+    }
+
+    #[test]
+    fn test_snapshot_secure_delete() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let input = Maildir::from(basedir.join("in"));
+        input.create_dirs().unwrap();
+        let source = std::fs::read_dir("testdata/maildir1/cur").unwrap().next();
+        let path = source.unwrap().unwrap().path();
+        let content = std::fs::read(&path).unwrap();
+        std::fs::write(
+            input.path().join("cur").join(path.file_name().unwrap()),
+            &content,
+        )
+        .unwrap();
+        let output = basedir.join("out");
+        let snapshots = basedir.join("snapshots");
+        let matches = archive_args(Command::new("test"))
+            .try_get_matches_from([
+                "test",
+                input.path().to_str().unwrap(),
+                output.to_str().unwrap(),
+                "--mode",
+                "move",
+                "--secure-delete",
+                "--snapshot",
+                snapshots.to_str().unwrap(),
+            ])
+            .unwrap();
+        let report = run(&parse_archive_args(&matches)).unwrap();
+        assert_eq!(report.archived.len(), 1);
+        assert_eq!(input.count_cur(), 0);
+        // The saved email is a copy, not a hardlink overwritten by the removal
+        let snapshot = std::fs::read_dir(&snapshots)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        let saved = Maildir::from(snapshot.path());
+        let entry = saved.list_cur().next().unwrap().unwrap();
+        assert_eq!(std::fs::read(entry.path()).unwrap(), content);
+    }
+
+    #[test]
+    fn test_quota_delete_via_trash() {
+        use crate::quota::MAILDIRSIZE;
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let input = Maildir::from(basedir.join("in"));
+        input.create_dirs().unwrap();
+        let source = std::fs::read_dir("testdata/maildir1/cur").unwrap().next();
+        let path = source.unwrap().unwrap().path();
+        let content = std::fs::read(&path).unwrap();
+        std::fs::write(
+            input.path().join("cur").join(path.file_name().unwrap()),
+            &content,
+        )
+        .unwrap();
+        let quota = format!("1000000S\n{} 1\n", content.len());
+        std::fs::write(input.path().join(MAILDIRSIZE), &quota).unwrap();
+        let output = basedir.join("out");
+        let matches = archive_args(Command::new("test"))
+            .try_get_matches_from([
+                "test",
+                input.path().to_str().unwrap(),
+                output.to_str().unwrap(),
+                "--mode",
+                "move",
+                "--delete-via",
+                "trash",
+            ])
+            .unwrap();
+        let report = run(&parse_archive_args(&matches)).unwrap();
+        assert_eq!(report.archived.len(), 1);
+        assert_eq!(input.count_cur(), 0);
+        assert_eq!(Maildir::from(input.path().join(".Trash")).count_cur(), 1);
+        // The email moved to the trash is still in the account
+        let updated = std::fs::read_to_string(input.path().join(MAILDIRSIZE)).unwrap();
+        assert_eq!(updated, quota);
+    }
+
+    #[test]
+    fn test_names_not_utf8() {
+        use mktemp::Temp;
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        let basedir = Temp::new_dir().unwrap();
+        let input = basedir.join(OsStr::from_bytes(b"in\xff"));
+        let folder = Maildir::from(input.join(OsStr::from_bytes(b".Caf\xe9")));
+        folder.create_dirs().unwrap();
+        Maildir::from(input.clone()).create_dirs().unwrap();
+        let filename = OsStr::from_bytes(b"1463868505.\xff:2,S");
+        std::fs::copy(
+            "testdata/maildir1/cur/1463868505.38518452d49213cb409aa1db32f53184:2,S",
+            folder.path().join("cur").join(filename),
+        )
+        .unwrap();
+        let output = basedir.join(OsStr::from_bytes(b"out\xfe"));
+        let matches = archive_args(Command::new("test"))
+            .try_get_matches_from([
+                OsStr::new("test"),
+                input.as_os_str(),
+                output.as_os_str(),
+                OsStr::new("-r"),
+                OsStr::new("--mode"),
+                OsStr::new("move"),
+            ])
+            .unwrap();
+        let report = run(&parse_archive_args(&matches)).unwrap();
+        assert_eq!(report.archived.len(), 1);
+        assert!(report.failed.is_empty());
+        assert_eq!(folder.count_cur(), 0);
+        let archive_folder = output.join(OsStr::from_bytes(b"Caf\xe9")).join("2016");
+        assert_eq!(Maildir::from(archive_folder).count_cur(), 1);
+    }
+
+    #[test]
+    fn test_parallel_folders() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let input = basedir.join("in");
+        for name in ["", ".a", ".b", ".c"] {
+            let maildir = Maildir::from(input.join(name));
+            maildir.create_dirs().unwrap();
+            for entry in std::fs::read_dir("testdata/maildir1/cur").unwrap() {
+                let path = entry.unwrap().path();
+                let dest = maildir.path().join("cur").join(path.file_name().unwrap());
+                std::fs::copy(&path, dest).unwrap();
+            }
+        }
+        let output = basedir.join("out");
+        let args = [input.to_str().unwrap(), output.to_str().unwrap()];
+        let matches = archive_args(Command::new("test"))
+            .try_get_matches_from(["test", args[0], args[1], "-r", "-j", "3", "--mode", "move"])
+            .unwrap();
+        let mut opts = parse_archive_args(&matches);
+        opts.limit = Some(2);
+        assert!(run(&opts).is_err());
+        opts.limit = None;
+        let report = run(&opts).unwrap();
+        assert_eq!(report.examined, 8);
+        // The copies of the emails in the other folders are duplicates
+        assert_eq!(report.archived.len() + report.skipped.len(), 8);
+        assert!(report.failed.is_empty());
+        assert_eq!(Maildir::from(input.join(".b")).count_cur(), 0);
+    }
+
+    #[test]
+    fn test_bounded_pipeline() {
+        use crate::run::PIPELINE_DEPTH;
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let input = Maildir::from(basedir.join("in"));
+        input.create_dirs().unwrap();
+        let count = 2 * PIPELINE_DEPTH + 1;
+        for n in 0..count {
+            let data = format!(
+                "Received: from a by b; Mon, 1 Feb 2016 10:00:00 +0000\nSubject: {}\n\nbody\n",
+                n
+            );
+            input.store_cur_with_flags(data.as_bytes(), "S").unwrap();
+        }
+        let output = basedir.join("out");
+        let args = [input.path().to_str().unwrap(), output.to_str().unwrap()];
+        for limit in [None, Some(10)] {
+            let matches = archive_args(Command::new("test"))
+                .try_get_matches_from(["test", args[0], args[1], "--mode", "copy"])
+                .unwrap();
+            let mut opts = parse_archive_args(&matches);
+            opts.limit = limit;
+            let report = run(&opts).unwrap();
+            assert_eq!(report.selected, limit.unwrap_or(count));
+            assert_eq!(report.archived.len(), limit.unwrap_or(count));
+        }
+    }
+
+    #[test]
+    fn test_directory_order() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let input = Maildir::from(basedir.join("in"));
+        input.create_dirs().unwrap();
+        for n in 0..20 {
+            let data = format!(
+                "Received: from a by b; Mon, 1 Feb 2016 10:00:00 +0000\nSubject: {}\n\nbody\n",
+                n
+            );
+            input.store_cur_with_flags(data.as_bytes(), "S").unwrap();
+        }
+        let output = basedir.join("out");
+        let args = [input.path().to_str().unwrap(), output.to_str().unwrap()];
+        // The sorted orders list the whole folder, the directory order stops
+        // listing at the limit
+        for (order, examined) in [("oldest", 20), ("directory", 5)] {
+            let matches = archive_args(Command::new("test"))
+                .try_get_matches_from([
+                    "test", args[0], args[1], "--mode", "copy", "--order", order,
+                ])
+                .unwrap();
+            let mut opts = parse_archive_args(&matches);
+            opts.limit = Some(5);
+            let report = run(&opts).unwrap();
+            assert_eq!((report.examined, report.selected), (examined, 5));
+        }
+    }
+
+    #[test]
+    fn test_big_bucket() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let input = Maildir::from(basedir.join("in"));
+        input.create_dirs().unwrap();
+        for body in ["small", &"big".repeat(100)] {
+            let data = format!(
+                "Received: from a by b; Mon, 1 Feb 2016 10:00:00 +0000\n\n{}\n",
+                body
+            );
+            input.store_cur_with_flags(data.as_bytes(), "S").unwrap();
+        }
+        let output = basedir.join("out");
+        let args = [input.path().to_str().unwrap(), output.to_str().unwrap()];
+        let matches = archive_args(Command::new("test"))
+            .try_get_matches_from([
+                "test",
+                args[0],
+                args[1],
+                "--mode",
+                "copy",
+                "--big-threshold",
+                "200",
+            ])
+            .unwrap();
+        run(&parse_archive_args(&matches)).unwrap();
+        assert_eq!(Maildir::from(output.join("2016")).count_cur(), 1);
+        assert_eq!(Maildir::from(output.join("big-messages")).count_cur(), 1);
+    }
+
+    #[test]
+    fn test_split_by_label() {
+        use crate::test_support::{TestEmail, TestMaildir};
+        let maildir = TestMaildir::new();
+        maildir.add(&TestEmail::new().header("X-Label", "receipts, travel"));
+        maildir.add(&TestEmail::new());
+        let matches = archive_args(Command::new("test"))
+            .try_get_matches_from([
+                "test",
+                maildir.input_maildir.path().to_str().unwrap(),
+                maildir.output_dir().to_str().unwrap(),
+                "--mode",
+                "copy",
+                "--split-by",
+                "label",
+            ])
+            .unwrap();
+        run(&parse_archive_args(&matches)).unwrap();
+        // The archived copy keeps its labels
+        let receipts = maildir.archive_folder("receipts");
+        let mail = receipts.list_cur().next().unwrap().unwrap();
+        assert_eq!(crate::split::email_labels(&mail), ["receipts", "travel"]);
+        assert_eq!(maildir.archive_folder("unlabeled").count_cur(), 1);
+    }
+
+    #[test]
+    fn test_strict() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let input = Maildir::from(basedir.join("in"));
+        input.create_dirs().unwrap();
+        let data = "Received: from a by b; Mon, 1 Feb 2016 10:00:00 +0000\n\nbody\n";
+        input.store_cur_with_flags(data.as_bytes(), "S").unwrap();
+        input
+            .store_cur_with_flags(b"Subject: no date\n\nbody\n", "S")
+            .unwrap();
+        let output = basedir.join("out");
+        let args = [input.path().to_str().unwrap(), output.to_str().unwrap()];
+        let matches = archive_args(Command::new("test"))
+            .try_get_matches_from(["test", args[0], args[1], "--mode", "copy"])
+            .unwrap();
+        let report = run(&parse_archive_args(&matches)).unwrap();
+        assert_eq!((report.examined, report.archived.len()), (2, 1));
+        // The email without a date stops the run
+        let matches = archive_args(Command::new("test"))
+            .try_get_matches_from(["test", args[0], args[1], "--mode", "copy", "--strict"])
+            .unwrap();
+        let res = run(&parse_archive_args(&matches));
+        assert!(matches!(res, Err(MaildirArchiverError::Strict(_))));
+    }
+
+    #[test]
+    fn test_dir_creation() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let input = Maildir::from(basedir.join("in"));
+        input.create_dirs().unwrap();
+        let data = "Received: from a by b; Mon, 1 Feb 2016 10:00:00 +0000\n\nbody\n";
+        input.store_cur_with_flags(data.as_bytes(), "S").unwrap();
+        let output = basedir.join("typo").join("out");
+        let args = [input.path().to_str().unwrap(), output.to_str().unwrap()];
+        let run_with = |flags: &[&str]| {
+            let matches = archive_args(Command::new("test"))
+                .try_get_matches_from(
+                    [&["test", args[0], args[1], "--mode", "copy"], flags].concat(),
+                )
+                .unwrap();
+            run(&parse_archive_args(&matches))
+        };
+        // The parent of the output directory is missing
+        let res = run_with(&[]);
+        assert!(matches!(res, Err(MaildirArchiverError::IoError(_))));
+        assert!(!output.exists());
+        run_with(&["--create-parents"]).unwrap();
+        assert_eq!(Maildir::from(output.join("2016")).count_cur(), 1);
+
+        std::fs::remove_dir_all(output.join("2016")).unwrap();
+        let report = run_with(&["--no-create-dirs"]).unwrap();
+        assert_eq!((report.archived.len(), report.failed.len()), (0, 1));
+        assert!(!output.join("2016").exists());
+    }
+
+    #[test]
+    fn test_rotate_after() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let input = Maildir::from(basedir.join("in"));
+        input.create_dirs().unwrap();
+        for n in 0..5 {
+            let data = format!(
+                "Received: from a by b; Mon, 1 Feb 2016 10:00:00 +0000\nSubject: {}\n\nbody\n",
+                n
+            );
+            input.store_cur_with_flags(data.as_bytes(), "S").unwrap();
+        }
+        let output = basedir.join("out");
+        let args = [input.path().to_str().unwrap(), output.to_str().unwrap()];
+        let matches = archive_args(Command::new("test"))
+            .try_get_matches_from([
+                "test",
+                args[0],
+                args[1],
+                "--mode",
+                "copy",
+                "--rotate-after",
+                "2",
+            ])
+            .unwrap();
+        let mut opts = parse_archive_args(&matches);
+        assert!(run(&opts).is_err());
+        opts.suffix = "-part{seq}".to_string();
+        run(&opts).unwrap();
+        let count =
+            |part: &str| Maildir::from(output.join(format!("2016-part{}", part))).count_cur();
+        assert_eq!((count("001"), count("002"), count("003")), (2, 2, 1));
+        // The next run fills the last part first
+        run(&opts).unwrap();
+        assert_eq!((count("003"), count("004"), count("005")), (2, 2, 2));
+    }
+
+    #[test]
+    fn test_imap_command() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let input = basedir.join("in");
+        let status = std::process::Command::new("cp")
+            .args(["-r", "testdata/maildir1"])
+            .arg(&input)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let spam = "Received: from a by b; Mon, 1 Feb 2016 10:00:00 +0000\n\
+                    X-Spam-Score: 10\nSubject: spam\n\nbody\n";
+        let input_maildir = Maildir::from(input.clone());
+        input_maildir
+            .store_cur_with_flags(spam.as_bytes(), "S")
+            .unwrap();
+        // Fake server listing the UIDs on SELECT and recording the EXPUNGE
+        let server = basedir.join("imap.sh");
+        std::fs::write(
+            &server,
+            format!(
+                "dir={}\n\
+                 echo '* PREAUTH'\n\
+                 read -r l; {{ echo '3 V1 N1'; ls \"$dir/cur\" | awk '{{print NR \" :\" $0}}'; }} \
+                 > \"$dir/dovecot-uidlist\"; echo 'A1 OK'\n\
+                 read -r l; echo 'A2 OK'\n\
+                 read -r l; echo \"$l\" > \"$dir/../expunged\"; echo 'A3 OK'\n\
+                 read -r l; echo 'A4 OK'\n",
+                input.display()
+            ),
+        )
+        .unwrap();
+        let output = basedir.join("out");
+        let command = format!("sh {}", server.display());
+        let args = [input.to_str().unwrap(), output.to_str().unwrap()];
+        let matches = archive_args(Command::new("test"))
+            .try_get_matches_from([
+                "test",
+                args[0],
+                args[1],
+                "--mode",
+                "move",
+                "--imap-command",
+                &command,
+                "--purge-spam-after",
+                "0",
+            ])
+            .unwrap();
+        let report = run(&parse_archive_args(&matches)).unwrap();
+        assert_eq!(report.archived.len(), 2);
+        assert_eq!(report.purged.len(), 1);
+        // The emails and the spam are removed by the server only
+        assert_eq!(input_maildir.count_cur(), 3);
+        let expunged = std::fs::read_to_string(basedir.join("expunged")).unwrap();
+        assert_eq!(expunged.trim_end(), "A3 UID EXPUNGE 1:3");
+    }
+}
@@ -0,0 +1,416 @@
+use crate::archiver::{trash_folder, MaildirArchiverError};
+use crate::args::ProgramOptions;
+use crate::attributes::Account;
+use log::info;
+use std::io;
+use std::path::PathBuf;
+
+/// Directories whose files an archive run may change: the input and output
+/// trees, and the directories of the files written by its options. The
+/// missing directories are created, unless nothing is written.
+pub fn writable_dirs(opts: &ProgramOptions, writes: bool) -> io::Result<Vec<PathBuf>> {
+    let input = opts.input_maildir.path();
+    let mut dirs = vec![input.to_path_buf(), opts.output_dir.clone()];
+    // The trash of a subfolder is a sibling of it
+    dirs.extend(trash_folder(input).parent().map(PathBuf::from));
+    if let Some(spam_dir) = opts.spam.as_ref().and_then(|spam| spam.output_dir.clone()) {
+        dirs.push(spam_dir);
+    }
+    dirs.extend(opts.routes.outputs().cloned());
+    let files = [
+        &opts.state_file,
+        &opts.seen_db,
+        &opts.sink_options.content_index,
+        &opts.log_file,
+    ];
+    for file in files.into_iter().flatten() {
+        dirs.extend(file.parent().map(PathBuf::from));
+    }
+    dirs.extend(opts.snapshot.clone());
+    dirs.extend(opts.sink_options.git_dir.clone());
+    if let Some(stripper) = &opts.store_options.attachment_stripper {
+        dirs.push(stripper.sidecar_dir.clone());
+    }
+    let mut existing = Vec::new();
+    for dir in dirs {
+        // A relative file in the current directory
+        let dir = match dir.as_os_str().is_empty() {
+            true => PathBuf::from("."),
+            false => dir,
+        };
+        if writes {
+            std::fs::create_dir_all(&dir)?;
+        }
+        if dir.exists() {
+            existing.push(dir);
+        }
+    }
+    Ok(existing)
+}
+
+/// Restrict the process, and the commands it runs, to changing the files
+/// under `dirs`. The other files can still be read.
+pub fn enter_sandbox(dirs: &[PathBuf]) -> Result<(), MaildirArchiverError> {
+    platform::enter_sandbox(dirs)?;
+    info!("Sandboxed, writing only under {} directories", dirs.len());
+    Ok(())
+}
+
+/// Take the privileges of a user for the rest of the process: its uid, its
+/// primary group and its supplementary groups. Root can't be regained.
+pub fn drop_privileges(account: &Account) -> io::Result<()> {
+    // SAFETY: plain system calls, `account.name` is a valid C string
+    unsafe {
+        if libc::initgroups(account.name.as_ptr(), account.gid as _) != 0
+            || libc::setgid(account.gid) != 0
+            || libc::setuid(account.uid) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        if account.uid != 0 && libc::setuid(0) == 0 {
+            return Err(io::Error::other("root privileges regained after setuid"));
+        }
+    }
+    info!(
+        "Running as {} (uid {}, gid {})",
+        account.name.to_string_lossy(),
+        account.uid,
+        account.gid
+    );
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use std::ffi::CString;
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+    use std::path::{Path, PathBuf};
+
+    const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1;
+    const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+    const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+    const ACCESS_FS_REMOVE_DIR: u64 = 1 << 4;
+    const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+    /// Creation of the character devices, directories, regular files,
+    /// sockets, fifos, block devices and symlinks
+    const ACCESS_FS_MAKE: u64 = 0b111_1111 << 6;
+    const ACCESS_FS_REFER: u64 = 1 << 13;
+    const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+
+    #[repr(C)]
+    struct RulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    struct PathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: i32,
+    }
+
+    fn check(res: libc::c_long) -> io::Result<libc::c_long> {
+        match res {
+            -1 => Err(io::Error::last_os_error()),
+            res => Ok(res),
+        }
+    }
+
+    /// Allow an access to the files beneath a path
+    fn add_rule(ruleset: &OwnedFd, path: &Path, allowed_access: u64) -> io::Result<()> {
+        let file = File::options()
+            .read(true)
+            .custom_flags(libc::O_PATH | libc::O_CLOEXEC)
+            .open(path)?;
+        let attr = PathBeneathAttr {
+            allowed_access,
+            parent_fd: file.as_raw_fd(),
+        };
+        // SAFETY: `attr` is a valid landlock_path_beneath_attr struct
+        check(unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset.as_raw_fd(),
+                LANDLOCK_RULE_PATH_BENEATH,
+                &attr as *const PathBeneathAttr,
+                0,
+            )
+        })?;
+        Ok(())
+    }
+
+    /// Landlock ruleset denying the changes of the files outside `dirs`
+    fn landlock(dirs: &[PathBuf]) -> io::Result<()> {
+        // SAFETY: querying the ABI version takes no attributes
+        let abi = check(unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                std::ptr::null::<RulesetAttr>(),
+                0,
+                LANDLOCK_CREATE_RULESET_VERSION,
+            )
+        })
+        .map_err(|e| io::Error::new(e.kind(), format!("Landlock not available: {}", e)))?;
+        let mut handled =
+            ACCESS_FS_WRITE_FILE | ACCESS_FS_REMOVE_DIR | ACCESS_FS_REMOVE_FILE | ACCESS_FS_MAKE;
+        // The moves between directories, like to the trash, are denied
+        // unless the refer right is handled and granted
+        if abi >= 2 {
+            handled |= ACCESS_FS_REFER;
+        }
+        if abi >= 3 {
+            handled |= ACCESS_FS_TRUNCATE;
+        }
+        let attr = RulesetAttr {
+            handled_access_fs: handled,
+        };
+        // SAFETY: `attr` is a valid landlock_ruleset_attr struct of the
+        // given size
+        let fd = check(unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr as *const RulesetAttr,
+                std::mem::size_of::<RulesetAttr>(),
+                0,
+            )
+        })?;
+        // SAFETY: the file descriptor was returned by the kernel
+        let ruleset = unsafe { OwnedFd::from_raw_fd(fd as libc::c_int) };
+        for dir in dirs {
+            add_rule(&ruleset, dir, handled)?;
+        }
+        // The commands run by the sinks and the hooks write to /dev/null
+        add_rule(
+            &ruleset,
+            Path::new("/dev/null"),
+            handled & (ACCESS_FS_WRITE_FILE | ACCESS_FS_TRUNCATE),
+        )?;
+        // SAFETY: the ruleset file descriptor is valid
+        check(unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) })?;
+        Ok(())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xc000_003e;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+    /// System calls never needed by an archive run, denied with `EPERM`
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    const DENIED_SYSCALLS: [libc::c_long; 17] = [
+        libc::SYS_ptrace,
+        libc::SYS_process_vm_writev,
+        libc::SYS_mount,
+        libc::SYS_umount2,
+        libc::SYS_pivot_root,
+        libc::SYS_chroot,
+        libc::SYS_setns,
+        libc::SYS_unshare,
+        libc::SYS_reboot,
+        libc::SYS_kexec_load,
+        libc::SYS_init_module,
+        libc::SYS_finit_module,
+        libc::SYS_delete_module,
+        libc::SYS_swapon,
+        libc::SYS_swapoff,
+        libc::SYS_bpf,
+        libc::SYS_perf_event_open,
+    ];
+
+    /// Seccomp filter denying the [`DENIED_SYSCALLS`]
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    fn seccomp() -> io::Result<()> {
+        const BPF_LD_W_ABS: u16 = 0x20;
+        const BPF_JMP_JEQ_K: u16 = 0x15;
+        const BPF_RET_K: u16 = 0x06;
+        // Offsets of the fields of seccomp_data
+        const NR: u32 = 0;
+        const ARCH: u32 = 4;
+        let statement = |code, k| libc::sock_filter {
+            code,
+            jt: 0,
+            jf: 0,
+            k,
+        };
+        let jump = |k, jt, jf| libc::sock_filter {
+            code: BPF_JMP_JEQ_K,
+            jt,
+            jf,
+            k,
+        };
+        let deny = libc::SECCOMP_RET_ERRNO | libc::EPERM as u32;
+        let mut filter = vec![
+            statement(BPF_LD_W_ABS, ARCH),
+            jump(AUDIT_ARCH, 1, 0),
+            statement(BPF_RET_K, libc::SECCOMP_RET_KILL_PROCESS),
+            statement(BPF_LD_W_ABS, NR),
+        ];
+        #[cfg(target_arch = "x86_64")]
+        {
+            // The x32 system calls have another numbering
+            const BPF_JMP_JGE_K: u16 = 0x35;
+            filter.push(libc::sock_filter {
+                code: BPF_JMP_JGE_K,
+                jt: 0,
+                jf: 1,
+                k: 0x4000_0000,
+            });
+            filter.push(statement(BPF_RET_K, deny));
+        }
+        for nr in DENIED_SYSCALLS {
+            filter.push(jump(nr as u32, 0, 1));
+            filter.push(statement(BPF_RET_K, deny));
+        }
+        filter.push(statement(BPF_RET_K, libc::SECCOMP_RET_ALLOW));
+        let program = libc::sock_fprog {
+            len: filter.len() as u16,
+            filter: filter.as_mut_ptr(),
+        };
+        // SAFETY: `program` points to the filter, copied by the kernel
+        match unsafe {
+            libc::prctl(
+                libc::PR_SET_SECCOMP,
+                libc::SECCOMP_MODE_FILTER,
+                &program as *const libc::sock_fprog,
+            )
+        } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    fn seccomp() -> io::Result<()> {
+        log::warn!("No seccomp filter for this architecture, only Landlock is applied");
+        Ok(())
+    }
+
+    pub fn enter_sandbox(dirs: &[PathBuf]) -> io::Result<()> {
+        // Checked before the paths are opened by Landlock
+        for dir in dirs {
+            CString::new(dir.as_os_str().as_bytes())?;
+        }
+        // SAFETY: no pointer arguments
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        landlock(dirs)?;
+        seccomp()
+    }
+}
+
+#[cfg(target_os = "openbsd")]
+mod platform {
+    use std::ffi::CString;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::PathBuf;
+
+    fn check(res: libc::c_int) -> io::Result<()> {
+        match res {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    pub fn enter_sandbox(dirs: &[PathBuf]) -> io::Result<()> {
+        let unveil = |path: &[u8], permissions: &str| -> io::Result<()> {
+            let path = CString::new(path)?;
+            let permissions = CString::new(permissions)?;
+            // SAFETY: both arguments are valid C strings
+            check(unsafe { libc::unveil(path.as_ptr(), permissions.as_ptr()) })
+        };
+        unveil(b"/", "rx")?;
+        unveil(b"/dev/null", "rw")?;
+        for dir in dirs {
+            unveil(dir.as_os_str().as_bytes(), "rwc")?;
+        }
+        // SAFETY: null arguments lock the unveiled paths
+        check(unsafe { libc::unveil(std::ptr::null(), std::ptr::null()) })?;
+        let promises = CString::new("stdio rpath wpath cpath fattr chown flock proc exec")?;
+        // SAFETY: the promises are a valid C string, the exec promises are
+        // not changed
+        check(unsafe { libc::pledge(promises.as_ptr(), std::ptr::null()) })
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "openbsd")))]
+mod platform {
+    use crate::archiver::MaildirArchiverError;
+    use std::path::PathBuf;
+
+    pub fn enter_sandbox(_dirs: &[PathBuf]) -> Result<(), MaildirArchiverError> {
+        Err(MaildirArchiverError::Unsupported(
+            "--sandbox is only supported on Linux and OpenBSD".to_string(),
+        ))
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use crate::attributes::parse_account;
+    use crate::sandbox::{drop_privileges, enter_sandbox};
+    use std::path::PathBuf;
+    use std::process::Command;
+
+    /// Directory of the test, set in the process running it sandboxed
+    const TEST_DIR: &str = "ARCHIVE_MAILDIR_SANDBOX_TEST_DIR";
+
+    #[test]
+    fn test_sandbox() {
+        // The restrictions apply to the whole process: the test runs again
+        // in a child process, sandboxed
+        if let Some(basedir) = std::env::var_os(TEST_DIR).map(PathBuf::from) {
+            let allowed = basedir.join("allowed");
+            // Landlock not available, like in old kernels
+            if enter_sandbox(std::slice::from_ref(&allowed)).is_err() {
+                return;
+            }
+            std::fs::write(allowed.join("a"), "a").unwrap();
+            assert!(std::fs::write(basedir.join("denied").join("a"), "a").is_err());
+            assert!(std::fs::read_dir(basedir.join("denied")).is_ok());
+            return;
+        }
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        std::fs::create_dir(basedir.join("allowed")).unwrap();
+        std::fs::create_dir(basedir.join("denied")).unwrap();
+        let status = Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "sandbox::tests::test_sandbox", "--quiet"])
+            .env(TEST_DIR, basedir.as_os_str())
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert!(!basedir.join("denied").join("a").exists());
+    }
+
+    #[test]
+    fn test_drop_privileges() {
+        if let Some(basedir) = std::env::var_os(TEST_DIR).map(PathBuf::from) {
+            drop_privileges(&parse_account("nobody").unwrap()).unwrap();
+            assert!(std::fs::write(basedir.join("a"), "a").is_err());
+            // SAFETY: plain system calls
+            unsafe {
+                assert_ne!(libc::geteuid(), 0);
+                assert_ne!(libc::setuid(0), 0);
+            }
+            return;
+        }
+        // Only root can take the privileges of another user
+        if unsafe { libc::geteuid() } != 0 || parse_account("nobody").is_err() {
+            return;
+        }
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let status = Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "sandbox::tests::test_drop_privileges", "--quiet"])
+            .env(TEST_DIR, basedir.as_os_str())
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert!(!basedir.join("a").exists());
+    }
+}
@@ -0,0 +1,169 @@
+use crate::archiver::{ArchiveMode, MaildirArchiver, MaildirArchiverError};
+use maildir::{MailEntry, Maildir};
+use std::collections::HashSet;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Format of the dry run output
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DryRunFormat {
+    /// Only log the emails that would be archived
+    Log,
+    /// Write the equivalent shell commands
+    Shell,
+}
+
+/// Quote a path for a POSIX shell, keeping the bytes of the names that are
+/// not UTF-8
+fn shell_quote(path: &Path) -> Vec<u8> {
+    let mut quoted = vec![b'\''];
+    for &b in path.as_os_str().as_bytes() {
+        match b {
+            b'\'' => quoted.extend_from_slice(b"'\\''"),
+            b => quoted.push(b),
+        }
+    }
+    quoted.push(b'\'');
+    quoted
+}
+
+/// Write a command line with its quoted path arguments
+fn write_command(writer: &mut dyn Write, command: &str, paths: &[&Path]) -> io::Result<()> {
+    writer.write_all(command.as_bytes())?;
+    for path in paths {
+        writer.write_all(b" ")?;
+        writer.write_all(&shell_quote(path))?;
+    }
+    writer.write_all(b"\n")
+}
+
+struct ShellScript {
+    writer: Box<dyn Write + Send>,
+    folders: HashSet<PathBuf>,
+}
+
+/// Dry run archiver writing the `mkdir`, `cp` and `rm` commands equivalent
+/// to archiving the emails with the given mode.
+///
+/// The commands copy the emails verbatim: the transformations of the store
+/// options are not represented.
+struct ShellScriptArchiver {
+    mode: ArchiveMode,
+    script: Mutex<ShellScript>,
+}
+
+impl MaildirArchiver for ShellScriptArchiver {
+    fn archive_email(
+        &self,
+        mail: &MailEntry,
+        _from_maildir: &Maildir,
+        archive_folder: &Path,
+    ) -> Result<(), MaildirArchiverError> {
+        let mut script = self.script.lock().unwrap();
+        if script.folders.is_empty() {
+            writeln!(script.writer, "#!/bin/sh\nset -e")?;
+        }
+        if script.folders.insert(archive_folder.to_path_buf()) {
+            let dirs: Vec<_> = ["cur", "new", "tmp"]
+                .iter()
+                .map(|d| archive_folder.join(d))
+                .collect();
+            let dirs: Vec<_> = dirs.iter().map(PathBuf::as_path).collect();
+            write_command(&mut script.writer, "mkdir -p", &dirs)?;
+        }
+        let dest = archive_folder
+            .join("cur")
+            .join(mail.path().file_name().unwrap());
+        let cp = "cp --preserve=mode,timestamps";
+        write_command(&mut script.writer, cp, &[mail.path(), &dest])?;
+        if self.mode == ArchiveMode::Move {
+            write_command(&mut script.writer, "rm", &[mail.path()])?;
+        }
+        Ok(())
+    }
+
+    fn finish(&self) -> Result<(), MaildirArchiverError> {
+        Ok(self.script.lock().unwrap().writer.flush()?)
+    }
+}
+
+/// Factory method that creates a dry run archiver writing to `writer` the
+/// shell commands equivalent to archiving the emails with `mode`. Only the
+/// copy and move modes can be represented, other modes write the commands of
+/// a move.
+pub fn create_shell_archiver(
+    mode: ArchiveMode,
+    writer: Box<dyn Write + Send>,
+) -> Box<dyn MaildirArchiver> {
+    Box::new(ShellScriptArchiver {
+        mode: match mode {
+            ArchiveMode::Copy => ArchiveMode::Copy,
+            _ => ArchiveMode::Move,
+        },
+        script: Mutex::new(ShellScript {
+            writer,
+            folders: HashSet::new(),
+        }),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::archiver::ArchiveMode;
+    use crate::script::create_shell_archiver;
+    use maildir::Maildir;
+    use std::ffi::OsStr;
+    use std::io::Write;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_shell_archiver() {
+        let maildir = Maildir::from(PathBuf::from("testdata/maildir1"));
+        let archive_folder = PathBuf::from("/archive/it's");
+        let buffer = SharedBuffer::default();
+        let archiver = create_shell_archiver(ArchiveMode::DryRun, Box::new(buffer.clone()));
+        for mail in maildir.list_cur() {
+            archiver
+                .archive_email(&mail.unwrap(), &maildir, &archive_folder)
+                .unwrap();
+        }
+        archiver.finish().unwrap();
+
+        let script = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<_> = script.lines().collect();
+        assert_eq!(lines.len(), 7);
+        assert_eq!(
+            lines[2],
+            "mkdir -p '/archive/it'\\''s/cur' '/archive/it'\\''s/new' '/archive/it'\\''s/tmp'"
+        );
+        assert!(lines[3].starts_with("cp --preserve=mode,timestamps 'testdata/maildir1/cur/"));
+        assert!(lines[4].starts_with("rm 'testdata/maildir1/cur/"));
+
+        // The names that are not UTF-8 are quoted verbatim
+        let archive_folder = PathBuf::from(OsStr::from_bytes(b"/archive/\xff"));
+        let mail = maildir.list_cur().next().unwrap().unwrap();
+        archiver
+            .archive_email(&mail, &maildir, &archive_folder)
+            .unwrap();
+        let script = buffer.0.lock().unwrap().clone();
+        let mkdir = b"\nmkdir -p '/archive/\xff/cur' '/archive/\xff/new' '/archive/\xff/tmp'\n";
+        assert!(script.windows(mkdir.len()).any(|w| w == mkdir));
+    }
+}
@@ -0,0 +1,197 @@
+//! Seekable archive container.
+//!
+//! Emails are stored in a zstd file as independent frames, one per email,
+//! followed by a seek table in the [zstd seekable format]. The file can be
+//! decompressed by any zstd tool, while a sidecar index (`<file>.idx`)
+//! maps email names to frame offsets, so a single email can be read without
+//! decompressing the whole archive folder.
+//!
+//! Each line of the index contains the offset of the frame, its compressed
+//! size, the size of the email and the email name, separated by tabs.
+//!
+//! [zstd seekable format]: https://github.com/facebook/zstd/blob/dev/contrib/seekable_format/zstd_seekable_compression_format.md
+
+use crate::archiver::path_with_suffix;
+use crate::args::ExtractOptions;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+const SKIPPABLE_MAGIC: u32 = 0x184D2A5E;
+const SEEKABLE_MAGIC: u32 = 0x8F92EAB1;
+
+/// Position of an email in a seekable archive
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub name: String,
+    pub offset: u64,
+    pub compressed_size: u64,
+    pub size: u64,
+}
+
+/// Path of the index of a seekable archive
+pub fn index_path(path: &Path) -> PathBuf {
+    path_with_suffix(path, ".idx")
+}
+
+/// Writer of seekable archives
+pub struct SeekableWriter<W: Write> {
+    writer: W,
+    offset: u64,
+    entries: Vec<IndexEntry>,
+}
+
+impl<W: Write> SeekableWriter<W> {
+    pub fn new(writer: W) -> Self {
+        SeekableWriter {
+            writer,
+            offset: 0,
+            entries: Vec::new(),
+        }
+    }
+
+    /// Append an email as a new frame
+    pub fn add(&mut self, name: &str, data: &[u8]) -> io::Result<()> {
+        let frame = zstd::bulk::compress(data, 0)?;
+        self.writer.write_all(&frame)?;
+        self.entries.push(IndexEntry {
+            name: name.to_string(),
+            offset: self.offset,
+            compressed_size: frame.len() as u64,
+            size: data.len() as u64,
+        });
+        self.offset += frame.len() as u64;
+        Ok(())
+    }
+
+    /// Write the seek table, returning the inner writer and the index
+    pub fn finish(mut self) -> io::Result<(W, Vec<IndexEntry>)> {
+        let mut table = Vec::new();
+        for entry in &self.entries {
+            table.extend((entry.compressed_size as u32).to_le_bytes());
+            table.extend((entry.size as u32).to_le_bytes());
+        }
+        table.extend((self.entries.len() as u32).to_le_bytes());
+        // Seek table descriptor: no checksums
+        table.push(0);
+        table.extend(SEEKABLE_MAGIC.to_le_bytes());
+        self.writer.write_all(&SKIPPABLE_MAGIC.to_le_bytes())?;
+        self.writer.write_all(&(table.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&table)?;
+        Ok((self.writer, self.entries))
+    }
+}
+
+/// Write the index of a seekable archive
+pub fn write_index<W: Write>(writer: &mut W, entries: &[IndexEntry]) -> io::Result<()> {
+    for entry in entries {
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}",
+            entry.offset, entry.compressed_size, entry.size, entry.name
+        )?;
+    }
+    Ok(())
+}
+
+fn parse_index_line(line: &str) -> Option<IndexEntry> {
+    let mut fields = line.splitn(4, '\t');
+    Some(IndexEntry {
+        offset: fields.next()?.parse().ok()?,
+        compressed_size: fields.next()?.parse().ok()?,
+        size: fields.next()?.parse().ok()?,
+        name: fields.next()?.to_string(),
+    })
+}
+
+/// Reader of seekable archives, giving random access to the emails
+pub struct SeekableReader {
+    file: File,
+    entries: HashMap<String, IndexEntry>,
+}
+
+impl SeekableReader {
+    /// Open a seekable archive and its index
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut entries = HashMap::new();
+        for line in BufReader::new(File::open(index_path(path))?).lines() {
+            let line = line?;
+            let entry = parse_index_line(&line).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid index line: {}", line),
+                )
+            })?;
+            entries.insert(entry.name.clone(), entry);
+        }
+        Ok(SeekableReader {
+            file: File::open(path)?,
+            entries,
+        })
+    }
+
+    /// Names of the emails in the archive
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.entries.keys().map(|k| k.as_str())
+    }
+
+    /// Read a single email
+    pub fn read(&mut self, name: &str) -> io::Result<Vec<u8>> {
+        let entry = self.entries.get(name).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("{} not found", name))
+        })?;
+        let mut frame = vec![0; entry.compressed_size as usize];
+        self.file.seek(SeekFrom::Start(entry.offset))?;
+        self.file.read_exact(&mut frame)?;
+        zstd::bulk::decompress(&frame, entry.size as usize)
+    }
+}
+
+/// Write the requested emails of a seekable archive to `out`, or their
+/// names if no email is requested.
+pub fn extract<W: Write>(opts: &ExtractOptions, out: &mut W) -> io::Result<()> {
+    let mut reader = SeekableReader::open(&opts.archive_file)?;
+    if opts.names.is_empty() {
+        let mut names: Vec<_> = reader.names().map(str::to_string).collect();
+        names.sort();
+        for name in names {
+            writeln!(out, "{}", name)?;
+        }
+    }
+    for name in &opts.names {
+        out.write_all(&reader.read(name)?)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::seekable::{index_path, write_index, SeekableReader, SeekableWriter};
+    use std::fs::File;
+    use std::io::Read;
+
+    #[test]
+    fn test_seekable_archive() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let path = basedir.join("2016.zst");
+        let mut writer = SeekableWriter::new(File::create(&path).unwrap());
+        writer.add("cur/a:2,S", b"Subject: a\n\nfirst\n").unwrap();
+        writer.add("cur/b:2,", b"Subject: b\n\nsecond\n").unwrap();
+        let (_, entries) = writer.finish().unwrap();
+        write_index(&mut File::create(index_path(&path)).unwrap(), &entries).unwrap();
+
+        let mut reader = SeekableReader::open(&path).unwrap();
+        assert_eq!(reader.read("cur/b:2,").unwrap(), b"Subject: b\n\nsecond\n");
+        assert!(reader.read("cur/c:2,").is_err());
+
+        // The whole file is still a valid zstd stream
+        let mut all = Vec::new();
+        zstd::Decoder::new(File::open(&path).unwrap())
+            .unwrap()
+            .read_to_end(&mut all)
+            .unwrap();
+        assert_eq!(all, b"Subject: a\n\nfirst\nSubject: b\n\nsecond\n");
+    }
+}
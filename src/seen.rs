@@ -0,0 +1,82 @@
+use crate::dedup::{file_digest, to_hex};
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// On-disk set of the SHA-256 digests of the archived emails, shared by all
+/// the runs that use it.
+///
+/// The database is a text file with a hexadecimal digest per line, new
+/// digests are appended as soon as an email is archived.
+pub struct SeenDatabase {
+    digests: Mutex<HashSet<String>>,
+    file: Mutex<File>,
+}
+
+impl SeenDatabase {
+    /// Open the database, creating it if it doesn't exist
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut digests = HashSet::new();
+        if path.exists() {
+            for line in BufReader::new(File::open(path)?).lines() {
+                let line = line?;
+                if !line.is_empty() {
+                    digests.insert(line);
+                }
+            }
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(SeenDatabase {
+            digests: Mutex::new(digests),
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Digest of an email, as stored in the database
+    pub fn digest(path: &Path) -> io::Result<String> {
+        Ok(to_hex(&file_digest(path)?))
+    }
+
+    /// Check if an email with the given digest has already been archived
+    pub fn contains(&self, digest: &str) -> bool {
+        self.digests.lock().unwrap().contains(digest)
+    }
+
+    /// Record an archived email
+    pub fn insert(&self, digest: &str) -> io::Result<()> {
+        if self.digests.lock().unwrap().insert(digest.to_string()) {
+            writeln!(self.file.lock().unwrap(), "{}", digest)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::seen::SeenDatabase;
+    use std::path::Path;
+
+    #[test]
+    fn test_seen_database() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let path = basedir.join("seen");
+        let digest = SeenDatabase::digest(Path::new(
+            "testdata/maildir1/cur/1463868505.38518452d49213cb409aa1db32f53184:2,S",
+        ))
+        .unwrap();
+
+        let seen = SeenDatabase::open(&path).unwrap();
+        assert!(!seen.contains(&digest));
+        seen.insert(&digest).unwrap();
+        seen.insert(&digest).unwrap();
+        assert!(seen.contains(&digest));
+        drop(seen);
+
+        let seen = SeenDatabase::open(&path).unwrap();
+        assert!(seen.contains(&digest));
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+    }
+}
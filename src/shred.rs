@@ -0,0 +1,63 @@
+use std::ffi::CString;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// Magic numbers of the copy-on-write filesystems, where overwriting a file
+/// writes new blocks and leaves the old content on disk
+const COPY_ON_WRITE_FILESYSTEMS: [(i64, &str); 3] = [
+    (0x9123683e, "btrfs"),
+    (0x2fc12fc1, "zfs"),
+    (0xca451a4e, "bcachefs"),
+];
+
+/// Name of the filesystem of `path` if it is a copy-on-write filesystem
+pub fn copy_on_write_filesystem(path: &Path) -> io::Result<Option<&'static str>> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `path` is a valid C string and `stat` a valid statfs struct
+    if unsafe { libc::statfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // The type of the field depends on the platform
+    #[allow(clippy::unnecessary_cast)]
+    let kind = stat.f_type as i64;
+    Ok(COPY_ON_WRITE_FILESYSTEMS
+        .iter()
+        .find(|(magic, _)| *magic == kind)
+        .map(|(_, name)| *name))
+}
+
+/// Overwrite the content of a file with zeros, flushed to disk, before it is
+/// removed. This is best effort: copy-on-write filesystems, snapshots and
+/// the remapping of the blocks by SSDs may keep the old content.
+pub fn overwrite_file(path: &Path) -> io::Result<()> {
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    let zeros = [0u8; 64 * 1024];
+    let mut remaining = file.metadata()?.len();
+    while remaining > 0 {
+        let size = remaining.min(zeros.len() as u64) as usize;
+        file.write_all(&zeros[..size])?;
+        remaining -= size as u64;
+    }
+    file.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::shred::{copy_on_write_filesystem, overwrite_file};
+
+    #[test]
+    fn test_overwrite_file() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let path = basedir.join("email");
+        std::fs::write(&path, vec![b'a'; 100 * 1024]).unwrap();
+        overwrite_file(&path).unwrap();
+        let content = std::fs::read(&path).unwrap();
+        assert_eq!(content.len(), 100 * 1024);
+        assert!(content.iter().all(|b| *b == 0));
+        assert!(copy_on_write_filesystem(&basedir).is_ok());
+    }
+}
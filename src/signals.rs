@@ -0,0 +1,125 @@
+use log::info;
+use signal_hook::consts::{SIGINT, SIGTERM, SIGUSR1, SIGUSR2};
+use signal_hook::flag;
+use signal_hook::iterator::Signals as SignalIterator;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Exit code of a run interrupted by SIGINT or SIGTERM
+pub const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+/// Signals checked between two emails.
+///
+/// SIGINT and SIGTERM request to stop after the email being archived, a
+/// second signal terminates the program immediately. SIGUSR1 requests to log
+/// the progress of the run. SIGUSR2 pauses the run, or resumes it if paused.
+///
+/// The default value is not connected to any signal handler.
+#[derive(Default)]
+pub struct Signals {
+    interrupted: Arc<AtomicBool>,
+    progress: Arc<AtomicBool>,
+    paused: Arc<AtomicBool>,
+}
+
+impl Signals {
+    pub fn register() -> io::Result<Self> {
+        let interrupted = Arc::new(AtomicBool::new(false));
+        let progress = Arc::new(AtomicBool::new(false));
+        for signal in [SIGINT, SIGTERM] {
+            flag::register_conditional_shutdown(
+                signal,
+                INTERRUPTED_EXIT_CODE,
+                interrupted.clone(),
+            )?;
+            flag::register(signal, interrupted.clone())?;
+        }
+        flag::register(SIGUSR1, progress.clone())?;
+        let paused = Arc::new(AtomicBool::new(false));
+        let mut toggles = SignalIterator::new([SIGUSR2])?;
+        let toggle = paused.clone();
+        thread::spawn(move || {
+            for _ in toggles.forever() {
+                toggle.fetch_xor(true, Ordering::Relaxed);
+            }
+        });
+        Ok(Signals {
+            interrupted,
+            progress,
+            paused,
+        })
+    }
+
+    /// Signals of a run embedded in an application, not connected to any
+    /// signal handler: setting `token` stops the run after the email being
+    /// archived
+    pub fn with_cancellation(token: Arc<AtomicBool>) -> Self {
+        Signals {
+            interrupted: token,
+            ..Default::default()
+        }
+    }
+
+    /// Token interrupting the run when set
+    pub fn cancellation_token(&self) -> Arc<AtomicBool> {
+        self.interrupted.clone()
+    }
+
+    /// Check if the run has been paused
+    pub fn paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Block while the run is paused, unless it is interrupted
+    pub fn wait_if_paused(&self) {
+        if !self.paused() {
+            return;
+        }
+        info!("Archive run paused");
+        while self.paused() && !self.interrupted() {
+            thread::sleep(Duration::from_millis(200));
+        }
+        info!("Archive run resumed");
+    }
+
+    /// Check if the run has been interrupted
+    pub fn interrupted(&self) -> bool {
+        self.interrupted.load(Ordering::Relaxed)
+    }
+
+    /// Check if the progress has been requested since the last call
+    pub fn progress_requested(&self) -> bool {
+        self.progress.swap(false, Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::signals::Signals;
+    use signal_hook::consts::{SIGUSR1, SIGUSR2};
+
+    #[test]
+    fn test_progress_signal() {
+        let signals = Signals::register().unwrap();
+        assert!(!signals.progress_requested());
+        signal_hook::low_level::raise(SIGUSR1).unwrap();
+        assert!(signals.progress_requested());
+        assert!(!signals.progress_requested());
+        assert!(!signals.interrupted());
+    }
+
+    #[test]
+    fn test_pause_signal() {
+        let signals = Signals::register().unwrap();
+        assert!(!signals.paused());
+        signal_hook::low_level::raise(SIGUSR2).unwrap();
+        let start = std::time::Instant::now();
+        while !signals.paused() && start.elapsed().as_secs() < 5 {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert!(signals.paused());
+    }
+}
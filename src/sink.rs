@@ -0,0 +1,1169 @@
+use crate::archiver::{path_with_suffix, MaildirArchiverError};
+use crate::attributes::{
+    copy_permissions, copy_xattrs, missing_directories, set_attributes, Owner,
+};
+use crate::dedup::{reader_digest, replace_with_hardlink, to_hex, ContentIndex};
+use crate::git::GitSink;
+use crate::mbox::write_mbox_message;
+use crate::sync::strip_sync_markers;
+use log::{debug, warn};
+use maildir::{MailEntry, Maildir};
+use mailparse::MailHeaderMap;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "compression")]
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::ffi::{OsStr, OsString};
+#[cfg(any(
+    feature = "compression",
+    all(feature = "io-uring", target_os = "linux")
+))]
+use std::fs::File;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+#[cfg(feature = "compression")]
+use std::io::{Seek, SeekFrom};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use time::OffsetDateTime;
+#[cfg(feature = "compression")]
+use zip::write::SimpleFileOptions;
+#[cfg(feature = "compression")]
+use zip::ZipWriter;
+
+/// Trait implemented by the destinations of archived emails.
+///
+/// The archive folder passed to [`ArchiveSink::store`] is the path computed
+/// from the split policy: each sink decides how to lay it out on disk.
+pub trait ArchiveSink: Send + Sync {
+    /// Store the content of an email in the given archive folder
+    fn store(
+        &self,
+        folder: &Path,
+        mail: &MailEntry,
+        data: &[u8],
+    ) -> Result<(), MaildirArchiverError>;
+
+    /// Complete pending writes, called once at the end of a run
+    fn finish(&self) -> Result<(), MaildirArchiverError> {
+        Ok(())
+    }
+
+    /// Check if the stored emails are only written by [`ArchiveSink::finish`]:
+    /// the archivers then remove the source emails after it
+    fn deferred(&self) -> bool {
+        false
+    }
+}
+
+/// Flags of an email as written to a maildir: sorted and without repeated
+/// letters, as required by the maildir specification. With `normalize`, the
+/// characters other than ASCII letters are also removed.
+pub fn maildir_flags(flags: &str, normalize: bool) -> String {
+    let mut flags: Vec<char> = flags
+        .chars()
+        .filter(|c| !normalize || c.is_ascii_alphabetic())
+        .collect();
+    flags.sort();
+    flags.dedup();
+    flags.into_iter().collect()
+}
+
+/// Info of the maildir filename of an archived email, after the `:`
+/// separator. The info of the source file is kept verbatim, with the
+/// nonstandard flags like the Dovecot keywords (`2,Sab`); with `normalize`,
+/// or for the emails without info, it is `2,` followed by the
+/// [`maildir_flags`].
+pub fn maildir_info(mail: &MailEntry, normalize: bool) -> OsString {
+    let name = mail.path().file_name().unwrap_or_default().as_bytes();
+    match name.iter().position(|&b| b == b':') {
+        Some(i) if !normalize => OsStr::from_bytes(&name[i + 1..]).to_os_string(),
+        _ => format!("2,{}", maildir_flags(mail.flags(), normalize)).into(),
+    }
+}
+
+/// Maildir filename of an email without the info, as raw bytes: unlike
+/// [`MailEntry::id`], the names that are not UTF-8 are kept intact
+pub fn email_base(mail: &MailEntry) -> &OsStr {
+    file_base(mail.path())
+}
+
+/// Maildir filename of the file of an email without the info
+pub fn file_base(path: &Path) -> &OsStr {
+    let name = path.file_name().unwrap_or_default();
+    match name.as_bytes().iter().position(|&b| b == b':') {
+        Some(i) => OsStr::from_bytes(&name.as_bytes()[..i]),
+        None => name,
+    }
+}
+
+/// Maildir filename of an email with the given base and info
+pub fn maildir_name(base: &OsStr, info: &OsStr) -> OsString {
+    let mut name = base.to_os_string();
+    name.push(":");
+    name.push(info);
+    name
+}
+
+/// Options of the files written by the maildir sink
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SinkOptions {
+    /// Remove the nonstandard characters from the flags
+    pub normalize_flags: bool,
+    /// Copy the owner, the group and the mode of the source files
+    pub preserve_perms: bool,
+    /// Copy the extended attributes of the source files, including their
+    /// SELinux security context
+    pub preserve_xattrs: bool,
+    /// Owner of the created files and directories
+    pub owner: Option<Owner>,
+    /// Mode of the created files, and of the created directories with the
+    /// search permission added where the files are readable
+    pub mode: Option<u32>,
+    /// Hash index of the archived emails: the emails with the content of an
+    /// indexed email are stored as hardlinks to it
+    pub content_index: Option<PathBuf>,
+    /// Bare git repository of the git sink
+    pub git_dir: Option<PathBuf>,
+    /// Keep the ids of the source emails, without the markers of the IMAP
+    /// synchronization tools
+    pub sync_safe: bool,
+    /// Directories created for the archive folders
+    pub dir_creation: DirCreation,
+    /// Write the emails by batches with io_uring, moving them to `cur` at the
+    /// end of the run
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    pub batch_writes: bool,
+}
+
+/// Directories created by the sinks and the runs
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DirCreation {
+    /// Create nothing: the archive folders must exist
+    None,
+    /// Create the archive folders in an existing output directory, or in
+    /// the output directory created in an existing directory
+    #[default]
+    Folders,
+    /// Create all the missing directories of the output path
+    Parents,
+}
+
+/// Archive folders ready to store emails, so that their directories are
+/// created or checked once per run
+#[derive(Default)]
+struct ReadyFolders(Mutex<HashSet<PathBuf>>);
+
+impl ReadyFolders {
+    /// Create the directories of a maildir archive folder the first time
+    /// it is used, or check that they exist with [`DirCreation::None`]
+    fn prepare(&self, folder: &Path, options: &SinkOptions) -> Result<(), MaildirArchiverError> {
+        if self.0.lock().unwrap().contains(folder) {
+            return Ok(());
+        }
+        if options.dir_creation == DirCreation::None {
+            let missing = ["cur", "new", "tmp"]
+                .iter()
+                .map(|dir| folder.join(dir))
+                .find(|dir| !dir.is_dir());
+            if let Some(dir) = missing {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{}: directory not found", dir.display()),
+                )
+                .into());
+            }
+        } else {
+            let (owner, mode) = (options.owner, options.mode);
+            let created = match owner.is_some() || mode.is_some() {
+                true => missing_directories(folder),
+                false => Vec::new(),
+            };
+            Maildir::from(folder.to_path_buf()).create_dirs()?;
+            for dir in created {
+                set_attributes(&dir, owner, mode)?;
+            }
+        }
+        self.0.lock().unwrap().insert(folder.to_path_buf());
+        Ok(())
+    }
+}
+
+/// Store an email in the `cur` directory of a maildir with the given info,
+/// returning its path
+fn store_with_info(
+    maildir: &Maildir,
+    data: &[u8],
+    info: &OsStr,
+) -> Result<PathBuf, MaildirArchiverError> {
+    let flags = info
+        .to_str()
+        .and_then(|info| info.strip_prefix("2,"))
+        .unwrap_or_default();
+    let id = maildir.store_cur_with_flags(data, flags)?;
+    let cur = maildir.path().join("cur");
+    let path = cur.join(format!("{}:2,{}", id, maildir_flags(flags, false)));
+    let verbatim = cur.join(maildir_name(OsStr::new(&id), info));
+    // The unique id is not used by other emails
+    if verbatim != path {
+        fs::rename(&path, &verbatim)?;
+    }
+    Ok(verbatim)
+}
+
+/// Store an email in the `cur` directory of a maildir with the given id and
+/// info, or with a new id if an email of the maildir already has it.
+/// Returns its path.
+fn store_with_id(
+    maildir: &Maildir,
+    id: &OsStr,
+    data: &[u8],
+    info: &OsStr,
+) -> Result<PathBuf, MaildirArchiverError> {
+    if id_exists(maildir, id) {
+        return store_with_info(maildir, data, info);
+    }
+    let tmp = maildir.path().join("tmp").join(id);
+    let mut file = OpenOptions::new().write(true).create_new(true).open(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    let path = maildir.path().join("cur").join(maildir_name(id, info));
+    // Unlike a rename, a hardlink doesn't replace an email stored meanwhile
+    let linked = fs::hard_link(&tmp, &path);
+    fs::remove_file(&tmp)?;
+    match linked {
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => store_with_info(maildir, data, info),
+        linked => Ok(linked.map(|()| path)?),
+    }
+}
+
+/// Check if an email of a maildir has the given id
+fn id_exists(maildir: &Maildir, id: &OsStr) -> bool {
+    maildir
+        .list_cur()
+        .chain(maildir.list_new())
+        .flatten()
+        .any(|mail| email_base(&mail) == id)
+}
+
+/// Unique name of a file written to a maildir by a batch, like
+/// `1700000000.M123456P42Q7.host`
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+fn unique_name() -> OsString {
+    static DELIVERIES: AtomicUsize = AtomicUsize::new(0);
+    let now = OffsetDateTime::now_utc();
+    let mut host = [0u8; 256];
+    // SAFETY: gethostname writes at most the length of the buffer
+    unsafe { libc::gethostname(host.as_mut_ptr().cast(), host.len()) };
+    let host = host.split(|b| *b == 0).next().unwrap_or_default();
+    let mut name = OsString::from(format!(
+        "{}.M{}P{}Q{}.",
+        now.unix_timestamp(),
+        now.microsecond(),
+        std::process::id(),
+        DELIVERIES.fetch_add(1, Ordering::Relaxed)
+    ));
+    name.push(OsStr::from_bytes(host));
+    name
+}
+
+/// Move a file of the `tmp` directory of a maildir to its `cur` directory
+/// with the given info, named after `id`, or after the file if `id` is
+/// missing or taken by an email stored meanwhile. Returns its path.
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+fn link_to_cur(
+    maildir: &Maildir,
+    tmp: &Path,
+    id: Option<&OsStr>,
+    info: &OsStr,
+) -> io::Result<PathBuf> {
+    let unique = tmp.file_name().unwrap();
+    let cur = maildir.path().join("cur");
+    let mut path = cur.join(maildir_name(id.unwrap_or(unique), info));
+    // Unlike a rename, a hardlink doesn't replace an email stored meanwhile
+    let mut linked = fs::hard_link(tmp, &path);
+    if id.is_some()
+        && linked
+            .as_ref()
+            .is_err_and(|e| e.kind() == io::ErrorKind::AlreadyExists)
+    {
+        path = cur.join(maildir_name(unique, info));
+        linked = fs::hard_link(tmp, &path);
+    }
+    fs::remove_file(tmp)?;
+    linked.map(|()| path)
+}
+
+/// Email of a write batch, written to the `tmp` directory of its archive
+/// folder
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+struct BatchedEmail {
+    maildir: Maildir,
+    tmp: PathBuf,
+    source: PathBuf,
+    /// Id kept with `--sync-safe`
+    id: Option<OsString>,
+    info: OsString,
+    digest: Option<String>,
+    size: u64,
+}
+
+/// Emails of the maildir sink written by batches with io_uring: each batch
+/// is written and fsynced in the `tmp` directories of the archive folders
+/// with one submission, and the written emails are moved to `cur` by
+/// [`ArchiveSink::finish`]
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+struct WriteBatch {
+    writer: crate::uring::BatchWriter,
+    /// Emails written by the next submission, with their files and content
+    queued: Mutex<Vec<(BatchedEmail, File, Vec<u8>)>>,
+    /// Emails written to `tmp`
+    written: Mutex<Vec<BatchedEmail>>,
+    /// First error of the batches, failing the completion of the sink
+    error: Mutex<Option<String>>,
+}
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+impl WriteBatch {
+    fn create() -> Option<Self> {
+        let Some(writer) = crate::uring::BatchWriter::create() else {
+            warn!("io_uring is not available, the emails are written without batches");
+            return None;
+        };
+        Some(WriteBatch {
+            writer,
+            queued: Mutex::new(Vec::new()),
+            written: Mutex::new(Vec::new()),
+            error: Mutex::new(None),
+        })
+    }
+
+    /// Write the queued emails
+    fn flush(&self) -> io::Result<()> {
+        let queued = std::mem::take(&mut *self.queued.lock().unwrap());
+        let (emails, mut writes): (Vec<_>, Vec<_>) = queued
+            .into_iter()
+            .map(|(email, file, data)| (email, (file, data)))
+            .unzip();
+        let result = self.writer.write(&mut writes).into_iter().collect();
+        self.written.lock().unwrap().extend(emails);
+        if let Err(e) = &result {
+            self.error
+                .lock()
+                .unwrap()
+                .get_or_insert_with(|| e.to_string());
+        }
+        result
+    }
+}
+
+/// Sink that stores emails in one maildir per archive folder
+#[derive(Default)]
+pub struct MaildirSink {
+    pub options: SinkOptions,
+    /// Content index, loaded at the first store
+    index: Mutex<Option<ContentIndex>>,
+    folders: ReadyFolders,
+    /// Batched writes, see [`SinkOptions::batch_writes`]
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    batch: Option<WriteBatch>,
+}
+
+impl MaildirSink {
+    pub fn new(options: SinkOptions) -> Self {
+        MaildirSink {
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            batch: options.batch_writes.then(WriteBatch::create).flatten(),
+            options,
+            ..Default::default()
+        }
+    }
+
+    /// Digest of the content of an email, for the content index
+    fn content_digest(&self, data: &[u8]) -> io::Result<Option<String>> {
+        match self.options.content_index {
+            Some(_) => Ok(Some(to_hex(&reader_digest(&mut &data[..])?))),
+            None => Ok(None),
+        }
+    }
+
+    /// Replace a stored email with a hardlink to an archived email with the
+    /// same content, or index it. Returns whether the email was replaced.
+    fn link_duplicate(
+        &self,
+        index_path: &Path,
+        path: &Path,
+        digest: String,
+        size: u64,
+    ) -> io::Result<bool> {
+        let mut index = self.index.lock().unwrap();
+        if index.is_none() {
+            *index = Some(ContentIndex::load(index_path)?);
+        }
+        let index = index.as_mut().unwrap();
+        let original = index
+            .get(&digest)
+            .filter(|original| fs::metadata(original).is_ok_and(|m| m.len() == size))
+            .map(Path::to_path_buf);
+        if let Some(original) = original {
+            match replace_with_hardlink(&original, path) {
+                Ok(()) => {
+                    debug!("{} is a hardlink to {}", path.display(), original.display());
+                    return Ok(true);
+                }
+                // Other filesystem or too many links: keep the copy
+                Err(e) => warn!("Cannot link {}: {}", path.display(), e),
+            }
+        }
+        index.insert(digest, path.to_path_buf());
+        Ok(false)
+    }
+
+    /// Complete the store of the email of `source` at `path`: replace it with
+    /// a hardlink to an email with the same digest, or set its attributes
+    fn complete(
+        &self,
+        source: &Path,
+        path: &Path,
+        digest: Option<String>,
+        size: u64,
+    ) -> Result<(), MaildirArchiverError> {
+        if let (Some(index_path), Some(digest)) = (&self.options.content_index, digest) {
+            // A hardlink shares the attributes of the archived email
+            if self.link_duplicate(index_path, path, digest, size)? {
+                return Ok(());
+            }
+        }
+        if self.options.preserve_perms {
+            copy_permissions(source, path)?;
+        }
+        if self.options.preserve_xattrs {
+            copy_xattrs(source, path)?;
+        }
+        set_attributes(path, self.options.owner, self.options.mode)?;
+        Ok(())
+    }
+
+    /// Queue an email in the write batch, writing the batch once full
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    fn queue(
+        &self,
+        batch: &WriteBatch,
+        email: BatchedEmail,
+        data: &[u8],
+    ) -> Result<(), MaildirArchiverError> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&email.tmp)?;
+        let mut queued = batch.queued.lock().unwrap();
+        queued.push((email, file, data.to_vec()));
+        let full = queued.len() >= crate::uring::WRITE_BATCH_DEPTH as usize;
+        drop(queued);
+        if full {
+            batch.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Write the last batch, then move the written emails to `cur`. The
+    /// written emails are removed instead if a batch failed.
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    fn deliver(&self, batch: &WriteBatch) -> Result<(), MaildirArchiverError> {
+        // A failed write is recorded in the error of the batch
+        let _ = batch.flush();
+        let written = std::mem::take(&mut *batch.written.lock().unwrap());
+        if let Some(error) = batch.error.lock().unwrap().take() {
+            for email in &written {
+                if let Err(e) = fs::remove_file(&email.tmp) {
+                    warn!("Cannot remove {}: {}", email.tmp.display(), e);
+                }
+            }
+            return Err(io::Error::other(format!("batched write failed: {}", error)).into());
+        }
+        for email in written {
+            let id = email
+                .id
+                .as_deref()
+                .filter(|id| !id_exists(&email.maildir, id));
+            let path = link_to_cur(&email.maildir, &email.tmp, id, &email.info)?;
+            self.complete(&email.source, &path, email.digest, email.size)?;
+        }
+        Ok(())
+    }
+}
+
+impl ArchiveSink for MaildirSink {
+    fn store(
+        &self,
+        folder: &Path,
+        mail: &MailEntry,
+        data: &[u8],
+    ) -> Result<(), MaildirArchiverError> {
+        let to_maildir = Maildir::from(folder.to_path_buf());
+        self.folders.prepare(folder, &self.options)?;
+        let info = maildir_info(mail, self.options.normalize_flags);
+        let digest = self.content_digest(data)?;
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if let Some(batch) = &self.batch {
+            let email = BatchedEmail {
+                tmp: to_maildir.path().join("tmp").join(unique_name()),
+                maildir: to_maildir,
+                source: mail.path().to_path_buf(),
+                id: self
+                    .options
+                    .sync_safe
+                    .then(|| strip_sync_markers(email_base(mail))),
+                info,
+                digest,
+                size: data.len() as u64,
+            };
+            return self.queue(batch, email, data);
+        }
+        let path = match self.options.sync_safe {
+            true => store_with_id(
+                &to_maildir,
+                &strip_sync_markers(email_base(mail)),
+                data,
+                &info,
+            )?,
+            false => store_with_info(&to_maildir, data, &info)?,
+        };
+        self.complete(mail.path(), &path, digest, data.len() as u64)
+    }
+
+    fn finish(&self) -> Result<(), MaildirArchiverError> {
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if let Some(batch) = &self.batch {
+            self.deliver(batch)?;
+        }
+        if let Some(index) = self.index.lock().unwrap().as_mut() {
+            index.save()?;
+        }
+        Ok(())
+    }
+
+    /// With batched writes, the emails are moved to `cur` by finish
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    fn deferred(&self) -> bool {
+        self.batch.is_some()
+    }
+}
+
+/// Sink that stores emails in one zip file per archive folder
+/// (`<folder>.zip`), with an entry per email named after its maildir file.
+///
+/// The emails are written to a temporary copy of the zip file
+/// (`<folder>.zip.tmp`), renamed over the zip file by
+/// [`ArchiveSink::finish`] once completed: an interrupted run leaves the
+/// zip files of the earlier runs intact.
+#[cfg(feature = "compression")]
+#[derive(Default)]
+pub struct ZipSink {
+    writers: Mutex<HashMap<PathBuf, ZipWriter<File>>>,
+}
+
+#[cfg(feature = "compression")]
+impl ZipSink {
+    fn open(path: &Path) -> Result<ZipWriter<File>, MaildirArchiverError> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let tmp = path_with_suffix(path, ".tmp");
+        match path.exists() {
+            true => {
+                fs::copy(path, &tmp)?;
+                let file = OpenOptions::new().read(true).write(true).open(&tmp)?;
+                Ok(ZipWriter::new_append(file)?)
+            }
+            false => Ok(ZipWriter::new(File::create(&tmp)?)),
+        }
+    }
+}
+
+#[cfg(feature = "compression")]
+impl ArchiveSink for ZipSink {
+    fn store(
+        &self,
+        folder: &Path,
+        mail: &MailEntry,
+        data: &[u8],
+    ) -> Result<(), MaildirArchiverError> {
+        let path = path_with_suffix(folder, ".zip");
+        let mut writers = self.writers.lock().unwrap();
+        if !writers.contains_key(&path) {
+            let writer = ZipSink::open(&path)?;
+            writers.insert(path.clone(), writer);
+        }
+        let writer = writers.get_mut(&path).unwrap();
+        let name = mail.path().file_name().unwrap().to_string_lossy();
+        writer.start_file(name, SimpleFileOptions::default())?;
+        writer.write_all(data)?;
+        Ok(())
+    }
+
+    fn finish(&self) -> Result<(), MaildirArchiverError> {
+        for (path, writer) in self.writers.lock().unwrap().drain() {
+            writer.finish()?.sync_all()?;
+            fs::rename(path_with_suffix(&path, ".tmp"), &path)?;
+        }
+        Ok(())
+    }
+
+    fn deferred(&self) -> bool {
+        true
+    }
+}
+
+/// Sink that stores emails in one tar file per archive folder
+/// (`<folder>.tar`), with an entry per email named after its maildir file.
+///
+/// Like the zip sink, the emails are written to a temporary copy of the tar
+/// file (`<folder>.tar.tmp`), renamed over the tar file by
+/// [`ArchiveSink::finish`].
+#[cfg(feature = "compression")]
+#[derive(Default)]
+pub struct TarSink {
+    builders: Mutex<HashMap<PathBuf, tar::Builder<File>>>,
+}
+
+#[cfg(feature = "compression")]
+impl TarSink {
+    /// Open the temporary copy of a tar file, positioned before its end of
+    /// archive marker
+    fn open(path: &Path) -> Result<tar::Builder<File>, MaildirArchiverError> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let tmp = path_with_suffix(path, ".tmp");
+        if !path.exists() {
+            return Ok(tar::Builder::new(File::create(&tmp)?));
+        }
+        fs::copy(path, &tmp)?;
+        let mut end = 0;
+        for entry in tar::Archive::new(File::open(&tmp)?).entries()? {
+            let entry = entry?;
+            end = entry.raw_file_position() + entry.size().div_ceil(512) * 512;
+        }
+        let mut file = OpenOptions::new().write(true).open(&tmp)?;
+        file.set_len(end)?;
+        file.seek(SeekFrom::Start(end))?;
+        Ok(tar::Builder::new(file))
+    }
+}
+
+#[cfg(feature = "compression")]
+impl ArchiveSink for TarSink {
+    fn store(
+        &self,
+        folder: &Path,
+        mail: &MailEntry,
+        data: &[u8],
+    ) -> Result<(), MaildirArchiverError> {
+        let path = path_with_suffix(folder, ".tar");
+        let mut builders = self.builders.lock().unwrap();
+        if !builders.contains_key(&path) {
+            let builder = TarSink::open(&path)?;
+            builders.insert(path.clone(), builder);
+        }
+        let builder = builders.get_mut(&path).unwrap();
+        let modified = fs::metadata(mail.path())?.modified()?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o600);
+        header.set_mtime(OffsetDateTime::from(modified).unix_timestamp().max(0) as u64);
+        let name = Path::new(mail.path().file_name().unwrap());
+        builder.append_data(&mut header, name, data)?;
+        Ok(())
+    }
+
+    fn finish(&self) -> Result<(), MaildirArchiverError> {
+        for (path, builder) in self.builders.lock().unwrap().drain() {
+            builder.into_inner()?.sync_all()?;
+            fs::rename(path_with_suffix(&path, ".tmp"), &path)?;
+        }
+        Ok(())
+    }
+
+    fn deferred(&self) -> bool {
+        true
+    }
+}
+
+/// Sink that appends emails to one mboxrd file per archive folder
+/// (`<folder>.mbox`).
+///
+/// The `From ` line carries the date of the `Received` header of the email,
+/// or the modification time of its file.
+pub struct MboxSink {}
+
+impl ArchiveSink for MboxSink {
+    fn store(
+        &self,
+        folder: &Path,
+        mail: &MailEntry,
+        data: &[u8],
+    ) -> Result<(), MaildirArchiverError> {
+        let path = path_with_suffix(folder, ".mbox");
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let date = match received_date(data) {
+            Some(date) => date,
+            None => OffsetDateTime::from(std::fs::metadata(mail.path())?.modified()?),
+        };
+        let mut message = Vec::new();
+        write_mbox_message(&mut message, data, mail.flags(), date)?;
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        file.write_all(&message)?;
+        Ok(())
+    }
+}
+
+/// Content-addressable sink: the content of each email is stored once, as
+/// `objects/<xx>/<digest>` next to the content index, where `<digest>` is
+/// the hexadecimal SHA-256 digest of the content and `<xx>` its first two
+/// characters. The archive folders are maildirs of hardlinks to the objects,
+/// or of symlinks if they are on another filesystem. The content index maps
+/// the digests to the objects.
+#[derive(Default)]
+pub struct CasSink {
+    pub options: SinkOptions,
+    index: Mutex<Option<ContentIndex>>,
+    folders: ReadyFolders,
+}
+
+impl CasSink {
+    pub fn new(options: SinkOptions) -> Self {
+        CasSink {
+            options,
+            ..Default::default()
+        }
+    }
+
+    /// Write an object if it is not stored yet, returning its path
+    fn store_object(&self, root: &Path, digest: &str, data: &[u8]) -> io::Result<PathBuf> {
+        let object = root.join("objects").join(&digest[..2]).join(digest);
+        if object.exists() {
+            return Ok(object);
+        }
+        let dir = object.parent().unwrap();
+        let (owner, mode) = (self.options.owner, self.options.mode);
+        // Only the objects directory and the object directories are created
+        let created: Vec<_> = [dir.parent().unwrap(), dir]
+            .into_iter()
+            .filter(|d| !d.exists())
+            .map(Path::to_path_buf)
+            .collect();
+        fs::create_dir_all(dir)?;
+        for dir in created {
+            set_attributes(&dir, owner, mode)?;
+        }
+        let tmp = dir.join(format!(".{}.tmp", digest));
+        fs::write(&tmp, data)?;
+        set_attributes(&tmp, owner, mode)?;
+        fs::rename(&tmp, &object)?;
+        Ok(object)
+    }
+}
+
+impl ArchiveSink for CasSink {
+    fn store(
+        &self,
+        folder: &Path,
+        mail: &MailEntry,
+        data: &[u8],
+    ) -> Result<(), MaildirArchiverError> {
+        let index_path = self.options.content_index.as_deref().ok_or_else(|| {
+            MaildirArchiverError::Unsupported("The cas format needs a content index".to_string())
+        })?;
+        let root = index_path.parent().unwrap_or(Path::new(""));
+        let digest = to_hex(&reader_digest(&mut &data[..])?);
+        let object = self.store_object(root, &digest, data)?;
+
+        self.folders.prepare(folder, &self.options)?;
+        let info = maildir_info(mail, self.options.normalize_flags);
+        let id = email_base(mail);
+        let cur = folder.join("cur");
+        let mut name = maildir_name(id, &info);
+        let mut n = 1;
+        while fs::symlink_metadata(cur.join(&name)).is_ok() {
+            let mut base = id.to_os_string();
+            base.push(format!(".{}", n));
+            name = maildir_name(&base, &info);
+            n += 1;
+        }
+        let link = cur.join(name);
+        if let Err(e) = fs::hard_link(&object, &link) {
+            debug!("Cannot hardlink {}: {}", object.display(), e);
+            std::os::unix::fs::symlink(fs::canonicalize(&object)?, &link)?;
+        }
+
+        let mut index = self.index.lock().unwrap();
+        if index.is_none() {
+            *index = Some(ContentIndex::load(index_path)?);
+        }
+        let index = index.as_mut().unwrap();
+        if index.get(&digest) != Some(&object) {
+            index.insert(digest, object);
+        }
+        Ok(())
+    }
+
+    fn finish(&self) -> Result<(), MaildirArchiverError> {
+        if let Some(index) = self.index.lock().unwrap().as_mut() {
+            index.save()?;
+        }
+        Ok(())
+    }
+}
+
+/// Date of the first `Received` header of an email
+fn received_date(data: &[u8]) -> Option<OffsetDateTime> {
+    let (headers, _) = mailparse::parse_headers(data).ok()?;
+    let received = headers.get_first_value("Received")?;
+    let timestamp = mailparse::dateparse(received.rsplit(';').next()?).ok()?;
+    OffsetDateTime::from_unix_timestamp(timestamp).ok()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchiveFormat {
+    Maildir,
+    #[cfg(feature = "compression")]
+    Zip,
+    #[cfg(feature = "compression")]
+    Tar,
+    Mbox,
+    Cas,
+    Git,
+}
+
+/// Create the sink of an archive format. The options apply to the maildir
+/// and cas sinks only, except `normalize_flags` and `git_dir` for the git
+/// sink.
+pub fn create_archive_sink(format: ArchiveFormat, options: &SinkOptions) -> Box<dyn ArchiveSink> {
+    match format {
+        ArchiveFormat::Maildir => Box::new(MaildirSink::new(options.clone())),
+        #[cfg(feature = "compression")]
+        ArchiveFormat::Zip => Box::new(ZipSink::default()),
+        #[cfg(feature = "compression")]
+        ArchiveFormat::Tar => Box::new(TarSink::default()),
+        ArchiveFormat::Mbox => Box::new(MboxSink {}),
+        ArchiveFormat::Cas => Box::new(CasSink::new(options.clone())),
+        ArchiveFormat::Git => Box::new(GitSink::new(
+            options.git_dir.as_deref().unwrap_or(Path::new(".")),
+            options.normalize_flags,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dedup::CONTENT_INDEX_FILE;
+    use crate::sink::{
+        email_base, maildir_flags, maildir_info, ArchiveSink, CasSink, MaildirSink, MboxSink,
+        SinkOptions,
+    };
+    use maildir::Maildir;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    #[test]
+    fn test_maildir_flags() {
+        assert_eq!(maildir_flags("SRS", false), "RS");
+        assert_eq!(maildir_flags("S-R!", false), "!-RS");
+        assert_eq!(maildir_flags("S-R!", true), "RS");
+
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let maildir = Maildir::from(basedir.join("in"));
+        maildir.create_dirs().unwrap();
+        let id = maildir
+            .store_cur_with_flags(b"Subject: a\r\n\r\nbody\r\n", "TS")
+            .unwrap();
+        let mail = maildir.find(&id).unwrap();
+        let sink = MaildirSink::new(SinkOptions {
+            normalize_flags: true,
+            preserve_perms: true,
+            ..Default::default()
+        });
+        let folder = basedir.join("out");
+        sink.store(&folder, &mail, b"Subject: a\r\n\r\nbody\r\n")
+            .unwrap();
+        let archived = Maildir::from(folder).list_cur().next().unwrap().unwrap();
+        assert_eq!(archived.flags(), "ST");
+        let mode = |path| std::fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode(archived.path()), mode(mail.path()));
+
+        // Without normalization the info is kept verbatim, Dovecot keywords
+        // included
+        let path = maildir.path().join("cur").join("1463868505.b.host:2,Sba");
+        std::fs::write(path, b"Subject: b\r\n\r\nbody\r\n").unwrap();
+        let mail = maildir.find("1463868505.b.host").unwrap();
+        assert_eq!(maildir_info(&mail, true), "2,Sab");
+        let sink = MaildirSink::default();
+        let folder = basedir.join("verbatim");
+        sink.store(&folder, &mail, b"Subject: b\r\n\r\nbody\r\n")
+            .unwrap();
+        let archived = Maildir::from(folder).list_cur().next().unwrap().unwrap();
+        assert!(archived.path().to_str().unwrap().ends_with(":2,Sba"));
+    }
+
+    #[test]
+    fn test_sync_safe() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let maildir = Maildir::from(basedir.join("in"));
+        maildir.create_dirs().unwrap();
+        let data = b"Subject: a\r\n\r\nbody\r\n";
+        let path = maildir
+            .path()
+            .join("cur")
+            .join("1463868505.a.host,U=42:2,S");
+        std::fs::write(path, data).unwrap();
+        let mail = maildir.find("1463868505.a.host,U=42").unwrap();
+        let sink = MaildirSink::new(SinkOptions {
+            sync_safe: true,
+            ..Default::default()
+        });
+        let folder = Maildir::from(basedir.join("out"));
+        sink.store(folder.path(), &mail, data).unwrap();
+        assert!(folder.find("1463868505.a.host").is_some());
+        // The id is taken by the first copy
+        sink.store(folder.path(), &mail, data).unwrap();
+        assert_eq!(folder.count_cur(), 2);
+        assert_eq!(
+            std::fs::read_dir(folder.path().join("tmp"))
+                .unwrap()
+                .count(),
+            0
+        );
+    }
+
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    #[test]
+    fn test_batch_writes() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let maildir = Maildir::from(basedir.join("in"));
+        maildir.create_dirs().unwrap();
+        let data = b"Subject: a\r\n\r\nbody\r\n";
+        let path = maildir
+            .path()
+            .join("cur")
+            .join("1463868505.a.host,U=42:2,S");
+        std::fs::write(path, data).unwrap();
+        let mail = maildir.find("1463868505.a.host,U=42").unwrap();
+        let sink = MaildirSink::new(SinkOptions {
+            sync_safe: true,
+            batch_writes: true,
+            ..Default::default()
+        });
+        // io_uring may be forbidden, like in some containers
+        if !sink.deferred() {
+            return;
+        }
+        let folder = Maildir::from(basedir.join("out"));
+        let tmp_count = || {
+            std::fs::read_dir(folder.path().join("tmp"))
+                .unwrap()
+                .count()
+        };
+        for _ in 0..40 {
+            sink.store(folder.path(), &mail, data).unwrap();
+        }
+        // The emails are written to tmp, and only moved to cur at the end
+        assert_eq!(tmp_count(), 40);
+        assert_eq!(folder.count_cur(), 0);
+        sink.finish().unwrap();
+        assert_eq!(tmp_count(), 0);
+        assert_eq!(folder.count_cur(), 40);
+        let kept = folder.find("1463868505.a.host").unwrap();
+        assert_eq!(std::fs::read(kept.path()).unwrap(), data);
+        assert!(folder.list_cur().flatten().all(|m| m.flags() == "S"));
+    }
+
+    #[test]
+    fn test_non_utf8_names() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let maildir = Maildir::from(basedir.join("in"));
+        maildir.create_dirs().unwrap();
+        let data = b"Subject: a\r\n\r\nbody\r\n";
+        let name = OsStr::from_bytes(b"1463868505.\xff.host,U=42:2,S\xfe");
+        std::fs::write(maildir.path().join("cur").join(name), data).unwrap();
+        let mail = maildir.list_cur().next().unwrap().unwrap();
+        assert_eq!(email_base(&mail).as_bytes(), b"1463868505.\xff.host,U=42");
+        assert_eq!(maildir_info(&mail, false).as_bytes(), b"2,S\xfe");
+        let sink = MaildirSink::new(SinkOptions {
+            sync_safe: true,
+            ..Default::default()
+        });
+        let folder = basedir.join("out");
+        sink.store(&folder, &mail, data).unwrap();
+        let archived = std::fs::read_dir(folder.join("cur"))
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            archived.file_name().as_bytes(),
+            b"1463868505.\xff.host:2,S\xfe"
+        );
+    }
+
+    #[test]
+    fn test_content_index() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let maildir = Maildir::from(std::path::PathBuf::from("testdata/maildir1"));
+        let mail = maildir.list_cur().next().unwrap().unwrap();
+        let data = std::fs::read(mail.path()).unwrap();
+        let options = SinkOptions {
+            content_index: Some(basedir.join(CONTENT_INDEX_FILE)),
+            ..Default::default()
+        };
+        let sink = MaildirSink::new(options.clone());
+        sink.store(&basedir.join("2016"), &mail, &data).unwrap();
+        sink.store(&basedir.join("all"), &mail, &data).unwrap();
+        sink.finish().unwrap();
+
+        // The next runs find the archived email in the saved index
+        let sink = MaildirSink::new(options);
+        sink.store(&basedir.join("2017"), &mail, &data).unwrap();
+        sink.store(&basedir.join("2017"), &mail, b"Subject: b\n\nbody\n")
+            .unwrap();
+        sink.finish().unwrap();
+        let nlink = |folder: &str| {
+            let archived = Maildir::from(basedir.join(folder));
+            let archived = archived.list_cur().next().unwrap().unwrap();
+            std::fs::metadata(archived.path()).unwrap().nlink()
+        };
+        assert_eq!(nlink("2016"), 3);
+        assert_eq!(nlink("all"), 3);
+        let index = std::fs::read_to_string(basedir.join(CONTENT_INDEX_FILE)).unwrap();
+        assert_eq!(index.lines().count(), 2);
+        assert!(index.contains("\t2016/cur/"));
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_zip_sink() {
+        use crate::sink::ZipSink;
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let maildir = Maildir::from(basedir.join("in"));
+        maildir.create_dirs().unwrap();
+        let filename = "1463868505.38518452d49213cb409aa1db32f53184:2,S";
+        for name in [filename, "1463868506.test:2,S"] {
+            std::fs::copy(
+                format!("testdata/maildir1/cur/{}", filename),
+                maildir.path().join("cur").join(name),
+            )
+            .unwrap();
+        }
+
+        // The second run appends to the zip file of the first one, left
+        // intact until the run completes it
+        let entries = || {
+            let file = std::fs::File::open(basedir.join("2016.zip")).unwrap();
+            zip::ZipArchive::new(file).unwrap().len()
+        };
+        for (i, mail) in maildir.list_cur().enumerate() {
+            let mail = mail.unwrap();
+            let data = std::fs::read(mail.path()).unwrap();
+            let sink = ZipSink::default();
+            sink.store(&basedir.join("2016"), &mail, &data).unwrap();
+            assert_eq!(basedir.join("2016.zip").exists(), i > 0);
+            if i > 0 {
+                assert_eq!(entries(), 1);
+            }
+            sink.finish().unwrap();
+        }
+        assert_eq!(entries(), 2);
+        assert!(!basedir.join("2016.zip.tmp").exists());
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_tar_sink() {
+        use crate::sink::TarSink;
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let maildir = Maildir::from(std::path::PathBuf::from("testdata/maildir1"));
+        // The second run appends to the tar file of the first one
+        for mail in maildir.list_cur() {
+            let mail = mail.unwrap();
+            let data = std::fs::read(mail.path()).unwrap();
+            let sink = TarSink::default();
+            sink.store(&basedir.join("all"), &mail, &data).unwrap();
+            sink.finish().unwrap();
+        }
+
+        let file = std::fs::File::open(basedir.join("all.tar")).unwrap();
+        let mut names: Vec<_> = tar::Archive::new(file)
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().into_owned())
+            .collect();
+        names.sort();
+        assert_eq!(names.len(), 2);
+        assert!(maildir.path().join("cur").join(&names[0]).exists());
+        assert!(!basedir.join("all.tar.tmp").exists());
+    }
+
+    #[test]
+    fn test_mbox_sink() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let maildir = Maildir::from(std::path::PathBuf::from("testdata/maildir1"));
+        let sink = MboxSink {};
+        for mail in maildir.list_cur() {
+            let mail = mail.unwrap();
+            let data = std::fs::read(mail.path()).unwrap();
+            sink.store(&basedir.join("all"), &mail, &data).unwrap();
+        }
+
+        let mbox = std::fs::read_to_string(basedir.join("all.mbox")).unwrap();
+        let separators: Vec<_> = mbox.lines().filter(|l| l.starts_with("From ")).collect();
+        assert_eq!(separators.len(), 2);
+        assert!(mbox.contains("From MAILER-DAEMON Sat May 21 22:08:27 2016\n"));
+    }
+
+    #[test]
+    fn test_cas_sink() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let maildir = Maildir::from(std::path::PathBuf::from("testdata/maildir1"));
+        let mail = maildir.list_cur().next().unwrap().unwrap();
+        let data = std::fs::read(mail.path()).unwrap();
+        let sink = CasSink::new(SinkOptions {
+            content_index: Some(basedir.join(CONTENT_INDEX_FILE)),
+            ..Default::default()
+        });
+        for folder in ["2016", "2016", "all"] {
+            sink.store(&basedir.join(folder), &mail, &data).unwrap();
+        }
+        sink.finish().unwrap();
+
+        let objects: Vec<_> = std::fs::read_dir(basedir.join("objects"))
+            .unwrap()
+            .collect();
+        assert_eq!(objects.len(), 1);
+        assert_eq!(Maildir::from(basedir.join("2016")).count_cur(), 2);
+        let archived = Maildir::from(basedir.join("all"));
+        let archived = archived.list_cur().next().unwrap().unwrap();
+        assert_eq!(std::fs::metadata(archived.path()).unwrap().nlink(), 4);
+        assert_eq!(std::fs::read(archived.path()).unwrap(), data);
+        let index = std::fs::read_to_string(basedir.join(CONTENT_INDEX_FILE)).unwrap();
+        assert!(index.contains("\tobjects/"));
+    }
+}
@@ -0,0 +1,130 @@
+use crate::folders::SourceFolder;
+use log::info;
+use maildir::MailEntry;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use time::macros::format_description;
+use time::OffsetDateTime;
+
+/// Check if a directory name is the timestamp of a snapshot, like
+/// `20240131T235959Z` or `20240131T235959Z.1`
+fn is_snapshot_name(name: &str) -> bool {
+    let bytes = name.as_bytes();
+    bytes.len() >= 16
+        && bytes[..8].iter().all(u8::is_ascii_digit)
+        && bytes[8] == b'T'
+        && bytes[9..15].iter().all(u8::is_ascii_digit)
+        && bytes[15] == b'Z'
+}
+
+/// Safety copy of the emails removed by a run, taken before removing them.
+///
+/// A snapshot is a timestamped directory with the layout of the input
+/// maildir, whose emails are hardlinks to the source emails, or copies if
+/// the snapshot is on another filesystem. Restoring a snapshot is moving its
+/// emails back to the input maildir.
+pub struct Snapshot {
+    pub dir: PathBuf,
+    /// Copy the emails instead of hardlinking them, for the runs overwriting
+    /// the removed emails, which would overwrite the hardlinks too
+    copy: bool,
+}
+
+impl Snapshot {
+    /// Create a new snapshot under `root`, named after the current time
+    pub fn create(root: &Path, copy: bool) -> io::Result<Self> {
+        let timestamp = OffsetDateTime::now_utc()
+            .format(format_description!(
+                "[year][month][day]T[hour][minute][second]Z"
+            ))
+            .map_err(io::Error::other)?;
+        fs::create_dir_all(root)?;
+        let mut dir = root.join(&timestamp);
+        let mut n = 1;
+        loop {
+            match fs::create_dir(&dir) {
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    dir = root.join(format!("{}.{}", timestamp, n));
+                    n += 1;
+                }
+                res => return res.map(|()| Snapshot { dir, copy }),
+            }
+        }
+    }
+
+    /// Save an email of a source folder in the snapshot
+    pub fn save(&self, folder: &SourceFolder, mail: &MailEntry) -> io::Result<()> {
+        let subdir = mail.path().parent().and_then(Path::file_name).unwrap();
+        let dir = self.dir.join(&folder.name).join(subdir);
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(mail.path().file_name().unwrap());
+        if self.copy || fs::hard_link(mail.path(), &path).is_err() {
+            // The copy may be a reflink on the filesystems supporting them
+            fs::copy(mail.path(), &path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Remove the oldest snapshots of `root`, keeping the `keep` most recent
+/// ones. Returns the number of removed snapshots.
+pub fn prune_snapshots(root: &Path, keep: usize) -> io::Result<usize> {
+    let mut snapshots = Vec::new();
+    for entry in fs::read_dir(root)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if entry.file_type()?.is_dir() && is_snapshot_name(&name) {
+            snapshots.push(entry.path());
+        }
+    }
+    // The timestamps sort in chronological order
+    snapshots.sort();
+    let count = snapshots.len().saturating_sub(keep);
+    for snapshot in &snapshots[..count] {
+        fs::remove_dir_all(snapshot)?;
+        info!("Removed snapshot {}", snapshot.display());
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::folders::SourceFolder;
+    use crate::snapshot::{is_snapshot_name, prune_snapshots, Snapshot};
+    use maildir::Maildir;
+
+    #[test]
+    fn test_snapshot() {
+        assert!(is_snapshot_name("20240131T235959Z"));
+        assert!(is_snapshot_name("20240131T235959Z.1"));
+        assert!(!is_snapshot_name("2024-01-31"));
+
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let folder = SourceFolder {
+            name: ".Sent".into(),
+            maildir: Maildir::from(basedir.join("mail").join(".Sent")),
+        };
+        folder.maildir.create_dirs().unwrap();
+        let id = folder
+            .maildir
+            .store_cur_with_flags(b"Subject: a\n\nbody\n", "S")
+            .unwrap();
+        let mail = folder.maildir.find(&id).unwrap();
+
+        let root = basedir.join("snapshots");
+        let snapshots: Vec<_> = (0..3)
+            .map(|_| Snapshot::create(&root, false).unwrap())
+            .collect();
+        snapshots[2].save(&folder, &mail).unwrap();
+        let saved = Maildir::from(snapshots[2].dir.join(".Sent"));
+        assert_eq!(saved.count_cur(), 1);
+        std::fs::create_dir(root.join("other")).unwrap();
+
+        assert_eq!(prune_snapshots(&root, 2).unwrap(), 1);
+        assert!(!snapshots[0].dir.exists());
+        assert!(snapshots[2].dir.exists());
+        assert!(root.join("other").exists());
+    }
+}
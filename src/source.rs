@@ -0,0 +1,60 @@
+use crate::archiver::MaildirArchiverError;
+use crate::folders::SourceFolder;
+use maildir::MailEntry;
+use std::borrow::Cow;
+
+/// Trait implemented by the inputs of the archive runs.
+///
+/// The emails are yielded as [`MailEntry`], giving their id, flags, path and
+/// headers to the filters and the archivers: a remote source stages its
+/// emails in a local maildir.
+pub trait MailSource {
+    /// Name of the source used in the log messages
+    fn name(&self) -> Cow<'_, str>;
+
+    /// Number of emails of the source, for the progress messages
+    fn count(&self) -> usize;
+
+    /// Emails of the source, not parsed yet
+    fn emails(&self) -> Box<dyn Iterator<Item = Result<MailEntry, MaildirArchiverError>> + '_>;
+}
+
+/// Folder of a local maildir: the emails of its `cur` directory
+impl MailSource for SourceFolder {
+    fn name(&self) -> Cow<'_, str> {
+        self.display_name()
+    }
+
+    fn count(&self) -> usize {
+        self.maildir.count_cur()
+    }
+
+    fn emails(&self) -> Box<dyn Iterator<Item = Result<MailEntry, MaildirArchiverError>> + '_> {
+        Box::new(self.maildir.list_cur().map(|entry| Ok(entry?)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::folders::SourceFolder;
+    use crate::source::MailSource;
+    use maildir::Maildir;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_maildir_source() {
+        let folder = SourceFolder {
+            name: Default::default(),
+            maildir: Maildir::from(PathBuf::from("testdata/maildir1")),
+        };
+        let source: &dyn MailSource = &folder;
+        assert_eq!(source.name(), "INBOX");
+        assert_eq!(source.count(), 2);
+        let mut ids: Vec<_> = source
+            .emails()
+            .map(|mail| mail.unwrap().id().to_string())
+            .collect();
+        ids.sort();
+        assert_eq!(ids[0], "1463868505.38518452d49213cb409aa1db32f53184");
+    }
+}
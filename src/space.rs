@@ -0,0 +1,74 @@
+use crate::archiver::MaildirArchiverError;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// Nearest existing ancestor of a path, where the archive folders not
+/// created yet will be written
+fn existing_ancestor(path: &Path) -> io::Result<&Path> {
+    path.ancestors()
+        .find(|p| p.exists())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, path.display().to_string()))
+}
+
+/// Space available to unprivileged users on the filesystem of `path`, in
+/// bytes
+pub fn available_space(path: &Path) -> io::Result<u64> {
+    let path = CString::new(existing_ancestor(path)?.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `path` is a valid C string and `stat` a valid statvfs struct
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // The types of the fields depend on the platform
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Check that the filesystems of the archive folders can hold the sizes to
+/// write to them, before writing anything
+pub fn check_free_space(sizes: &BTreeMap<PathBuf, u64>) -> Result<(), MaildirArchiverError> {
+    // Archive folders of the same filesystem share its free space
+    let mut required: HashMap<u64, (&Path, u64)> = HashMap::new();
+    for (folder, size) in sizes {
+        let dev = existing_ancestor(folder)?.metadata()?.dev();
+        required.entry(dev).or_insert((folder, 0)).1 += size;
+    }
+    for (folder, size) in required.into_values() {
+        let available = available_space(folder)?;
+        if size > available {
+            return Err(MaildirArchiverError::InsufficientSpace {
+                path: folder.to_path_buf(),
+                required: size,
+                available,
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::archiver::MaildirArchiverError;
+    use crate::space::{available_space, check_free_space};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_check_free_space() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let folder = basedir.join("archive").join("2016");
+        let available = available_space(&folder).unwrap();
+        assert!(available > 0);
+        let mut sizes = BTreeMap::from([(folder.clone(), 1024)]);
+        check_free_space(&sizes).unwrap();
+        sizes.insert(basedir.join("2017"), u64::MAX - 1024);
+        assert!(matches!(
+            check_free_space(&sizes),
+            Err(MaildirArchiverError::InsufficientSpace { .. })
+        ));
+    }
+}
@@ -0,0 +1,68 @@
+use crate::archiver::MaildirArchiverError;
+use crate::filter::spam_score;
+use maildir::MailEntry;
+use mailparse::MailHeaderMap;
+use std::path::PathBuf;
+use time::{Date, Duration, OffsetDateTime};
+
+/// Routing of the emails classified as spam, archived in their own archive
+/// tree or purged
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpamRouting {
+    /// Spam score above which an email is spam
+    pub threshold: f64,
+    /// Directory of the spam archive, the output directory if `None`
+    pub output_dir: Option<PathBuf>,
+    /// Delete the spam older than the given number of days instead of
+    /// archiving it
+    pub purge_after: Option<i64>,
+}
+
+impl SpamRouting {
+    /// Check if an email is spam: flagged by `X-Spam-Flag` or with a spam
+    /// score above the threshold
+    pub fn is_spam(&self, mail: &mut MailEntry) -> Result<bool, MaildirArchiverError> {
+        let flagged = mail
+            .headers()?
+            .get_first_value("X-Spam-Flag")
+            .is_some_and(|v| v.trim().eq_ignore_ascii_case("yes"));
+        Ok(flagged || spam_score(mail)?.is_some_and(|score| score > self.threshold))
+    }
+
+    /// Date before which the spam is purged
+    pub fn purge_before(&self) -> Option<Date> {
+        self.purge_after
+            .map(|days| (OffsetDateTime::now_utc() - Duration::days(days)).date())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::spam::SpamRouting;
+    use maildir::Maildir;
+
+    #[test]
+    fn test_is_spam() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let maildir = Maildir::from(basedir.to_path_buf());
+        maildir.create_dirs().unwrap();
+        let email = |headers: &str| {
+            let data = format!("{}Subject: a\r\n\r\nbody\r\n", headers);
+            let id = maildir.store_cur_with_flags(data.as_bytes(), "S").unwrap();
+            maildir.find(&id).unwrap()
+        };
+        let routing = SpamRouting {
+            threshold: 5.0,
+            output_dir: None,
+            purge_after: Some(30),
+        };
+        assert!(routing.is_spam(&mut email("X-Spam-Flag: YES\r\n")).unwrap());
+        assert!(routing
+            .is_spam(&mut email("X-Spam-Score: 5.1\r\n"))
+            .unwrap());
+        assert!(!routing.is_spam(&mut email("X-Spam-Score: 5\r\n")).unwrap());
+        assert!(!routing.is_spam(&mut email("")).unwrap());
+        assert!(routing.purge_before().is_some());
+    }
+}
@@ -0,0 +1,563 @@
+use crate::archiver::MaildirArchiverError;
+use maildir::{MailEntry, MailEntryError};
+use mailparse::{addrparse_header, dateparse, parse_headers, MailAddr, MailHeaderMap};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use time::macros::format_description;
+use time::{Date, Duration, Month, OffsetDateTime, Weekday};
+
+/// Policy used to group archived emails in folders.
+///
+/// In the configuration file the policies are named like the `--split-by`
+/// values, the fiscal year starting in April being `fiscal-year-04`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum SplitBy {
+    Year,
+    Day,
+    Month,
+    /// Group emails by hour of the day in UTC (`2024-01-31T09`), for the
+    /// mailboxes receiving too many emails a day
+    Hour,
+    /// Group emails by ISO 8601 week (`2024-W05`), the weeks starting on
+    /// Monday and belonging to the year of their Thursday
+    IsoWeek,
+    /// Group emails by fiscal year starting in the given month (`FY2023`),
+    /// named after the calendar year in which the fiscal year starts
+    FiscalYear(Month),
+    /// Group emails by their primary recipient
+    Recipient,
+    /// Group emails by their first label, from the `X-Label` or `Keywords`
+    /// headers
+    Label,
+    None,
+}
+
+/// Folder name of the emails without a recipient address
+pub const UNKNOWN_RECIPIENT: &str = "unknown";
+
+/// Folder name of the emails without a label
+pub const UNLABELED: &str = "unlabeled";
+
+/// Tokens of a prefix or a suffix replaced by the part number of a rotated
+/// archive folder, see `--rotate-after`
+pub const SEQUENCE_TOKENS: [&str; 2] = ["{seq}", "{part}"];
+
+/// Check if a folder name template contains a sequence token
+pub fn has_sequence(template: &str) -> bool {
+    SEQUENCE_TOKENS.iter().any(|token| template.contains(token))
+}
+
+/// Replace the sequence tokens of a folder name by a part number, padded to
+/// three digits
+pub fn expand_sequence(template: &str, part: usize) -> String {
+    SEQUENCE_TOKENS
+        .iter()
+        .fold(template.to_string(), |name, token| {
+            name.replace(token, &format!("{:03}", part))
+        })
+}
+
+/// Split a prefix or a suffix at its first sequence token
+fn split_sequence(affix: &str) -> Option<(&str, &str)> {
+    SEQUENCE_TOKENS
+        .iter()
+        .filter_map(|token| affix.find(token).map(|i| (i, token.len())))
+        .min()
+        .map(|(i, len)| (&affix[..i], &affix[i + len..]))
+}
+
+/// Remove a prefix and a suffix from a folder name, their sequence token
+/// matching any part number
+fn strip_affixes<'a>(name: &'a str, prefix: &str, suffix: &str) -> Option<&'a str> {
+    let name = match split_sequence(prefix) {
+        Some((before, after)) => name
+            .strip_prefix(before)?
+            .trim_start_matches(|c: char| c.is_ascii_digit())
+            .strip_prefix(after)?,
+        None => name.strip_prefix(prefix)?,
+    };
+    match split_sequence(suffix) {
+        Some((before, after)) => name
+            .strip_suffix(after)?
+            .trim_end_matches(|c: char| c.is_ascii_digit())
+            .strip_suffix(before),
+        None => name.strip_suffix(suffix),
+    }
+}
+
+impl TryFrom<String> for SplitBy {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.as_str() {
+            "year" => Ok(SplitBy::Year),
+            "month" => Ok(SplitBy::Month),
+            "day" => Ok(SplitBy::Day),
+            "hour" => Ok(SplitBy::Hour),
+            "iso-week" => Ok(SplitBy::IsoWeek),
+            "fiscal-year" => Ok(SplitBy::FiscalYear(Month::January)),
+            "recipient" => Ok(SplitBy::Recipient),
+            "label" => Ok(SplitBy::Label),
+            "none" => Ok(SplitBy::None),
+            _ => value
+                .strip_prefix("fiscal-year-")
+                .and_then(|month| month.parse::<u8>().ok())
+                .and_then(|month| Month::try_from(month).ok())
+                .map(SplitBy::FiscalYear)
+                .ok_or_else(|| format!("unknown split policy {}", value)),
+        }
+    }
+}
+
+impl From<SplitBy> for String {
+    fn from(split_by: SplitBy) -> Self {
+        match split_by {
+            SplitBy::Year => "year".to_string(),
+            SplitBy::Month => "month".to_string(),
+            SplitBy::Day => "day".to_string(),
+            SplitBy::Hour => "hour".to_string(),
+            SplitBy::IsoWeek => "iso-week".to_string(),
+            SplitBy::FiscalYear(start) => format!("fiscal-year-{:02}", start as u8),
+            SplitBy::Recipient => "recipient".to_string(),
+            SplitBy::Label => "label".to_string(),
+            SplitBy::None => "none".to_string(),
+        }
+    }
+}
+
+impl SplitBy {
+    /// Name of the archive folder for an email with the given date.
+    ///
+    /// The recipient and label policies don't depend on the date, use
+    /// [`SplitBy::email_folder_name`] to get the folder of an email.
+    pub fn folder_name(&self, prefix: &str, suffix: &str, date: OffsetDateTime) -> String {
+        let dateformat = match self {
+            SplitBy::Year => format_description!("[year]"),
+            SplitBy::Month => format_description!("[year]-[month]"),
+            SplitBy::Day => format_description!("[year]-[month]-[day]"),
+            SplitBy::Hour => format_description!("[year]-[month]-[day]T[hour]"),
+            SplitBy::IsoWeek => format_description!("[year base:iso_week]-W[week_number]"),
+            SplitBy::FiscalYear(start) => {
+                let year = match date.month() as u8 >= *start as u8 {
+                    true => date.year(),
+                    false => date.year() - 1,
+                };
+                return format!("{}FY{}{}", prefix, year, suffix);
+            }
+            SplitBy::Recipient => return format!("{}{}{}", prefix, UNKNOWN_RECIPIENT, suffix),
+            SplitBy::Label => return format!("{}{}{}", prefix, UNLABELED, suffix),
+            SplitBy::None => format_description!(""),
+        };
+        format!("{}{}{}", prefix, date.format(&dateformat).unwrap(), suffix)
+    }
+
+    /// Name of the archive folder for an email received at `date`
+    pub fn email_folder_name(
+        &self,
+        prefix: &str,
+        suffix: &str,
+        mail: &mut MailEntry,
+        date: OffsetDateTime,
+    ) -> String {
+        match self {
+            SplitBy::Recipient => format!(
+                "{}{}{}",
+                prefix,
+                email_recipient(mail).unwrap_or_else(|| UNKNOWN_RECIPIENT.to_string()),
+                suffix
+            ),
+            SplitBy::Label => format!(
+                "{}{}{}",
+                prefix,
+                email_labels(mail)
+                    .iter()
+                    .find_map(|label| folder_safe_label(label))
+                    .unwrap_or_else(|| UNLABELED.to_string()),
+                suffix
+            ),
+            _ => self.folder_name(prefix, suffix, date),
+        }
+    }
+
+    /// First day of the period covered by an archive folder, if the folder
+    /// name matches this policy.
+    pub fn folder_date(&self, prefix: &str, suffix: &str, name: &str) -> Option<Date> {
+        let value = strip_affixes(name, prefix, suffix)?;
+        let value = match self {
+            SplitBy::Year => format!("{}-01-01", value),
+            SplitBy::Month => format!("{}-01", value),
+            SplitBy::Day => value.to_string(),
+            // The day of the hour
+            SplitBy::Hour => {
+                let (day, hour) = value.split_once('T')?;
+                match hour.len() == 2 && hour.parse::<u8>().is_ok_and(|hour| hour < 24) {
+                    true => day.to_string(),
+                    false => return None,
+                }
+            }
+            SplitBy::IsoWeek => {
+                let (year, week) = value.split_once("-W")?;
+                return Date::from_iso_week_date(
+                    year.parse().ok()?,
+                    week.parse().ok()?,
+                    Weekday::Monday,
+                )
+                .ok();
+            }
+            SplitBy::FiscalYear(start) => {
+                let year = value.strip_prefix("FY")?.parse().ok()?;
+                return Date::from_calendar_date(year, *start, 1).ok();
+            }
+            SplitBy::Recipient | SplitBy::Label | SplitBy::None => return None,
+        };
+        Date::parse(&value, format_description!("[year]-[month]-[day]")).ok()
+    }
+
+    /// First day of the period following the one starting at `date`.
+    pub fn next_folder_date(&self, date: Date) -> Option<Date> {
+        match self {
+            SplitBy::Year | SplitBy::FiscalYear(_) => date.replace_year(date.year() + 1).ok(),
+            SplitBy::Month => match date.month() {
+                Month::December => Date::from_calendar_date(date.year() + 1, Month::January, 1),
+                month => Date::from_calendar_date(date.year(), month.next(), 1),
+            }
+            .ok(),
+            // The hours of a day end with it
+            SplitBy::Day | SplitBy::Hour => date.next_day(),
+            SplitBy::IsoWeek => date.checked_add(Duration::weeks(1)),
+            SplitBy::Recipient | SplitBy::Label | SplitBy::None => None,
+        }
+    }
+
+    /// Check if an archive folder only contains emails older than `before`.
+    pub fn is_folder_before(&self, prefix: &str, suffix: &str, name: &str, before: Date) -> bool {
+        self.folder_date(prefix, suffix, name)
+            .and_then(|date| self.next_folder_date(date))
+            .is_some_and(|end| end <= before)
+    }
+}
+
+/// Order in which the emails are archived
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MailOrder {
+    Oldest,
+    Newest,
+    Path,
+    /// Order of the directory listing: the emails are streamed without
+    /// listing the whole folder first
+    Directory,
+    /// Order of the inode numbers, close to the order of the files on disk:
+    /// the emails are sorted before being read, reducing the seeks on
+    /// spinning disks
+    Inode,
+}
+
+impl MailOrder {
+    /// Sort emails with their dates. Emails with the same date are sorted by
+    /// path, so the order doesn't depend on the directory listing.
+    pub fn sort(&self, emails: &mut [(MailEntry, OffsetDateTime)]) {
+        match self {
+            MailOrder::Oldest => emails.sort_by(|a, b| (a.1, a.0.path()).cmp(&(b.1, b.0.path()))),
+            MailOrder::Newest => emails.sort_by(|a, b| (b.1, a.0.path()).cmp(&(a.1, b.0.path()))),
+            MailOrder::Path => emails.sort_by(|a, b| a.0.path().cmp(b.0.path())),
+            // The emails are already in the listing order
+            MailOrder::Directory | MailOrder::Inode => {}
+        }
+    }
+
+    /// Sort emails by inode number, without reading them
+    pub fn sort_by_inode(emails: &mut [MailEntry]) {
+        emails.sort_by_cached_key(|mail| {
+            fs::symlink_metadata(mail.path()).map_or(0, |metadata| metadata.ino())
+        });
+    }
+}
+
+/// Header section of an email file, read without its body
+fn read_header_section(path: &Path) -> io::Result<Vec<u8>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut section = Vec::new();
+    loop {
+        let len = reader.read_until(b'\n', &mut section)?;
+        let line = &section[section.len() - len..];
+        if len == 0 || line == b"\n" || line == b"\r\n" {
+            return Ok(section);
+        }
+    }
+}
+
+/// Date used to archive the email, taken from its `Received` header.
+///
+/// Only the headers are read, and the content is not kept in the entry: the
+/// listed candidates don't hold the emails in memory.
+pub fn email_date(mail: &MailEntry) -> Result<OffsetDateTime, MaildirArchiverError> {
+    let section = read_header_section(mail.path())?;
+    let (headers, _) = parse_headers(&section)?;
+    let received = headers
+        .get_first_value("Received")
+        .ok_or(MailEntryError::DateError("No Received header found"))?;
+    let timestamp = dateparse(received.rsplit(';').next().unwrap_or_default())?;
+    Ok(OffsetDateTime::from_unix_timestamp(timestamp)?)
+}
+
+/// Primary recipient of an email: the first address of its `To` header, or
+/// its `Delivered-To` header. The address is lowercased and the characters
+/// not allowed in a folder name are replaced by `_`.
+pub fn email_recipient(mail: &mut MailEntry) -> Option<String> {
+    let headers = mail.headers().ok()?;
+    let to = headers
+        .get_first_header("To")
+        .and_then(|h| addrparse_header(h).ok())
+        .and_then(|addrs| {
+            addrs.iter().find_map(|addr| match addr {
+                MailAddr::Single(info) => Some(info.addr.clone()),
+                MailAddr::Group(group) => group.addrs.first().map(|info| info.addr.clone()),
+            })
+        });
+    let address = to.or_else(|| headers.get_first_value("Delivered-To"))?;
+    folder_safe_address(&address)
+}
+
+/// Email address usable as a folder name: lowercased, with the characters
+/// not allowed in a folder name replaced by `_`. None if nothing is left.
+pub fn folder_safe_address(address: &str) -> Option<String> {
+    let address: String = address
+        .trim()
+        .to_lowercase()
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | '0'..='9' | '@' | '.' | '-' | '_' | '+' => c,
+            _ => '_',
+        })
+        .collect();
+    match address.trim_start_matches('.') {
+        "" => None,
+        address => Some(address.to_string()),
+    }
+}
+
+/// Labels of an email: the comma separated values of its `X-Label` headers,
+/// set by Mutt, then of its `Keywords` headers, without duplicates. Like for
+/// [`email_date`], only the headers are read.
+pub fn email_labels(mail: &MailEntry) -> Vec<String> {
+    let Ok(section) = read_header_section(mail.path()) else {
+        return Vec::new();
+    };
+    let Ok((headers, _)) = parse_headers(&section) else {
+        return Vec::new();
+    };
+    let mut labels: Vec<String> = Vec::new();
+    let values = headers.get_all_values("X-Label");
+    for value in values.iter().chain(&headers.get_all_values("Keywords")) {
+        for label in value.split(',').map(str::trim) {
+            if !label.is_empty() && !labels.iter().any(|l| l == label) {
+                labels.push(label.to_string());
+            }
+        }
+    }
+    labels
+}
+
+/// Label usable as a folder name: the characters not allowed in a folder
+/// name are replaced by `_`, the case is kept. None if nothing is left.
+pub fn folder_safe_label(label: &str) -> Option<String> {
+    let label: String = label
+        .trim()
+        .chars()
+        .map(|c| match c {
+            c if c.is_alphanumeric() => c,
+            '@' | '.' | '-' | '_' | '+' => c,
+            _ => '_',
+        })
+        .collect();
+    match label.trim_start_matches('.') {
+        "" => None,
+        label => Some(label.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::split::{
+        email_date, email_labels, email_recipient, expand_sequence, folder_safe_label,
+        has_sequence, MailOrder, SplitBy,
+    };
+    use time::macros::{date, datetime};
+    use time::Month;
+
+    #[test]
+    fn test_folder_name() {
+        let date = datetime!(2016-05-21 22:08:25 UTC);
+        assert_eq!(SplitBy::Year.folder_name("", "", date), "2016");
+        assert_eq!(SplitBy::Month.folder_name("", "", date), "2016-05");
+        assert_eq!(SplitBy::Day.folder_name("a-", ".d", date), "a-2016-05-21.d");
+        assert_eq!(SplitBy::Hour.folder_name("", "", date), "2016-05-21T22");
+        assert_eq!(SplitBy::None.folder_name("all", "", date), "all");
+        assert_eq!(SplitBy::IsoWeek.folder_name("", "", date), "2016-W20");
+        let new_year = datetime!(2021-01-01 12:00 UTC);
+        assert_eq!(SplitBy::IsoWeek.folder_name("", "", new_year), "2020-W53");
+        let december = datetime!(2024-12-30 12:00 UTC);
+        assert_eq!(SplitBy::IsoWeek.folder_name("", "", december), "2025-W01");
+        let fiscal_year = SplitBy::FiscalYear(Month::April);
+        assert_eq!(fiscal_year.folder_name("", "", date), "FY2016");
+        assert_eq!(fiscal_year.folder_name("", "", new_year), "FY2020");
+        assert_eq!(
+            SplitBy::try_from("fiscal-year-04".to_string()),
+            Ok(fiscal_year)
+        );
+        assert_eq!(String::from(fiscal_year), "fiscal-year-04");
+        assert!(SplitBy::try_from("fiscal-year-13".to_string()).is_err());
+
+        let maildir = maildir::Maildir::from(std::path::PathBuf::from("testdata/maildir1"));
+        let mut mail = maildir
+            .find("1463868505.38518452d49213cb409aa1db32f53184")
+            .unwrap();
+        assert_eq!(
+            email_recipient(&mut mail).as_deref(),
+            Some("test.foobar@example.com")
+        );
+        assert_eq!(
+            SplitBy::Recipient.email_folder_name("to-", "", &mut mail, date),
+            "to-test.foobar@example.com"
+        );
+    }
+
+    #[test]
+    fn test_email_labels() {
+        use crate::test_support::{TestEmail, TestMaildir};
+        let date = datetime!(2016-05-21 22:08:26 UTC);
+        let maildir = TestMaildir::new();
+        let mut mail = maildir.add(
+            &TestEmail::new()
+                .header("X-Label", "Work, travel/2016")
+                .header("Keywords", "travel/2016, receipts"),
+        );
+        assert_eq!(email_labels(&mail), ["Work", "travel/2016", "receipts"]);
+        assert_eq!(
+            SplitBy::Label.email_folder_name("", "", &mut mail, date),
+            "Work"
+        );
+        assert_eq!(
+            folder_safe_label(" Project X/2016 ").as_deref(),
+            Some("Project_X_2016")
+        );
+        assert_eq!(folder_safe_label(".."), None);
+        let mut mail = maildir.add(&TestEmail::new().header("X-Label", ", "));
+        assert!(email_labels(&mail).is_empty());
+        assert_eq!(
+            SplitBy::Label.email_folder_name("label-", "", &mut mail, date),
+            "label-unlabeled"
+        );
+        assert_eq!(SplitBy::try_from("label".to_string()), Ok(SplitBy::Label));
+    }
+
+    #[test]
+    fn test_folder_date() {
+        assert_eq!(
+            SplitBy::Year.folder_date("", "", "2016"),
+            Some(date!(2016 - 01 - 01))
+        );
+        assert_eq!(
+            SplitBy::Month.folder_date("a-", "", "a-2016-05"),
+            Some(date!(2016 - 05 - 01))
+        );
+        assert_eq!(SplitBy::Month.folder_date("", "", "2016"), None);
+        assert_eq!(SplitBy::Day.folder_date("", "", "other"), None);
+        assert_eq!(
+            SplitBy::Hour.folder_date("", "", "2016-05-21T22"),
+            Some(date!(2016 - 05 - 21))
+        );
+        assert_eq!(SplitBy::Hour.folder_date("", "", "2016-05-21T24"), None);
+        assert!(SplitBy::Hour.is_folder_before("", "", "2016-05-21T23", date!(2016 - 05 - 22)));
+        assert!(!SplitBy::Hour.is_folder_before("", "", "2016-05-21T00", date!(2016 - 05 - 21)));
+        assert_eq!(
+            SplitBy::IsoWeek.folder_date("", "", "2020-W53"),
+            Some(date!(2020 - 12 - 28))
+        );
+        assert_eq!(SplitBy::IsoWeek.folder_date("", "", "2021-W53"), None);
+        assert!(SplitBy::IsoWeek.is_folder_before("", "", "2020-W53", date!(2021 - 01 - 04)));
+        assert!(!SplitBy::IsoWeek.is_folder_before("", "", "2020-W53", date!(2021 - 01 - 03)));
+        let fiscal_year = SplitBy::FiscalYear(Month::April);
+        assert_eq!(
+            fiscal_year.folder_date("", "", "FY2023"),
+            Some(date!(2023 - 04 - 01))
+        );
+        assert!(fiscal_year.is_folder_before("", "", "FY2023", date!(2024 - 04 - 01)));
+        assert!(!fiscal_year.is_folder_before("", "", "FY2023", date!(2024 - 03 - 31)));
+        assert!(SplitBy::Month.is_folder_before("", "", "2016-12", date!(2017 - 01 - 01)));
+        assert!(!SplitBy::Year.is_folder_before("", "", "2016", date!(2016 - 12 - 31)));
+    }
+
+    #[test]
+    fn test_sequence() {
+        assert!(has_sequence("-listmail-part{seq}"));
+        assert!(!has_sequence("-listmail"));
+        assert_eq!(
+            expand_sequence("-listmail-part{part}", 3),
+            "-listmail-part003"
+        );
+        let name = format!("2020{}", expand_sequence("-part{seq}", 12));
+        assert_eq!(
+            SplitBy::Year.folder_date("", "-part{seq}", &name),
+            Some(date!(2020 - 01 - 01))
+        );
+        assert_eq!(
+            SplitBy::Month.folder_date("{part}_", "", "1000_2020-02"),
+            Some(date!(2020 - 02 - 01))
+        );
+        assert_eq!(
+            SplitBy::Year.folder_date("", "-part{seq}", "2020-part"),
+            Some(date!(2020 - 01 - 01))
+        );
+        assert_eq!(SplitBy::Year.folder_date("", "-part{seq}", "2020"), None);
+    }
+
+    #[test]
+    fn test_mail_order() {
+        let maildir = maildir::Maildir::from(std::path::PathBuf::from("testdata/maildir1"));
+        let mut emails: Vec<_> = maildir
+            .list_cur()
+            .map(|m| {
+                let m = m.unwrap();
+                let date = email_date(&m).unwrap();
+                (m, date)
+            })
+            .collect();
+        let ids = |emails: &[(maildir::MailEntry, time::OffsetDateTime)]| {
+            emails
+                .iter()
+                .map(|(m, _)| m.id().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        MailOrder::Newest.sort(&mut emails);
+        assert_eq!(
+            ids(&emails),
+            [
+                "1700000000.4f1c2b7a9e3d4c0b8a6f5e2d1c0b9a87",
+                "1463868505.38518452d49213cb409aa1db32f53184"
+            ]
+        );
+        MailOrder::Oldest.sort(&mut emails);
+        assert_eq!(
+            ids(&emails),
+            [
+                "1463868505.38518452d49213cb409aa1db32f53184",
+                "1700000000.4f1c2b7a9e3d4c0b8a6f5e2d1c0b9a87"
+            ]
+        );
+
+        use std::os::unix::fs::MetadataExt;
+        let mut emails: Vec<_> = maildir.list_cur().map(Result::unwrap).collect();
+        MailOrder::sort_by_inode(&mut emails);
+        let inodes: Vec<_> = emails
+            .iter()
+            .map(|m| std::fs::metadata(m.path()).unwrap().ino())
+            .collect();
+        assert!(inodes.windows(2).all(|w| w[0] <= w[1]));
+    }
+}
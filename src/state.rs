@@ -0,0 +1,107 @@
+use crate::archiver::path_with_suffix;
+use maildir::{MailEntry, Maildir};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// State of the last successful archive run of a maildir, used by
+/// incremental runs to skip the emails already examined
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RunState {
+    /// Unix timestamp of the last successful run
+    pub last_run: i64,
+    /// Unix timestamp of the date threshold of the last successful run
+    pub high_water_mark: i64,
+    /// Ids of the emails examined by the last run but left in the maildir:
+    /// newer than its threshold, filtered out or skipped on errors
+    #[serde(default)]
+    pub retained: BTreeSet<String>,
+}
+
+/// Default path of the state file of a maildir
+pub fn default_state_path(maildir: &Maildir) -> PathBuf {
+    maildir.path().join("archive-maildir.state")
+}
+
+/// Delivery time of an email, taken from the timestamp at the beginning of
+/// its name, or from the modification time of the file.
+pub fn delivery_time(mail: &MailEntry) -> Option<i64> {
+    if let Some(Ok(timestamp)) = mail.id().split('.').next().map(str::parse) {
+        return Some(timestamp);
+    }
+    let modified = fs::metadata(mail.path()).ok()?.modified().ok()?;
+    Some(modified.duration_since(UNIX_EPOCH).ok()?.as_secs() as i64)
+}
+
+impl RunState {
+    /// Read the state file, returning `None` if it doesn't exist
+    pub fn read(path: &Path) -> io::Result<Option<Self>> {
+        match fs::read(path) {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Atomically replace the state file
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let tmp = path_with_suffix(path, ".tmp");
+        fs::write(&tmp, serde_json::to_vec(self)?)?;
+        fs::rename(&tmp, path)
+    }
+
+    /// Record an email left in the maildir by the run, if the next run would
+    /// skip it otherwise: the emails delivered after the last run or its
+    /// threshold are examined anyway
+    pub fn retain(&mut self, id: String, delivery_time: i64) {
+        if delivery_time < self.last_run.min(self.high_water_mark) {
+            self.retained.insert(id);
+        }
+    }
+
+    /// Check if an email must be examined: emails delivered before both the
+    /// last run and its threshold have already been archived, unless the
+    /// last run left them in the maildir.
+    pub fn is_pending(&self, mail: &MailEntry) -> bool {
+        delivery_time(mail).is_none_or(|t| t >= self.last_run.min(self.high_water_mark))
+            || self.retained.contains(mail.id())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::state::{delivery_time, RunState};
+    use maildir::Maildir;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_run_state() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let path = basedir.join("state");
+        assert_eq!(RunState::read(&path).unwrap(), None);
+        let mut state = RunState {
+            last_run: 1700000500,
+            high_water_mark: 1600000000,
+            retained: Default::default(),
+        };
+        state.write(&path).unwrap();
+        assert_eq!(RunState::read(&path).unwrap(), Some(state.clone()));
+
+        let maildir = Maildir::from(PathBuf::from("testdata/maildir1"));
+        let old = maildir
+            .find("1463868505.38518452d49213cb409aa1db32f53184")
+            .unwrap();
+        let new = maildir
+            .find("1700000000.4f1c2b7a9e3d4c0b8a6f5e2d1c0b9a87")
+            .unwrap();
+        assert_eq!(delivery_time(&old), Some(1463868505));
+        assert!(!state.is_pending(&old));
+        assert!(state.is_pending(&new));
+        state.retained.insert(old.id().to_string());
+        assert!(state.is_pending(&old));
+    }
+}
@@ -0,0 +1,324 @@
+use crate::archiver::MaildirArchiverError;
+use crate::args::StatsOptions;
+use crate::bench::BenchReport;
+use crate::dedup::DedupReport;
+use crate::diff::DiffReport;
+use crate::expunge::ExpungeReport;
+use crate::folders::list_source_folders;
+use crate::fsck::FsckReport;
+use crate::orphans::OrphanReport;
+use crate::run::RunReport;
+use crate::split::email_date;
+use crate::views::ViewsReport;
+use log::error;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+/// Output of the statistics of the commands
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StatsFormat {
+    /// Only the summary log messages
+    Log,
+    /// A header line and a line of values on the standard output
+    Csv,
+}
+
+/// Trait implemented by the reports of the commands, giving their
+/// statistics as a table row.
+///
+/// The columns are stable: new columns are only added at the end.
+pub trait Statistics {
+    fn columns(&self) -> Vec<&'static str>;
+    fn values(&self) -> Vec<String>;
+}
+
+/// Statistic of the commands returning a single count
+pub struct Count(pub &'static str, pub usize);
+
+impl Statistics for Count {
+    fn columns(&self) -> Vec<&'static str> {
+        vec![self.0]
+    }
+
+    fn values(&self) -> Vec<String> {
+        vec![self.1.to_string()]
+    }
+}
+
+impl Statistics for RunReport {
+    fn columns(&self) -> Vec<&'static str> {
+        vec![
+            "examined",
+            "selected",
+            "archived",
+            "skipped",
+            "purged",
+            "failed",
+            "archived_bytes",
+            "stuck_in_new",
+            "interrupted",
+        ]
+    }
+
+    fn values(&self) -> Vec<String> {
+        vec![
+            self.examined.to_string(),
+            self.selected.to_string(),
+            self.archived.len().to_string(),
+            self.skipped.len().to_string(),
+            self.purged.len().to_string(),
+            self.failed.len().to_string(),
+            self.archived_bytes.to_string(),
+            self.stuck_in_new.to_string(),
+            self.interrupted.to_string(),
+        ]
+    }
+}
+
+impl Statistics for BenchReport {
+    fn columns(&self) -> Vec<&'static str> {
+        vec![
+            "scanned",
+            "examined",
+            "selected",
+            "archived",
+            "failed",
+            "archived_bytes",
+            "scan_seconds",
+            "filter_seconds",
+            "archive_seconds",
+        ]
+    }
+
+    fn values(&self) -> Vec<String> {
+        vec![
+            self.scanned.to_string(),
+            self.examined.to_string(),
+            self.selected.to_string(),
+            self.archived.to_string(),
+            self.failed.to_string(),
+            self.archived_bytes.to_string(),
+            format!("{:.3}", self.scan_time.as_secs_f64()),
+            format!("{:.3}", self.filter_time.as_secs_f64()),
+            format!("{:.3}", self.archive_time.as_secs_f64()),
+        ]
+    }
+}
+
+impl Statistics for DedupReport {
+    fn columns(&self) -> Vec<&'static str> {
+        vec!["duplicates", "reclaimed_bytes"]
+    }
+
+    fn values(&self) -> Vec<String> {
+        vec![self.duplicates.to_string(), self.reclaimed.to_string()]
+    }
+}
+
+impl Statistics for DiffReport {
+    fn columns(&self) -> Vec<&'static str> {
+        vec!["source_only", "archive_only"]
+    }
+
+    fn values(&self) -> Vec<String> {
+        vec![self.source_only.to_string(), self.archive_only.to_string()]
+    }
+}
+
+impl Statistics for FsckReport {
+    fn columns(&self) -> Vec<&'static str> {
+        vec!["problems", "repaired"]
+    }
+
+    fn values(&self) -> Vec<String> {
+        vec![self.problems.len().to_string(), self.repaired.to_string()]
+    }
+}
+
+impl Statistics for ExpungeReport {
+    fn columns(&self) -> Vec<&'static str> {
+        vec!["expunged", "pending", "failed"]
+    }
+
+    fn values(&self) -> Vec<String> {
+        vec![
+            self.expunged.to_string(),
+            self.pending.to_string(),
+            self.failed.to_string(),
+        ]
+    }
+}
+
+impl Statistics for ViewsReport {
+    fn columns(&self) -> Vec<&'static str> {
+        vec!["emails", "links"]
+    }
+
+    fn values(&self) -> Vec<String> {
+        vec![self.emails.to_string(), self.links.to_string()]
+    }
+}
+
+impl Statistics for OrphanReport {
+    fn columns(&self) -> Vec<&'static str> {
+        vec!["orphans", "fixed"]
+    }
+
+    fn values(&self) -> Vec<String> {
+        vec![self.orphans.len().to_string(), self.fixed.to_string()]
+    }
+}
+
+/// Period of the rows of the histogram of the `stats` subcommand
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HistogramPeriod {
+    Month,
+    Year,
+}
+
+/// Emails of a period of the histogram
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Bucket {
+    pub emails: usize,
+    pub bytes: u64,
+}
+
+/// Emails of a maildir by period (`YYYY-MM` or `YYYY`), or a single `total`
+/// period without histogram
+pub type Histogram = BTreeMap<String, Bucket>;
+
+/// Count the emails of the input maildir and their sizes, by month or year
+/// of reception if `histogram` is set
+pub fn maildir_stats(opts: &StatsOptions) -> Result<Histogram, MaildirArchiverError> {
+    let mut histogram = Histogram::new();
+    for folder in list_source_folders(&opts.maildir, opts.recursive)? {
+        for mail in folder.maildir.list_cur().chain(folder.maildir.list_new()) {
+            let mail = mail?;
+            let period = match opts.histogram {
+                Some(period) => match email_date(&mail) {
+                    Ok(date) if period == HistogramPeriod::Month => {
+                        format!("{}-{:02}", date.year(), date.month() as u8)
+                    }
+                    Ok(date) => date.year().to_string(),
+                    Err(e) => {
+                        error!("{}", e);
+                        continue;
+                    }
+                },
+                None => "total".to_string(),
+            };
+            let bucket = histogram.entry(period).or_default();
+            bucket.emails += 1;
+            bucket.bytes += std::fs::metadata(mail.path())?.len();
+        }
+    }
+    Ok(histogram)
+}
+
+/// Write a histogram as a table with the `period`, `emails` and `bytes`
+/// columns: CSV, or separated by tabs
+pub fn write_histogram<W: Write>(
+    out: &mut W,
+    format: StatsFormat,
+    histogram: &Histogram,
+) -> io::Result<()> {
+    let separator = match format {
+        StatsFormat::Csv => ",",
+        StatsFormat::Log => "\t",
+    };
+    writeln!(out, "period{0}emails{0}bytes", separator)?;
+    for (period, bucket) in histogram {
+        writeln!(
+            out,
+            "{1}{0}{2}{0}{3}",
+            separator,
+            csv_field(period),
+            bucket.emails,
+            bucket.bytes
+        )?;
+    }
+    Ok(())
+}
+
+/// Field of a CSV line, quoted if needed
+pub(crate) fn csv_field(value: &str) -> String {
+    match value.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", value.replace('"', "\"\"")),
+        false => value.to_string(),
+    }
+}
+
+/// Write the statistics as CSV: a header line and a line of values
+pub fn write_csv<W: Write>(out: &mut W, stats: &dyn Statistics) -> io::Result<()> {
+    let line = |fields: Vec<String>| {
+        fields
+            .iter()
+            .map(|f| csv_field(f))
+            .collect::<Vec<_>>()
+            .join(",")
+    };
+    let columns = stats.columns().into_iter().map(str::to_string).collect();
+    writeln!(out, "{}", line(columns))?;
+    writeln!(out, "{}", line(stats.values()))
+}
+
+/// Write the statistics in the requested format to the standard output
+pub fn print_stats(format: StatsFormat, stats: &dyn Statistics) -> io::Result<()> {
+    match format {
+        StatsFormat::Log => Ok(()),
+        StatsFormat::Csv => write_csv(&mut io::stdout(), stats),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::args::StatsOptions;
+    use crate::dedup::DedupReport;
+    use crate::run::RunReport;
+    use crate::stats::{
+        maildir_stats, write_csv, write_histogram, Count, HistogramPeriod, Statistics, StatsFormat,
+    };
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_write_csv() {
+        let mut out = Vec::new();
+        let report = DedupReport {
+            duplicates: 2,
+            reclaimed: 1024,
+        };
+        write_csv(&mut out, &report).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "duplicates,reclaimed_bytes\n2,1024\n"
+        );
+
+        let report = RunReport::default();
+        assert_eq!(report.columns().len(), report.values().len());
+        assert_eq!(report.columns()[0], "examined");
+
+        let mut out = Vec::new();
+        write_csv(&mut out, &Count("a,b", 1)).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "\"a,b\"\n1\n");
+    }
+
+    #[test]
+    fn test_histogram() {
+        let mut opts = StatsOptions {
+            maildir: PathBuf::from("testdata/maildir1"),
+            recursive: false,
+            histogram: Some(HistogramPeriod::Month),
+        };
+        let histogram = maildir_stats(&opts).unwrap();
+        let periods: Vec<_> = histogram.keys().map(String::as_str).collect();
+        assert_eq!(periods, ["2016-05", "2023-11"]);
+        let mut out = Vec::new();
+        write_histogram(&mut out, StatsFormat::Csv, &histogram).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("period,emails,bytes\n2016-05,1,"));
+
+        opts.histogram = None;
+        let histogram = maildir_stats(&opts).unwrap();
+        assert_eq!(histogram["total"].emails, 2);
+    }
+}
@@ -0,0 +1,160 @@
+use crate::archiver::{line_ending, MaildirArchiverError};
+use crate::dedup::{reader_digest, to_hex};
+use crate::filter::is_attachment;
+use mailparse::{parse_mail, ParsedMail};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Detach large attachments from emails into a content-addressed sidecar
+/// directory.
+///
+/// Each detached attachment is stored once in `<dir>/<xx>/<sha256>` and
+/// replaced in the email by a `message/external-body` part pointing to the
+/// file, keeping the other parts untouched.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AttachmentStripper {
+    pub sidecar_dir: PathBuf,
+    pub min_size: u64,
+}
+
+/// Detached part of an email: byte range in the email and its replacement
+struct Detached {
+    start: usize,
+    end: usize,
+    placeholder: Vec<u8>,
+}
+
+fn quote(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+impl AttachmentStripper {
+    /// Store the attachment content in the sidecar directory, returning its path
+    fn store(&self, content: &[u8]) -> Result<PathBuf, MaildirArchiverError> {
+        let digest = to_hex(&reader_digest(&mut &content[..])?);
+        let dir = self.sidecar_dir.join(&digest[..2]);
+        let path = dir.join(&digest);
+        if !path.exists() {
+            fs::create_dir_all(&dir)?;
+            let tmp = dir.join(format!(".{}.tmp", digest));
+            fs::write(&tmp, content)?;
+            fs::rename(&tmp, &path)?;
+        }
+        Ok(fs::canonicalize(path)?)
+    }
+
+    fn placeholder(part: &ParsedMail, path: &Path, size: usize, eol: &str) -> Vec<u8> {
+        let disposition = part.get_content_disposition();
+        let filename = disposition
+            .params
+            .get("filename")
+            .or_else(|| part.ctype.params.get("name"))
+            .cloned()
+            .unwrap_or_default();
+        let mut lines = vec![
+            format!(
+                "Content-Type: message/external-body; access-type=\"local-file\";{}\tname=\"{}\"; size={}",
+                eol,
+                quote(&path.display().to_string()),
+                size
+            ),
+            format!("Content-Disposition: attachment; filename=\"{}\"", quote(&filename)),
+            String::new(),
+            format!("Content-Type: {}", part.ctype.mimetype),
+            String::new(),
+            format!(
+                "Attachment {} ({} bytes) detached to {}",
+                filename,
+                size,
+                path.display()
+            ),
+        ];
+        lines.push(String::new());
+        lines.join(eol).into_bytes()
+    }
+
+    fn detach(
+        &self,
+        part: &ParsedMail,
+        data: &[u8],
+        eol: &str,
+        detached: &mut Vec<Detached>,
+    ) -> Result<(), MaildirArchiverError> {
+        for subpart in &part.subparts {
+            if !subpart.subparts.is_empty() {
+                self.detach(subpart, data, eol, detached)?;
+                continue;
+            }
+            if !is_attachment(subpart) {
+                continue;
+            }
+            let content = subpart.get_body_raw()?;
+            if (content.len() as u64) < self.min_size {
+                continue;
+            }
+            let path = self.store(&content)?;
+            // Parts are subslices of the email data
+            let start = subpart.raw_bytes.as_ptr() as usize - data.as_ptr() as usize;
+            detached.push(Detached {
+                start,
+                end: start + subpart.raw_bytes.len(),
+                placeholder: AttachmentStripper::placeholder(subpart, &path, content.len(), eol),
+            });
+        }
+        Ok(())
+    }
+
+    /// Return the email with its large attachments detached
+    pub fn strip(&self, data: &[u8]) -> Result<Vec<u8>, MaildirArchiverError> {
+        let parsed = parse_mail(data)?;
+        let eol = line_ending(data);
+        let mut detached = Vec::new();
+        self.detach(&parsed, data, eol, &mut detached)?;
+        let mut stripped = Vec::with_capacity(data.len());
+        let mut offset = 0;
+        for part in detached {
+            stripped.extend_from_slice(&data[offset..part.start]);
+            stripped.extend_from_slice(&part.placeholder);
+            offset = part.end;
+        }
+        stripped.extend_from_slice(&data[offset..]);
+        Ok(stripped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::strip::AttachmentStripper;
+
+    #[test]
+    fn test_strip_attachment() {
+        use mktemp::Temp;
+        let sidecar_dir = Temp::new_dir().unwrap();
+        let data =
+            std::fs::read("testdata/maildir1/cur/1700000000.4f1c2b7a9e3d4c0b8a6f5e2d1c0b9a87:2,RS")
+                .unwrap();
+
+        let stripper = AttachmentStripper {
+            sidecar_dir: sidecar_dir.to_path_buf(),
+            min_size: 1024,
+        };
+        assert_eq!(stripper.strip(&data).unwrap(), data);
+
+        let stripper = AttachmentStripper {
+            sidecar_dir: sidecar_dir.to_path_buf(),
+            min_size: 0,
+        };
+        let stripped = String::from_utf8(stripper.strip(&data).unwrap()).unwrap();
+        assert!(stripped.contains("Please find the report attached."));
+        assert!(stripped.contains("Content-Type: message/external-body"));
+        assert!(!stripped.contains("JVBERi0xLjQK"));
+        assert!(stripped.ends_with("--boundary42--\n"));
+
+        let parsed = mailparse::parse_mail(stripped.as_bytes()).unwrap();
+        assert_eq!(parsed.subparts.len(), 2);
+        assert_eq!(parsed.subparts[1].ctype.mimetype, "message/external-body");
+        let path = parsed.subparts[1].ctype.params["name"].clone();
+        assert!(std::fs::read(path).unwrap().starts_with(b"%PDF-1.4"));
+    }
+}
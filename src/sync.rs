@@ -0,0 +1,68 @@
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+/// Infixes added to the maildir filenames by the IMAP synchronization tools:
+/// the UID of the email in its folder (`,U=<uid>`, mbsync and offlineimap)
+/// and the digest of the folder name (`,FMD5=<digest>`, offlineimap)
+const SYNC_MARKERS: [&str; 2] = [",U=", ",FMD5="];
+
+/// Position of a marker in an id
+fn find_marker(id: &[u8], marker: &str) -> Option<usize> {
+    id.windows(marker.len())
+        .position(|window| window == marker.as_bytes())
+}
+
+/// Check if the id of an email has the markers of a synchronization tool
+pub fn has_sync_markers(id: &OsStr) -> bool {
+    SYNC_MARKERS
+        .iter()
+        .any(|marker| find_marker(id.as_bytes(), marker).is_some())
+}
+
+/// Id of an email without the markers of the synchronization tools.
+///
+/// The markers are only valid in the synchronized folder of the email: a
+/// copy keeping them in another folder is taken by mbsync or offlineimap for
+/// the email with that UID there, and is overwritten or re-downloaded.
+pub fn strip_sync_markers(id: &OsStr) -> OsString {
+    let mut id = id.as_bytes().to_vec();
+    for marker in SYNC_MARKERS {
+        while let Some(start) = find_marker(&id, marker) {
+            let value = &id[start + marker.len()..];
+            let end = value
+                .iter()
+                .position(|b| !b.is_ascii_alphanumeric())
+                .unwrap_or(value.len());
+            id.drain(start..start + marker.len() + end);
+        }
+    }
+    OsString::from_vec(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sync::{has_sync_markers, strip_sync_markers};
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    #[test]
+    fn test_strip_sync_markers() {
+        let id = OsStr::new;
+        assert!(has_sync_markers(id("1463868505.123_1.host,U=42")));
+        assert!(!has_sync_markers(id("1463868505.123_1.host")));
+        assert_eq!(
+            strip_sync_markers(id("1463868505.123_1.host,U=42")),
+            "1463868505.123_1.host"
+        );
+        assert_eq!(
+            strip_sync_markers(id(
+                "1463868505_0.1234.host,U=7,FMD5=7e33429f656f1e6e9d79b29c3f82c57e"
+            )),
+            "1463868505_0.1234.host"
+        );
+        assert_eq!(strip_sync_markers(id("a,b")), "a,b");
+        // The bytes that are not UTF-8 are kept
+        let stripped = strip_sync_markers(OsStr::from_bytes(b"1463868505.\xff.host,U=1"));
+        assert_eq!(stripped.as_bytes(), b"1463868505.\xff.host");
+    }
+}
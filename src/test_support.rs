@@ -0,0 +1,181 @@
+use maildir::{MailEntry, Maildir};
+use mktemp::Temp;
+use std::path::{Path, PathBuf};
+use time::format_description::well_known::Rfc2822;
+use time::macros::datetime;
+use time::OffsetDateTime;
+
+/// Email generated for a test, stored with [`TestMaildir::add`] or
+/// [`TestEmail::store`].
+///
+/// ```
+/// use archive_maildir::test_support::{TestEmail, TestMaildir};
+/// use time::macros::datetime;
+///
+/// let maildir = TestMaildir::new();
+/// let email = TestEmail::new()
+///     .received(datetime!(2016-05-21 10:00 UTC))
+///     .flags("RS")
+///     .size(4096);
+/// let mail = maildir.add(&email);
+/// assert_eq!(std::fs::metadata(mail.path()).unwrap().len(), 4096);
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TestEmail {
+    received: Option<OffsetDateTime>,
+    flags: String,
+    size: usize,
+    headers: Vec<(String, String)>,
+    in_new: bool,
+}
+
+impl Default for TestEmail {
+    fn default() -> Self {
+        TestEmail {
+            received: Some(datetime!(2016-02-01 10:00 UTC)),
+            flags: "S".to_string(),
+            size: 0,
+            headers: vec![
+                ("From".to_string(), "sender@example.com".to_string()),
+                ("To".to_string(), "recipient@example.com".to_string()),
+                ("Subject".to_string(), "Test email".to_string()),
+            ],
+            in_new: false,
+        }
+    }
+}
+
+impl TestEmail {
+    /// Seen email from `sender@example.com`, received on February 1, 2016
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Date of the `Received` header
+    pub fn received(mut self, date: OffsetDateTime) -> Self {
+        self.received = Some(date);
+        self
+    }
+
+    /// Email without a `Received` header, whose date can't be found
+    pub fn undated(mut self) -> Self {
+        self.received = None;
+        self
+    }
+
+    /// Maildir flags, like `RS`
+    pub fn flags(mut self, flags: &str) -> Self {
+        self.flags = flags.to_string();
+        self
+    }
+
+    /// Size of the email file in bytes, reached by padding the body. The
+    /// smaller sizes give the email without padding.
+    pub fn size(mut self, size: usize) -> Self {
+        self.size = size;
+        self
+    }
+
+    /// Set a header, replacing the header with the same name
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Store the email in `new`, as not seen by a mail client yet
+    pub fn in_new(mut self) -> Self {
+        self.in_new = true;
+        self
+    }
+
+    /// Content of the email
+    pub fn data(&self) -> Vec<u8> {
+        let mut data = String::new();
+        if let Some(received) = self.received {
+            let date = received.format(&Rfc2822).unwrap();
+            data.push_str(&format!("Received: from a by b; {}\n", date));
+            data.push_str(&format!("Date: {}\n", date));
+        }
+        for (name, value) in &self.headers {
+            data.push_str(&format!("{}: {}\n", name, value));
+        }
+        data.push_str("\nbody\n");
+        // Lines of at most 76 characters
+        while data.len() < self.size {
+            let line = (self.size - data.len()).min(77);
+            data.push_str(&"x".repeat(line - 1));
+            data.push('\n');
+        }
+        data.into_bytes()
+    }
+
+    /// Store the email in a maildir, returning its id
+    pub fn store(&self, maildir: &Maildir) -> String {
+        match self.in_new {
+            true => maildir.store_new(&self.data()).unwrap(),
+            false => maildir
+                .store_cur_with_flags(&self.data(), &self.flags)
+                .unwrap(),
+        }
+    }
+}
+
+/// Temporary directory with an input maildir (`in`) and an output directory
+/// (`out`), removed when dropped
+pub struct TestMaildir {
+    basedir: Temp,
+    pub input_maildir: Maildir,
+    /// Output maildir of the tests archiving to a single folder
+    pub output_maildir: Maildir,
+}
+
+impl Default for TestMaildir {
+    fn default() -> Self {
+        let basedir = Temp::new_dir().unwrap();
+        let input_maildir = Maildir::from(basedir.join("in"));
+        input_maildir.create_dirs().unwrap();
+        let output_maildir = Maildir::from(basedir.join("out"));
+        TestMaildir {
+            basedir,
+            input_maildir,
+            output_maildir,
+        }
+    }
+}
+
+impl TestMaildir {
+    /// Empty input maildir, without an output directory
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Temporary directory of the maildirs
+    pub fn path(&self) -> &Path {
+        &self.basedir
+    }
+
+    /// Output directory of the archive runs
+    pub fn output_dir(&self) -> PathBuf {
+        self.output_maildir.path().to_path_buf()
+    }
+
+    /// Maildir++ subfolder of the input maildir, like `.Sent`, created if
+    /// missing
+    pub fn folder(&self, name: &str) -> Maildir {
+        let folder = Maildir::from(self.input_maildir.path().join(name));
+        folder.create_dirs().unwrap();
+        folder
+    }
+
+    /// Store an email in the input maildir
+    pub fn add(&self, email: &TestEmail) -> MailEntry {
+        let id = email.store(&self.input_maildir);
+        self.input_maildir.find(&id).unwrap()
+    }
+
+    /// Archive folder of the output directory, like `2016`
+    pub fn archive_folder(&self, bucket: &str) -> Maildir {
+        Maildir::from(self.output_maildir.path().join(bucket))
+    }
+}
@@ -0,0 +1,138 @@
+use crate::archiver::{line_ending, provenance_headers, MaildirArchiverError, StoreOptions};
+use crate::encryption::Encryptor;
+use crate::redact::HeaderRedactor;
+use crate::strip::AttachmentStripper;
+use maildir::Maildir;
+use serde::{Deserialize, Serialize};
+
+/// Trait implemented by the transformations applied to the content of the
+/// emails between reading them from the source maildir and storing them.
+pub trait MessageTransformer {
+    fn transform(
+        &self,
+        data: Vec<u8>,
+        from_maildir: &Maildir,
+    ) -> Result<Vec<u8>, MaildirArchiverError>;
+}
+
+/// Step of the transformation pipeline
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TransformStep {
+    Redact,
+    Stamp,
+    Strip,
+    Encrypt,
+}
+
+/// Order of the steps when not given with `--transform-order`
+pub const DEFAULT_TRANSFORM_ORDER: [TransformStep; 4] = [
+    TransformStep::Redact,
+    TransformStep::Stamp,
+    TransformStep::Strip,
+    TransformStep::Encrypt,
+];
+
+/// Complete order of the pipeline: the steps of `order`, followed by the
+/// missing ones in the default order
+pub fn transform_order(order: &[TransformStep]) -> Vec<TransformStep> {
+    let mut steps = Vec::new();
+    for step in order.iter().chain(&DEFAULT_TRANSFORM_ORDER) {
+        if !steps.contains(step) {
+            steps.push(*step);
+        }
+    }
+    steps
+}
+
+impl MessageTransformer for HeaderRedactor {
+    fn transform(&self, data: Vec<u8>, _: &Maildir) -> Result<Vec<u8>, MaildirArchiverError> {
+        Ok(self.redact(&data)?)
+    }
+}
+
+/// Transformer adding the `X-Archived-At` and `X-Original-Maildir` headers
+pub struct ProvenanceStamper {}
+
+impl MessageTransformer for ProvenanceStamper {
+    fn transform(
+        &self,
+        data: Vec<u8>,
+        from_maildir: &Maildir,
+    ) -> Result<Vec<u8>, MaildirArchiverError> {
+        let mut stamped = provenance_headers(from_maildir, line_ending(&data))?.into_bytes();
+        stamped.extend_from_slice(&data);
+        Ok(stamped)
+    }
+}
+
+impl MessageTransformer for AttachmentStripper {
+    fn transform(&self, data: Vec<u8>, _: &Maildir) -> Result<Vec<u8>, MaildirArchiverError> {
+        self.strip(&data)
+    }
+}
+
+impl MessageTransformer for Encryptor {
+    fn transform(&self, data: Vec<u8>, _: &Maildir) -> Result<Vec<u8>, MaildirArchiverError> {
+        self.encrypt(&data)
+    }
+}
+
+impl StoreOptions {
+    /// Transformers enabled by the options, in the order of the pipeline
+    pub fn transformers(&self) -> Vec<&dyn MessageTransformer> {
+        transform_order(&self.transform_order)
+            .into_iter()
+            .filter_map(|step| -> Option<&dyn MessageTransformer> {
+                match step {
+                    TransformStep::Redact => Some(self.header_redactor.as_ref()?),
+                    TransformStep::Stamp => self.stamp_provenance.then_some(&ProvenanceStamper {}),
+                    TransformStep::Strip => Some(self.attachment_stripper.as_ref()?),
+                    TransformStep::Encrypt => Some(self.encryptor.as_ref()?),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::archiver::StoreOptions;
+    use crate::redact::{HeaderRedactor, RedactMode};
+    use crate::transform::{transform_order, TransformStep};
+    use maildir::Maildir;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_transform_order() {
+        assert_eq!(
+            transform_order(&[TransformStep::Encrypt, TransformStep::Redact]),
+            [
+                TransformStep::Encrypt,
+                TransformStep::Redact,
+                TransformStep::Stamp,
+                TransformStep::Strip
+            ]
+        );
+
+        // Stamping after redacting keeps the provenance headers
+        let redacted = ["X-Archived-At".to_string()];
+        let mut options = StoreOptions {
+            header_redactor: Some(HeaderRedactor::new(&redacted, RedactMode::Remove)),
+            stamp_provenance: true,
+            ..Default::default()
+        };
+        let maildir = Maildir::from(PathBuf::from("testdata/maildir1"));
+        let apply = |options: &StoreOptions| {
+            let data = b"Subject: a\r\n\r\nbody\r\n".to_vec();
+            let data = options
+                .transformers()
+                .iter()
+                .fold(data, |data, t| t.transform(data, &maildir).unwrap());
+            String::from_utf8(data).unwrap()
+        };
+        assert!(apply(&options).contains("X-Archived-At"));
+        options.transform_order = vec![TransformStep::Stamp];
+        assert!(!apply(&options).contains("X-Archived-At"));
+    }
+}
@@ -0,0 +1,587 @@
+//! Batched reading of the source emails and writing of the archived copies
+//! with io_uring, on Linux.
+//!
+//! The ring is set up with the raw system calls: the emails selected for
+//! archiving are read ahead by batches of one submission, and the archivers
+//! take their content from the read-ahead cache instead of reading the
+//! files one by one. With `--batch-writes`, the maildir sink writes the
+//! archived copies by batches too, each write followed by the fsync of its
+//! file in the same submission.
+
+use log::{debug, warn};
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+
+/// Number of emails read by a submission
+pub const READ_AHEAD_DEPTH: u32 = 64;
+
+/// Number of emails written by a submission, with a write and an fsync each
+pub const WRITE_BATCH_DEPTH: u32 = 32;
+
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x8000000;
+const IORING_OFF_SQES: libc::off_t = 0x10000000;
+const IORING_ENTER_GETEVENTS: libc::c_uint = 1;
+const IORING_OP_FSYNC: u8 = 3;
+const IORING_OP_READ: u8 = 22;
+const IORING_OP_WRITE: u8 = 23;
+/// Flag of a submission entry whose completion starts the next entry
+const IOSQE_IO_LINK: u8 = 1 << 2;
+
+#[repr(C)]
+#[derive(Default)]
+struct SqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets,
+}
+
+/// Submission queue entry
+#[repr(C)]
+#[derive(Default)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    rw_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    addr3: u64,
+    pad: u64,
+}
+
+/// Completion queue entry
+#[repr(C)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+/// Memory mapping of a part of the ring
+struct Mapping {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+impl Mapping {
+    fn new(fd: libc::c_int, len: usize, offset: libc::off_t) -> io::Result<Self> {
+        // SAFETY: a shared mapping of the ring file descriptor, checked below
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                offset,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mapping { ptr, len })
+    }
+
+    /// Pointer at an offset of the mapping, given by the kernel
+    fn at<T>(&self, offset: u32) -> *mut T {
+        // SAFETY: the offsets given by the kernel are inside the mapping
+        unsafe { self.ptr.cast::<u8>().add(offset as usize).cast() }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // SAFETY: the mapping is not used after the drop
+        unsafe { libc::munmap(self.ptr, self.len) };
+    }
+}
+
+/// io_uring instance used by a single thread at a time
+pub struct Ring {
+    fd: libc::c_int,
+    /// Reads may still be in flight after a failed submission: the ring is
+    /// no longer used
+    broken: bool,
+    params: Params,
+    sq: Mapping,
+    cq: Mapping,
+    sqes: Mapping,
+}
+
+// SAFETY: the mappings are only accessed through &mut self
+unsafe impl Send for Ring {}
+
+impl Ring {
+    /// Set up a ring with room for `entries` submissions
+    pub fn new(entries: u32) -> io::Result<Self> {
+        let mut params = Params::default();
+        // SAFETY: `params` is a valid io_uring_params struct
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_setup,
+                entries,
+                &mut params as *mut Params,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = fd as libc::c_int;
+        let close = |e| {
+            // SAFETY: the file descriptor was returned by io_uring_setup
+            unsafe { libc::close(fd) };
+            e
+        };
+        let sq_len = params.sq_off.array as usize + params.sq_entries as usize * 4;
+        let cq_len =
+            params.cq_off.cqes as usize + params.cq_entries as usize * std::mem::size_of::<Cqe>();
+        let sqes_len = params.sq_entries as usize * std::mem::size_of::<Sqe>();
+        let sq = Mapping::new(fd, sq_len, IORING_OFF_SQ_RING).map_err(close)?;
+        let cq = Mapping::new(fd, cq_len, IORING_OFF_CQ_RING).map_err(close)?;
+        let sqes = Mapping::new(fd, sqes_len, IORING_OFF_SQES).map_err(close)?;
+        Ok(Ring {
+            fd,
+            broken: false,
+            params,
+            sq,
+            cq,
+            sqes,
+        })
+    }
+
+    /// Read from the start of each file into its buffer, sized to the file,
+    /// with one submission per batch of the ring size. Returns the number of
+    /// bytes read in each buffer.
+    fn read_all(&mut self, reads: &mut [(&File, Vec<u8>)]) -> Vec<io::Result<usize>> {
+        let mut results: Vec<io::Result<usize>> = Vec::with_capacity(reads.len());
+        for chunk in reads.chunks_mut(self.params.sq_entries as usize) {
+            let entries = chunk
+                .iter_mut()
+                .map(|(file, buffer)| Sqe {
+                    opcode: IORING_OP_READ,
+                    fd: file.as_raw_fd(),
+                    addr: buffer.as_mut_ptr() as u64,
+                    len: buffer.len().min(u32::MAX as usize) as u32,
+                    ..Default::default()
+                })
+                .collect();
+            let completions = self.submit(entries);
+            for (completion, (_, buffer)) in completions.into_iter().zip(chunk.iter_mut()) {
+                results.push(completion.unwrap_or_else(|| {
+                    // The kernel may still write in the buffer: it is leaked
+                    // instead of being freed
+                    std::mem::forget(std::mem::take(buffer));
+                    Err(io::Error::other("io_uring read in flight"))
+                }));
+            }
+        }
+        results
+    }
+
+    /// Write each file from its start and fsync it, with one submission per
+    /// batch of half the ring size: the fsync of a file is linked to its
+    /// write, so that it only starts once the write is completed. The short
+    /// writes and the writes failed with io_uring are completed with the
+    /// standard IO.
+    fn write_all(&mut self, writes: &mut [(File, Vec<u8>)]) -> Vec<io::Result<()>> {
+        let mut results = Vec::with_capacity(writes.len());
+        for chunk in writes.chunks_mut(self.params.sq_entries as usize / 2) {
+            let mut entries = Vec::with_capacity(2 * chunk.len());
+            for (file, data) in chunk.iter() {
+                entries.push(Sqe {
+                    opcode: IORING_OP_WRITE,
+                    flags: IOSQE_IO_LINK,
+                    fd: file.as_raw_fd(),
+                    addr: data.as_ptr() as u64,
+                    len: data.len().min(u32::MAX as usize) as u32,
+                    ..Default::default()
+                });
+                entries.push(Sqe {
+                    opcode: IORING_OP_FSYNC,
+                    fd: file.as_raw_fd(),
+                    ..Default::default()
+                });
+            }
+            let mut completions = self.submit(entries).into_iter();
+            for (file, data) in chunk.iter_mut() {
+                let write = completions.next().unwrap();
+                let fsync = completions.next().unwrap();
+                results.push(match (write, fsync) {
+                    // The kernel may still read the data: it is leaked
+                    // instead of being freed
+                    (None, _) | (_, None) => {
+                        std::mem::forget(std::mem::take(data));
+                        Err(io::Error::other("io_uring write in flight"))
+                    }
+                    (Some(Ok(written)), Some(fsync)) if written == data.len() => fsync.map(|_| ()),
+                    // A short write cancels the linked fsync
+                    (Some(Ok(written)), _) => complete_write(file, data, written),
+                    (Some(Err(_)), _) => complete_write(file, data, 0),
+                });
+            }
+        }
+        results
+    }
+
+    /// Submit the entries in one submission and wait for their completions.
+    /// Returns the result of each entry, none for the entries still in
+    /// flight after a failed submission: the ring is then no longer used,
+    /// and the kernel may still use their buffers.
+    fn submit(&mut self, entries: Vec<Sqe>) -> Vec<Option<io::Result<usize>>> {
+        if self.broken {
+            return entries
+                .iter()
+                .map(|_| Some(Err(io::Error::other("io_uring ring disabled"))))
+                .collect();
+        }
+        let sq_off = &self.params.sq_off;
+        let tail = self.sq.at::<AtomicU32>(sq_off.tail);
+        // SAFETY: the ring mask is written by the kernel at the setup
+        let mask = unsafe { *self.sq.at::<u32>(sq_off.ring_mask) };
+        let array = self.sq.at::<u32>(sq_off.array);
+        let sqes = self.sqes.at::<Sqe>(0);
+        // SAFETY: the ring is only used by this thread, the entries are
+        // written before the tail is published
+        let first_tail = unsafe { (*tail).load(Ordering::Acquire) };
+        let mut sq_tail = first_tail;
+        let count = entries.len();
+        for (i, entry) in entries.into_iter().enumerate() {
+            let index = sq_tail & mask;
+            // SAFETY: the index is masked to the ring entries, and the
+            // buffers of the entries stay alive until their completion
+            unsafe {
+                *sqes.add(index as usize) = Sqe {
+                    user_data: i as u64,
+                    ..entry
+                };
+                *array.add(index as usize) = index;
+            }
+            sq_tail = sq_tail.wrapping_add(1);
+        }
+        // SAFETY: the tail is an atomic of the mapped submission ring
+        unsafe { (*tail).store(sq_tail, Ordering::Release) };
+
+        let mut results: Vec<Option<io::Result<usize>>> = (0..count)
+            .map(|_| Some(Err(io::Error::other("io_uring entry not completed"))))
+            .collect();
+        let mut completed = vec![false; count];
+        let mut pending = count;
+        let cq_off = &self.params.cq_off;
+        let cq_head = self.cq.at::<AtomicU32>(cq_off.head);
+        let cq_tail = self.cq.at::<AtomicU32>(cq_off.tail);
+        // SAFETY: the ring mask is written by the kernel at the setup
+        let cq_mask = unsafe { *self.cq.at::<u32>(cq_off.ring_mask) };
+        let cqes = self.cq.at::<Cqe>(cq_off.cqes);
+        let mut to_submit = count;
+        while pending > 0 {
+            // SAFETY: the buffers of the submitted entries outlive the call,
+            // which waits for all their completions or lets the caller leak
+            // the buffers
+            let res = unsafe {
+                libc::syscall(
+                    libc::SYS_io_uring_enter,
+                    self.fd,
+                    to_submit as libc::c_uint,
+                    1 as libc::c_uint,
+                    IORING_ENTER_GETEVENTS,
+                    ptr::null::<libc::sigset_t>(),
+                    0usize,
+                )
+            };
+            if res < 0 {
+                let e = io::Error::last_os_error();
+                match e.raw_os_error() {
+                    Some(libc::EINTR | libc::EAGAIN | libc::EBUSY) => continue,
+                    // Nothing was submitted: the entries are withdrawn and
+                    // the files are read or written without the ring
+                    _ if to_submit == count => {
+                        debug!("io_uring_enter failed: {}", e);
+                        // SAFETY: the kernel consumed none of the entries
+                        unsafe { (*tail).store(first_tail, Ordering::Release) };
+                        break;
+                    }
+                    // The kernel may still use the buffers of the entries in
+                    // flight, and the next files are read or written without
+                    // the ring
+                    _ => {
+                        warn!("io_uring_enter failed with entries in flight: {}", e);
+                        for (done, result) in completed.iter().zip(results.iter_mut()) {
+                            if !done {
+                                *result = None;
+                            }
+                        }
+                        self.broken = true;
+                        break;
+                    }
+                }
+            }
+            to_submit -= (res as usize).min(to_submit);
+            // SAFETY: the head and the tail are atomics of the mapped
+            // completion ring
+            let mut head = unsafe { (*cq_head).load(Ordering::Acquire) };
+            let tail = unsafe { (*cq_tail).load(Ordering::Acquire) };
+            while head != tail {
+                // SAFETY: the entries between the head and the tail are
+                // written by the kernel, the index is masked to the ring
+                let cqe = unsafe { &*cqes.add((head & cq_mask) as usize) };
+                let i = cqe.user_data as usize;
+                results[i] = Some(match cqe.res {
+                    res if res < 0 => Err(io::Error::from_raw_os_error(-res)),
+                    res => Ok(res as usize),
+                });
+                completed[i] = true;
+                pending -= 1;
+                head = head.wrapping_add(1);
+            }
+            // SAFETY: the completions up to the head have been read
+            unsafe { (*cq_head).store(head, Ordering::Release) };
+        }
+        results
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        // SAFETY: the file descriptor was returned by io_uring_setup
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/// Read a file with io_uring, completing a short read with the standard
+/// IO
+fn complete(mut file: &File, mut buffer: Vec<u8>, read: usize) -> io::Result<Vec<u8>> {
+    buffer.truncate(read);
+    file.seek(SeekFrom::Start(read as u64))?;
+    file.read_to_end(&mut buffer)?;
+    Ok(buffer)
+}
+
+/// Complete a file written with io_uring with the standard IO, from the
+/// `written` bytes of its data, and fsync it
+fn complete_write(file: &File, data: &[u8], written: usize) -> io::Result<()> {
+    file.write_all_at(&data[written..], written as u64)?;
+    file.sync_all()
+}
+
+/// Writer of files by batches with io_uring, see [`WRITE_BATCH_DEPTH`]
+pub struct BatchWriter(Mutex<Ring>);
+
+impl BatchWriter {
+    /// Batch writer, none if io_uring is not available
+    pub fn create() -> Option<Self> {
+        match Ring::new(2 * WRITE_BATCH_DEPTH) {
+            Ok(ring) => Some(BatchWriter(Mutex::new(ring))),
+            Err(e) => {
+                debug!("io_uring not available: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Write each file from its start and fsync it, returning the result of
+    /// each file
+    pub fn write(&self, writes: &mut [(File, Vec<u8>)]) -> Vec<io::Result<()>> {
+        self.0.lock().unwrap().write_all(writes)
+    }
+}
+
+/// Read-ahead cache of the emails to archive, filled by batches with
+/// io_uring
+pub struct ReadAhead {
+    ring: Mutex<Ring>,
+    emails: Mutex<HashMap<PathBuf, Vec<u8>>>,
+}
+
+impl ReadAhead {
+    /// Read-ahead cache, none if io_uring is not available, like in the
+    /// containers forbidding it
+    pub fn create() -> Option<Self> {
+        match Ring::new(READ_AHEAD_DEPTH) {
+            Ok(ring) => Some(ReadAhead {
+                ring: Mutex::new(ring),
+                emails: Mutex::new(HashMap::new()),
+            }),
+            Err(e) => {
+                debug!("io_uring not available: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Read files into the cache, in a single batch
+    pub fn load(&self, paths: &[PathBuf]) {
+        let mut files = Vec::new();
+        for path in paths {
+            let opened = File::open(path).and_then(|file| {
+                let size = file.metadata()?.len() as usize;
+                Ok((path, file, size))
+            });
+            match opened {
+                Ok(file) => files.push(file),
+                // The error is reported when the email is read again
+                Err(e) => debug!("Cannot read ahead {}: {}", path.display(), e),
+            }
+        }
+        let mut reads: Vec<_> = files
+            .iter()
+            .map(|(_, file, size)| (file, vec![0; *size]))
+            .collect();
+        let results = self.ring.lock().unwrap().read_all(&mut reads);
+        let mut emails = self.emails.lock().unwrap();
+        let buffers = reads.into_iter().map(|(_, buffer)| buffer);
+        for ((path, file, _), (buffer, res)) in files.iter().zip(buffers.zip(results)) {
+            match res.and_then(|read| complete(file, buffer, read)) {
+                Ok(data) => {
+                    emails.insert(path.to_path_buf(), data);
+                }
+                Err(e) => debug!("Cannot read ahead {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    /// Take the content of a file from the cache, none if it was not read
+    /// ahead
+    pub fn take(&self, path: &Path) -> Option<Vec<u8>> {
+        self.emails.lock().unwrap().remove(path)
+    }
+
+    /// Drop the content of files that were read ahead but not taken
+    pub fn forget(&self, paths: &[PathBuf]) {
+        let mut emails = self.emails.lock().unwrap();
+        for path in paths {
+            emails.remove(path);
+        }
+    }
+
+    /// Iterator over `items` reading ahead their files by batches of
+    /// [`READ_AHEAD_DEPTH`]. The files of a batch not taken by the archivers
+    /// are dropped from the cache with the next batch.
+    pub fn batches<'a, T: 'a>(
+        &'a self,
+        mut items: impl Iterator<Item = T> + 'a,
+        path: impl Fn(&T) -> &Path + 'a,
+    ) -> impl Iterator<Item = T> + 'a {
+        let mut batch = VecDeque::new();
+        let mut loaded = Vec::new();
+        std::iter::from_fn(move || {
+            if batch.is_empty() {
+                self.forget(&loaded);
+                batch.extend(items.by_ref().take(READ_AHEAD_DEPTH as usize));
+                loaded = batch.iter().map(|item| path(item).to_path_buf()).collect();
+                self.load(&loaded);
+            }
+            batch.pop_front()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::uring::{ReadAhead, Ring};
+    use std::fs::File;
+
+    #[test]
+    fn test_read_ahead() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let paths: Vec<_> = (0..5)
+            .map(|n| {
+                let path = basedir.join(n.to_string());
+                std::fs::write(&path, "x".repeat(n * 1000)).unwrap();
+                path
+            })
+            .collect();
+        // io_uring may be forbidden, like in some containers
+        let Ok(mut ring) = Ring::new(2) else {
+            return;
+        };
+        let files: Vec<_> = paths.iter().map(|p| File::open(p).unwrap()).collect();
+        let mut reads: Vec<_> = files.iter().map(|f| (f, vec![0; 4000])).collect();
+        let results = ring.read_all(&mut reads);
+        let sizes: Vec<_> = results.into_iter().map(Result::unwrap).collect();
+        assert_eq!(sizes, [0, 1000, 2000, 3000, 4000]);
+        // A broken ring leaves the reads to the standard IO
+        ring.broken = true;
+        assert!(ring.read_all(&mut reads).iter().all(Result::is_err));
+
+        let read_ahead = ReadAhead::create().unwrap();
+        let items: Vec<_> = read_ahead
+            .batches(paths.iter(), |path| path.as_path())
+            .map(|path| read_ahead.take(path).unwrap())
+            .collect();
+        assert_eq!(items[4], "x".repeat(4000).as_bytes());
+        assert!(read_ahead.take(&paths[0]).is_none());
+    }
+
+    #[test]
+    fn test_write_all() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let Ok(mut ring) = Ring::new(4) else {
+            return;
+        };
+        let paths: Vec<_> = (0..5).map(|n| basedir.join(n.to_string())).collect();
+        let mut writes: Vec<_> = paths
+            .iter()
+            .enumerate()
+            .map(|(n, path)| (File::create(path).unwrap(), vec![b'x'; n * 1000]))
+            .collect();
+        assert!(ring.write_all(&mut writes).iter().all(Result::is_ok));
+        assert_eq!(std::fs::read(&paths[4]).unwrap(), vec![b'x'; 4000]);
+        // A broken ring leaves the writes to the standard IO
+        ring.broken = true;
+        writes[2].1 = b"y".to_vec();
+        assert!(ring.write_all(&mut writes).iter().all(Result::is_ok));
+        let expected = [&b"y"[..], &[b'x'; 1999]].concat();
+        assert_eq!(std::fs::read(&paths[2]).unwrap(), expected);
+    }
+}
@@ -0,0 +1,127 @@
+//! IMAP modified UTF-7 (RFC 3501, section 5.1.3), used by the IMAP servers
+//! to store the non-ASCII folder names on disk (`.&ANY-ffentlich` for
+//! `.Öffentlich`).
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+,";
+
+/// Encoding of the folder names of the archive
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NameEncoding {
+    /// Keep the names of the source folders
+    Preserve,
+    /// Decode the names of the source folders to UTF-8
+    Utf8,
+    /// Encode the names in IMAP modified UTF-7
+    ImapUtf7,
+}
+
+impl NameEncoding {
+    /// Name of an archive folder for the (possibly encoded) name `name`
+    pub fn apply(&self, name: &str) -> String {
+        match self {
+            NameEncoding::Preserve => name.to_string(),
+            NameEncoding::Utf8 => decode(name).unwrap_or_else(|| name.to_string()),
+            NameEncoding::ImapUtf7 => encode(&decode(name).unwrap_or_else(|| name.to_string())),
+        }
+    }
+}
+
+fn encode_utf16(units: &[u16], out: &mut String) {
+    let bytes: Vec<u8> = units.iter().flat_map(|u| u.to_be_bytes()).collect();
+    out.push('&');
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+        for i in 0..=chunk.len() {
+            out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+        }
+    }
+    out.push('-');
+}
+
+/// Encode a folder name in IMAP modified UTF-7
+pub fn encode(name: &str) -> String {
+    let mut out = String::new();
+    let mut shifted = Vec::new();
+    for c in name.chars() {
+        if (' '..='~').contains(&c) {
+            if !shifted.is_empty() {
+                encode_utf16(&shifted, &mut out);
+                shifted.clear();
+            }
+            match c {
+                '&' => out.push_str("&-"),
+                c => out.push(c),
+            }
+        } else {
+            let mut buf = [0; 2];
+            shifted.extend_from_slice(c.encode_utf16(&mut buf));
+        }
+    }
+    if !shifted.is_empty() {
+        encode_utf16(&shifted, &mut out);
+    }
+    out
+}
+
+/// Decode a folder name in IMAP modified UTF-7, returning `None` if the name
+/// is not valid modified UTF-7.
+pub fn decode(name: &str) -> Option<String> {
+    let mut out = String::new();
+    let mut rest = name;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        let end = start + rest[start..].find('-')?;
+        let encoded = &rest[start + 1..end];
+        if encoded.is_empty() {
+            out.push('&');
+        } else {
+            let mut bits = 0u32;
+            let mut nbits = 0;
+            let mut bytes = Vec::new();
+            for c in encoded.bytes() {
+                bits = bits << 6 | ALPHABET.iter().position(|a| *a == c)? as u32;
+                nbits += 6;
+                if nbits >= 8 {
+                    nbits -= 8;
+                    bytes.push((bits >> nbits) as u8);
+                    bits &= (1 << nbits) - 1;
+                }
+            }
+            if bytes.len() % 2 != 0 {
+                return None;
+            }
+            let units: Vec<u16> = bytes
+                .chunks(2)
+                .map(|b| u16::from_be_bytes([b[0], b[1]]))
+                .collect();
+            out.push_str(&String::from_utf16(&units).ok()?);
+        }
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::utf7::{decode, encode, NameEncoding};
+
+    #[test]
+    fn test_modified_utf7() {
+        assert_eq!(decode(".&ANY-ffentlich").as_deref(), Some(".Öffentlich"));
+        assert_eq!(decode("R&-D").as_deref(), Some("R&D"));
+        assert_eq!(decode("&ZeVnLIqe-").as_deref(), Some("日本語"));
+        assert_eq!(decode("&APY"), None);
+        assert_eq!(encode(".Öffentlich"), ".&ANY-ffentlich");
+        assert_eq!(encode("R&D 日本語"), "R&-D &ZeVnLIqe-");
+        assert_eq!(NameEncoding::Utf8.apply("&ANY-ffentlich"), "Öffentlich");
+        assert_eq!(NameEncoding::ImapUtf7.apply("Öffentlich"), "&ANY-ffentlich");
+        assert_eq!(
+            NameEncoding::ImapUtf7.apply("&ANY-ffentlich"),
+            "&ANY-ffentlich"
+        );
+    }
+}
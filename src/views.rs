@@ -0,0 +1,193 @@
+use crate::archiver::MaildirArchiverError;
+use crate::args::ViewsOptions;
+use crate::dedup::find_emails;
+use crate::split::folder_safe_address;
+use log::{debug, info};
+use maildir::Maildir;
+use mailparse::{addrparse_header, parse_headers, MailAddr, MailHeaderMap};
+use std::fs;
+use std::io;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use time::OffsetDateTime;
+
+/// Axis of a view of the archive
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ViewAxis {
+    /// Address of the `From` header
+    Sender,
+    /// Year of the `Date` header
+    Year,
+    /// Month of the `Date` header, like `2024-01`
+    Month,
+    /// Identifier of the `List-Id` header
+    List,
+}
+
+impl ViewAxis {
+    /// Name of the directory of the view
+    pub fn dir_name(&self) -> &'static str {
+        match self {
+            ViewAxis::Sender => "by-sender",
+            ViewAxis::Year => "by-year",
+            ViewAxis::Month => "by-month",
+            ViewAxis::List => "by-list",
+        }
+    }
+
+    /// Folder of an email in the view, none if the email has no value for
+    /// the axis
+    fn folder_name(&self, headers: &[mailparse::MailHeader]) -> Option<String> {
+        match self {
+            ViewAxis::Sender => {
+                let address = headers
+                    .get_first_header("From")
+                    .and_then(|h| addrparse_header(h).ok())
+                    .and_then(|addrs| {
+                        addrs.iter().find_map(|addr| match addr {
+                            MailAddr::Single(info) => Some(info.addr.clone()),
+                            MailAddr::Group(group) => {
+                                group.addrs.first().map(|info| info.addr.clone())
+                            }
+                        })
+                    })?;
+                folder_safe_address(&address)
+            }
+            ViewAxis::Year | ViewAxis::Month => {
+                let timestamp = mailparse::dateparse(&headers.get_first_value("Date")?).ok()?;
+                let date = OffsetDateTime::from_unix_timestamp(timestamp).ok()?;
+                Some(match self {
+                    ViewAxis::Year => format!("{}", date.year()),
+                    _ => format!("{}-{:02}", date.year(), date.month() as u8),
+                })
+            }
+            ViewAxis::List => {
+                let value = headers.get_first_value("List-Id")?;
+                let id = match (value.rfind('<'), value.rfind('>')) {
+                    (Some(start), Some(end)) if start < end => &value[start + 1..end],
+                    _ => value.as_str(),
+                };
+                folder_safe_address(id)
+            }
+        }
+    }
+}
+
+/// Result of a views build
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ViewsReport {
+    /// Number of archived emails found
+    pub emails: usize,
+    /// Number of symlinks created
+    pub links: usize,
+}
+
+/// Link an email in the folder of a view, as the maildir subdirectory of
+/// the email
+fn link_email(view_folder: &Path, email: &Path) -> io::Result<()> {
+    let maildir = Maildir::from(view_folder.to_path_buf());
+    maildir.create_dirs()?;
+    let subdir = email.parent().and_then(Path::file_name).unwrap();
+    let link = view_folder.join(subdir).join(email.file_name().unwrap());
+    match symlink(email, &link) {
+        // The email has been linked by a previous email with the same name
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+        res => res,
+    }
+}
+
+/// Build symlink farms over the archive: a directory per axis, like
+/// `by-sender`, with a maildir per value whose emails are symlinks to the
+/// archived emails. The views are rebuilt from scratch, the archive is not
+/// changed.
+pub fn build_views(opts: &ViewsOptions) -> Result<ViewsReport, MaildirArchiverError> {
+    let archive_dir = fs::canonicalize(&opts.archive_dir)?;
+    let views_dir = opts
+        .views_dir
+        .clone()
+        .unwrap_or_else(|| archive_dir.join("views"));
+    // The symlinks of the views are not regular files, find_emails skips them
+    let mut emails: Vec<PathBuf> = Vec::new();
+    find_emails(&archive_dir, &mut emails)?;
+    emails.sort();
+    for axis in &opts.axes {
+        let dir = views_dir.join(axis.dir_name());
+        if dir.exists() {
+            fs::remove_dir_all(&dir)?;
+        }
+    }
+    let mut report = ViewsReport {
+        emails: emails.len(),
+        links: 0,
+    };
+    for email in &emails {
+        let data = fs::read(email)?;
+        let headers = match parse_headers(&data) {
+            Ok((headers, _)) => headers,
+            Err(e) => {
+                debug!("Invalid email {}: {}", email.display(), e);
+                continue;
+            }
+        };
+        for axis in &opts.axes {
+            if let Some(name) = axis.folder_name(&headers) {
+                link_email(&views_dir.join(axis.dir_name()).join(name), email)?;
+                report.links += 1;
+            }
+        }
+    }
+    info!("Views built in {}", views_dir.display());
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::args::ViewsOptions;
+    use crate::views::{build_views, ViewAxis};
+    use maildir::Maildir;
+
+    #[test]
+    fn test_build_views() {
+        use mktemp::Temp;
+        let basedir = Temp::new_dir().unwrap();
+        let archive = Maildir::from(basedir.join("2016"));
+        archive.create_dirs().unwrap();
+        for (from, list) in [
+            ("A@example.com", "<a.lists.example.com>"),
+            ("b@example.com", ""),
+        ] {
+            let data = format!(
+                "From: Someone <{}>\nDate: Sat, 21 May 2016 22:08:25 +0000\nList-Id: {}\n\nbody\n",
+                from, list
+            );
+            archive.store_cur_with_flags(data.as_bytes(), "S").unwrap();
+        }
+        let opts = ViewsOptions {
+            archive_dir: basedir.to_path_buf(),
+            views_dir: None,
+            axes: vec![ViewAxis::Sender, ViewAxis::Month, ViewAxis::List],
+        };
+        let report = build_views(&opts).unwrap();
+        assert_eq!((report.emails, report.links), (2, 5));
+        let views = basedir.join("views");
+        let view = Maildir::from(views.join("by-sender").join("a@example.com"));
+        let mail = view.list_cur().next().unwrap().unwrap();
+        assert!(std::fs::symlink_metadata(mail.path())
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(
+            Maildir::from(views.join("by-month").join("2016-05")).count_cur(),
+            2
+        );
+        assert!(views.join("by-list").join("a.lists.example.com").is_dir());
+
+        // The views are rebuilt, without counting their own links
+        let report = build_views(&opts).unwrap();
+        assert_eq!((report.emails, report.links), (2, 5));
+        assert_eq!(
+            Maildir::from(views.join("by-month").join("2016-05")).count_cur(),
+            2
+        );
+    }
+}
@@ -0,0 +1,23 @@
+//! Runs of the archive-maildir program
+
+use mktemp::Temp;
+use std::fs;
+use std::process::Command;
+
+#[test]
+fn archive_without_logging() {
+    // The program is only built with the command line interface
+    let Some(program) = option_env!("CARGO_BIN_EXE_archive-maildir") else {
+        return;
+    };
+    let output = Temp::new_dir().unwrap();
+    let status = Command::new(program)
+        .args(["--mode", "copy", "--before", "2025-01-01"])
+        .arg(concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/maildir1"))
+        .arg(output.as_path())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let cur = output.as_path().join("2016").join("cur");
+    assert_eq!(fs::read_dir(cur).unwrap().count(), 1);
+}